@@ -0,0 +1,183 @@
+// a mailbox's acl, the A in its mbentry, with the rights as flags rather
+// than letters. the letters are rfc 4314's, which cyrus writes in this order
+// with c and d tacked on after e for clients that only know rfc 2086. c means
+// k and d means t and e, but only when they're there on their own
+//
+// an identifier with a - in front takes rights away rather than giving them
+
+use std::fmt;
+
+use bitflags::bitflags;
+
+use crate::mbentry::Mbentry;
+use crate::twoskip::Error;
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
+    pub struct Rights: u32 {
+        const LOOKUP = 1 << 0;
+        const READ = 1 << 1;
+        const SEEN = 1 << 2;
+        const WRITE = 1 << 3;
+        const INSERT = 1 << 4;
+        const POST = 1 << 5;
+        const CREATE = 1 << 6;
+        const DELETE_MAILBOX = 1 << 7;
+        const DELETE_MESSAGE = 1 << 8;
+        const EXPUNGE = 1 << 9;
+        const ADMIN = 1 << 10;
+        const ANNOTATE = 1 << 11;
+        // 0 to 9, for sites to give meanings of their own
+        const USER0 = 1 << 12;
+        const USER1 = 1 << 13;
+        const USER2 = 1 << 14;
+        const USER3 = 1 << 15;
+        const USER4 = 1 << 16;
+        const USER5 = 1 << 17;
+        const USER6 = 1 << 18;
+        const USER7 = 1 << 19;
+        const USER8 = 1 << 20;
+        const USER9 = 1 << 21;
+    }
+}
+
+const LETTERS: [(u8, Rights); 12] = [
+    (b'l', Rights::LOOKUP),
+    (b'r', Rights::READ),
+    (b's', Rights::SEEN),
+    (b'w', Rights::WRITE),
+    (b'i', Rights::INSERT),
+    (b'p', Rights::POST),
+    (b'k', Rights::CREATE),
+    (b'x', Rights::DELETE_MAILBOX),
+    (b't', Rights::DELETE_MESSAGE),
+    (b'e', Rights::EXPUNGE),
+    (b'a', Rights::ADMIN),
+    (b'n', Rights::ANNOTATE),
+];
+
+impl Rights {
+    // letters cyrus doesn't know it ignores, and so does this
+    pub fn from_letters(letters: &str) -> Rights {
+        let mut rights = Rights::empty();
+        let mut legacy = Rights::empty();
+        for c in letters.bytes() {
+            match c {
+                b'c' => legacy |= Rights::CREATE,
+                b'd' => legacy |= Rights::DELETE_MESSAGE | Rights::EXPUNGE,
+                b'0'..=b'9' => rights |= Rights::from_bits_retain(1 << (12 + c - b'0')),
+                _ => {
+                    if let Some((_, right)) = LETTERS.iter().find(|(l, _)| *l == c) {
+                        rights |= *right;
+                    }
+                }
+            }
+        }
+        if !rights.intersects(Rights::CREATE) {
+            rights |= legacy & Rights::CREATE;
+        }
+        if !rights.intersects(Rights::DELETE_MESSAGE | Rights::EXPUNGE) {
+            rights |= legacy & (Rights::DELETE_MESSAGE | Rights::EXPUNGE);
+        }
+        rights
+    }
+}
+
+impl fmt::Display for Rights {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut letters = String::new();
+        for &(c, right) in &LETTERS {
+            if self.contains(right) {
+                letters.push(c as char);
+            }
+            if c == b'e' {
+                if self.contains(Rights::CREATE) {
+                    letters.push('c');
+                }
+                if self.contains(Rights::DELETE_MESSAGE) {
+                    letters.push('d');
+                }
+            }
+        }
+        for n in 0..10u8 {
+            if self.contains(Rights::from_bits_retain(1 << (12 + n))) {
+                letters.push((b'0' + n) as char);
+            }
+        }
+        f.write_str(&letters)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Acl {
+    // in the order they were written, which cyrus keeps
+    entries: Vec<(String, Rights)>,
+}
+
+impl Acl {
+    pub fn new(entries: &[(String, String)]) -> Acl {
+        Acl {
+            entries: entries
+                .iter()
+                .map(|(id, letters)| (id.clone(), Rights::from_letters(letters)))
+                .collect(),
+        }
+    }
+
+    // the acl out of a mailboxes.db value
+    pub fn parse(value: &[u8]) -> Result<Acl, Error> {
+        Ok(Acl::new(&Mbentry::parse(value)?.acl))
+    }
+
+    // as Mbentry has them, to go back into one
+    pub fn entries(&self) -> Vec<(String, String)> {
+        self.iter()
+            .map(|(id, rights)| (id.to_string(), rights.to_string()))
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Rights)> {
+        self.entries.iter().map(|(id, rights)| (&id[..], *rights))
+    }
+
+    // what the acl says about identifier itself, empty if it's not there
+    pub fn get(&self, identifier: &str) -> Rights {
+        self.entries
+            .iter()
+            .find(|(id, _)| id == identifier)
+            .map_or(Rights::empty(), |(_, rights)| *rights)
+    }
+
+    // what identifier ends up with, counting anyone and what the - entries
+    // take away. groups and admins aren't known here, so they don't count
+    pub fn effective(&self, identifier: &str) -> Rights {
+        let given = self.get(identifier) | self.get("anyone");
+        let taken = self.get(&format!("-{}", identifier)) | self.get("-anyone");
+        given - taken
+    }
+
+    // add to what identifier has, putting it on the end if it's new
+    pub fn grant(&mut self, identifier: &str, rights: Rights) {
+        match self.entries.iter_mut().find(|(id, _)| id == identifier) {
+            Some((_, have)) => *have |= rights,
+            None => self.entries.push((identifier.to_string(), rights)),
+        }
+    }
+
+    // take away from what identifier has, and drop it once it has nothing
+    pub fn revoke(&mut self, identifier: &str, rights: Rights) {
+        let have = self.get(identifier);
+        self.set(identifier, have - rights);
+    }
+
+    // replace what identifier has, in the same place. nothing removes it
+    pub fn set(&mut self, identifier: &str, rights: Rights) {
+        match self.entries.iter_mut().find(|(id, _)| id == identifier) {
+            Some((_, have)) => *have = rights,
+            None => self.entries.push((identifier.to_string(), rights)),
+        }
+        self.entries
+            .retain(|(id, have)| id != identifier || !have.is_empty());
+    }
+}
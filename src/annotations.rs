@@ -0,0 +1,157 @@
+// annotations.db, where cyrus keeps METADATA (and the older ANNOTATEMORE)
+// for mailboxes and the server. a key is three nul terminated strings,
+//
+//   mboxname \0 entry \0 userid \0
+//
+// with no mboxname for the server's own annotations and no userid for
+// shared (value.shared) rather than private ones. a value is
+//
+//   length, value \0, content type \0, modseq, flags
+//
+// where length is a big endian u32 that cyrus writes through an unsigned
+// long, so on the 64 bit machines everyone has it's followed by 4 bytes of
+// 0. the modseq is a big endian u64, and of the flags only deleted means
+// anything. records from old versions stop after the content type, which
+// cyrus doesn't look at any more but still writes
+
+use std::iter::Peekable;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::twoskip::{Db, DbIter, Error};
+
+const DELETED: u8 = 1 << 0;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AnnotationKey {
+    pub mboxname: String,
+    pub entry: String,
+    pub userid: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Annotation {
+    pub value: Vec<u8>,
+    pub content_type: String,
+    pub modseq: u64,
+    pub deleted: bool,
+}
+
+// a mailbox's name and all its annotations, "" for the server's
+pub type Group = (String, Vec<(AnnotationKey, Annotation)>);
+
+pub struct ByMailbox<'a> {
+    records: Peekable<DbIter<'a>>,
+}
+
+impl AnnotationKey {
+    pub fn parse(key: &[u8]) -> Result<AnnotationKey, Error> {
+        let field = |s: &[u8]| {
+            std::str::from_utf8(s)
+                .map(str::to_string)
+                .map_err(|_| Error::InvalidField { field: "key" })
+        };
+        match key
+            .strip_suffix(b"\0")
+            .map(|k| k.split(|&c| c == 0).collect::<Vec<_>>())
+        {
+            Some(fields) if fields.len() == 3 => Ok(AnnotationKey {
+                mboxname: field(fields[0])?,
+                entry: field(fields[1])?,
+                userid: field(fields[2])?,
+            }),
+            _ => Err(Error::InvalidField { field: "key" }),
+        }
+    }
+
+    pub fn to_key(&self) -> Vec<u8> {
+        [&self.mboxname, &self.entry, &self.userid]
+            .iter()
+            .flat_map(|s| s.bytes().chain([0]))
+            .collect()
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.userid.is_empty()
+    }
+}
+
+impl Annotation {
+    pub fn parse(value: &[u8]) -> Result<Annotation, Error> {
+        let invalid = || Error::InvalidField { field: "value" };
+        let len = value
+            .get(..4)
+            .map(BigEndian::read_u32)
+            .ok_or_else(invalid)? as usize;
+        let data = value.get(8..).ok_or_else(invalid)?;
+        let v = data.get(..len).ok_or_else(invalid)?;
+        let rest = data.get(len + 1..).ok_or_else(invalid)?;
+        let end = rest.iter().position(|&c| c == 0).ok_or_else(invalid)?;
+        let content_type = std::str::from_utf8(&rest[..end]).map_err(|_| invalid())?;
+        let rest = &rest[end + 1..];
+        Ok(Annotation {
+            value: v.to_vec(),
+            content_type: content_type.to_string(),
+            modseq: rest.get(..8).map_or(0, BigEndian::read_u64),
+            deleted: rest.get(8).is_some_and(|&flags| flags & DELETED != 0),
+        })
+    }
+
+    pub fn to_value(&self) -> Vec<u8> {
+        let mut out = vec![0; 8];
+        BigEndian::write_u32(&mut out[..4], self.value.len() as u32);
+        out.extend_from_slice(&self.value);
+        out.push(0);
+        out.extend_from_slice(self.content_type.as_bytes());
+        out.push(0);
+        let mut modseq = [0; 8];
+        BigEndian::write_u64(&mut modseq, self.modseq);
+        out.extend_from_slice(&modseq);
+        out.push(if self.deleted { DELETED } else { 0 });
+        out
+    }
+}
+
+// every annotation, a mailbox at a time. a mailbox's keys are all together
+// in the file, since the nul after its name sorts before anything that
+// could make a longer name
+pub fn by_mailbox(db: &Db) -> ByMailbox<'_> {
+    ByMailbox {
+        records: db.iter_live().peekable(),
+    }
+}
+
+impl<'a> Iterator for ByMailbox<'a> {
+    type Item = Result<Group, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut group: Option<Group> = None;
+        loop {
+            if let (Some(Ok(r)), Some((name, _))) = (self.records.peek(), &group) {
+                let same = r
+                    .key()
+                    .strip_prefix(name.as_bytes())
+                    .is_some_and(|rest| rest.starts_with(b"\0"));
+                if !same {
+                    break;
+                }
+            }
+            let r = match self.records.next() {
+                Some(Ok(r)) => r,
+                Some(Err(e)) => return Some(Err(e)),
+                None => break,
+            };
+            let parsed = AnnotationKey::parse(r.key())
+                .and_then(|key| Ok((key, Annotation::parse(r.value())?)));
+            let (key, annotation) = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => return Some(Err(e)),
+            };
+            match group {
+                Some((_, ref mut annotations)) => annotations.push((key, annotation)),
+                None => group = Some((key.mboxname.clone(), vec![(key, annotation)])),
+            }
+        }
+        group.map(Ok)
+    }
+}
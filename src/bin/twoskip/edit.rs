@@ -0,0 +1,201 @@
+// changing one value by hand: it goes out to a file, $EDITOR gets that, and
+// whatever's saved goes back in if nobody else changed the key meanwhile.
+// cyrus keeps most things as dlists, which are one long line; --dlist spreads
+// those out a pair to a line and joins them up again afterwards, written
+// back the way cyrus writes them
+
+use std::env;
+use std::error::Error as StdError;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use twoskip::dlist::{self, Dlist};
+use twoskip::twoskip::{Error, OpenOptions};
+
+pub fn run(path: &OsString, key: &OsString, dlist: bool) -> Result<i32, Box<dyn StdError>> {
+    let key = key.as_bytes();
+    let old = OpenOptions::new()
+        .read_only(true)
+        .open(path)?
+        .get_value_owned(key)?;
+    let value = old.clone().unwrap_or_default();
+
+    let pretty = match dlist {
+        true => match pretty(&value) {
+            Some(pretty) => Some(pretty),
+            None => {
+                eprintln!("twoskip: not a dlist I can lay out, editing it as it is");
+                None
+            }
+        },
+        false => None,
+    };
+    let mut text = pretty.clone().unwrap_or_else(|| value.clone());
+    // editors want to end the file with a newline, so give them one and take
+    // it off again after
+    text.push(b'\n');
+
+    // values can be anything, so nobody else gets to see them or swap the
+    // file for one of their own
+    let dir = private_dir()?;
+    let tmp = dir.join("value");
+    let edited = write_new(&tmp, &text)
+        .and_then(|_| edit_file(&tmp))
+        .and_then(|_| fs::read(&tmp).map_err(|e| e.into()));
+    let mut edited = match edited {
+        Ok(edited) => edited,
+        Err(e) => {
+            fs::remove_dir_all(&dir).ok();
+            return Err(e);
+        }
+    };
+
+    if edited == text {
+        fs::remove_dir_all(&dir).ok();
+        eprintln!("twoskip: unchanged");
+        return Ok(0);
+    }
+    if edited.last() == Some(&b'\n') {
+        edited.pop();
+    }
+    let new = match pretty {
+        Some(_) => match flatten(&edited) {
+            Ok(new) => new,
+            // keep what was typed, it might have taken a while
+            Err(e) => {
+                let msg = format!(
+                    "that's not a dlist any more ({}), it's in {}",
+                    e,
+                    tmp.display()
+                );
+                return Err(msg.into());
+            }
+        },
+        None => edited,
+    };
+    fs::remove_dir_all(&dir).ok();
+
+    let mut db = OpenOptions::new().open(path)?;
+    match db.store_if(key, old.as_deref(), &new) {
+        Ok(()) => Ok(0),
+        Err(Error::ValueMismatch) => {
+            Err("the key changed while it was being edited, so it's been left alone".into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// a directory only we can get into, made fresh, like mkdtemp
+fn private_dir() -> Result<PathBuf, Box<dyn StdError>> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    for n in 0..100 {
+        let dir = env::temp_dir().join(format!(
+            "twoskip-edit-{}-{:08x}-{}",
+            process::id(),
+            nanos,
+            n
+        ));
+        match fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err("couldn't make a directory to edit in".into())
+}
+
+fn write_new(path: &Path, data: &[u8]) -> Result<(), Box<dyn StdError>> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(data)?;
+    Ok(())
+}
+
+// $EDITOR can have arguments of its own, so let the shell split it up
+fn edit_file(file: &Path) -> Result<(), Box<dyn StdError>> {
+    let editor = env::var_os("VISUAL")
+        .or_else(|| env::var_os("EDITOR"))
+        .unwrap_or_else(|| OsString::from("vi"));
+    let mut script = editor;
+    script.push(" \"$@\"");
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(&script)
+        .arg("sh")
+        .arg(file)
+        .status()?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(format!("editor failed ({}), nothing stored", status).into()),
+    }
+}
+
+// only if reading it back gives exactly what we started with, so that
+// saving without changes really changes nothing
+fn pretty(value: &[u8]) -> Option<Vec<u8>> {
+    let dlist = dlist::parse(value).ok()?;
+    if !matches!(dlist, Dlist::Map(_)) || dlist.to_vec() != value {
+        return None;
+    }
+    let mut out = vec![];
+    lay_out(&dlist, 0, &mut out);
+    match flatten(&out) {
+        Ok(ref flat) if flat == value => Some(out),
+        _ => None,
+    }
+}
+
+// back to one line, written the way cyrus would
+fn flatten(s: &[u8]) -> Result<Vec<u8>, Error> {
+    Ok(dlist::parse_multiline(s)?.to_vec())
+}
+
+// a map gets a line per pair, and a list stays on one line unless there's
+// another list or map inside
+fn lay_out(dlist: &Dlist, indent: usize, out: &mut Vec<u8>) {
+    let newline = |out: &mut Vec<u8>, indent: usize| {
+        out.push(b'\n');
+        out.resize(out.len() + 2 * indent, b' ');
+    };
+    match dlist {
+        Dlist::Map(pairs) if !pairs.is_empty() => {
+            out.extend_from_slice(b"%(");
+            for (key, value) in pairs {
+                newline(out, indent + 1);
+                out.extend_from_slice(&Dlist::Atom(key.clone()).to_vec());
+                out.push(b' ');
+                lay_out(value, indent + 1, out);
+            }
+            newline(out, indent);
+            out.push(b')');
+        }
+        Dlist::List(items)
+            if items
+                .iter()
+                .any(|i| matches!(i, Dlist::List(_) | Dlist::Map(_))) =>
+        {
+            out.push(b'(');
+            for item in items {
+                newline(out, indent + 1);
+                lay_out(item, indent + 1, out);
+            }
+            newline(out, indent);
+            out.push(b')');
+        }
+        _ => out.extend_from_slice(&dlist.to_vec()),
+    }
+}
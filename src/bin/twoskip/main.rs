@@ -0,0 +1,841 @@
+// command line access to twoskip files, for poking at cyrus databases
+// without writing a program to do it
+
+use std::env;
+use std::error::Error as StdError;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::process;
+
+use std::thread;
+use std::time::Duration;
+
+use twoskip::export::{
+    encode_base64, escape, from_db_dump, from_flat, from_quotalegacy, from_stream, to_cdb, to_csv,
+    to_flat, to_stream, Base64, CsvOptions, Quoting, StreamPosition,
+};
+use twoskip::skiplist;
+use twoskip::twoskip::{
+    diff as diff_dbs, merge as merge_dbs, salvage, Change, Db, DiffEntry, Error, OpenOptions,
+    ProblemKind, SizeStats,
+};
+
+mod edit;
+mod shell;
+
+const USAGE: &str = "usage: twoskip <command> [options] FILE [args]
+
+commands:
+    dump FILE             show every record in the file
+    get FILE KEY          print the value of KEY
+    list FILE [PREFIX]    print keys and values, all or just under PREFIX
+    set FILE KEY VALUE    store VALUE under KEY
+    del FILE KEY          delete KEY
+    import FILE IN        store every KEY<tab>VALUE line from IN (- for
+                          stdin) in one transaction, creating FILE if
+                          need be. --flat reads cyrus flat escaping, and
+                          --stream what export --format stream wrote
+    export FILE           write every record to stdout. --format is one of
+                          json, ndjson or tsv (the default), and --base64
+                          encodes the values. --format csv takes a
+                          --delimiter, --quote always or needed (the
+                          default), and --base64 for keys too or
+                          --base64-binary for only what isn't text.
+                          --format stream is for import --stream at the
+                          other end, and goes on after --resume GEN:OFFSET
+                          if a transfer broke off. --format cdb writes a
+                          constant database to --output NEW instead
+    verify FILE           check the whole file, and exit 0 if it's fine, 1 if
+                          it only needs recovery or a repack, 4 if records
+                          are damaged and 8 if it can't be checked at all
+    repack FILE           rewrite the file without its old and deleted
+                          records, or with --output NEW, write that to NEW
+                          and leave FILE alone
+    shell FILE            an interactive prompt for all of the above
+    diff FILE OTHER       list the keys only in FILE (-), only in OTHER (+)
+                          and in both with different values (!), and exit 1
+                          if there were any. --format json for json
+    stats FILE            counts, sizes and levels of the records, as json
+                          with --json
+    follow FILE           print records as they're committed, checking every
+                          --interval milliseconds (default 500)
+    hexdump FILE          the raw bytes of every record, labelled, starting
+                          from the header or the record at --offset
+    edit FILE KEY         change the value of KEY in $EDITOR, spreading it
+                          out over lines first with --dlist
+    explain FILE KEY      the way a lookup of KEY goes down the levels:
+                          each pointer followed and the key compared
+    recover FILE          roll back anything a crash left unfinished and
+                          check the rest. if that's damaged too, --salvage
+                          NEW copies everything still readable into NEW.
+                          exits like verify, except 1 means it fixed things
+    merge OUT IN...       write the live records of every IN into a new file
+                          OUT. where they disagree about a key, --on-conflict
+                          last-wins (the default), first-wins or fail
+    from-skiplist FILE IN write the records of IN, a file from cyrus' old
+                          skiplist backend, into a new FILE
+    from-db-dump FILE IN  the same for what db_dump printed of one of its
+                          berkeley databases, from IN or - for stdin
+    from-quotalegacy FILE DIR
+                          the quotas in DIR, the quota directory of cyrus'
+                          quotalegacy backend, and its domains' next to it
+
+anything that goes wrong exits 2, as bad usage does, except for verify and
+recover, which exit 8 if they couldn't finish checking";
+
+fn main() {
+    let args: Vec<OsString> = env::args_os().skip(1).collect();
+    match run(args) {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("twoskip: {}", e);
+            process::exit(2);
+        }
+    }
+}
+
+// verify and recover have exit codes of their own, where 1 is a file that
+// only needs recovery, so an error there means it wasn't checked
+fn or_unchecked(r: Result<i32, Box<dyn StdError>>) -> Result<i32, Box<dyn StdError>> {
+    r.or_else(|e| {
+        eprintln!("twoskip: {}", e);
+        Ok(UNCHECKED)
+    })
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+// pull --name out of the arguments, wherever it is
+fn take_flag(args: &mut Vec<OsString>, name: &str) -> bool {
+    match args.iter().position(|a| a == name) {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
+// and --name VALUE or --name=VALUE
+fn take_option(args: &mut Vec<OsString>, name: &str) -> Option<OsString> {
+    let mut prefix = name.as_bytes().to_vec();
+    prefix.push(b'=');
+    for i in 0..args.len() {
+        if args[i] == name {
+            if i + 1 == args.len() {
+                usage();
+            }
+            args.remove(i);
+            return Some(args.remove(i));
+        }
+        if let Some(value) = args[i].as_bytes().strip_prefix(prefix.as_slice()) {
+            let value = OsString::from(std::ffi::OsStr::from_bytes(value));
+            args.remove(i);
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn run(mut args: Vec<OsString>) -> Result<i32, Box<dyn StdError>> {
+    let flat = take_flag(&mut args, "--flat");
+    let stream = take_flag(&mut args, "--stream");
+    let base64 = take_flag(&mut args, "--base64");
+    let json = take_flag(&mut args, "--json");
+    let dlist = take_flag(&mut args, "--dlist");
+    let base64_binary = take_flag(&mut args, "--base64-binary");
+    let format = take_option(&mut args, "--format");
+    let mut csv = CsvOptions::new();
+    if let Some(d) = take_option(&mut args, "--delimiter") {
+        match d.as_bytes() {
+            [d] => csv.delimiter(*d),
+            _ => usage(),
+        };
+    }
+    match take_option(&mut args, "--quote")
+        .as_ref()
+        .map(|q| q.as_bytes())
+    {
+        None | Some(b"needed") => csv.quoting(Quoting::Needed),
+        Some(b"always") => csv.quoting(Quoting::Always),
+        Some(_) => usage(),
+    };
+    match (base64, base64_binary) {
+        (true, _) => csv.base64(Base64::Always),
+        (false, true) => csv.base64(Base64::Binary),
+        (false, false) => csv.base64(Base64::Never),
+    };
+    let output = take_option(&mut args, "--output");
+    let offset = take_option(&mut args, "--offset");
+    let resume = match take_option(&mut args, "--resume") {
+        Some(r) => match parse_position(&r) {
+            Some(r) => Some(r),
+            None => usage(),
+        },
+        None => None,
+    };
+    let salvage_to = take_option(&mut args, "--salvage");
+    let on_conflict = match take_option(&mut args, "--on-conflict") {
+        None => Conflict::LastWins,
+        Some(c) => match c.as_bytes() {
+            b"last-wins" => Conflict::LastWins,
+            b"first-wins" => Conflict::FirstWins,
+            b"fail" => Conflict::Fail,
+            _ => usage(),
+        },
+    };
+    let interval = match take_option(&mut args, "--interval") {
+        Some(ms) => match ms.to_str().and_then(|ms| ms.parse().ok()) {
+            Some(ms) => Duration::from_millis(ms),
+            None => usage(),
+        },
+        None => Duration::from_millis(500),
+    };
+    if args.iter().any(|a| a.as_bytes().starts_with(b"--")) {
+        usage();
+    }
+
+    let (cmd, path, rest) = match args.as_slice() {
+        [cmd, path, rest @ ..] => (cmd.as_bytes(), path, rest),
+        _ => usage(),
+    };
+
+    let out = io::stdout();
+    let mut out = out.lock();
+    match (cmd, rest) {
+        (b"dump", []) => {
+            open(path)?.dump_to(&mut out)?;
+            Ok(0)
+        }
+        (b"get", [key]) => match open(path)?.get(key.as_bytes())? {
+            Some(r) => {
+                out.write_all(r.value())?;
+                out.write_all(b"\n")?;
+                Ok(0)
+            }
+            None => Ok(1),
+        },
+        (b"list", []) | (b"list", [_]) => {
+            let prefix = rest.first().map(|p| p.as_bytes()).unwrap_or(b"");
+            let db = open(path)?;
+            for r in db.iter_prefix(prefix) {
+                let r = r?;
+                out.write_all(r.key())?;
+                out.write_all(b"\t")?;
+                out.write_all(r.value())?;
+                out.write_all(b"\n")?;
+            }
+            Ok(0)
+        }
+        (b"set", [key, value]) => {
+            open_rw(path, false)?.put(key.as_bytes(), value.as_bytes())?;
+            Ok(0)
+        }
+        (b"del", [key]) => match open_rw(path, false)?.delete(key.as_bytes())? {
+            true => Ok(0),
+            false => Ok(1),
+        },
+        (b"import", [input]) => import(path, input, flat, stream),
+        (b"export", []) if format.as_ref().map(|f| f.as_bytes()) == Some(b"cdb") => {
+            match output {
+                Some(ref output) => export_cdb(&open(path)?, output)?,
+                None => usage(),
+            }
+            Ok(0)
+        }
+        (b"export", []) if format.as_ref().map(|f| f.as_bytes()) == Some(b"stream") => {
+            to_stream(&open(path)?, &mut out, resume)?;
+            Ok(0)
+        }
+        (b"export", []) if format.as_ref().map(|f| f.as_bytes()) == Some(b"csv") => {
+            to_csv(&open(path)?, &mut out, &csv)?;
+            Ok(0)
+        }
+        (b"export", []) => {
+            let format = match format.as_ref().map(|f| f.as_bytes()) {
+                None | Some(b"tsv") => Format::Tsv,
+                Some(b"json") => Format::Json,
+                Some(b"ndjson") => Format::Ndjson,
+                Some(_) => usage(),
+            };
+            export(&open(path)?, &mut out, format, base64)?;
+            Ok(0)
+        }
+        (b"verify", []) => or_unchecked(verify(path, &mut out)),
+        (b"follow", []) => follow(path, &mut out, interval),
+        (b"recover", []) => or_unchecked(recover(path, &mut out, salvage_to.as_ref())),
+        (b"from-skiplist", [input]) => {
+            skiplist::open(input)?.to_twoskip(path)?;
+            Ok(0)
+        }
+        (b"from-quotalegacy", [dir]) => {
+            from_quotalegacy(dir, path)?;
+            Ok(0)
+        }
+        (b"from-db-dump", [input]) => {
+            match input.as_bytes() {
+                b"-" => from_db_dump(io::stdin().lock(), path)?,
+                _ => from_db_dump(BufReader::new(File::open(input)?), path)?,
+            };
+            Ok(0)
+        }
+        (b"merge", inputs) if !inputs.is_empty() => merge(path, inputs, on_conflict),
+        (b"edit", [key]) => {
+            drop(out);
+            edit::run(path, key, dlist)
+        }
+        (b"explain", [key]) => match open(path)?.explain_to(&mut out, key.as_bytes())? {
+            true => Ok(0),
+            false => Ok(1),
+        },
+        (b"hexdump", []) => {
+            let from = match offset {
+                Some(ref n) => match parse_number(n) {
+                    Some(n) => n,
+                    None => usage(),
+                },
+                None => 0,
+            };
+            open(path)?.hexdump_to(&mut out, from)?;
+            Ok(0)
+        }
+        (b"stats", []) => {
+            match json {
+                true => write_stats_json(&open(path)?, &mut out)?,
+                false => write_stats(&open(path)?, &mut out)?,
+            }
+            Ok(0)
+        }
+        (b"diff", [other]) => {
+            let json = match format.as_ref().map(|f| f.as_bytes()) {
+                None => false,
+                Some(b"json") => true,
+                Some(_) => usage(),
+            };
+            diff(&open(path)?, &open(other)?, &mut out, json)
+        }
+        (b"shell", []) => {
+            drop(out);
+            shell::run(path)
+        }
+        (b"repack", []) => {
+            let before = describe(path)?;
+            let after = match output {
+                None => {
+                    open_rw(path, false)?.repack()?;
+                    describe(path)?
+                }
+                Some(ref output) => {
+                    open(path)?.repack_to(output)?;
+                    describe(output)?
+                }
+            };
+            writeln!(out, "before: {}\nafter:  {}", before, after)?;
+            Ok(0)
+        }
+        _ => usage(),
+    }
+}
+
+fn import(
+    path: &OsString,
+    input: &OsString,
+    flat: bool,
+    stream: bool,
+) -> Result<i32, Box<dyn StdError>> {
+    let input: Box<dyn Read> = match input.as_bytes() {
+        b"-" => Box::new(io::stdin()),
+        _ => Box::new(File::open(input)?),
+    };
+
+    // unlike the others, a stream that stops short keeps what it got
+    if stream {
+        let report = from_stream(&mut open_rw(path, true)?, BufReader::new(input))?;
+        if report.complete {
+            return Ok(0);
+        }
+        match report.position {
+            Some(p) => eprintln!(
+                "twoskip: the stream stopped short after {} records, go on with --resume {}:{:x}",
+                report.records, p.generation, p.offset
+            ),
+            None => eprintln!("twoskip: the stream stopped before any records"),
+        }
+        return Ok(1);
+    }
+
+    // a bad line anywhere means none of it goes in
+    let mut db = open_rw(path, true)?;
+    if flat {
+        from_flat(&mut db, BufReader::new(input))?;
+        return Ok(0);
+    }
+    let mut txn = db.begin()?;
+    for (n, line) in BufReader::new(input).split(b'\n').enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let tab = match line.iter().position(|&c| c == b'\t') {
+            Some(tab) => tab,
+            None => return Err(format!("line {}: no tab", n + 1).into()),
+        };
+        txn.put(&line[..tab], &line[tab + 1..])?;
+    }
+    txn.commit()?;
+    Ok(0)
+}
+
+// like tail -f. a commit moves current_size on, and everything between
+// there and where it was before is new. a repack rewrites the lot, so all
+// we can do then is say so and carry on from the new end
+fn follow<W: Write>(
+    path: &OsString,
+    out: &mut W,
+    interval: Duration,
+) -> Result<i32, Box<dyn StdError>> {
+    let mut db = open(path)?;
+    let mut generation = db.header().generation();
+    let mut seen = db.header().current_size();
+    writeln!(
+        out,
+        "-- generation {}, {} records",
+        generation,
+        db.header().num_records()
+    )?;
+    out.flush()?;
+
+    loop {
+        thread::sleep(interval);
+        if !db.is_stale()? {
+            continue;
+        }
+        db.reopen()?;
+
+        if db.header().generation() != generation {
+            generation = db.header().generation();
+            writeln!(
+                out,
+                "-- repacked, generation {}, {} records",
+                generation,
+                db.header().num_records()
+            )?;
+        } else {
+            for (offset, change) in db.changes_since(seen)? {
+                write!(out, "{:08x} ", offset)?;
+                match change {
+                    Change::Put { key, value } => {
+                        out.write_all(b"+ ")?;
+                        out.write_all(&escape(&key))?;
+                        out.write_all(b"\t")?;
+                        out.write_all(&escape(&value))?;
+                    }
+                    Change::Delete { key: Some(key) } => {
+                        out.write_all(b"- ")?;
+                        out.write_all(&escape(&key))?;
+                    }
+                    Change::Delete { key: None } => out.write_all(b"- ?")?,
+                    Change::Commit => out.write_all(b"-- commit")?,
+                }
+                out.write_all(b"\n")?;
+            }
+        }
+        seen = db.header().current_size();
+        out.flush()?;
+    }
+}
+
+// walk both in key order, side by side
+fn diff<W: Write>(a: &Db, b: &Db, out: &mut W, json: bool) -> Result<i32, Box<dyn StdError>> {
+    let mut found: [Vec<Vec<u8>>; 3] = [vec![], vec![], vec![]];
+    let mut differ = false;
+    for entry in diff_dbs(a, b) {
+        let entry = entry?;
+        let which = match entry {
+            DiffEntry::Removed { .. } => 0,
+            DiffEntry::Added { .. } => 1,
+            DiffEntry::Changed { .. } => 2,
+        };
+        differ = true;
+        match json {
+            true => found[which].push(entry.key().to_vec()),
+            false => {
+                out.write_all([&b"- "[..], b"+ ", b"! "][which])?;
+                out.write_all(&escape(entry.key()))?;
+                out.write_all(b"\n")?;
+            }
+        }
+    }
+
+    if json {
+        let names = ["only_in_a", "only_in_b", "different"];
+        writeln!(out, "{{")?;
+        for (i, keys) in found.iter().enumerate() {
+            let keys: Result<Vec<String>, _> = keys.iter().map(|k| json_string(k)).collect();
+            let sep = if i < 2 { "," } else { "" };
+            writeln!(out, "  \"{}\": [{}]{}", names[i], keys?.join(", "), sep)?;
+        }
+        writeln!(out, "}}")?;
+    }
+    Ok(differ as i32)
+}
+
+// exit codes in the style of fsck
+const CLEAN: i32 = 0;
+const RECOVERABLE: i32 = 1;
+const FATAL: i32 = 4;
+const UNCHECKED: i32 = 8;
+
+fn verify<W: Write>(path: &OsString, out: &mut W) -> Result<i32, Box<dyn StdError>> {
+    let db = match OpenOptions::new().read_only(true).recover(false).open(path) {
+        Ok(db) => db,
+        Err(e @ Error::InternalError(_)) => {
+            writeln!(out, "can't check: {}", e)?;
+            return Ok(UNCHECKED);
+        }
+        Err(e) => {
+            writeln!(out, "header: {}", e)?;
+            return Ok(FATAL);
+        }
+    };
+
+    let mut code = CLEAN;
+    if db.needs_recovery() {
+        writeln!(
+            out,
+            "{:08x} unfinished transaction",
+            db.header().current_size()
+        )?;
+        code = RECOVERABLE;
+    }
+    let report = db.verify()?;
+    for problem in &report.problems {
+        let what = match problem.kind {
+            ProblemKind::Unreadable(ref e) => format!("unreadable record: {}", e),
+            ProblemKind::HeadChecksum => "head checksum mismatch".to_string(),
+            ProblemKind::TailChecksum => "tail checksum mismatch".to_string(),
+            ProblemKind::BadPointer { slot, target } => {
+                format!(
+                    "pointer {} leads to {:08x}, which isn't a record",
+                    slot, target
+                )
+            }
+            ProblemKind::Overrun => "last record runs past the committed end".to_string(),
+            ProblemKind::NumRecords { header, counted } => {
+                format!("header says {} records, found {}", header, counted)
+            }
+        };
+        writeln!(out, "{:08x} {}", problem.offset, what)?;
+        code = match problem.kind {
+            ProblemKind::NumRecords { .. } => code.max(RECOVERABLE),
+            _ => FATAL,
+        };
+    }
+    writeln!(
+        out,
+        "{} records, {} problems",
+        report.records,
+        report.problems.len()
+    )?;
+    Ok(code)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Conflict {
+    LastWins,
+    FirstWins,
+    Fail,
+}
+
+fn merge(
+    out: &OsString,
+    inputs: &[OsString],
+    on_conflict: Conflict,
+) -> Result<i32, Box<dyn StdError>> {
+    let dbs = inputs
+        .iter()
+        .map(open)
+        .collect::<Result<Vec<Db>, Error>>()?;
+    let dbs: Vec<&Db> = dbs.iter().collect();
+
+    // a failed merge leaves nothing behind
+    let mut disagreement = None;
+    let merged = merge_dbs(&dbs, out, |key, values| match on_conflict {
+        Conflict::LastWins => Ok(values.last().map(|(_, v)| v.to_vec())),
+        Conflict::FirstWins => Ok(values.first().map(|(_, v)| v.to_vec())),
+        Conflict::Fail => {
+            disagreement = Some(format!(
+                "{:?} and {:?} disagree about {}",
+                inputs[values[0].0],
+                inputs[values[1].0],
+                String::from_utf8_lossy(key)
+            ));
+            Err(Error::ValueMismatch)
+        }
+    });
+    match (merged, disagreement) {
+        (Err(Error::ValueMismatch), Some(msg)) => Err(msg.into()),
+        (merged, _) => {
+            merged?;
+            Ok(0)
+        }
+    }
+}
+
+// what ctl_cyrusdb -r does, and a bit more
+fn recover<W: Write>(
+    path: &OsString,
+    out: &mut W,
+    salvage_to: Option<&OsString>,
+) -> Result<i32, Box<dyn StdError>> {
+    let mut code = CLEAN;
+    if let Ok(db) = OpenOptions::new().read_only(true).recover(false).open(path) {
+        if db.needs_recovery() {
+            drop(db);
+            // opening it to write rolls it back
+            open_rw(path, false)?;
+            writeln!(out, "rolled back an unfinished transaction")?;
+            code = RECOVERABLE;
+        }
+    }
+
+    let mut report = vec![];
+    match verify(path, &mut report)? {
+        CLEAN => {}
+        // only the count is wrong, and a repack counts again
+        RECOVERABLE => {
+            open_rw(path, false)?.repack()?;
+            writeln!(out, "repacked to fix the record count")?;
+            code = RECOVERABLE;
+        }
+        verified => {
+            out.write_all(&report)?;
+            let dst = match salvage_to {
+                Some(dst) => dst,
+                None => {
+                    writeln!(
+                        out,
+                        "still damaged, --salvage NEW would copy out what's left"
+                    )?;
+                    return Ok(verified);
+                }
+            };
+            let salvaged = salvage(path, dst)?;
+            write!(
+                out,
+                "salvaged {} records into {:?}",
+                salvaged.recovered, dst
+            )?;
+            if let Some(lost) = salvaged.lost {
+                write!(out, ", {} lost", lost)?;
+            }
+            writeln!(
+                out,
+                ", skipping {} damaged stretches and {} deletes that couldn't be placed",
+                salvaged.damaged, salvaged.unmatched_deletes
+            )?;
+            return Ok(RECOVERABLE);
+        }
+    }
+    writeln!(out, "clean")?;
+    Ok(code)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    Ndjson,
+    Tsv,
+}
+
+// tsv is escaped the way cyrus' flat files are, so import --flat can read it
+// back. json can only carry text, so values that aren't need --base64
+fn export<W: Write>(
+    db: &Db,
+    out: &mut W,
+    format: Format,
+    base64: bool,
+) -> Result<(), Box<dyn StdError>> {
+    if format == Format::Tsv && !base64 {
+        return Ok(to_flat(db, out)?);
+    }
+    let mut first = true;
+    if format == Format::Json {
+        out.write_all(b"{")?;
+    }
+    for r in db.iter_live() {
+        let r = r?;
+        let value = match base64 {
+            true => encode_base64(r.value()).into_bytes(),
+            false => r.value().to_vec(),
+        };
+        match format {
+            Format::Tsv => {
+                out.write_all(&escape(r.key()))?;
+                out.write_all(b"\t")?;
+                out.write_all(&escape(&value))?;
+                out.write_all(b"\n")?;
+            }
+            Format::Json | Format::Ndjson => {
+                let key = json_string(r.key())?;
+                let value = match json_string(&value) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        return Err(format!("{} in the value of {}, try --base64", e, key).into())
+                    }
+                };
+                match format {
+                    Format::Json => {
+                        let sep = if first { "" } else { "," };
+                        write!(out, "{}\n  {}: {}", sep, key, value)?;
+                    }
+                    _ => writeln!(out, "{{\"key\": {}, \"value\": {}}}", key, value)?,
+                }
+            }
+        }
+        first = false;
+    }
+    if format == Format::Json {
+        out.write_all(if first { b"}\n" } else { b"\n}\n" })?;
+    }
+    Ok(())
+}
+
+// a cdb has to be written out of order, so it can't go to stdout
+fn export_cdb(db: &Db, output: &OsString) -> Result<(), Box<dyn StdError>> {
+    let f = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(output)?;
+    let r = to_cdb(db, &mut BufWriter::new(f));
+    if r.is_err() {
+        std::fs::remove_file(output).ok();
+    }
+    Ok(r?)
+}
+
+fn json_string(s: &[u8]) -> Result<String, Box<dyn StdError>> {
+    let s = std::str::from_utf8(s).map_err(|_| "bytes that aren't utf-8")?;
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    Ok(out)
+}
+
+fn fragmentation(db: &Db) -> f64 {
+    let header = db.header();
+    header.current_size() as f64 / header.repack_size().max(1) as f64
+}
+
+fn write_stats<W: Write>(db: &Db, out: &mut W) -> Result<(), Box<dyn StdError>> {
+    let stats = db.stats()?;
+    let header = db.header();
+    let sizes = |s: &SizeStats| {
+        format!(
+            "min {} mean {:.1} p50 {} p90 {} p99 {} max {}",
+            s.min, s.mean, s.p50, s.p90, s.p99, s.max
+        )
+    };
+    writeln!(out, "generation:     {}", header.generation())?;
+    writeln!(out, "current size:   {}", header.current_size())?;
+    writeln!(out, "repack size:    {}", header.repack_size())?;
+    writeln!(out, "fragmentation:  {:.2}", fragmentation(db))?;
+    writeln!(out, "live records:   {}", stats.live_records)?;
+    writeln!(out, "dead records:   {}", stats.dead_records)?;
+    writeln!(out, "deletes:        {}", stats.delete_records)?;
+    writeln!(out, "commits:        {}", stats.commits)?;
+    writeln!(out, "key bytes:      {}", stats.key_bytes)?;
+    writeln!(out, "   per key:     {}", sizes(&stats.key_sizes))?;
+    writeln!(out, "value bytes:    {}", stats.value_bytes)?;
+    writeln!(out, "   per value:   {}", sizes(&stats.value_sizes))?;
+    writeln!(out, "blank bytes:    {}", stats.blank_bytes)?;
+    writeln!(out, "levels:")?;
+    let most = stats.levels.iter().cloned().max().unwrap_or(0).max(1);
+    for (level, &n) in stats.levels.iter().enumerate().filter(|(_, &n)| n > 0) {
+        let bar = "#".repeat((n * 50).div_ceil(most) as usize);
+        writeln!(out, "   {:2} {:10} {}", level, n, bar)?;
+    }
+    Ok(())
+}
+
+fn write_stats_json<W: Write>(db: &Db, out: &mut W) -> Result<(), Box<dyn StdError>> {
+    let stats = db.stats()?;
+    let header = db.header();
+    let sizes = |s: &SizeStats| {
+        format!(
+            "{{\"min\": {}, \"mean\": {}, \"p50\": {}, \"p90\": {}, \"p99\": {}, \"max\": {}}}",
+            s.min, s.mean, s.p50, s.p90, s.p99, s.max
+        )
+    };
+    let levels: Vec<String> = stats.levels.iter().map(|n| n.to_string()).collect();
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"generation\": {},", header.generation())?;
+    writeln!(out, "  \"current_size\": {},", header.current_size())?;
+    writeln!(out, "  \"repack_size\": {},", header.repack_size())?;
+    writeln!(out, "  \"fragmentation\": {},", fragmentation(db))?;
+    writeln!(out, "  \"live_records\": {},", stats.live_records)?;
+    writeln!(out, "  \"dead_records\": {},", stats.dead_records)?;
+    writeln!(out, "  \"delete_records\": {},", stats.delete_records)?;
+    writeln!(out, "  \"commits\": {},", stats.commits)?;
+    writeln!(out, "  \"key_bytes\": {},", stats.key_bytes)?;
+    writeln!(out, "  \"key_sizes\": {},", sizes(&stats.key_sizes))?;
+    writeln!(out, "  \"value_bytes\": {},", stats.value_bytes)?;
+    writeln!(out, "  \"value_sizes\": {},", sizes(&stats.value_sizes))?;
+    writeln!(out, "  \"blank_bytes\": {},", stats.blank_bytes)?;
+    writeln!(out, "  \"levels\": [{}]", levels.join(", "))?;
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+// offsets can be given in hex (0x...), since that's how they're printed
+fn parse_number(s: &OsString) -> Option<usize> {
+    let s = s.to_str()?;
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+// GENERATION:OFFSET, the offset in hex as import --stream gives it
+fn parse_position(s: &OsString) -> Option<StreamPosition> {
+    let (generation, offset) = s.to_str()?.split_once(':')?;
+    Some(StreamPosition {
+        generation: generation.parse().ok()?,
+        offset: usize::from_str_radix(offset, 16).ok()?,
+    })
+}
+
+fn describe(path: &OsString) -> Result<String, Error> {
+    let db = open(path)?;
+    let size = std::fs::metadata(path)?.len();
+    Ok(format!(
+        "{} records, {} bytes",
+        db.header().num_records(),
+        size
+    ))
+}
+
+fn open(path: &OsString) -> Result<Db, Error> {
+    OpenOptions::new().read_only(true).open(path)
+}
+
+fn open_rw(path: &OsString, create: bool) -> Result<Db, Error> {
+    OpenOptions::new().create(create).open(path)
+}
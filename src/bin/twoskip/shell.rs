@@ -0,0 +1,388 @@
+// an interactive prompt over one database. on a terminal the line editor
+// does history (kept in ~/.twoskip_history) and tab completion of commands
+// and key prefixes; otherwise it just reads commands a line at a time
+
+use std::env;
+use std::error::Error as StdError;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::mem;
+use std::path::PathBuf;
+
+use twoskip::twoskip::{Db, OpenOptions};
+
+const COMMANDS: &[&str] = &[
+    "del", "get", "help", "list", "prefix", "quit", "set", "stats",
+];
+
+const HELP: &str = "get KEY          print the value of KEY
+set KEY VALUE    store VALUE under KEY
+del KEY          delete KEY
+list [PREFIX]    print keys and values, all or just under PREFIX
+prefix PREFIX    print just the keys under PREFIX
+stats            show what's in the file
+quit             leave (as does ^D)
+
+quote keys with spaces in them with \"...\", or escape them with \\";
+
+const HISTORY_LEN: usize = 1000;
+
+pub fn run(path: &OsString) -> Result<i32, Box<dyn StdError>> {
+    let mut db = OpenOptions::new().open(path)?;
+    let mut editor = Editor::new();
+    let out = io::stdout();
+
+    while let Some(line) = editor.read_line(&db)? {
+        let words = match split(&line) {
+            Some(words) => words,
+            None => {
+                println!("error: unfinished quote");
+                continue;
+            }
+        };
+        if words.is_empty() {
+            continue;
+        }
+        if words[0] == b"quit" || words[0] == b"exit" {
+            break;
+        }
+
+        let mut out = out.lock();
+        if let Err(e) = command(&mut db, &words, &mut out) {
+            writeln!(out, "error: {}", e)?;
+        }
+    }
+
+    editor.save_history();
+    Ok(0)
+}
+
+fn command<W: Write>(db: &mut Db, words: &[Vec<u8>], out: &mut W) -> Result<(), Box<dyn StdError>> {
+    // other processes may have been busy since the last command
+    if db.is_stale()? {
+        db.reopen()?;
+    }
+
+    let args: Vec<&[u8]> = words[1..].iter().map(|w| w.as_slice()).collect();
+    match (words[0].as_slice(), args.as_slice()) {
+        (b"get", [key]) => match db.get(key)? {
+            Some(r) => {
+                out.write_all(r.value())?;
+                out.write_all(b"\n")?;
+            }
+            None => writeln!(out, "not found")?,
+        },
+        (b"set", [key, value]) => db.put(key, value)?,
+        (b"del", [key]) => {
+            if !db.delete(key)? {
+                writeln!(out, "not found")?;
+            }
+        }
+        (b"list", []) | (b"list", [_]) => {
+            for r in db.iter_prefix(args.first().cloned().unwrap_or(b"")) {
+                let r = r?;
+                out.write_all(r.key())?;
+                out.write_all(b"\t")?;
+                out.write_all(r.value())?;
+                out.write_all(b"\n")?;
+            }
+        }
+        (b"prefix", [prefix]) => {
+            for key in db.iter_prefix(prefix).map(|r| r.map(|r| r.key().to_vec())) {
+                out.write_all(&key?)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        (b"stats", []) => crate::write_stats(db, out)?,
+        (b"help", _) => writeln!(out, "{}", HELP)?,
+        _ => writeln!(out, "eh? try help")?,
+    }
+    Ok(())
+}
+
+// words are split on spaces, except inside double quotes or after a
+// backslash. None if a quote is left open
+fn split(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut words = vec![];
+    let mut word = None;
+    let mut quoted = false;
+    let mut bytes = line.iter();
+    while let Some(&c) = bytes.next() {
+        match c {
+            b'\\' => {
+                if let Some(&c) = bytes.next() {
+                    word.get_or_insert_with(Vec::new).push(c);
+                }
+            }
+            b'"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(Vec::new);
+            }
+            b' ' | b'\t' if !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(Vec::new).push(c),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    words.extend(word.take());
+    Some(words)
+}
+
+// what completion would put back: backslash the spaces, quotes and
+// backslashes so it splits up again as the same word
+fn escape_word(word: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(word.len());
+    for &c in word {
+        if c == b' ' || c == b'"' || c == b'\\' || c == b'\t' {
+            out.push(b'\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+struct Editor {
+    // the terminal's settings from before we took it over, if it is one
+    saved: Option<libc::termios>,
+    history: Vec<Vec<u8>>,
+    history_path: Option<PathBuf>,
+}
+
+impl Editor {
+    fn new() -> Editor {
+        let history_path =
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".twoskip_history"));
+        let history = match history_path.as_ref().and_then(|p| fs::read(p).ok()) {
+            Some(data) => data
+                .split(|&c| c == b'\n')
+                .filter(|l| !l.is_empty())
+                .map(|l| l.to_vec())
+                .collect(),
+            None => vec![],
+        };
+
+        let saved = unsafe {
+            let mut t: libc::termios = mem::zeroed();
+            match libc::isatty(0) == 1 && libc::tcgetattr(0, &mut t) == 0 {
+                true => Some(t),
+                false => None,
+            }
+        };
+
+        Editor {
+            saved,
+            history,
+            history_path,
+        }
+    }
+
+    fn save_history(&self) {
+        if let (Some(path), Some(_)) = (self.history_path.as_ref(), self.saved) {
+            let start = self.history.len().saturating_sub(HISTORY_LEN);
+            let mut data = self.history[start..].join(&b'\n');
+            data.push(b'\n');
+            fs::write(path, data).ok();
+        }
+    }
+
+    fn read_line(&mut self, db: &Db) -> io::Result<Option<Vec<u8>>> {
+        let saved = match self.saved {
+            Some(saved) => saved,
+            None => {
+                let mut line = vec![];
+                return match io::stdin().lock().read_until(b'\n', &mut line)? {
+                    0 => Ok(None),
+                    _ => {
+                        if line.last() == Some(&b'\n') {
+                            line.pop();
+                        }
+                        Ok(Some(line))
+                    }
+                };
+            }
+        };
+
+        // no line buffering, echo or signals while we're editing
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        unsafe { libc::tcsetattr(0, libc::TCSADRAIN, &raw) };
+        let line = self.edit(db);
+        unsafe { libc::tcsetattr(0, libc::TCSADRAIN, &saved) };
+
+        if let Ok(Some(ref line)) = line {
+            if !line.is_empty() && self.history.last() != Some(line) {
+                self.history.push(line.clone());
+            }
+        }
+        line
+    }
+
+    fn edit(&mut self, db: &Db) -> io::Result<Option<Vec<u8>>> {
+        let stdin = io::stdin();
+        let mut input = stdin.lock().bytes();
+        let mut next = || input.next().transpose();
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+
+        let mut line: Vec<u8> = vec![];
+        let mut pos = 0;
+        // where we are in the history, and what was typed before going there
+        let mut back = self.history.len();
+        let mut typed = vec![];
+        let mut last_tab = false;
+
+        loop {
+            write!(out, "\r\x1b[Ktwoskip> ")?;
+            out.write_all(&line)?;
+            if pos < line.len() {
+                write!(out, "\x1b[{}D", line.len() - pos)?;
+            }
+            out.flush()?;
+
+            let c = match next()? {
+                Some(c) => c,
+                None => return Ok(None),
+            };
+            let tab = c == b'\t';
+            match c {
+                b'\r' | b'\n' => {
+                    writeln!(out, "\r")?;
+                    return Ok(Some(line));
+                }
+                // ^D on an empty line is the end, otherwise it deletes
+                4 if line.is_empty() => {
+                    writeln!(out, "\r")?;
+                    return Ok(None);
+                }
+                4 if pos < line.len() => {
+                    line.remove(pos);
+                }
+                // ^C throws the line away
+                3 => {
+                    writeln!(out, "^C\r")?;
+                    line.clear();
+                    pos = 0;
+                }
+                1 => pos = 0,
+                5 => pos = line.len(),
+                // ^U
+                21 => {
+                    line.drain(..pos);
+                    pos = 0;
+                }
+                127 | 8 if pos > 0 => {
+                    pos -= 1;
+                    line.remove(pos);
+                }
+                b'\t' => {
+                    let (extra, choices) = complete(db, &line[..pos]);
+                    if !extra.is_empty() {
+                        line.splice(pos..pos, extra.iter().cloned());
+                        pos += extra.len();
+                    } else if last_tab && choices.len() > 1 {
+                        writeln!(out, "\r")?;
+                        for choice in &choices {
+                            out.write_all(choice)?;
+                            write!(out, "\r\n")?;
+                        }
+                    }
+                }
+                0x1b => {
+                    if next()? != Some(b'[') {
+                        continue;
+                    }
+                    match next()? {
+                        Some(b'A') if back > 0 => {
+                            if back == self.history.len() {
+                                typed = line.clone();
+                            }
+                            back -= 1;
+                            line = self.history[back].clone();
+                            pos = line.len();
+                        }
+                        Some(b'B') if back < self.history.len() => {
+                            back += 1;
+                            line = match self.history.get(back) {
+                                Some(l) => l.clone(),
+                                None => typed.clone(),
+                            };
+                            pos = line.len();
+                        }
+                        Some(b'C') if pos < line.len() => pos += 1,
+                        Some(b'D') if pos > 0 => pos -= 1,
+                        _ => {}
+                    }
+                }
+                c if c >= b' ' && c != 127 => {
+                    line.insert(pos, c);
+                    pos += 1;
+                }
+                _ => {}
+            }
+            last_tab = tab;
+        }
+    }
+}
+
+// how many candidates a second tab will show
+const MAX_CHOICES: usize = 100;
+
+// what can be added to the end of the last word, and the words it could be.
+// the first word is a command, and anything after that is a key
+fn complete(db: &Db, line: &[u8]) -> (Vec<u8>, Vec<Vec<u8>>) {
+    let words = match split(line) {
+        Some(words) => words,
+        None => return (vec![], vec![]),
+    };
+    let ends_word = line.is_empty() || line.ends_with(b" ") && !line.ends_with(b"\\ ");
+    let (partial, first) = match (ends_word, words.len()) {
+        (true, n) => (vec![], n == 0),
+        (false, n) => (words[n - 1].clone(), n == 1),
+    };
+
+    let mut choices = vec![];
+    let mut count = 0;
+    let mut common: Option<Vec<u8>> = None;
+    let mut add = |word: &[u8]| {
+        count += 1;
+        common = Some(match common.take() {
+            None => word.to_vec(),
+            Some(c) => {
+                let n = c.iter().zip(word).take_while(|(a, b)| a == b).count();
+                c[..n].to_vec()
+            }
+        });
+        if choices.len() < MAX_CHOICES {
+            choices.push(word.to_vec());
+        }
+    };
+    match first {
+        true => COMMANDS
+            .iter()
+            .filter(|c| c.as_bytes().starts_with(&partial))
+            .for_each(|c| add(c.as_bytes())),
+        false => {
+            for r in db.iter_prefix(&partial) {
+                match r {
+                    Ok(r) => add(r.key()),
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+
+    // only one way to go means the word's finished
+    let mut extra = match common {
+        Some(common) => escape_word(&common[partial.len()..]),
+        None => vec![],
+    };
+    if count == 1 {
+        extra.push(b' ');
+    }
+    (extra, choices)
+}
@@ -0,0 +1,139 @@
+// the .bloom sidecar: a bloom filter over every live key, so a get for a key
+// that isn't there can usually say so without going near the skip list. it
+// sits next to the database, as mailboxes.db.bloom, and looks like
+//
+//   magic       8 bytes, "TWOBLOOM"
+//   generation  8 bytes BE, the database's generation when it was built
+//   end         8 bytes BE, the database's current_size when it was built
+//   hashes      4 bytes BE, how many bits each key sets
+//   crc32       4 bytes BE, of the bits
+//   bits        the rest, a multiple of 8 bytes
+//
+// it's only good for the file it was built from, so a repack (which bumps
+// the generation) makes it useless until it's built again. anything
+// written since is after end, and Db looks there itself. a sidecar that's
+// missing, damaged or from some other file is just not used
+//
+// the bit positions come from a crc64 of the key split in two, h1 + i*h2,
+// which is stable across builds and machines
+
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, ByteOrder};
+use crc::Crc;
+
+use crate::twoskip::Error;
+
+const MAGIC: &[u8; 8] = b"TWOBLOOM";
+const HEADER_SIZE: usize = 32;
+
+// ten bits and seven hashes a key is about a 1% false positive rate
+const BITS_PER_KEY: usize = 10;
+const HASHES: u32 = 7;
+// more than this from a file is damage, and would make every lookup a
+// long loop
+const MAX_HASHES: u32 = 32;
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+const CRC64: Crc<u64> = Crc::<u64>::new(&crc::CRC_64_XZ);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Bloom {
+    pub(crate) generation: u64,
+    pub(crate) end: usize,
+    hashes: u32,
+    bits: Vec<u8>,
+}
+
+pub(crate) fn sidecar_path(db_path: &Path) -> PathBuf {
+    let mut path = OsString::from(db_path);
+    path.push(".bloom");
+    PathBuf::from(path)
+}
+
+impl Bloom {
+    pub(crate) fn new(num_keys: usize, generation: u64, end: usize) -> Bloom {
+        let bytes = (num_keys * BITS_PER_KEY).div_ceil(64).max(1) * 8;
+        Bloom {
+            generation,
+            end,
+            hashes: HASHES,
+            bits: vec![0; bytes],
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: &[u8]) {
+        for bit in self.positions(key) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    // false means the key definitely wasn't there when this was built
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        self.positions(key)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let hash = CRC64.checksum(key);
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let nbits = self.bits.len() as u64 * 8;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize)
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0; HEADER_SIZE];
+        buf[..8].copy_from_slice(MAGIC);
+        BigEndian::write_u64(&mut buf[8..], self.generation);
+        BigEndian::write_u64(&mut buf[16..], self.end as u64);
+        BigEndian::write_u32(&mut buf[24..], self.hashes);
+        BigEndian::write_u32(&mut buf[28..], CRC32.checksum(&self.bits));
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+
+    pub(crate) fn parse(buf: &[u8]) -> Option<Bloom> {
+        if buf.len() <= HEADER_SIZE || &buf[..8] != MAGIC {
+            return None;
+        }
+        let bits = &buf[HEADER_SIZE..];
+        let hashes = BigEndian::read_u32(&buf[24..]);
+        if !bits.len().is_multiple_of(8)
+            || !(1..=MAX_HASHES).contains(&hashes)
+            || BigEndian::read_u32(&buf[28..]) != CRC32.checksum(bits)
+        {
+            return None;
+        }
+        Some(Bloom {
+            generation: BigEndian::read_u64(&buf[8..]),
+            end: usize::try_from(BigEndian::read_u64(&buf[16..])).ok()?,
+            hashes,
+            bits: bits.to_vec(),
+        })
+    }
+
+    pub(crate) fn load(db_path: &Path) -> Option<Bloom> {
+        Bloom::parse(&fs::read(sidecar_path(db_path)).ok()?)
+    }
+
+    // written to the side and renamed into place, so a reader never sees
+    // half of one
+    pub(crate) fn save(&self, db_path: &Path) -> Result<(), Error> {
+        let path = sidecar_path(db_path);
+        let mut new_path = OsString::from(&path);
+        new_path.push(".NEW");
+        let written = fs::File::create(&new_path).and_then(|mut f| {
+            f.write_all(&self.to_bytes())?;
+            f.sync_all()
+        });
+        if let Err(e) = written {
+            fs::remove_file(&new_path).ok();
+            return Err(e.into());
+        }
+        fs::rename(&new_path, &path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,306 @@
+// a user's conversations.db, user/f/fred.conversations, where cyrus keeps
+// track of which messages belong to which conversation (thread) and what
+// each conversation adds up to in each folder. the keys come in families,
+// told apart by how they start:
+//
+//   $COUNTED_FLAGS  the flags conversations keep counts of, space separated
+//   $FOLDER_NAMES   a dlist of the folders, a folder's number being where
+//                   it is in the list
+//   B<cid>          a conversation, cid being 16 hex digits
+//   G<guid>         where the message with that sha1 is, as folder:uid or
+//                   folder:uid:part for parts of it
+//   F<mboxname>     a folder's totals
+//   <msgid>         which conversations a message-id has turned up in
+//
+// B and F values start with a version number, then a dlist. the dlists
+// are lists with the fields in a fixed order, the ones cyrus has added
+// since 3.0 coming after the ones here and being kept in rest as they were
+
+use std::iter::Peekable;
+use std::slice;
+use std::str::FromStr;
+
+use crate::dlist::{self, Dlist};
+use crate::twoskip::{Db, Error};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConvKey<'a> {
+    CountedFlags,
+    FolderNames,
+    Conversation(u64),
+    Guid(&'a str),
+    FolderStatus(&'a [u8]),
+    MessageId(&'a [u8]),
+    Other(&'a [u8]),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Conversation {
+    pub version: u32,
+    pub modseq: u64,
+    pub num_records: u32,
+    pub exists: u32,
+    pub unseen: u32,
+    // one for each of $COUNTED_FLAGS, in the same order
+    pub counts: Vec<u32>,
+    pub folders: Vec<ConvFolder>,
+    pub senders: Vec<ConvSender>,
+    pub subject: Vec<u8>,
+    pub size: u32,
+    pub rest: Vec<Dlist<'static>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConvFolder {
+    // into $FOLDER_NAMES
+    pub number: u32,
+    pub modseq: u64,
+    pub num_records: u32,
+    pub exists: u32,
+    pub unseen: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConvSender {
+    pub name: Vec<u8>,
+    pub route: Vec<u8>,
+    pub mailbox: Vec<u8>,
+    pub domain: Vec<u8>,
+    pub lastseen: i64,
+    pub exists: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FolderStatus {
+    pub version: u32,
+    pub modseq: u64,
+    pub exists: u32,
+    pub unseen: u32,
+    pub rest: Vec<Dlist<'static>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GuidLocation {
+    pub folder: u32,
+    pub uid: u32,
+    pub part: Option<String>,
+}
+
+impl<'a> ConvKey<'a> {
+    pub fn parse(key: &'a [u8]) -> ConvKey<'a> {
+        let hex = |s: &[u8]| s.iter().all(u8::is_ascii_hexdigit);
+        match key {
+            b"$COUNTED_FLAGS" => ConvKey::CountedFlags,
+            b"$FOLDER_NAMES" => ConvKey::FolderNames,
+            [b'B', cid @ ..] if cid.len() == 16 && hex(cid) => {
+                match std::str::from_utf8(cid)
+                    .ok()
+                    .and_then(|c| u64::from_str_radix(c, 16).ok())
+                {
+                    Some(cid) => ConvKey::Conversation(cid),
+                    None => ConvKey::Other(key),
+                }
+            }
+            [b'G', guid @ ..] if guid.len() == 40 && hex(guid) => match std::str::from_utf8(guid) {
+                Ok(guid) => ConvKey::Guid(guid),
+                Err(_) => ConvKey::Other(key),
+            },
+            [b'F', mboxname @ ..] if !mboxname.is_empty() => ConvKey::FolderStatus(mboxname),
+            [b'<', ..] => ConvKey::MessageId(key),
+            _ => ConvKey::Other(key),
+        }
+    }
+}
+
+pub fn counted_flags(value: &[u8]) -> Result<Vec<String>, Error> {
+    let flags = std::str::from_utf8(value).map_err(|_| Error::InvalidField {
+        field: "$COUNTED_FLAGS",
+    })?;
+    Ok(flags.split_ascii_whitespace().map(str::to_string).collect())
+}
+
+pub fn folder_names(value: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    let field = "$FOLDER_NAMES";
+    list(&dlist::parse(value)?, field)?
+        .iter()
+        .map(|name| bytes(name, field))
+        .collect()
+}
+
+// the folder names for a whole conversations.db, for turning folder
+// numbers back into mailboxes
+pub fn folders(db: &Db) -> Result<Vec<Vec<u8>>, Error> {
+    match db.get_value(b"$FOLDER_NAMES")? {
+        Some(value) => folder_names(value),
+        None => Ok(vec![]),
+    }
+}
+
+impl Conversation {
+    pub fn parse(value: &[u8]) -> Result<Conversation, Error> {
+        let (version, d) = versioned(value)?;
+        let mut fields = Fields::new(&d, "B")?;
+        let mut conv = Conversation {
+            version,
+            modseq: fields.number()?,
+            num_records: fields.number()?,
+            exists: fields.number()?,
+            unseen: fields.number()?,
+            ..Conversation::default()
+        };
+        conv.counts = match fields.next() {
+            Some(counts) => list(counts, "B")?
+                .iter()
+                .map(|c| number(c, "B"))
+                .collect::<Result<_, _>>()?,
+            None => vec![],
+        };
+        if let Some(folders) = fields.next() {
+            for folder in list(folders, "B")? {
+                let mut f = Fields::new(folder, "B")?;
+                conv.folders.push(ConvFolder {
+                    number: f.number()?,
+                    modseq: f.number()?,
+                    num_records: f.number()?,
+                    exists: f.number()?,
+                    unseen: f.number()?,
+                });
+            }
+        }
+        if let Some(senders) = fields.next() {
+            for sender in list(senders, "B")? {
+                let mut s = Fields::new(sender, "B")?;
+                conv.senders.push(ConvSender {
+                    name: s.bytes()?,
+                    route: s.bytes()?,
+                    mailbox: s.bytes()?,
+                    domain: s.bytes()?,
+                    lastseen: s.number()?,
+                    exists: s.number()?,
+                });
+            }
+        }
+        if fields.peek().is_some() {
+            conv.subject = fields.bytes()?;
+        }
+        if fields.peek().is_some() {
+            conv.size = fields.number()?;
+        }
+        conv.rest = fields.rest();
+        Ok(conv)
+    }
+}
+
+impl FolderStatus {
+    pub fn parse(value: &[u8]) -> Result<FolderStatus, Error> {
+        let (version, d) = versioned(value)?;
+        let mut fields = Fields::new(&d, "F")?;
+        Ok(FolderStatus {
+            version,
+            modseq: fields.number()?,
+            exists: fields.number()?,
+            unseen: fields.number()?,
+            rest: fields.rest(),
+        })
+    }
+}
+
+impl GuidLocation {
+    pub fn parse_all(value: &[u8]) -> Result<Vec<GuidLocation>, Error> {
+        let invalid = || Error::InvalidField { field: "G" };
+        let d = dlist::parse(value)?;
+        list(&d, "G")?
+            .iter()
+            .map(|item| {
+                let item = item.as_str().ok_or_else(invalid)?;
+                let mut parts = item.splitn(3, ':');
+                let mut next = || {
+                    parts
+                        .next()
+                        .and_then(|p| p.parse().ok())
+                        .ok_or_else(invalid)
+                };
+                Ok(GuidLocation {
+                    folder: next()?,
+                    uid: next()?,
+                    part: parts.next().map(str::to_string),
+                })
+            })
+            .collect()
+    }
+}
+
+// "1 (...)", or a dlist with no version in front, which is version 0
+fn versioned(value: &[u8]) -> Result<(u32, Dlist<'_>), Error> {
+    let digits = value.iter().take_while(|c| c.is_ascii_digit()).count();
+    if digits > 0 && value.get(digits) == Some(&b' ') {
+        let version = std::str::from_utf8(&value[..digits])
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .ok_or(Error::InvalidField { field: "version" })?;
+        return Ok((version, dlist::parse(&value[digits + 1..])?));
+    }
+    Ok((0, dlist::parse(value)?))
+}
+
+// a list's items one after another, each checked as it's taken
+struct Fields<'d, 'a> {
+    items: Peekable<slice::Iter<'d, Dlist<'a>>>,
+    field: &'static str,
+}
+
+impl<'d, 'a> Fields<'d, 'a> {
+    fn new(d: &'d Dlist<'a>, field: &'static str) -> Result<Fields<'d, 'a>, Error> {
+        Ok(Fields {
+            items: list(d, field)?.iter().peekable(),
+            field,
+        })
+    }
+
+    fn next(&mut self) -> Option<&'d Dlist<'a>> {
+        self.items.next()
+    }
+
+    fn peek(&mut self) -> Option<&&'d Dlist<'a>> {
+        self.items.peek()
+    }
+
+    fn number<T: FromStr>(&mut self) -> Result<T, Error> {
+        let field = self.field;
+        self.next()
+            .ok_or(Error::InvalidField { field })
+            .and_then(|d| number(d, field))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let field = self.field;
+        self.next()
+            .ok_or(Error::InvalidField { field })
+            .and_then(|d| bytes(d, field))
+    }
+
+    fn rest(self) -> Vec<Dlist<'static>> {
+        self.items.map(|d| d.clone().into_owned()).collect()
+    }
+}
+
+fn list<'d, 'a>(d: &'d Dlist<'a>, field: &'static str) -> Result<&'d [Dlist<'a>], Error> {
+    d.as_list().ok_or(Error::InvalidField { field })
+}
+
+// NIL is nothing, as cyrus writes a sender without a name
+fn bytes(d: &Dlist, field: &'static str) -> Result<Vec<u8>, Error> {
+    match d {
+        Dlist::Nil => Ok(vec![]),
+        _ => d
+            .as_bytes()
+            .map(<[u8]>::to_vec)
+            .ok_or(Error::InvalidField { field }),
+    }
+}
+
+fn number<T: FromStr>(d: &Dlist, field: &'static str) -> Result<T, Error> {
+    d.as_str()
+        .and_then(|s| s.parse().ok())
+        .ok_or(Error::InvalidField { field })
+}
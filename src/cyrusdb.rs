@@ -0,0 +1,160 @@
+// the interface cyrus' own code has to its databases (lib/cyrusdb.h), so
+// callers can be written once for whichever backend a file happens to use.
+// they're traits of objects, as in cyrus the backend is picked by name when
+// the file is opened. values borrow from the database and are good until it
+// next changes.
+//
+// a transaction holds the write lock from begin until commit or abort, and
+// dropping one without either aborts it. the skiplist backend can only be
+// read, so anything that would write to one is Error::ReadOnly
+
+use std::path::Path;
+
+use crate::skiplist;
+use crate::twoskip::{self, Error, Txn};
+
+// a key and its value
+pub type Pair<'a> = (&'a [u8], &'a [u8]);
+
+pub type Goodp<'f> = dyn FnMut(&[u8], &[u8]) -> bool + 'f;
+pub type Callback<'f> = dyn FnMut(&[u8], &[u8]) -> i32 + 'f;
+
+pub trait CyrusDb {
+    fn fetch(&self, key: &[u8]) -> Result<Option<&[u8]>, Error>;
+
+    // the record with the smallest key strictly greater than key
+    fn fetchnext(&self, key: &[u8]) -> Result<Option<Pair<'_>>, Error>;
+
+    // cb on every record whose key starts with prefix and that goodp likes,
+    // in key order, until cb returns something other than 0, which is then
+    // what this returns
+    fn foreach(&self, prefix: &[u8], goodp: &mut Goodp, cb: &mut Callback) -> Result<i32, Error>;
+
+    // each in a transaction of its own
+    fn store(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error>;
+    fn delete(&mut self, key: &[u8]) -> Result<bool, Error>;
+
+    fn begin(&mut self) -> Result<Box<dyn CyrusTxn + '_>, Error>;
+}
+
+// the same, inside a transaction, which sees its own changes
+pub trait CyrusTxn {
+    fn fetch(&self, key: &[u8]) -> Result<Option<&[u8]>, Error>;
+    fn fetchnext(&self, key: &[u8]) -> Result<Option<Pair<'_>>, Error>;
+    fn foreach(&self, prefix: &[u8], goodp: &mut Goodp, cb: &mut Callback) -> Result<i32, Error>;
+    fn store(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error>;
+    fn delete(&mut self, key: &[u8]) -> Result<bool, Error>;
+    fn commit(self: Box<Self>) -> Result<(), Error>;
+    fn abort(self: Box<Self>) -> Result<(), Error>;
+}
+
+// backend is the name cyrus uses for it in imapd.conf. with create, a file
+// that isn't there yet is made, if the backend can do that
+pub fn open<P: AsRef<Path>>(
+    backend: &str,
+    path: P,
+    create: bool,
+) -> Result<Box<dyn CyrusDb>, Error> {
+    match backend {
+        "twoskip" => Ok(Box::new(
+            twoskip::OpenOptions::new().create(create).open(path)?,
+        )),
+        "skiplist" if create && !path.as_ref().exists() => Err(Error::ReadOnly),
+        "skiplist" => Ok(Box::new(skiplist::open(path)?)),
+        _ => Err(Error::InternalError(
+            format!("no {} backend", backend).into(),
+        )),
+    }
+}
+
+impl CyrusDb for twoskip::Db {
+    fn fetch(&self, key: &[u8]) -> Result<Option<&[u8]>, Error> {
+        self.get_value(key)
+    }
+
+    fn fetchnext(&self, key: &[u8]) -> Result<Option<Pair<'_>>, Error> {
+        Ok(twoskip::Db::fetchnext(self, key)?.map(|r| (r.key(), r.value())))
+    }
+
+    fn foreach(&self, prefix: &[u8], goodp: &mut Goodp, cb: &mut Callback) -> Result<i32, Error> {
+        twoskip::Db::foreach(self, prefix, goodp, cb)
+    }
+
+    fn store(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.put(key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<bool, Error> {
+        twoskip::Db::delete(self, key)
+    }
+
+    fn begin(&mut self) -> Result<Box<dyn CyrusTxn + '_>, Error> {
+        Ok(Box::new(twoskip::Db::begin(self)?))
+    }
+}
+
+impl<'a> CyrusTxn for Txn<'a> {
+    fn fetch(&self, key: &[u8]) -> Result<Option<&[u8]>, Error> {
+        Ok(self.get(key)?.map(|r| r.value()))
+    }
+
+    fn fetchnext(&self, key: &[u8]) -> Result<Option<Pair<'_>>, Error> {
+        Ok(Txn::fetchnext(self, key)?.map(|r| (r.key(), r.value())))
+    }
+
+    fn foreach(&self, prefix: &[u8], goodp: &mut Goodp, cb: &mut Callback) -> Result<i32, Error> {
+        Txn::foreach(self, prefix, goodp, cb)
+    }
+
+    fn store(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.put(key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<bool, Error> {
+        Txn::delete(self, key)
+    }
+
+    fn commit(self: Box<Self>) -> Result<(), Error> {
+        Txn::commit(*self)
+    }
+
+    fn abort(self: Box<Self>) -> Result<(), Error> {
+        Txn::abort(*self)
+    }
+}
+
+impl CyrusDb for skiplist::Db {
+    fn fetch(&self, key: &[u8]) -> Result<Option<&[u8]>, Error> {
+        self.get_value(key)
+    }
+
+    fn fetchnext(&self, key: &[u8]) -> Result<Option<Pair<'_>>, Error> {
+        Ok(skiplist::Db::fetchnext(self, key)?.map(|r| (r.key(), r.value())))
+    }
+
+    fn foreach(&self, prefix: &[u8], goodp: &mut Goodp, cb: &mut Callback) -> Result<i32, Error> {
+        for r in self.iter_prefix(prefix) {
+            let r = r?;
+            if !goodp(r.key(), r.value()) {
+                continue;
+            }
+            match cb(r.key(), r.value()) {
+                0 => {}
+                n => return Ok(n),
+            }
+        }
+        Ok(0)
+    }
+
+    fn store(&mut self, _: &[u8], _: &[u8]) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn delete(&mut self, _: &[u8]) -> Result<bool, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn begin(&mut self) -> Result<Box<dyn CyrusTxn + '_>, Error> {
+        Err(Error::ReadOnly)
+    }
+}
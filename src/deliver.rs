@@ -0,0 +1,113 @@
+// deliver.db, where lmtpd remembers what it's delivered so it can throw
+// away duplicates, and sieve remembers the vacation replies and redirects
+// it's sent. a key is
+//
+//   id \0 to \0 date
+//
+// id usually a message-id, to the mailbox or address it went to and date
+// the message's Date header. sieve's own entries have ids and tos with dots
+// round them, like .vacation. and .sieve.. a value is when it was marked
+// and, from cyrus 2.3 on, the uid of the message that was delivered, each
+// a time_t and an unsigned long as the machine has them, which these days
+// means 8 bytes of little endian
+//
+// cyr_expire -E takes out everything marked more than so many days ago,
+// and expire does the same
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::twoskip::{Db, DbIter, Error};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeliverKey {
+    pub id: Vec<u8>,
+    pub to: Vec<u8>,
+    pub date: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Delivery {
+    // unix time
+    pub mark: i64,
+    // 0 where it wasn't recorded
+    pub uid: u64,
+}
+
+pub struct ExpiredBefore<'a> {
+    records: DbIter<'a>,
+    time: i64,
+}
+
+impl DeliverKey {
+    pub fn parse(key: &[u8]) -> Result<DeliverKey, Error> {
+        let mut fields = key.splitn(3, |&c| c == 0);
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(id), Some(to), Some(date)) => Ok(DeliverKey {
+                id: id.to_vec(),
+                to: to.to_vec(),
+                date: date.to_vec(),
+            }),
+            _ => Err(Error::InvalidField { field: "key" }),
+        }
+    }
+
+    pub fn to_key(&self) -> Vec<u8> {
+        let mut key = Vec::with_capacity(self.id.len() + self.to.len() + self.date.len() + 2);
+        key.extend_from_slice(&self.id);
+        key.push(0);
+        key.extend_from_slice(&self.to);
+        key.push(0);
+        key.extend_from_slice(&self.date);
+        key
+    }
+}
+
+impl Delivery {
+    pub fn parse(value: &[u8]) -> Result<Delivery, Error> {
+        let mark = value
+            .get(..8)
+            .map(LittleEndian::read_i64)
+            .ok_or(Error::InvalidField { field: "mark" })?;
+        Ok(Delivery {
+            mark,
+            uid: value.get(8..16).map_or(0, LittleEndian::read_u64),
+        })
+    }
+
+    pub fn to_value(&self) -> Vec<u8> {
+        let mut value = vec![0; 16];
+        LittleEndian::write_i64(&mut value[..8], self.mark);
+        LittleEndian::write_u64(&mut value[8..], self.uid);
+        value
+    }
+}
+
+// the entries marked before time, a unix time
+pub fn expired_before(db: &Db, time: i64) -> ExpiredBefore<'_> {
+    ExpiredBefore {
+        records: db.iter_live(),
+        time,
+    }
+}
+
+// delete everything marked before time, in one transaction, and say how
+// many went
+pub fn expire(db: &mut Db, time: i64) -> Result<usize, Error> {
+    db.delete_where(|_, value| Ok(Delivery::parse(value)?.mark < time))
+}
+
+impl<'a> Iterator for ExpiredBefore<'a> {
+    type Item = Result<(DeliverKey, Delivery), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for r in self.records.by_ref() {
+            let parsed =
+                r.and_then(|r| Ok((DeliverKey::parse(r.key())?, Delivery::parse(r.value())?)));
+            match parsed {
+                Ok((_, delivery)) if delivery.mark >= self.time => continue,
+                parsed => return Some(parsed),
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,339 @@
+// cyrus' dlists, the text format most of its databases keep their values in.
+// mailboxes.db has things like
+//
+//   %(A %(fred lrswipkxtecdan anyone p) I 46c8e8a5-... P default V 1450299080)
+//
+// which is a map (%( ... )) of key value pairs, the values being atoms,
+// lists (( ... )) or more maps. an atom can also be written as a quoted
+// string ("a b"), or as a literal ({3}\r\n and then 3 raw bytes) when it has
+// bytes a string can't carry. NIL is nothing at all.
+//
+// parsing borrows from the value wherever it can, which is everywhere except
+// quoted strings with backslashes in them. to_vec writes a dlist back out
+// byte for byte the way cyrus' dlist_printbuf would, and with serde on,
+// from_bytes reads one into anything that's Deserialize and to_bytes writes
+// anything that's Serialize as one
+
+use std::borrow::Cow;
+
+use crate::twoskip::Error;
+
+#[cfg(feature = "serde")]
+mod de;
+#[cfg(feature = "serde")]
+mod ser;
+
+#[cfg(feature = "serde")]
+pub use de::{from_bytes, from_dlist, Deserializer};
+#[cfg(feature = "serde")]
+pub use ser::{to_bytes, to_dlist, Serializer};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Dlist<'a> {
+    Nil,
+    // however it was written, just the bytes
+    Atom(Cow<'a, [u8]>),
+    List(Vec<Dlist<'a>>),
+    // in the order they were written, which cyrus keeps
+    Map(Vec<Pair<'a>>),
+}
+
+// a key of a map and its value
+pub type Pair<'a> = (Cow<'a, [u8]>, Dlist<'a>);
+
+pub fn parse(value: &[u8]) -> Result<Dlist<'_>, Error> {
+    parse_with(value, b" ")
+}
+
+// the same, but with tabs and line breaks between things as well as spaces,
+// for one that's been spread over lines for someone to read. cyrus never
+// writes them like that, so parse doesn't take them
+pub fn parse_multiline(value: &[u8]) -> Result<Dlist<'_>, Error> {
+    parse_with(value, b" \t\r\n")
+}
+
+fn parse_with<'a>(value: &'a [u8], spaces: &'static [u8]) -> Result<Dlist<'a>, Error> {
+    let mut parser = Parser {
+        data: value,
+        at: 0,
+        spaces,
+    };
+    let dlist = parser.item()?;
+    parser.skip_space();
+    match parser.at == value.len() {
+        true => Ok(dlist),
+        false => Err(parser.error()),
+    }
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    at: usize,
+    spaces: &'static [u8],
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self) -> Error {
+        Error::InvalidDlist { offset: self.at }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.at).copied()
+    }
+
+    fn at_space(&self) -> bool {
+        matches!(self.peek(), Some(c) if self.spaces.contains(&c))
+    }
+
+    fn skip_space(&mut self) {
+        while self.at_space() {
+            self.at += 1;
+        }
+    }
+
+    fn item(&mut self) -> Result<Dlist<'a>, Error> {
+        self.skip_space();
+        match self.peek() {
+            Some(b'%') if self.data.get(self.at + 1) == Some(&b'(') => {
+                self.at += 2;
+                let mut pairs = vec![];
+                while !self.close()? {
+                    let key = match self.item()? {
+                        Dlist::Atom(key) => key,
+                        _ => return Err(self.error()),
+                    };
+                    pairs.push((key, self.item()?));
+                }
+                Ok(Dlist::Map(pairs))
+            }
+            Some(b'(') => {
+                self.at += 1;
+                let mut items = vec![];
+                while !self.close()? {
+                    items.push(self.item()?);
+                }
+                Ok(Dlist::List(items))
+            }
+            Some(b'"') => self.quoted().map(Dlist::Atom),
+            Some(b'{') => self.literal().map(|l| Dlist::Atom(Cow::Borrowed(l))),
+            Some(b')') | None => Err(self.error()),
+            Some(_) => {
+                let start = self.at;
+                while !matches!(self.peek(), None | Some(b'(' | b')' | b'\r' | b'\n'))
+                    && !self.at_space()
+                {
+                    self.at += 1;
+                }
+                match &self.data[start..self.at] {
+                    b"" => Err(self.error()),
+                    b"NIL" => Ok(Dlist::Nil),
+                    atom => Ok(Dlist::Atom(Cow::Borrowed(atom))),
+                }
+            }
+        }
+    }
+
+    // true, and past it, if the next thing is the end of a list or map
+    fn close(&mut self) -> Result<bool, Error> {
+        self.skip_space();
+        match self.peek() {
+            Some(b')') => {
+                self.at += 1;
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+            None => Err(self.error()),
+        }
+    }
+
+    fn quoted(&mut self) -> Result<Cow<'a, [u8]>, Error> {
+        self.at += 1;
+        let start = self.at;
+        let mut unescaped: Option<Vec<u8>> = None;
+        loop {
+            match self.peek() {
+                None | Some(b'\r' | b'\n') => return Err(self.error()),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    let buf = unescaped.get_or_insert_with(|| self.data[start..self.at].to_vec());
+                    match self.data.get(self.at + 1) {
+                        Some(&c) => buf.push(c),
+                        None => return Err(self.error()),
+                    }
+                    self.at += 2;
+                }
+                Some(c) => {
+                    if let Some(ref mut buf) = unescaped {
+                        buf.push(c);
+                    }
+                    self.at += 1;
+                }
+            }
+        }
+        let s = match unescaped {
+            Some(buf) => Cow::Owned(buf),
+            None => Cow::Borrowed(&self.data[start..self.at]),
+        };
+        self.at += 1;
+        Ok(s)
+    }
+
+    // {n}\r\n or {n+}\r\n, then n bytes of anything
+    fn literal(&mut self) -> Result<&'a [u8], Error> {
+        let start = self.at + 1;
+        let end = self.data[start..]
+            .iter()
+            .position(|&c| c == b'}')
+            .map(|n| start + n)
+            .ok_or_else(|| self.error())?;
+        let digits = self.data[start..end]
+            .strip_suffix(b"+")
+            .unwrap_or(&self.data[start..end]);
+        let len = std::str::from_utf8(digits)
+            .ok()
+            .filter(|d| !d.is_empty() && d.bytes().all(|c| c.is_ascii_digit()))
+            .and_then(|d| d.parse::<usize>().ok())
+            .ok_or_else(|| self.error())?;
+        self.at = end + 1;
+        if !self.data[self.at..].starts_with(b"\r\n") {
+            return Err(self.error());
+        }
+        self.at += 2;
+        let bytes = self
+            .data
+            .get(self.at..self.at.saturating_add(len))
+            .ok_or_else(|| self.error())?;
+        self.at += len;
+        Ok(bytes)
+    }
+}
+
+impl<'a> Dlist<'a> {
+    // the value for key, if this is a map that has it
+    pub fn get(&self, key: &[u8]) -> Option<&Dlist<'a>> {
+        match self {
+            Dlist::Map(pairs) => pairs.iter().find(|(k, _)| &k[..] == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Dlist::Atom(atom) => Some(atom),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_list(&self) -> Option<&[Dlist<'a>]> {
+        match self {
+            Dlist::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&[Pair<'a>]> {
+        match self {
+            Dlist::Map(pairs) => Some(pairs),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut Dlist<'a>> {
+        match self {
+            Dlist::Map(pairs) => pairs
+                .iter_mut()
+                .find(|(k, _)| &k[..] == key)
+                .map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Dlist::Nil)
+    }
+
+    // a copy that doesn't borrow from the value any more
+    pub fn into_owned(self) -> Dlist<'static> {
+        let owned = |bytes: Cow<'a, [u8]>| Cow::Owned(bytes.into_owned());
+        match self {
+            Dlist::Nil => Dlist::Nil,
+            Dlist::Atom(atom) => Dlist::Atom(owned(atom)),
+            Dlist::List(items) => Dlist::List(items.into_iter().map(Dlist::into_owned).collect()),
+            Dlist::Map(pairs) => Dlist::Map(
+                pairs
+                    .into_iter()
+                    .map(|(k, v)| (owned(k), v.into_owned()))
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.write(&mut out);
+        out
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Dlist::Nil => out.extend_from_slice(b"NIL"),
+            Dlist::Atom(atom) => write_astring(atom, out),
+            Dlist::List(items) => {
+                out.push(b'(');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(b' ');
+                    }
+                    item.write(out);
+                }
+                out.push(b')');
+            }
+            Dlist::Map(pairs) => {
+                out.extend_from_slice(b"%(");
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        out.push(b' ');
+                    }
+                    write_astring(key, out);
+                    out.push(b' ');
+                    value.write(out);
+                }
+                out.push(b')');
+            }
+        }
+    }
+}
+
+// prot_printastring: bare if imparse_isatom says it can be (and it isn't
+// NIL), else what prot_printmap does, which is a quoted string if there's
+// nothing in it a quoted string can't have and a literal if there is
+fn write_astring(s: &[u8], out: &mut Vec<u8>) {
+    if s.is_empty() {
+        out.extend_from_slice(b"\"\"");
+    } else if s.len() < 1024 && s != b"NIL" && s.iter().all(|&c| atom_char(c)) {
+        out.extend_from_slice(s);
+    } else if s.len() < 1024 && s.iter().all(|&c| quoted_char(c)) {
+        out.push(b'"');
+        out.extend_from_slice(s);
+        out.push(b'"');
+    } else {
+        out.extend_from_slice(format!("{{{}}}\r\n", s.len()).as_bytes());
+        out.extend_from_slice(s);
+    }
+}
+
+// cyrus' test is c < 0x1f, so 0x1f itself is fine
+fn atom_char(c: u8) -> bool {
+    !matches!(
+        c,
+        0..=0x1e | 0x7f..=0xff | b' ' | b'{' | b'(' | b')' | b'"' | b'%' | b'*' | b'\\'
+    )
+}
+
+fn quoted_char(c: u8) -> bool {
+    !matches!(c, 0 | 0x80..=0xff | b'\r' | b'\n' | b'"' | b'%' | b'\\')
+}
@@ -0,0 +1,312 @@
+// serde's Deserialize for dlists, so a value can go straight into a struct
+// of its own:
+//
+//   #[derive(Deserialize)]
+//   struct Entry<'a> {
+//       #[serde(rename = "P")]
+//       partition: &'a str,
+//       #[serde(rename = "V")]
+//       uidvalidity: u32,
+//   }
+//
+// maps are maps or structs, lists are sequences or tuples, and atoms are
+// whatever's asked for, parsed from the text for numbers. NIL is None, and
+// a key that isn't there is too. an atom is the name of a unit variant, and
+// a map with one key in it a variant with something in it. anything that
+// was a plain atom or literal can be borrowed, as &str or &[u8]
+
+use std::borrow::Cow;
+use std::fmt;
+
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::Deserialize;
+
+use super::{parse, Dlist, Pair};
+use crate::twoskip::Error;
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::InternalError(format!("dlist: {}", msg).into())
+    }
+}
+
+pub fn from_bytes<'de, T: Deserialize<'de>>(value: &'de [u8]) -> Result<T, Error> {
+    from_dlist(&parse(value)?)
+}
+
+pub fn from_dlist<'de, T: Deserialize<'de>>(dlist: &Dlist<'de>) -> Result<T, Error> {
+    T::deserialize(Deserializer { dlist })
+}
+
+pub struct Deserializer<'a, 'de> {
+    dlist: &'a Dlist<'de>,
+}
+
+impl<'a, 'de> Deserializer<'a, 'de> {
+    pub fn new(dlist: &'a Dlist<'de>) -> Deserializer<'a, 'de> {
+        Deserializer { dlist }
+    }
+
+    fn atom(&self) -> Result<&'a Cow<'de, [u8]>, Error> {
+        match self.dlist {
+            Dlist::Atom(atom) => Ok(atom),
+            _ => Err(self.unexpected("an atom")),
+        }
+    }
+
+    fn str(&self) -> Result<&'a str, Error> {
+        std::str::from_utf8(self.atom()?).map_err(|_| de::Error::custom("atom isn't utf-8"))
+    }
+
+    fn number<T: std::str::FromStr>(&self) -> Result<T, Error> {
+        let s = self.str()?;
+        s.parse()
+            .map_err(|_| de::Error::custom(format!("{:?} isn't a number of that kind", s)))
+    }
+
+    fn unexpected(&self, wanted: &str) -> Error {
+        let got = match self.dlist {
+            Dlist::Nil => "NIL",
+            Dlist::Atom(_) => "an atom",
+            Dlist::List(_) => "a list",
+            Dlist::Map(_) => "a map",
+        };
+        de::Error::custom(format!("expected {}, got {}", wanted, got))
+    }
+}
+
+macro_rules! number {
+    ($($deserialize:ident $visit:ident),*) => {
+        $(
+            fn $deserialize<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                visitor.$visit(self.number()?)
+            }
+        )*
+    };
+}
+
+impl<'a, 'de> de::Deserializer<'de> for Deserializer<'a, 'de> {
+    type Error = Error;
+
+    // text if it's utf-8, which it nearly always is, and bytes if not
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.dlist {
+            Dlist::Nil => visitor.visit_none(),
+            Dlist::Atom(Cow::Borrowed(atom)) => match std::str::from_utf8(atom) {
+                Ok(s) => visitor.visit_borrowed_str(s),
+                Err(_) => visitor.visit_borrowed_bytes(atom),
+            },
+            Dlist::Atom(Cow::Owned(atom)) => match std::str::from_utf8(atom) {
+                Ok(s) => visitor.visit_str(s),
+                Err(_) => visitor.visit_bytes(atom),
+            },
+            Dlist::List(_) => self.deserialize_seq(visitor),
+            Dlist::Map(_) => self.deserialize_map(visitor),
+        }
+    }
+
+    number!(
+        deserialize_u8 visit_u8, deserialize_u16 visit_u16, deserialize_u32 visit_u32,
+        deserialize_u64 visit_u64, deserialize_i8 visit_i8, deserialize_i16 visit_i16,
+        deserialize_i32 visit_i32, deserialize_i64 visit_i64, deserialize_f32 visit_f32,
+        deserialize_f64 visit_f64
+    );
+
+    // cyrus writes flags as 0 and 1
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.str()? {
+            "1" => visitor.visit_bool(true),
+            "0" => visitor.visit_bool(false),
+            s => Err(de::Error::custom(format!("{:?} isn't 0 or 1", s))),
+        }
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let mut chars = self.str()?.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => visitor.visit_char(c),
+            _ => Err(de::Error::custom("expected one character")),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let not_utf8 = |_| <Error as de::Error>::custom("atom isn't utf-8");
+        match self.atom()? {
+            Cow::Borrowed(atom) => {
+                visitor.visit_borrowed_str(std::str::from_utf8(atom).map_err(not_utf8)?)
+            }
+            Cow::Owned(atom) => visitor.visit_str(std::str::from_utf8(atom).map_err(not_utf8)?),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.atom()? {
+            Cow::Borrowed(atom) => visitor.visit_borrowed_bytes(atom),
+            Cow::Owned(atom) => visitor.visit_bytes(atom),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.dlist {
+            Dlist::Nil => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.dlist {
+            Dlist::List(items) => visitor.visit_seq(SeqAccess {
+                items: items.iter(),
+            }),
+            // an empty list is written as () but NIL is nothing too
+            Dlist::Nil => visitor.visit_seq(SeqAccess { items: [].iter() }),
+            _ => Err(self.unexpected("a list")),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.dlist {
+            Dlist::Map(pairs) => visitor.visit_map(MapAccess {
+                pairs: pairs.iter(),
+                value: None,
+            }),
+            _ => Err(self.unexpected("a map")),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.dlist {
+            Dlist::Atom(_) => visitor.visit_enum(self.str()?.into_deserializer()),
+            Dlist::Map(pairs) if pairs.len() == 1 => {
+                visitor.visit_enum(MapAccessDeserializer::new(MapAccess {
+                    pairs: pairs.iter(),
+                    value: None,
+                }))
+            }
+            _ => Err(self.unexpected("an atom or a map of one")),
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+}
+
+struct SeqAccess<'a, 'de> {
+    items: std::slice::Iter<'a, Dlist<'de>>,
+}
+
+impl<'a, 'de> de::SeqAccess<'de> for SeqAccess<'a, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        match self.items.next() {
+            Some(dlist) => seed.deserialize(Deserializer { dlist }).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.items.len())
+    }
+}
+
+struct MapAccess<'a, 'de> {
+    pairs: std::slice::Iter<'a, Pair<'de>>,
+    value: Option<&'a Dlist<'de>>,
+}
+
+impl<'a, 'de> de::MapAccess<'de> for MapAccess<'a, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        let (key, value) = match self.pairs.next() {
+            Some((key, value)) => (key, value),
+            None => return Ok(None),
+        };
+        self.value = Some(value);
+        let key = Dlist::Atom(key.clone());
+        seed.deserialize(Deserializer { dlist: &key }).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        match self.value.take() {
+            Some(dlist) => seed.deserialize(Deserializer { dlist }),
+            None => Err(de::Error::custom("value asked for before its key")),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.pairs.len())
+    }
+}
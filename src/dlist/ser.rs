@@ -0,0 +1,361 @@
+// the other way round from de: anything that's Serialize as a dlist, the
+// way cyrus would have written it. structs and maps are maps, sequences and
+// tuples lists, numbers atoms of their digits, bools 0 and 1 and None NIL.
+// a unit variant is its name, and any other variant a map of its name to
+// what's in it, which is what de reads back. map keys have to come out as
+// atoms, and floats aren't anything cyrus writes, so they're errors
+
+use std::borrow::Cow;
+
+use serde::ser::{self, Serialize};
+
+use super::{Dlist, Pair};
+use crate::twoskip::Error;
+
+impl ser::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Error {
+        Error::InternalError(format!("dlist: {}", msg).into())
+    }
+}
+
+pub fn to_dlist<T: Serialize + ?Sized>(value: &T) -> Result<Dlist<'static>, Error> {
+    value.serialize(Serializer)
+}
+
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+    Ok(to_dlist(value)?.to_vec())
+}
+
+pub struct Serializer;
+
+fn atom<T: ToString>(t: T) -> Dlist<'static> {
+    Dlist::Atom(Cow::Owned(t.to_string().into_bytes()))
+}
+
+fn variant(name: &'static str, value: Dlist<'static>) -> Dlist<'static> {
+    Dlist::Map(vec![(Cow::Borrowed(name.as_bytes()), value)])
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Dlist<'static>;
+    type Error = Error;
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeList;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeMap;
+    type SerializeStructVariant = SerializeMap;
+
+    fn serialize_bool(self, v: bool) -> Result<Dlist<'static>, Error> {
+        Ok(atom(u8::from(v)))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Dlist<'static>, Error> {
+        Ok(atom(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Dlist<'static>, Error> {
+        Ok(atom(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Dlist<'static>, Error> {
+        Ok(atom(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Dlist<'static>, Error> {
+        Ok(atom(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Dlist<'static>, Error> {
+        Ok(atom(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Dlist<'static>, Error> {
+        Ok(atom(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Dlist<'static>, Error> {
+        Ok(atom(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Dlist<'static>, Error> {
+        Ok(atom(v))
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Dlist<'static>, Error> {
+        Err(ser::Error::custom("floats can't be written"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Dlist<'static>, Error> {
+        Err(ser::Error::custom("floats can't be written"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Dlist<'static>, Error> {
+        Ok(atom(v))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Dlist<'static>, Error> {
+        Ok(atom(v))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Dlist<'static>, Error> {
+        Ok(Dlist::Atom(Cow::Owned(v.to_vec())))
+    }
+
+    fn serialize_none(self) -> Result<Dlist<'static>, Error> {
+        Ok(Dlist::Nil)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Dlist<'static>, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Dlist<'static>, Error> {
+        Ok(Dlist::Nil)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Dlist<'static>, Error> {
+        Ok(Dlist::Nil)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Dlist<'static>, Error> {
+        Ok(atom(variant))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Dlist<'static>, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Dlist<'static>, Error> {
+        Ok(variant(name, value.serialize(self)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeList, Error> {
+        Ok(SerializeList {
+            items: Vec::with_capacity(len.unwrap_or(0)),
+            variant: None,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeList, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeList, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        name: &'static str,
+        len: usize,
+    ) -> Result<SerializeList, Error> {
+        Ok(SerializeList {
+            items: Vec::with_capacity(len),
+            variant: Some(name),
+        })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeMap, Error> {
+        Ok(SerializeMap {
+            pairs: Vec::with_capacity(len.unwrap_or(0)),
+            key: None,
+            variant: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeMap, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        name: &'static str,
+        len: usize,
+    ) -> Result<SerializeMap, Error> {
+        Ok(SerializeMap {
+            pairs: Vec::with_capacity(len),
+            key: None,
+            variant: Some(name),
+        })
+    }
+}
+
+pub struct SerializeList {
+    items: Vec<Dlist<'static>>,
+    variant: Option<&'static str>,
+}
+
+impl SerializeList {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.items.push(value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Dlist<'static> {
+        match self.variant {
+            Some(name) => variant(name, Dlist::List(self.items)),
+            None => Dlist::List(self.items),
+        }
+    }
+}
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = Dlist<'static>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Dlist<'static>, Error> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = Dlist<'static>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Dlist<'static>, Error> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = Dlist<'static>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Dlist<'static>, Error> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeList {
+    type Ok = Dlist<'static>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Dlist<'static>, Error> {
+        Ok(self.finish())
+    }
+}
+
+pub struct SerializeMap {
+    pairs: Vec<Pair<'static>>,
+    key: Option<Cow<'static, [u8]>>,
+    variant: Option<&'static str>,
+}
+
+impl SerializeMap {
+    fn insert<T: Serialize + ?Sized>(
+        &mut self,
+        key: Cow<'static, [u8]>,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.pairs.push((key, value.serialize(Serializer)?));
+        Ok(())
+    }
+
+    fn finish(self) -> Dlist<'static> {
+        match self.variant {
+            Some(name) => variant(name, Dlist::Map(self.pairs)),
+            None => Dlist::Map(self.pairs),
+        }
+    }
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Dlist<'static>;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        match key.serialize(Serializer)? {
+            Dlist::Atom(key) => {
+                self.key = Some(key);
+                Ok(())
+            }
+            _ => Err(ser::Error::custom("map keys have to be atoms")),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        match self.key.take() {
+            Some(key) => self.insert(key, value),
+            None => Err(ser::Error::custom("value given before its key")),
+        }
+    }
+
+    fn end(self) -> Result<Dlist<'static>, Error> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeStruct for SerializeMap {
+    type Ok = Dlist<'static>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(Cow::Borrowed(key.as_bytes()), value)
+    }
+
+    fn end(self) -> Result<Dlist<'static>, Error> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeMap {
+    type Ok = Dlist<'static>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(Cow::Borrowed(key.as_bytes()), value)
+    }
+
+    fn end(self) -> Result<Dlist<'static>, Error> {
+        Ok(self.finish())
+    }
+}
@@ -0,0 +1,791 @@
+// moving records between twoskip and other formats.
+//
+// cyrus' flat backend is a text file of KEY<tab>VALUE lines in key order,
+// with tab, newline, carriage return, NUL and backslash escaped so that any
+// bytes fit on one line. to_flat writes one and from_flat reads one back.
+// keys with those bytes in them can sort differently once escaped, so a
+// flat file cyrus itself would search needs them not to have any.
+//
+// with the sqlite feature, to_sqlite and from_sqlite do the same with a
+// sqlite database holding a table of key and value blobs, and with the lmdb
+// feature, to_lmdb and from_lmdb with an lmdb environment.
+//
+// to_csv writes csv, with CsvOptions for the delimiter, quoting and what to
+// do about bytes that won't survive a csv pipeline.
+//
+// to_stream sends the records framed with where they are in the file, so
+// that a transfer that breaks off can pick up again from the last record
+// that arrived; from_stream is the receiving end.
+//
+// to_cdb writes a constant database, djb's read-only hash table format,
+// for when all that's wanted is fast lookups of a snapshot.
+//
+// from_db_dump reads what berkeley db's db_dump prints, for databases from
+// cyrus' long gone berkeley backend.
+//
+// from_quotalegacy reads the directory of little files that cyrus'
+// quotalegacy backend keeps quotas in.
+//
+// with the parquet feature, to_parquet writes every record in the file, not
+// just the live ones, as a parquet file for duckdb, spark and friends
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+#[cfg(feature = "parquet")]
+use std::sync::Arc;
+
+#[cfg(feature = "lmdb")]
+use crate::lmdb::Env;
+#[cfg(feature = "sqlite")]
+use crate::sqlite::Connection;
+use crate::twoskip::{Builder, Db, Error};
+
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+#[cfg(feature = "parquet")]
+use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+#[cfg(feature = "parquet")]
+use parquet::file::{properties::WriterProperties, writer::SerializedFileWriter};
+#[cfg(feature = "parquet")]
+use parquet::schema::parser::parse_message_type;
+
+pub fn to_flat<W: Write>(db: &Db, out: &mut W) -> Result<(), Error> {
+    for r in db.iter_live() {
+        let r = r?;
+        out.write_all(&escape(r.key()))?;
+        out.write_all(b"\t")?;
+        out.write_all(&escape(r.value()))?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+// every line goes in one transaction, so a bad line anywhere means none of
+// them do. blank lines are skipped. says how many records were stored
+pub fn from_flat<R: BufRead>(db: &mut Db, input: R) -> Result<usize, Error> {
+    let mut txn = db.begin()?;
+    let mut count = 0;
+    for (n, line) in input.split(b'\n').enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let tab = match line.iter().position(|&c| c == b'\t') {
+            Some(tab) => tab,
+            None => {
+                return Err(Error::InvalidLine {
+                    line: n + 1,
+                    reason: "no tab",
+                })
+            }
+        };
+        txn.put(&unescape(&line[..tab]), &unescape(&line[tab + 1..]))?;
+        count += 1;
+    }
+    txn.commit()?;
+    Ok(count)
+}
+
+// when to_csv puts a field in double quotes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quoting {
+    // when it has the delimiter, a quote, or a line break in it (RFC 4180)
+    Needed,
+    Always,
+}
+
+// when to_csv base64 encodes a field
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Base64 {
+    Never,
+    // when it isn't utf-8 or has control characters other than tabs and
+    // line breaks in it, as statuscache keys do. those fields are written
+    // as "base64:" and then the encoding, and so is any field that starts
+    // with "base64:" already, so that it's always clear which is which
+    Binary,
+    // every key and value, with no prefix
+    Always,
+}
+
+#[derive(Clone, Debug)]
+pub struct CsvOptions {
+    delimiter: u8,
+    quoting: Quoting,
+    base64: Base64,
+    header: bool,
+}
+
+impl CsvOptions {
+    pub fn new() -> CsvOptions {
+        CsvOptions {
+            delimiter: b',',
+            quoting: Quoting::Needed,
+            base64: Base64::Never,
+            header: true,
+        }
+    }
+
+    // anything but a quote or a line break
+    pub fn delimiter(&mut self, delimiter: u8) -> &mut CsvOptions {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn quoting(&mut self, quoting: Quoting) -> &mut CsvOptions {
+        self.quoting = quoting;
+        self
+    }
+
+    pub fn base64(&mut self, base64: Base64) -> &mut CsvOptions {
+        self.base64 = base64;
+        self
+    }
+
+    // a first line of "key,value", on by default
+    pub fn header(&mut self, header: bool) -> &mut CsvOptions {
+        self.header = header;
+        self
+    }
+
+    fn write_field<W: Write>(&self, out: &mut W, field: &[u8]) -> Result<(), Error> {
+        let binary = |f: &[u8]| match std::str::from_utf8(f) {
+            Ok(s) => {
+                s.chars().any(|c| c.is_control() && !"\t\r\n".contains(c))
+                    || s.starts_with("base64:")
+            }
+            Err(_) => true,
+        };
+        let encoded;
+        let field = match self.base64 {
+            Base64::Always => {
+                encoded = encode_base64(field).into_bytes();
+                &encoded
+            }
+            Base64::Binary if binary(field) => {
+                encoded = format!("base64:{}", encode_base64(field)).into_bytes();
+                &encoded
+            }
+            _ => field,
+        };
+
+        let special = |c: &u8| *c == self.delimiter || b"\"\r\n".contains(c);
+        if self.quoting == Quoting::Needed && !field.iter().any(special) {
+            out.write_all(field)?;
+            return Ok(());
+        }
+        out.write_all(b"\"")?;
+        for part in field.split_inclusive(|&c| c == b'"') {
+            out.write_all(part)?;
+            if part.last() == Some(&b'"') {
+                out.write_all(b"\"")?;
+            }
+        }
+        out.write_all(b"\"")?;
+        Ok(())
+    }
+}
+
+impl Default for CsvOptions {
+    fn default() -> CsvOptions {
+        CsvOptions::new()
+    }
+}
+
+// a line for each live record, key then value, in key order
+pub fn to_csv<W: Write>(db: &Db, out: &mut W, options: &CsvOptions) -> Result<(), Error> {
+    if b"\"\r\n".contains(&options.delimiter) {
+        return Err(Error::InternalError(
+            "csv: the delimiter can't be a quote or a line break".into(),
+        ));
+    }
+    let delimiter = [options.delimiter];
+    if options.header {
+        out.write_all(b"key")?;
+        out.write_all(&delimiter)?;
+        out.write_all(b"value\n")?;
+    }
+    for r in db.iter_live() {
+        let r = r?;
+        options.write_field(out, r.key())?;
+        out.write_all(&delimiter)?;
+        options.write_field(out, r.value())?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+const STREAM_MAGIC: &[u8] = b"twoskip stream\n";
+const STREAM_VERSION: u8 = 1;
+const FRAME_RECORD: u8 = b'R';
+const FRAME_END: u8 = b'E';
+
+// how far a stream got: the generation of the file it came from and the
+// offset there of the last record received
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StreamPosition {
+    pub generation: u64,
+    pub offset: usize,
+}
+
+// what from_stream got
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StreamReport {
+    // None if not even one record arrived, and it has to start over
+    pub position: Option<StreamPosition>,
+    pub records: u64,
+    // false if the stream broke off before the end
+    pub complete: bool,
+}
+
+// the live records in key order. the stream starts with the magic, a
+// version byte and the file's generation, then for each record 'R', its
+// offset, and the lengths and bytes of the key and value, and ends with 'E'.
+// numbers are 64 bit big endian. with after, it goes on from the record
+// following the one at that position, which works for as long as the file
+// isn't repacked (GenerationMismatch once it is), even if that record's
+// been replaced or deleted since
+pub fn to_stream<W: Write>(
+    db: &Db,
+    out: &mut W,
+    after: Option<StreamPosition>,
+) -> Result<(), Error> {
+    let generation = db.header().generation();
+    let mut cursor = db.cursor();
+    let mut r = match after {
+        Some(after) if after.generation != generation => return Err(Error::GenerationMismatch),
+        Some(after) => {
+            let key = db.key_at(after.offset)?;
+            match cursor.seek(key)? {
+                Some(r) if r.key() == key => cursor.next()?,
+                r => r,
+            }
+        }
+        None => cursor.next()?,
+    };
+
+    out.write_all(STREAM_MAGIC)?;
+    out.write_u8(STREAM_VERSION)?;
+    out.write_u64::<BigEndian>(generation)?;
+    while let Some(record) = r {
+        out.write_u8(FRAME_RECORD)?;
+        out.write_u64::<BigEndian>(record.offset() as u64)?;
+        out.write_u64::<BigEndian>(record.key().len() as u64)?;
+        out.write_u64::<BigEndian>(record.value().len() as u64)?;
+        out.write_all(record.key())?;
+        out.write_all(record.value())?;
+        r = cursor.next()?;
+    }
+    out.write_u8(FRAME_END)?;
+    Ok(())
+}
+
+// stores the records of a stream from to_stream. if it breaks off, what did
+// arrive is kept, and the report's position is where to_stream should go on
+// from. it's one transaction, so a failure of any other kind keeps nothing
+pub fn from_stream<R: Read>(db: &mut Db, mut input: R) -> Result<StreamReport, Error> {
+    let mut report = StreamReport::default();
+    let mut txn = db.begin()?;
+    match read_stream(&mut input, &mut report, |key, value| txn.put(key, value)) {
+        Ok(()) => report.complete = true,
+        Err(e) if e.io_kind() == Some(io::ErrorKind::UnexpectedEof) => {}
+        Err(e) => return Err(e),
+    }
+    txn.commit()?;
+    Ok(report)
+}
+
+fn read_stream<R, F>(input: &mut R, report: &mut StreamReport, mut put: F) -> Result<(), Error>
+where
+    R: Read,
+    F: FnMut(&[u8], &[u8]) -> Result<(), Error>,
+{
+    let bad = |what: &str| Error::InternalError(format!("stream: {}", what).into());
+
+    let mut magic = [0; STREAM_MAGIC.len()];
+    input.read_exact(&mut magic)?;
+    if magic != STREAM_MAGIC {
+        return Err(bad("not a twoskip stream"));
+    }
+    if input.read_u8()? != STREAM_VERSION {
+        return Err(bad("unknown version"));
+    }
+    let generation = input.read_u64::<BigEndian>()?;
+
+    loop {
+        match input.read_u8()? {
+            FRAME_END => return Ok(()),
+            FRAME_RECORD => {}
+            _ => return Err(bad("unknown frame")),
+        }
+        let offset = input.read_u64::<BigEndian>()? as usize;
+        let key_len = input.read_u64::<BigEndian>()?;
+        let value_len = input.read_u64::<BigEndian>()?;
+        // read through take, so a bad length can't ask for the moon up front
+        let mut key = vec![];
+        let mut value = vec![];
+        for (buf, len) in [(&mut key, key_len), (&mut value, value_len)] {
+            if input.by_ref().take(len).read_to_end(buf)? as u64 != len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+        put(&key, &value)?;
+        report.records += 1;
+        report.position = Some(StreamPosition { generation, offset });
+    }
+}
+
+// a new twoskip file at path from a dump. after a header of name=value lines
+// ending in HEADER=END come key and value lines in turn, each starting with
+// a space, and then DATA=END. with -p (format=print) bytes that aren't
+// printable are \ and two hex digits, and \\ is a backslash; otherwise
+// (format=bytevalue) it's all hex. berkeley could be keeping the keys in any
+// order, so they're sorted before they go in. says how many records there were
+pub fn from_db_dump<R: BufRead, P: AsRef<Path>>(input: R, path: P) -> Result<usize, Error> {
+    let mut in_header = true;
+    let mut print = None;
+    let mut done = false;
+    let mut records = BTreeMap::new();
+    let mut key = None;
+
+    let mut n = 0;
+    for line in input.split(b'\n') {
+        let line = line?;
+        n += 1;
+        let bad = |reason| Error::InvalidLine { line: n, reason };
+        match line.as_slice() {
+            b"" => {}
+            b"HEADER=END" if in_header => in_header = false,
+            b"format=print" if in_header => print = Some(true),
+            b"format=bytevalue" if in_header => print = Some(false),
+            b"type=btree" | b"type=hash" if in_header => {}
+            l if in_header && l.starts_with(b"type=") => {
+                return Err(bad("only btree and hash databases have keys"))
+            }
+            _ if in_header => {}
+            _ if done => return Err(bad("more than one database in the dump")),
+            b"DATA=END" if key.is_some() => return Err(bad("a key without a value")),
+            b"DATA=END" => done = true,
+            l if l[0] == b' ' => {
+                let bytes = match print {
+                    Some(true) => unprint(&l[1..]),
+                    Some(false) => unhex(&l[1..]),
+                    None => return Err(bad("no format in the header")),
+                };
+                let bytes = bytes.ok_or_else(|| bad("bad escape"))?;
+                match key.take() {
+                    None => key = Some(bytes),
+                    Some(key) => {
+                        records.insert(key, bytes);
+                    }
+                }
+            }
+            _ => return Err(bad("data lines start with a space")),
+        }
+    }
+    if !done {
+        return Err(Error::InvalidLine {
+            line: n,
+            reason: "the dump ends before DATA=END",
+        });
+    }
+
+    let mut builder = Builder::create(path)?;
+    builder.extend(&records)?;
+    builder.finish()?;
+    Ok(records.len())
+}
+
+fn unprint(s: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        match s[i] {
+            b'\\' if s.get(i + 1) == Some(&b'\\') => {
+                out.push(b'\\');
+                i += 2;
+            }
+            b'\\' => {
+                out.extend(unhex(s.get(i + 1..i + 3)?)?);
+                i += 3;
+            }
+            c => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    Some(out)
+}
+
+fn unhex(s: &[u8]) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    s.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+// a new twoskip file at path from a quotalegacy directory, which has a file
+// for each quota root in quota/<hash>/<root>. next to it, domains have theirs
+// in domain/<hash>/<domain>/quota/<hash>/<root>, and their own in
+// .../quota/root. keys are the roots, as domain!root in a domain, and values
+// are the files with their lines joined up by spaces ("used limit", then any
+// other resources' name, used and limit), as cyrus' quotalegacy backend
+// hands them out and its quota code reads them from any backend. says how
+// many quota roots there were
+pub fn from_quotalegacy<P: AsRef<Path>, Q: AsRef<Path>>(quota: P, path: Q) -> Result<usize, Error> {
+    let quota = quota.as_ref();
+    let mut records = BTreeMap::new();
+    read_quota_dir(quota, b"", &mut records)?;
+
+    let domains = quota.parent().map(|p| p.join("domain"));
+    if let Some(domains) = domains.filter(|d| d.is_dir()) {
+        for hash in fs::read_dir(domains)? {
+            for domain in fs::read_dir(hash?.path())? {
+                let domain = domain?;
+                let dir = domain.path().join("quota");
+                if !dir.is_dir() {
+                    continue;
+                }
+                let mut prefix = domain.file_name().as_bytes().to_vec();
+                prefix.push(b'!');
+                let root = dir.join("root");
+                if root.is_file() {
+                    records.insert(prefix.clone(), read_quota_file(&root)?);
+                }
+                read_quota_dir(&dir, &prefix, &mut records)?;
+            }
+        }
+    }
+
+    let mut builder = Builder::create(path)?;
+    builder.extend(&records)?;
+    builder.finish()?;
+    Ok(records.len())
+}
+
+// every file in the hash directories under dir, except the .NEW ones cyrus
+// writes before renaming them into place
+fn read_quota_dir(
+    dir: &Path,
+    prefix: &[u8],
+    records: &mut BTreeMap<Vec<u8>, Vec<u8>>,
+) -> Result<(), Error> {
+    for hash in fs::read_dir(dir)? {
+        let hash = hash?;
+        if !hash.file_type()?.is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(hash.path())? {
+            let file = file?;
+            let name = file.file_name();
+            if !file.file_type()?.is_file() || name.as_bytes().ends_with(b".NEW") {
+                continue;
+            }
+            let key = [prefix, name.as_bytes()].concat();
+            records.insert(key, read_quota_file(&file.path())?);
+        }
+    }
+    Ok(())
+}
+
+fn read_quota_file(file: &Path) -> Result<Vec<u8>, Error> {
+    let data = fs::read(file)?;
+    let fields: Vec<&[u8]> = data
+        .split(|c| c.is_ascii_whitespace())
+        .filter(|f| !f.is_empty())
+        .collect();
+    let number = |f: &[u8]| {
+        std::str::from_utf8(f)
+            .ok()
+            .and_then(|f| f.parse::<i64>().ok())
+            .is_some()
+    };
+    if fields.len() < 2 || !number(fields[0]) || !number(fields[1]) {
+        let msg = format!("quotalegacy: {} isn't a quota file", file.display());
+        return Err(Error::InternalError(msg.into()));
+    }
+    Ok(fields.join(&b' '))
+}
+
+// the whole of out, from the start: a 2048 byte header pointing at 256 hash
+// tables, then the records, then the tables. records are lengths and then
+// key and value, table slots are a hash and where its record is, and all the
+// numbers are 32 bit little endian, which is why there's a 4GB limit
+pub fn to_cdb<W: Write + Seek>(db: &Db, out: &mut W) -> Result<(), Error> {
+    let too_big = || Error::InternalError("cdb: over 4GB".into());
+    let pos32 = |pos: u64| u32::try_from(pos).map_err(|_| too_big());
+
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&[0; 2048])?;
+    let mut pos = 2048u64;
+    let mut tables: Vec<Vec<(u32, u32)>> = vec![vec![]; 256];
+    for r in db.iter_live() {
+        let r = r?;
+        let hash = cdb_hash(r.key());
+        tables[(hash & 0xff) as usize].push((hash, pos32(pos)?));
+        out.write_u32::<LittleEndian>(pos32(r.key().len() as u64)?)?;
+        out.write_u32::<LittleEndian>(pos32(r.value().len() as u64)?)?;
+        out.write_all(r.key())?;
+        out.write_all(r.value())?;
+        pos += 8 + r.key().len() as u64 + r.value().len() as u64;
+    }
+
+    // each table is twice as big as it needs to be, so probing for a key
+    // that isn't there stops soon
+    let mut header = Vec::with_capacity(2048);
+    for entries in &tables {
+        let len = entries.len() * 2;
+        header.write_u32::<LittleEndian>(pos32(pos)?)?;
+        header.write_u32::<LittleEndian>(len as u32)?;
+
+        let mut slots = vec![(0, 0); len];
+        for &(hash, at) in entries {
+            let mut i = (hash >> 8) as usize % len;
+            while slots[i].1 != 0 {
+                i = (i + 1) % len;
+            }
+            slots[i] = (hash, at);
+        }
+        for (hash, at) in slots {
+            out.write_u32::<LittleEndian>(hash)?;
+            out.write_u32::<LittleEndian>(at)?;
+        }
+        pos += 8 * len as u64;
+    }
+    pos32(pos)?;
+
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&header)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn cdb_hash(key: &[u8]) -> u32 {
+    key.iter()
+        .fold(5381u32, |h, &c| (h << 5).wrapping_add(h) ^ c as u32)
+}
+
+// a new table called records in the database at path, which is created if
+// it isn't there. it's an error if the table already is
+#[cfg(feature = "sqlite")]
+pub fn to_sqlite<P: AsRef<Path>>(db: &Db, path: P) -> Result<(), Error> {
+    let conn = Connection::open(path.as_ref(), true)?;
+    conn.exec("BEGIN")?;
+    conn.exec(
+        "CREATE TABLE records (key BLOB NOT NULL PRIMARY KEY, value BLOB NOT NULL) WITHOUT ROWID",
+    )?;
+    let mut insert = conn.prepare("INSERT INTO records (key, value) VALUES (?, ?)")?;
+    for r in db.iter_live() {
+        let r = r?;
+        insert.bind_blob(1, r.key())?;
+        insert.bind_blob(2, r.value())?;
+        insert.step()?;
+        insert.reset()?;
+    }
+    drop(insert);
+    conn.exec("COMMIT")
+}
+
+// everything in the records table, in one transaction. text works as well
+// as blobs, for tables that were filled in by hand
+#[cfg(feature = "sqlite")]
+pub fn from_sqlite<P: AsRef<Path>>(db: &mut Db, path: P) -> Result<usize, Error> {
+    let conn = Connection::open(path.as_ref(), false)?;
+    let mut select = conn.prepare("SELECT key, value FROM records")?;
+    let mut txn = db.begin()?;
+    let mut count = 0;
+    while select.step()? {
+        txn.put(select.column_blob(0), select.column_blob(1))?;
+        count += 1;
+    }
+    txn.commit()?;
+    Ok(count)
+}
+
+// into the main database of the lmdb environment in the directory at path,
+// which is created if need be. it has to be empty. lmdb puts keys in byte
+// order, and so does twoskip unless it was opened with some other comparator,
+// in which case this fails rather than put them in a different order
+#[cfg(feature = "lmdb")]
+pub fn to_lmdb<P: AsRef<Path>>(db: &Db, path: P) -> Result<(), Error> {
+    fs::create_dir_all(&path)?;
+    // a generous guess at how big it'll get, since it's only address space
+    let env = Env::open(
+        path.as_ref(),
+        false,
+        4 * db.header().current_size() + (64 << 20),
+    )?;
+    let mut txn = env.begin(false)?;
+    txn.each(|_, _| Err(Error::InternalError("lmdb: database isn't empty".into())))?;
+    for r in db.iter_live() {
+        let r = r?;
+        txn.append(r.key(), r.value())?;
+    }
+    txn.commit()
+}
+
+// everything in the environment's main database, in one transaction
+#[cfg(feature = "lmdb")]
+pub fn from_lmdb<P: AsRef<Path>>(db: &mut Db, path: P) -> Result<usize, Error> {
+    let env = Env::open(path.as_ref(), true, 0)?;
+    let read = env.begin(true)?;
+    let mut txn = db.begin()?;
+    let mut count = 0;
+    read.each(|key, value| {
+        count += 1;
+        txn.put(key, value)
+    })?;
+    txn.commit()?;
+    Ok(count)
+}
+
+// a new parquet file at path with a row for every record from the start of
+// the file, in the order they were written: key and value (null for the
+// records that don't have them), type as dump() names it, and offset.
+// rows go out in groups so the whole file never has to be held at once
+#[cfg(feature = "parquet")]
+pub fn to_parquet<P: AsRef<Path>>(db: &Db, path: P) -> Result<(), Error> {
+    const SCHEMA: &str = "message records {
+        optional binary key;
+        optional binary value;
+        required binary type (STRING);
+        required int64 offset;
+    }";
+    const ROWS_PER_GROUP: usize = 64 * 1024;
+
+    #[derive(Default)]
+    struct Rows {
+        keys: Vec<ByteArray>,
+        values: Vec<ByteArray>,
+        // 1 where there's a key and value, 0 where they're null
+        present: Vec<i16>,
+        types: Vec<ByteArray>,
+        offsets: Vec<i64>,
+    }
+
+    let schema = Arc::new(parse_message_type(SCHEMA)?);
+    let props = Arc::new(WriterProperties::builder().build());
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    let mut writer = SerializedFileWriter::new(file, schema, props)?;
+
+    let mut flush = |rows: &mut Rows| -> Result<(), Error> {
+        let mut group = writer.next_row_group()?;
+        let mut n = 0;
+        while let Some(mut column) = group.next_column()? {
+            match n {
+                0 | 1 => {
+                    let data = if n == 0 { &rows.keys } else { &rows.values };
+                    column
+                        .typed::<ByteArrayType>()
+                        .write_batch(data, Some(&rows.present), None)?;
+                }
+                2 => {
+                    column
+                        .typed::<ByteArrayType>()
+                        .write_batch(&rows.types, None, None)?;
+                }
+                _ => {
+                    column
+                        .typed::<Int64Type>()
+                        .write_batch(&rows.offsets, None, None)?;
+                }
+            }
+            column.close()?;
+            n += 1;
+        }
+        group.close()?;
+        *rows = Rows::default();
+        Ok(())
+    };
+
+    let mut rows = Rows::default();
+    let written = db.each_record(|r| {
+        if r.has_data() {
+            rows.keys.push(r.key().to_vec().into());
+            rows.values.push(r.value().to_vec().into());
+        }
+        rows.present.push(r.has_data() as i16);
+        rows.types.push(r.kind().into());
+        rows.offsets.push(r.offset() as i64);
+        if rows.offsets.len() == ROWS_PER_GROUP {
+            flush(&mut rows)?;
+        }
+        Ok(())
+    });
+    let written = written.and_then(|_| match rows.offsets.is_empty() {
+        true => Ok(()),
+        false => flush(&mut rows),
+    });
+    let written = written.and_then(|_| {
+        writer.close()?;
+        Ok(())
+    });
+    if written.is_err() {
+        fs::remove_file(&path).ok();
+    }
+    written
+}
+
+pub fn escape(s: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    for &c in s {
+        match c {
+            b'\0' => out.extend_from_slice(b"\\0"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// like cyrus, a backslash before anything else just means that thing
+pub fn unescape(s: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.iter();
+    while let Some(&c) = bytes.next() {
+        if c != b'\\' {
+            out.push(c);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'0') => out.push(b'\0'),
+            Some(b't') => out.push(b'\t'),
+            Some(b'r') => out.push(b'\r'),
+            Some(b'n') => out.push(b'\n'),
+            Some(&c) => out.push(c),
+            None => out.push(b'\\'),
+        }
+    }
+    out
+}
+
+// standard base64, padded
+pub fn encode_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
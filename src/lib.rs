@@ -1,4 +1,28 @@
+pub mod acl;
+pub mod annotations;
+mod bloom;
+pub mod conversations;
+pub mod cyrusdb;
+pub mod deliver;
+pub mod dlist;
+pub mod export;
+#[cfg(feature = "lmdb")]
+mod lmdb;
+pub mod mailboxes;
+pub mod mbentry;
+pub mod mboxname;
+pub mod ptscache;
+pub mod seen;
+pub mod skiplist;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+pub mod statuscache;
+pub mod tls_sessions;
 pub mod twoskip;
+pub mod typed;
+
+#[cfg(test)]
+use tempdir::TempDir;
 
 #[test]
 fn it_works() {
@@ -12,3 +36,3398 @@ fn it_works() {
     let r = rr.unwrap().unwrap();
     assert_eq!(r.value(), b"%(A %(pinguser254 lrswipkxtecdn admin lrswipkxtecdan anyone p) I 2eababff-a28e-40bc-b00c-00d6ff6ad10b P default T c V 1450299080 F 17365878007025498411 M 1450299078)");
 }
+
+#[test]
+fn create_and_reopen() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    {
+        let mut db = twoskip::create(&path).unwrap();
+        db.put(b"foo", b"bar").unwrap();
+        db.put(b"baz", b"quux").unwrap();
+        assert!(db.delete(b"baz").unwrap());
+    }
+
+    assert!(twoskip::create(&path).is_err());
+
+    let db = twoskip::open_or_create(&path).unwrap();
+    assert_eq!(db.get(b"foo").unwrap().unwrap().value(), b"bar");
+    assert!(db.get(b"baz").unwrap().is_none());
+}
+
+#[test]
+fn repack_keeps_live_records() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..100 {
+        db.put(format!("key{:03}", i).as_bytes(), b"old").unwrap();
+    }
+    for i in 0..100 {
+        match i % 3 {
+            0 => assert!(db.delete(format!("key{:03}", i).as_bytes()).unwrap()),
+            1 => db.put(format!("key{:03}", i).as_bytes(), b"new").unwrap(),
+            _ => {}
+        }
+    }
+
+    let before = std::fs::metadata(&path).unwrap().len();
+    db.repack().unwrap();
+    let after = std::fs::metadata(&path).unwrap().len();
+    assert!(after < before);
+
+    let db = twoskip::open(&path).unwrap();
+    for i in 0..100 {
+        let r = db.get(format!("key{:03}", i).as_bytes()).unwrap();
+        match i % 3 {
+            0 => assert!(r.is_none()),
+            1 => assert_eq!(r.unwrap().value(), b"new"),
+            _ => assert_eq!(r.unwrap().value(), b"old"),
+        }
+    }
+}
+
+#[test]
+fn level_distribution() {
+    let mut counts = [0usize; twoskip::MAX_LEVEL as usize + 1];
+    for _ in 0..100000 {
+        let level = twoskip::random_level();
+        assert!((1..=twoskip::MAX_LEVEL).contains(&level));
+        counts[level as usize] += 1;
+    }
+
+    // each level should have about half as many records as the one below
+    for l in 1..4 {
+        let ratio = counts[l] as f64 / counts[l + 1] as f64;
+        assert!(ratio > 1.7 && ratio < 2.3, "level {} ratio {}", l, ratio);
+    }
+
+    assert_eq!(twoskip::level_from(|| 0.0), twoskip::MAX_LEVEL);
+    assert_eq!(twoskip::level_from(|| 1.0), 1);
+}
+
+#[test]
+fn write_batch() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    db.put(b"gone", b"soon").unwrap();
+
+    let mut batch = twoskip::WriteBatch::new();
+    for i in 0..1000 {
+        batch.put(format!("key{:04}", i).as_bytes(), b"value");
+    }
+    batch.delete(b"gone").delete(b"missing");
+    assert_eq!(batch.len(), 1002);
+    db.write_batch(&batch).unwrap();
+
+    assert!(db.get(b"gone").unwrap().is_none());
+    assert_eq!(db.get(b"key0999").unwrap().unwrap().value(), b"value");
+}
+
+#[test]
+fn recover_uncommitted_tail() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    {
+        let mut db = twoskip::create(&path).unwrap();
+        for i in 0..50 {
+            db.put(format!("key{:02}", i).as_bytes(), b"committed")
+                .unwrap();
+        }
+
+        // a writer that dies mid-transaction never gets to abort
+        let mut txn = db.begin().unwrap();
+        for i in 25..75 {
+            txn.put(format!("key{:02}", i).as_bytes(), b"uncommitted")
+                .unwrap();
+        }
+        txn.delete(b"key00").unwrap();
+        std::mem::forget(txn);
+    }
+    let crashed = std::fs::metadata(&path).unwrap().len();
+
+    // the abandoned records are blanked out, not truncated away
+    let db = twoskip::open(&path).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), crashed);
+    assert!((db.header().current_size() as u64) < crashed);
+    for i in 0..75 {
+        let r = db.get(format!("key{:02}", i).as_bytes()).unwrap();
+        match i {
+            0..=49 => assert_eq!(r.unwrap().value(), b"committed"),
+            _ => assert!(r.is_none()),
+        }
+    }
+
+    // a record that claims no levels at all is an error, not a panic
+    let path = dir.path().join("level0.db");
+    let mut db = twoskip::create(&path).unwrap();
+    db.put(b"key", b"value").unwrap();
+    let offset = db.get(b"key").unwrap().unwrap().offset();
+    drop(db);
+    let mut data = std::fs::read(&path).unwrap();
+    data[offset + 1] = 0;
+    data.extend_from_slice(&[b'X'; 16]);
+    std::fs::write(&path, &data).unwrap();
+    let r = twoskip::OpenOptions::new()
+        .verify_checksums(false)
+        .open(&path);
+    assert!(matches!(r, Err(twoskip::Error::InvalidLevel)));
+}
+
+#[test]
+fn writers_take_turns() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    let mut txn = db.begin().unwrap();
+    txn.put(b"first", b"1").unwrap();
+
+    // the second writer has to wait for the first to commit, and then see
+    // what it wrote
+    let other = path.clone();
+    let writer = std::thread::spawn(move || {
+        let mut db = twoskip::open(&other).unwrap();
+        db.put(b"second", b"2").unwrap();
+    });
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    txn.commit().unwrap();
+    writer.join().unwrap();
+
+    let db = twoskip::open(&path).unwrap();
+    assert_eq!(db.get(b"first").unwrap().unwrap().value(), b"1");
+    assert_eq!(db.get(b"second").unwrap().unwrap().value(), b"2");
+}
+
+#[test]
+fn open_options() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    assert!(twoskip::OpenOptions::new().open(&path).is_err());
+
+    let mut db = twoskip::OpenOptions::new()
+        .create(true)
+        .lock(twoskip::LockMode::Disabled)
+        .open(&path)
+        .unwrap();
+    db.put(b"foo", b"bar").unwrap();
+
+    let mut db = twoskip::OpenOptions::new()
+        .read_only(true)
+        .open(&path)
+        .unwrap();
+    assert_eq!(db.get(b"foo").unwrap().unwrap().value(), b"bar");
+    match db.put(b"foo", b"baz") {
+        Err(twoskip::Error::ReadOnly) => {}
+        r => panic!("expected ReadOnly, got {:?}", r),
+    }
+}
+
+#[test]
+fn snapshot_ignores_later_commits() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for key in ["a", "key", "z"] {
+        db.put(key.as_bytes(), b"before").unwrap();
+    }
+
+    let snap = db.snapshot().unwrap();
+
+    // writers don't wait for it. twice, so that level 0 pointers the
+    // snapshot needs get overwritten in the file
+    let other = path.clone();
+    let writer = std::thread::spawn(move || {
+        let mut db = twoskip::open(&other).unwrap();
+        db.put(b"key", b"after").unwrap();
+        db.put(b"key", b"again").unwrap();
+        db.put(b"b", b"new").unwrap();
+        db.delete(b"z").unwrap();
+    });
+    writer.join().unwrap();
+
+    assert_eq!(snap.get(b"key").unwrap().unwrap().value(), b"before");
+    assert!(snap.get(b"b").unwrap().is_none());
+    let keys: Vec<Vec<u8>> = snap
+        .iter_live()
+        .map(|r| r.unwrap().key().to_vec())
+        .collect();
+    assert_eq!(keys, vec![b"a".to_vec(), b"key".to_vec(), b"z".to_vec()]);
+    drop(snap);
+
+    let snap = db.snapshot().unwrap();
+    assert_eq!(snap.get(b"key").unwrap().unwrap().value(), b"again");
+    assert!(snap.get(b"z").unwrap().is_none());
+}
+
+#[test]
+fn header_follows_commits() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    let created = db.header();
+    assert_eq!(created.num_records(), 0);
+    assert_eq!(created.repack_size(), created.current_size());
+
+    db.put(b"a", b"1").unwrap();
+    db.put(b"b", b"2").unwrap();
+    db.put(b"a", b"3").unwrap();
+    db.delete(b"b").unwrap();
+    db.put(b"c", b"4").unwrap();
+
+    let header = db.header();
+    assert_eq!(header.num_records(), 2);
+    assert_eq!(
+        header.current_size() as u64,
+        std::fs::metadata(&path).unwrap().len()
+    );
+    assert_eq!(header.repack_size(), created.repack_size());
+
+    db.repack().unwrap();
+    assert_eq!(db.header().generation(), created.generation() + 1);
+    assert_eq!(db.header().num_records(), 2);
+    assert_eq!(db.header().repack_size(), db.header().current_size());
+
+    let info = db.header_info();
+    assert_eq!(info.version, 1);
+    assert_eq!(info.generation, db.header().generation());
+    assert_eq!(info.num_records, 2);
+    assert_eq!(info.current_size, info.repack_size);
+    assert!(info.flags.is_empty());
+}
+
+#[test]
+fn checkpoint_on_open() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for _ in 0..10 {
+        let mut batch = twoskip::WriteBatch::new();
+        for i in 0..100 {
+            batch.put(format!("key{:03}", i).as_bytes(), b"value");
+        }
+        db.write_batch(&batch).unwrap();
+    }
+    let before = db.header().current_size();
+    drop(db);
+
+    let db = twoskip::OpenOptions::new()
+        .checkpoint_threshold(2.0)
+        .open(&path)
+        .unwrap();
+    assert!(db.header().current_size() < before);
+    assert_eq!(db.header().num_records(), 100);
+}
+
+#[test]
+fn savepoints() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..20 {
+        db.put(format!("key{:02}", i).as_bytes(), b"orig").unwrap();
+    }
+
+    let mut txn = db.begin().unwrap();
+    txn.put(b"key05", b"kept").unwrap();
+    let outer = txn.savepoint();
+    txn.put(b"key10", b"outer").unwrap();
+    txn.delete(b"key11").unwrap();
+    let inner = txn.savepoint();
+    txn.put(b"key12", b"inner").unwrap();
+    txn.put(b"key99", b"inner").unwrap();
+
+    txn.rollback_to(&inner).unwrap();
+    assert_eq!(txn.get(b"key12").unwrap().unwrap().value(), b"orig");
+    assert!(txn.get(b"key99").unwrap().is_none());
+    assert_eq!(txn.get(b"key10").unwrap().unwrap().value(), b"outer");
+
+    txn.rollback_to(&outer).unwrap();
+    assert!(txn.rollback_to(&inner).is_err());
+    txn.put(b"key15", b"after").unwrap();
+    txn.commit().unwrap();
+
+    let db = twoskip::open(&path).unwrap();
+    assert_eq!(db.header().num_records(), 20);
+    for i in 0..20 {
+        let value = db.get(format!("key{:02}", i).as_bytes()).unwrap().unwrap();
+        match i {
+            5 => assert_eq!(value.value(), b"kept"),
+            15 => assert_eq!(value.value(), b"after"),
+            _ => assert_eq!(value.value(), b"orig"),
+        }
+    }
+}
+
+#[test]
+fn store_if() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    db.store_if(b"key", None, b"one").unwrap();
+    match db.store_if(b"key", None, b"two") {
+        Err(twoskip::Error::ValueMismatch) => {}
+        r => panic!("expected ValueMismatch, got {:?}", r),
+    }
+    assert!(db.store_if(b"key", Some(b"two"), b"three").is_err());
+    db.store_if(b"key", Some(b"one"), b"two").unwrap();
+    assert_eq!(db.get(b"key").unwrap().unwrap().value(), b"two");
+}
+
+#[test]
+fn dirty_flag() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    db.put(b"key", b"committed").unwrap();
+    assert!(!db.header().flags().contains(twoskip::HeaderFlags::DIRTY));
+
+    // a writer that dies mid-transaction leaves the flag behind
+    let mut txn = db.begin().unwrap();
+    txn.put(b"key", b"uncommitted").unwrap();
+    std::mem::forget(txn);
+    drop(db);
+
+    match twoskip::OpenOptions::new().read_only(true).open(&path) {
+        Err(twoskip::Error::Dirty) => {}
+        r => panic!("expected Dirty, got {:?}", r.map(|_| ())),
+    }
+
+    // unless all that's wanted is whatever's there
+    let db = twoskip::OpenOptions::new()
+        .permissive(true)
+        .open(&path)
+        .unwrap();
+    assert!(db.header().flags().contains(twoskip::HeaderFlags::DIRTY));
+    assert_eq!(db.get(b"key").unwrap().unwrap().value(), b"committed");
+    drop(db);
+
+    let db = twoskip::open(&path).unwrap();
+    assert!(!db.header().flags().contains(twoskip::HeaderFlags::DIRTY));
+    assert_eq!(db.get(b"key").unwrap().unwrap().value(), b"committed");
+}
+
+#[test]
+fn blank_space_reuse() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    db.put(b"key", b"committed").unwrap();
+    let committed = db.header().current_size();
+
+    let mut txn = db.begin().unwrap();
+    for i in 0..10 {
+        txn.put(format!("key{}", i).as_bytes(), b"aborted").unwrap();
+    }
+    txn.abort().unwrap();
+    let size = std::fs::metadata(&path).unwrap().len();
+
+    let mut out = vec![];
+    db.dump_to(&mut out).unwrap();
+    let dump = String::from_utf8(out).unwrap();
+    let last = dump.lines().last().unwrap();
+    assert_eq!(
+        last,
+        format!(
+            "{:08x} BLANK len={:08x}",
+            committed,
+            size as usize - committed
+        )
+    );
+
+    // the next transaction goes into the blank space, and what's left of it
+    // stays blank
+    db.put(b"other", b"committed").unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+    let mut out = vec![];
+    db.dump_to(&mut out).unwrap();
+    let dump = String::from_utf8(out).unwrap();
+    let last = dump.lines().last().unwrap();
+    assert!(last.starts_with(&format!("{:08x} BLANK", db.header().current_size())));
+
+    let db = twoskip::open(&path).unwrap();
+    assert_eq!(db.get(b"other").unwrap().unwrap().value(), b"committed");
+    assert!(db.get(b"key0").unwrap().is_none());
+}
+
+#[test]
+fn clear() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..100 {
+        db.put(format!("key{:02}", i).as_bytes(), b"value").unwrap();
+    }
+    let generation = db.header().generation();
+
+    db.clear().unwrap();
+    assert_eq!(db.header().num_records(), 0);
+    assert_eq!(db.header().generation(), generation + 1);
+    assert!(db.get(b"key00").unwrap().is_none());
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 64 + 8 + 32 * 8 + 8);
+
+    db.put(b"key00", b"again").unwrap();
+    let db = twoskip::open(&path).unwrap();
+    assert_eq!(db.header().num_records(), 1);
+    assert_eq!(db.get(b"key00").unwrap().unwrap().value(), b"again");
+}
+
+#[test]
+fn replace_is_atomic() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..100 {
+        db.put(format!("key{:02}", i).as_bytes(), b"value").unwrap();
+    }
+
+    // a writer holding the old file moves over to the new one when it locks
+    let mut other = twoskip::open(&path).unwrap();
+    db.repack().unwrap();
+    assert!(!dir.path().join("test.db.NEW").exists());
+    other.put(b"key100", b"after").unwrap();
+    assert_eq!(other.header().generation(), db.header().generation());
+
+    let db = twoskip::open(&path).unwrap();
+    assert_eq!(db.header().num_records(), 101);
+    assert_eq!(db.get(b"key100").unwrap().unwrap().value(), b"after");
+}
+
+#[test]
+fn repack_policy() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+    twoskip::create(&path).unwrap();
+
+    let asked = Arc::new(AtomicUsize::new(0));
+    let counter = asked.clone();
+    let policy = twoskip::RepackPolicy {
+        ratio: 2.0,
+        min_size: 4096,
+        callback: None,
+    }
+    .callback(move |header| {
+        assert!(header.current_size() > 2 * header.repack_size());
+        // put off the first one
+        counter.fetch_add(1, Ordering::SeqCst) > 0
+    });
+
+    let mut db = twoskip::OpenOptions::new()
+        .repack_policy(policy)
+        .open(&path)
+        .unwrap();
+    for i in 0..200 {
+        db.put(b"key", format!("value{}", i).as_bytes()).unwrap();
+    }
+
+    assert!(asked.load(Ordering::SeqCst) > 1);
+    assert!(db.header().generation() > 1);
+    assert!(db.header().current_size() <= 2 * db.header().repack_size().max(4096));
+    assert_eq!(db.get(b"key").unwrap().unwrap().value(), b"value199");
+}
+
+#[test]
+fn iter_prefix() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for user in &["anne", "pinguser", "pinguser254", "zed"] {
+        for folder in &["", ".Drafts", ".Sent", ".Trash"] {
+            let key = format!("user.{}{}", user, folder);
+            db.put(key.as_bytes(), b"%(A (anne lrswipkxtecdan))")
+                .unwrap();
+        }
+    }
+    db.delete(b"user.pinguser254.Sent").unwrap();
+
+    let keys: Vec<Vec<u8>> = db
+        .iter_prefix(b"user.pinguser254.")
+        .map(|r| r.unwrap().key().to_vec())
+        .collect();
+    assert_eq!(
+        keys,
+        vec![
+            b"user.pinguser254.Drafts".to_vec(),
+            b"user.pinguser254.Trash".to_vec(),
+        ]
+    );
+
+    assert_eq!(db.iter_prefix(b"user.").count(), 15);
+    assert_eq!(db.iter_prefix(b"").count(), 15);
+    assert_eq!(db.iter_prefix(b"user.pinguser2").count(), 3);
+    assert_eq!(db.iter_prefix(b"user.bob").count(), 0);
+    assert_eq!(db.iter_prefix(b"zzz").count(), 0);
+
+    assert_eq!(db.count_prefix(b"user.pinguser254.").unwrap(), 2);
+    assert_eq!(db.count_prefix(b"user.pinguser").unwrap(), 7);
+    assert_eq!(db.count_prefix(b"user.bob").unwrap(), 0);
+    assert_eq!(db.count_prefix(b"").unwrap(), 15);
+
+    let matches = |pattern: &[u8]| -> Vec<Vec<u8>> {
+        db.iter_match(pattern)
+            .map(|r| r.unwrap().key().to_vec())
+            .collect()
+    };
+    assert_eq!(
+        matches(b"user.%.Drafts"),
+        vec![
+            b"user.anne.Drafts".to_vec(),
+            b"user.pinguser.Drafts".to_vec(),
+            b"user.pinguser254.Drafts".to_vec(),
+            b"user.zed.Drafts".to_vec(),
+        ]
+    );
+    assert_eq!(matches(b"user.%").len(), 4);
+    assert_eq!(matches(b"user.*").len(), 15);
+    assert_eq!(matches(b"*Trash").len(), 4);
+    assert_eq!(matches(b"user.pinguser%").len(), 2);
+    assert_eq!(matches(b"user.pinguser*").len(), 7);
+    assert_eq!(matches(b"user.zed.Sent"), vec![b"user.zed.Sent".to_vec()]);
+}
+
+#[test]
+fn foreach() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..20 {
+        db.put(
+            format!("user.u{:02}", i).as_bytes(),
+            format!("{}", i % 3).as_bytes(),
+        )
+        .unwrap();
+    }
+    db.put(b"zzz", b"0").unwrap();
+
+    let mut seen = vec![];
+    let r = db
+        .foreach(
+            b"user.",
+            |_, value| value == b"0",
+            |key, _| {
+                seen.push(key.to_vec());
+                0
+            },
+        )
+        .unwrap();
+    assert_eq!(r, 0);
+    assert_eq!(seen.len(), 7);
+    assert!(seen.iter().all(|k| k.starts_with(b"user.")));
+
+    // a nonzero return stops the walk and comes back out
+    let mut calls = 0;
+    let r = db
+        .foreach(
+            b"",
+            |_, _| true,
+            |key, _| {
+                calls += 1;
+                match key {
+                    b"user.u04" => 42,
+                    _ => 0,
+                }
+            },
+        )
+        .unwrap();
+    assert_eq!(r, 42);
+    assert_eq!(calls, 5);
+}
+
+#[test]
+fn fetchnext() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in (0..100).step_by(2) {
+        db.put(format!("key{:02}", i).as_bytes(), b"value").unwrap();
+    }
+    db.delete(b"key50").unwrap();
+
+    let next = |key: &[u8]| db.fetchnext(key).unwrap().map(|r| r.key().to_vec());
+    assert_eq!(next(b""), Some(b"key00".to_vec()));
+    assert_eq!(next(b"key00"), Some(b"key02".to_vec()));
+    assert_eq!(next(b"key01"), Some(b"key02".to_vec()));
+    assert_eq!(next(b"key48"), Some(b"key52".to_vec()));
+    assert_eq!(next(b"key50"), Some(b"key52".to_vec()));
+    assert_eq!(next(b"key98"), None);
+    assert_eq!(next(b"zzz"), None);
+}
+
+#[test]
+fn iter_live() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    let mut model = std::collections::BTreeMap::new();
+    for i in 0..300u32 {
+        let key = format!("key{:03}", (i * 7) % 101).into_bytes();
+        match i % 5 {
+            0 => {
+                db.delete(&key).unwrap();
+                model.remove(&key);
+            }
+            _ => {
+                let value = format!("value{}", i).into_bytes();
+                db.put(&key, &value).unwrap();
+                model.insert(key, value);
+            }
+        }
+    }
+
+    let live: Vec<(Vec<u8>, Vec<u8>)> = db
+        .iter_live()
+        .map(|r| {
+            let r = r.unwrap();
+            (r.key().to_vec(), r.value().to_vec())
+        })
+        .collect();
+    assert_eq!(live, model.into_iter().collect::<Vec<_>>());
+
+    let keys: Vec<&[u8]> = db.keys().map(|k| k.unwrap()).collect();
+    assert_eq!(keys.len(), live.len());
+    assert!(keys.iter().zip(&live).all(|(k, (key, _))| k == key));
+
+    let mut rev: Vec<Vec<u8>> = db.iter_rev().map(|r| r.unwrap().key().to_vec()).collect();
+    rev.reverse();
+    assert_eq!(rev, live.into_iter().map(|(k, _)| k).collect::<Vec<_>>());
+}
+
+#[test]
+fn iter_reports_corruption() {
+    use std::os::unix::fs::FileExt;
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..10 {
+        db.put(format!("key{}", i).as_bytes(), b"value").unwrap();
+    }
+    let first = db.fetchnext(b"").unwrap().unwrap().offset();
+    drop(db);
+
+    let f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    f.write_all_at(b"?", first as u64).unwrap();
+
+    for verify in [true, false] {
+        let db = twoskip::OpenOptions::new()
+            .verify_checksums(verify)
+            .recover(false)
+            .open(&path)
+            .unwrap();
+        let mut iter = db.iter_live();
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+}
+
+#[test]
+fn cursor() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..50 {
+        db.put(format!("key{:02}", i * 2).as_bytes(), b"value")
+            .unwrap();
+    }
+    db.delete(b"key40").unwrap();
+
+    let key = |r: Option<twoskip::Record>| r.map(|r| r.key().to_vec());
+    let mut cursor = db.cursor();
+    assert_eq!(key(cursor.current().unwrap()), None);
+    assert_eq!(key(cursor.prev().unwrap()), None);
+    assert_eq!(key(cursor.next().unwrap()), Some(b"key00".to_vec()));
+    assert_eq!(key(cursor.prev().unwrap()), None);
+
+    assert_eq!(key(cursor.seek(b"key37").unwrap()), Some(b"key38".to_vec()));
+    assert_eq!(key(cursor.next().unwrap()), Some(b"key42".to_vec()));
+    assert_eq!(key(cursor.prev().unwrap()), Some(b"key38".to_vec()));
+    assert_eq!(key(cursor.prev().unwrap()), Some(b"key36".to_vec()));
+
+    assert_eq!(key(cursor.seek(b"key98").unwrap()), Some(b"key98".to_vec()));
+    assert_eq!(key(cursor.next().unwrap()), None);
+    assert_eq!(key(cursor.next().unwrap()), None);
+    assert_eq!(key(cursor.prev().unwrap()), Some(b"key98".to_vec()));
+    assert_eq!(key(cursor.seek(b"zzz").unwrap()), None);
+    assert_eq!(key(cursor.prev().unwrap()), Some(b"key98".to_vec()));
+}
+
+#[test]
+fn multi_get() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..500 {
+        db.put(
+            format!("key{:03}", i * 2).as_bytes(),
+            format!("{}", i).as_bytes(),
+        )
+        .unwrap();
+    }
+    db.delete(b"key500").unwrap();
+
+    let keys: Vec<Vec<u8>> = [777, 4, 500, 3, 998, 0, 4, 999, 120, 121]
+        .iter()
+        .map(|i| format!("key{:03}", i).into_bytes())
+        .collect();
+    let results = db.multi_get(keys.iter().map(|k| k.as_slice())).unwrap();
+    assert_eq!(results.len(), keys.len());
+    for (key, r) in keys.iter().zip(results) {
+        let expected = db.get(key).unwrap().map(|r| r.value().to_vec());
+        assert_eq!(r.map(|r| r.value().to_vec()), expected);
+    }
+    assert!(db.multi_get(Vec::<&[u8]>::new()).unwrap().is_empty());
+
+    for i in 0..1000 {
+        let key = format!("key{:03}", i);
+        let expected = i % 2 == 0 && i != 500;
+        assert_eq!(db.contains_key(key.as_bytes()).unwrap(), expected);
+    }
+    assert!(!db.contains_key(b"").unwrap());
+}
+
+#[test]
+fn owned_record() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    db.put(b"user.anne", b"%(A (anne lrswipkxtecdan))").unwrap();
+    drop(db);
+
+    // the db is gone by the time the record comes back
+    let lookup = |key: &[u8]| -> Option<twoskip::OwnedRecord> {
+        let db = twoskip::open(&path).unwrap();
+        let r = db.get(key).unwrap();
+        r.map(|r| r.to_owned())
+    };
+    let r = lookup(b"user.anne").unwrap();
+    assert_eq!(r.key(), b"user.anne");
+    assert_eq!(r.value(), b"%(A (anne lrswipkxtecdan))");
+    assert_eq!(r.clone().into_parts().1, r.value().to_vec());
+    assert!(lookup(b"user.bob").is_none());
+
+    let db = twoskip::open(&path).unwrap();
+    assert_eq!(
+        db.get_value(b"user.anne").unwrap(),
+        Some(&b"%(A (anne lrswipkxtecdan))"[..])
+    );
+    assert_eq!(db.get_value(b"user.bob").unwrap(), None);
+    let value = db.get_value_owned(b"user.anne").unwrap().unwrap();
+    drop(db);
+    assert_eq!(value, b"%(A (anne lrswipkxtecdan))");
+}
+
+#[test]
+fn list_pages() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..25 {
+        db.put(format!("user.anne.f{:02}", i).as_bytes(), b"")
+            .unwrap();
+    }
+    db.put(b"user.anne", b"").unwrap();
+    db.put(b"user.bob", b"").unwrap();
+
+    let mut keys = vec![];
+    let mut token: Option<Vec<u8>> = None;
+    let mut pages = 0;
+    loop {
+        // a fresh handle each time, like separate requests
+        let db = twoskip::open(&path).unwrap();
+        let page = db.list(b"user.anne.", token.as_deref(), 10).unwrap();
+        pages += 1;
+        keys.extend(page.records.into_iter().map(|r| r.into_parts().0));
+        match page.next {
+            Some(next) => token = Some(next),
+            None => break,
+        }
+    }
+    assert_eq!(pages, 3);
+    assert_eq!(keys.len(), 25);
+    assert_eq!(keys[0], b"user.anne.f00");
+    assert_eq!(keys[24], b"user.anne.f24");
+
+    // an exact final page has no token
+    let page = db.list(b"user.anne.", Some(b"user.anne.f14"), 10).unwrap();
+    assert_eq!(page.records.len(), 10);
+    assert!(page.next.is_none());
+    assert!(db.list(b"user.carl", None, 10).unwrap().records.is_empty());
+
+    // an empty page still says where to carry on from
+    let page = db.list(b"user.anne.", None, 0).unwrap();
+    assert!(page.records.is_empty());
+    let next = db.list(b"user.anne.", page.next.as_deref(), 1).unwrap();
+    assert_eq!(next.records[0].key(), b"user.anne.f00");
+    let page = db.list(b"user.anne.", Some(b"user.anne.f03"), 0).unwrap();
+    assert_eq!(page.next.as_deref(), Some(&b"user.anne.f03"[..]));
+}
+
+#[test]
+fn value_reader() {
+    use std::io::Read;
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let value: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+    let mut db = twoskip::create(&path).unwrap();
+    db.put(b"blob", &value).unwrap();
+
+    let r = db.get(b"blob").unwrap().unwrap();
+    let mut reader = r.value_reader();
+    let mut chunk = [0u8; 4096];
+    let mut read = 0;
+    loop {
+        let n = reader.read(&mut chunk).unwrap();
+        if n == 0 {
+            break;
+        }
+        assert_eq!(&chunk[..n], &value[read..read + n]);
+        read += n;
+    }
+    assert_eq!(read, value.len());
+}
+
+#[test]
+fn comparator() {
+    #[derive(Debug)]
+    struct CaseInsensitive;
+
+    impl twoskip::Comparator for CaseInsensitive {
+        fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+            a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase())
+        }
+    }
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+    twoskip::create(&path).unwrap();
+
+    let mut options = twoskip::OpenOptions::new();
+    options.comparator(CaseInsensitive);
+    let mut db = options.open(&path).unwrap();
+    db.put(b"user.Zed", b"1").unwrap();
+    db.put(b"user.anne", b"2").unwrap();
+    db.put(b"USER.Bob", b"3").unwrap();
+    db.put(b"user.ANNE", b"4").unwrap();
+
+    assert_eq!(db.header().num_records(), 3);
+    assert_eq!(db.get_value(b"User.Anne").unwrap(), Some(&b"4"[..]));
+    assert_eq!(db.get_value(b"user.bob").unwrap(), Some(&b"3"[..]));
+    let keys: Vec<&[u8]> = db.keys().map(|k| k.unwrap()).collect();
+    assert_eq!(keys, vec![&b"user.ANNE"[..], b"USER.Bob", b"user.Zed"]);
+
+    // memcmp order is the default
+    let db = twoskip::open(&path).unwrap();
+    assert_eq!(db.get_value(b"User.Anne").unwrap(), None);
+}
+
+#[test]
+fn walk_levels() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..200 {
+        db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+    }
+
+    // every level is a sorted sublist of the one below it
+    let first = db.fetchnext(b"").unwrap().unwrap();
+    let mut below = 200;
+    for level in 0..4 {
+        let mut count = 0;
+        let mut r = db.iter_live().find_map(|r| {
+            let r = r.unwrap();
+            match r.level() > level {
+                true => Some(r),
+                false => None,
+            }
+        });
+        let mut last = vec![];
+        while let Some(rec) = r {
+            assert!(rec.key() > &last[..]);
+            last = rec.key().to_vec();
+            count += 1;
+            r = rec.next_at_level(level).unwrap();
+        }
+        match level {
+            0 => assert_eq!(count, 200),
+            _ => assert!(count <= below),
+        }
+        below = count;
+    }
+    assert!(first.next_at_level(first.level()).is_err());
+}
+
+#[test]
+fn stats() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..100 {
+        db.put(format!("key{:02}", i).as_bytes(), &vec![b'x'; i])
+            .unwrap();
+    }
+    for i in 0..10 {
+        db.put(format!("key{:02}", i).as_bytes(), b"new").unwrap();
+    }
+    for i in 90..100 {
+        db.delete(format!("key{:02}", i).as_bytes()).unwrap();
+    }
+    let mut txn = db.begin().unwrap();
+    txn.put(b"aborted", b"value").unwrap();
+    txn.abort().unwrap();
+
+    let stats = db.stats().unwrap();
+    assert_eq!(stats.live_records, 90);
+    assert_eq!(stats.dead_records, 20);
+    assert_eq!(stats.delete_records, 10);
+    assert_eq!(stats.commits, 120);
+    assert!(stats.blank_bytes > 0);
+    assert_eq!(stats.key_bytes, 90 * 5);
+    assert_eq!(stats.key_sizes.min, 5);
+    assert_eq!(stats.key_sizes.p99, 5);
+    assert_eq!(stats.value_bytes, 10 * 3 + (10..90).sum::<u64>());
+    assert_eq!(stats.value_sizes.min, 3);
+    assert_eq!(stats.value_sizes.max, 89);
+    assert_eq!(stats.levels.iter().sum::<u64>(), 90);
+    assert_eq!(stats.levels[0], 0);
+
+    // anything past the end of the last commit isn't counted
+    drop(db);
+    let mut data = std::fs::read(&path).unwrap();
+    data.extend_from_slice(&[b'X'; 16]);
+    std::fs::write(&path, &data).unwrap();
+    let db = twoskip::open(&path).unwrap();
+    let after = db.stats().unwrap();
+    assert_eq!(after.commits, 120);
+    assert_eq!(after.blank_bytes, stats.blank_bytes + 16);
+}
+
+#[test]
+fn stale_reopen() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut writer = twoskip::create(&path).unwrap();
+    writer.put(b"first", b"1").unwrap();
+
+    let mut reader = twoskip::open(&path).unwrap();
+    assert!(!reader.is_stale().unwrap());
+
+    // a commit to the same file is picked up by the next read
+    writer.put(b"second", b"2").unwrap();
+    assert!(reader.is_stale().unwrap());
+    assert_eq!(reader.get_value(b"second").unwrap(), Some(&b"2"[..]));
+    assert!(!reader.is_stale().unwrap());
+
+    writer.repack().unwrap();
+    assert!(reader.is_stale().unwrap());
+    reader.reopen().unwrap();
+    assert!(!reader.is_stale().unwrap());
+    assert_eq!(reader.header().generation(), writer.header().generation());
+    assert_eq!(reader.get_value(b"first").unwrap(), Some(&b"1"[..]));
+}
+
+#[test]
+fn reader_follows_writer() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut writer = twoskip::create(&path).unwrap();
+    writer.put(b"key", b"0").unwrap();
+    let reader = twoskip::open(&path).unwrap();
+    assert_eq!(reader.get_value(b"key").unwrap(), Some(&b"0"[..]));
+
+    // enough to grow the file well past what the reader first mapped
+    let value = vec![b'x'; 4096];
+    for _ in 0..1000 {
+        writer.put(b"key", &value).unwrap();
+    }
+    writer.put(b"key", b"last").unwrap();
+    writer.put(b"other", b"1").unwrap();
+
+    assert_eq!(reader.get_value(b"key").unwrap(), Some(&b"last"[..]));
+    let live: Vec<_> = reader.iter_live().map(|r| r.unwrap().to_owned()).collect();
+    assert_eq!(live.len(), 2);
+    assert_eq!(live[1].value(), b"1");
+    assert!(reader.verify().unwrap().problems.is_empty());
+}
+
+#[test]
+fn verify_tails() {
+    use std::os::unix::fs::FileExt;
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    db.put(b"good", b"value").unwrap();
+    db.put(b"rotten", b"value").unwrap();
+    assert!(db.get(b"rotten").unwrap().unwrap().verify().is_ok());
+    drop(db);
+
+    // flip a bit in the value, which the head crc doesn't cover
+    let data = std::fs::read(&path).unwrap();
+    let at = data.windows(11).position(|w| w == b"rottenvalue").unwrap() + 6;
+    let f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    f.write_all_at(&[data[at] ^ 1], at as u64).unwrap();
+
+    let db = twoskip::open(&path).unwrap();
+    let r = db.get(b"rotten").unwrap().unwrap();
+    assert_eq!(r.value(), b"walue");
+    assert!(r.verify().is_err());
+    assert!(db.get(b"good").unwrap().unwrap().verify().is_ok());
+
+    let db = twoskip::OpenOptions::new()
+        .verify_tails(true)
+        .open(&path)
+        .unwrap();
+    assert!(db.get(b"rotten").is_err());
+}
+
+#[test]
+fn verify() {
+    use std::os::unix::fs::FileExt;
+    use twoskip::ProblemKind;
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..50 {
+        db.put(format!("key{:02}", i).as_bytes(), b"value").unwrap();
+    }
+    db.delete(b"key10").unwrap();
+    let report = db.verify().unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert_eq!(report.records, 1 + 50 + 1 + 51);
+
+    let rotten = db.get(b"key20").unwrap().unwrap().offset();
+    let pointer = db.get(b"key30").unwrap().unwrap().offset();
+    drop(db);
+
+    let data = std::fs::read(&path).unwrap();
+    let at = data.windows(10).position(|w| w == b"key20value").unwrap() + 5;
+    let f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    f.write_all_at(&[data[at] ^ 1], at as u64).unwrap();
+    f.write_all_at(&[0, 0, 0, 0, 0, 0, 0x12, 0x34], pointer as u64 + 8)
+        .unwrap();
+
+    let db = twoskip::open(&path).unwrap();
+    let problems = db.verify().unwrap().problems;
+    let kinds = |offset| -> Vec<ProblemKind> {
+        problems
+            .iter()
+            .filter(|p| p.offset == offset)
+            .map(|p| p.kind.clone())
+            .collect()
+    };
+    assert_eq!(kinds(rotten), vec![ProblemKind::TailChecksum]);
+    assert!(kinds(pointer).contains(&ProblemKind::HeadChecksum));
+    assert!(kinds(pointer).contains(&ProblemKind::BadPointer {
+        slot: 0,
+        target: 0x1234
+    }));
+
+    // pointers into a transaction that never finished are what recovery
+    // puts right, not damage
+    let path = dir.path().join("dirty.db");
+    let mut db = twoskip::create(&path).unwrap();
+    db.put(b"key", b"committed").unwrap();
+    let mut txn = db.begin().unwrap();
+    txn.put(b"key", b"uncommitted").unwrap();
+    std::mem::forget(txn);
+    drop(db);
+    let db = twoskip::OpenOptions::new()
+        .read_only(true)
+        .recover(false)
+        .open(&path)
+        .unwrap();
+    assert!(db.needs_recovery());
+    let report = db.verify().unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+}
+
+#[test]
+fn bad_pointers_are_errors() {
+    use std::os::unix::fs::FileExt;
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..20 {
+        db.put(format!("key{:02}", i).as_bytes(), b"value").unwrap();
+    }
+    let first = db.fetchnext(b"").unwrap().unwrap().offset();
+    let size = db.header().current_size();
+    drop(db);
+
+    // point the first record's level 0 slots somewhere they can't go
+    let f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    for (slot, target) in [(0, 1u64 << 40), (1, 24)] {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(&target.to_be_bytes());
+        f.write_all_at(&word, (first + 8 + 8 * slot) as u64)
+            .unwrap();
+    }
+
+    let db = twoskip::OpenOptions::new()
+        .verify_checksums(false)
+        .recover(false)
+        .open(&path)
+        .unwrap();
+    assert!(db.iter_live().any(|r| r.is_err()));
+    assert!(db.keys().any(|k| k.is_err()));
+
+    // a file cut short of its header's current size won't open
+    f.set_len(size as u64 - 16).unwrap();
+    assert!(twoskip::open(&path).is_err());
+}
+
+#[test]
+fn pointer_cycle() {
+    use std::os::unix::fs::FileExt;
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..20 {
+        db.put(format!("key{:02}", i).as_bytes(), b"value").unwrap();
+    }
+    let back = db.fetchnext(b"key05").unwrap().unwrap().offset();
+    let from = db.fetchnext(b"key10").unwrap().unwrap().offset();
+    drop(db);
+
+    // send key11's level 0 pointers back to key06
+    let f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    for slot in 0..2 {
+        f.write_all_at(&(back as u64).to_be_bytes(), (from + 8 + 8 * slot) as u64)
+            .unwrap();
+    }
+
+    let db = twoskip::OpenOptions::new()
+        .verify_checksums(false)
+        .recover(false)
+        .open(&path)
+        .unwrap();
+    let err = db.iter_live().find_map(|r| r.err()).unwrap();
+    assert!(matches!(err, twoskip::Error::PointerCycle { offset } if offset == back));
+}
+
+#[test]
+fn permissive() {
+    use std::os::unix::fs::FileExt;
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..10 {
+        db.put(format!("key{:02}", i).as_bytes(), b"value").unwrap();
+    }
+    // where the head crc is, and the value just after the key
+    let crc_at = |key: &[u8]| {
+        let r = db.get(key).unwrap().unwrap();
+        r.offset() + 8 + 8 * (r.level() as usize + 1)
+    };
+    let head = crc_at(b"key03");
+    let tail = crc_at(b"key06") + 8 + 5;
+    drop(db);
+
+    // flipped rather than overwritten, since a crc byte could already be
+    // whatever we'd write
+    let f = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    for at in [head, tail] {
+        let mut byte = [0];
+        f.read_exact_at(&mut byte, at as u64).unwrap();
+        f.write_all_at(&[!byte[0]], at as u64).unwrap();
+    }
+
+    let db = twoskip::OpenOptions::new()
+        .verify_tails(true)
+        .recover(false)
+        .open(&path)
+        .unwrap();
+    assert!(db.iter_live().any(|r| r.is_err()));
+
+    let mut db = twoskip::OpenOptions::new()
+        .permissive(true)
+        .recover(false)
+        .open(&path)
+        .unwrap();
+    let keys: Vec<Vec<u8>> = db.keys().map(|k| k.unwrap().to_vec()).collect();
+    assert_eq!(keys.len(), 8);
+    assert!(!keys.contains(&b"key03".to_vec()) && !keys.contains(&b"key06".to_vec()));
+
+    let mut out = vec![];
+    db.dump_to(&mut out).unwrap();
+    assert!(!String::from_utf8_lossy(&out).contains("key06"));
+
+    let problems = db.problems();
+    assert_eq!(problems.len(), 2);
+    assert_eq!(problems[0].kind, twoskip::ProblemKind::HeadChecksum);
+    assert_eq!(problems[1].kind, twoskip::ProblemKind::TailChecksum);
+    assert!(db.put(b"key03", b"value").is_err());
+}
+
+#[test]
+fn salvage() {
+    use std::os::unix::fs::FileExt;
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..50 {
+        db.put(format!("key{:02}", i).as_bytes(), b"value").unwrap();
+    }
+    for i in (0..50).step_by(3) {
+        db.delete(format!("key{:02}", i).as_bytes()).unwrap();
+    }
+    for i in (0..50).step_by(5) {
+        db.put(format!("key{:02}", i).as_bytes(), b"again").unwrap();
+    }
+    let wanted: Vec<(Vec<u8>, Vec<u8>)> = db
+        .iter_live()
+        .map(|r| r.unwrap().to_owned().into_parts())
+        .collect();
+    let damage = db.get(b"key38").unwrap().unwrap().offset() + 8;
+    drop(db);
+
+    // a clean file comes back just as it was
+    let clean = dir.path().join("clean.db");
+    let report = twoskip::salvage(&path, &clean).unwrap();
+    assert_eq!(report.recovered, wanted.len() as u64);
+    assert_eq!(report.lost, Some(0));
+    assert_eq!(report.damaged, 0);
+    assert_eq!(report.unmatched_deletes, 0);
+    let got: Vec<(Vec<u8>, Vec<u8>)> = twoskip::open(&clean)
+        .unwrap()
+        .iter_live()
+        .map(|r| r.unwrap().to_owned().into_parts())
+        .collect();
+    assert_eq!(got, wanted);
+    assert!(twoskip::salvage(&path, &clean).is_err());
+
+    // wreck the header and one record, and everything else survives
+    let f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    f.write_all_at(b"XXXX", 0).unwrap();
+    f.write_all_at(b"XXXX", damage as u64).unwrap();
+    assert!(twoskip::open(&path).is_err());
+
+    let salvaged = dir.path().join("salvaged.db");
+    let report = twoskip::salvage(&path, &salvaged).unwrap();
+    assert_eq!(report.recovered, wanted.len() as u64 - 1);
+    assert_eq!(report.lost, None);
+    assert_eq!(report.damaged, 1);
+    assert_eq!(report.unmatched_deletes, 0);
+    let db = twoskip::open(&salvaged).unwrap();
+    assert!(db.get(b"key38").unwrap().is_none());
+    assert_eq!(db.get(b"key25").unwrap().unwrap().value(), b"again");
+    assert!(db.get(b"key27").unwrap().is_none());
+    assert!(db.verify().unwrap().is_ok());
+}
+
+#[test]
+fn checksum_errors() {
+    use std::os::unix::fs::FileExt;
+    use twoskip::{Error, Structure};
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    db.put(b"foo", b"bar").unwrap();
+    let r = db.get(b"foo").unwrap().unwrap();
+    let (offset, value) = (
+        r.offset(),
+        r.offset() + 8 + 8 * (r.level() as usize + 1) + 8 + 3,
+    );
+    drop(db);
+
+    let f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    f.write_all_at(b"c", value as u64).unwrap();
+    let db = twoskip::OpenOptions::new()
+        .verify_tails(true)
+        .open(&path)
+        .unwrap();
+    match db.get(b"foo") {
+        Err(Error::ChecksumMismatch {
+            structure: Structure::RecordTail,
+            offset: at,
+            key: Some(key),
+            expected,
+            actual,
+        }) => {
+            assert_eq!((at, key.as_slice()), (offset, &b"foo"[..]));
+            assert_ne!(expected, actual);
+        }
+        r => panic!("{:?}", r.err()),
+    }
+    drop(db);
+
+    f.write_all_at(b"X", 24).unwrap();
+    match twoskip::open(&path) {
+        Err(e @ Error::ChecksumMismatch { .. }) => {
+            assert!(e
+                .to_string()
+                .starts_with("checksum mismatch in Header at 00000000"))
+        }
+        r => panic!("{:?}", r.err()),
+    }
+}
+
+#[test]
+fn repack_to() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+    let copy = dir.path().join("copy.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..100 {
+        db.put(format!("key{:02}", i % 10).as_bytes(), b"value")
+            .unwrap();
+    }
+    db.delete(b"key03").unwrap();
+    let size = db.header().current_size();
+
+    db.repack_to(&copy).unwrap();
+    assert_eq!(db.header().current_size(), size);
+    assert!(db.repack_to(&copy).is_err());
+
+    let packed = twoskip::open(&copy).unwrap();
+    assert!(packed.header().current_size() < size);
+    assert_eq!(packed.header().num_records(), 9);
+    let keys: Vec<Vec<u8>> = packed.keys().map(|k| k.unwrap().to_vec()).collect();
+    let wanted: Vec<Vec<u8>> = db.keys().map(|k| k.unwrap().to_vec()).collect();
+    assert_eq!(keys, wanted);
+}
+
+#[test]
+fn changes_since() {
+    use twoskip::Change;
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for key in [&b"a"[..], b"b", b"c", b"d"] {
+        db.put(key, b"1").unwrap();
+    }
+    let since = db.header().current_size();
+    let changes = |db: &twoskip::Db| -> Vec<Change> {
+        let changes = db.changes_since(since).unwrap();
+        changes.into_iter().map(|(_, c)| c).collect()
+    };
+    let put = |k: &[u8], v: &[u8]| Change::Put {
+        key: k.to_vec(),
+        value: v.to_vec(),
+    };
+    let delete = |k: &[u8]| Change::Delete {
+        key: Some(k.to_vec()),
+    };
+
+    let mut txn = db.begin().unwrap();
+    txn.put(b"b", b"2").unwrap();
+    txn.delete(b"c").unwrap();
+    txn.commit().unwrap();
+    assert_eq!(
+        changes(&db),
+        vec![put(b"b", b"2"), delete(b"c"), Change::Commit]
+    );
+
+    // deleting d changes b's pointers again, and c is forgotten
+    db.delete(b"d").unwrap();
+    db.delete(b"a").unwrap();
+    let changes = changes(&db);
+    assert_eq!(
+        changes,
+        vec![
+            put(b"b", b"2"),
+            Change::Delete { key: None },
+            Change::Commit,
+            delete(b"d"),
+            Change::Commit,
+            delete(b"a"),
+            Change::Commit,
+        ]
+    );
+    assert_eq!(db.changes_since(0).unwrap().len(), 8 + changes.len());
+}
+
+#[test]
+fn explain() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..50 {
+        db.put(format!("key{:02}", i).as_bytes(), b"value").unwrap();
+    }
+    db.delete(b"key20").unwrap();
+
+    let mut out = vec![];
+    assert!(db.explain_to(&mut out, b"key33").unwrap());
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with("looking for \"key33\" from the DUMMY at 00000040"));
+    assert!(out
+        .trim_end()
+        .lines()
+        .last()
+        .unwrap()
+        .starts_with("found at "));
+
+    let mut out = vec![];
+    assert!(!db.explain_to(&mut out, b"key20").unwrap());
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("less, down"));
+    assert!(out.ends_with("comparisons\n"));
+    assert!(out.lines().last().unwrap().starts_with("not found after "));
+}
+
+#[test]
+fn hexdump() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let mut db = twoskip::create(&path).unwrap();
+    db.put(b"a", b"1").unwrap();
+    let r = db.get(b"a").unwrap().unwrap();
+    let (offset, level) = (r.offset(), r.level() as usize);
+
+    let mut out = vec![];
+    db.hexdump_to(&mut out, 0).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert!(lines[0].starts_with("00000000 HEADER v=1 gen=1 num=1 sz="));
+    assert!(lines[1].starts_with("    00000000  a1 02 8b 0d 74 77 6f 73"));
+    assert!(lines[1].ends_with("magic"));
+
+    // the head, the level+1 pointers, the crcs and then the key and value
+    let at = lines
+        .iter()
+        .position(|l| l.starts_with(&format!("{:08x} RECORD kl=00000001 dl=00000001", offset)))
+        .unwrap();
+    let crcs = offset + 8 + 8 * (level + 1);
+    assert!(lines[at + 1].starts_with(&format!("    {:08x}  2b {:02x}", offset, level)));
+    assert!(lines[at + 1].ends_with("head"));
+    assert!(lines[at + 2].starts_with(&format!("    {:08x}  ", offset + 8)));
+    assert!(lines[at + 2].ends_with("next"));
+    let crc_line = lines[at + 1..]
+        .iter()
+        .find(|l| l.starts_with(&format!("    {:08x}  ", crcs)))
+        .unwrap();
+    assert!(crc_line.ends_with("ok, tail 7777d1aa ok"));
+    let key_line = lines[at + 1..]
+        .iter()
+        .find(|l| l.starts_with(&format!("    {:08x}  61 31 00", crcs + 8)))
+        .unwrap();
+    assert!(key_line.ends_with("key, value"));
+
+    // starting further in leaves the header out
+    let mut out = vec![];
+    db.hexdump_to(&mut out, offset).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with(&format!("{:08x} RECORD", offset)));
+}
+
+#[test]
+fn flat_round_trip() {
+    let dir = TempDir::new("twoskip").unwrap();
+
+    let mut db = twoskip::create(dir.path().join("a.db")).unwrap();
+    db.put(b"user.a", b"plain").unwrap();
+    db.put(b"user\tb", b"line\none\\two\r\0").unwrap();
+
+    let mut flat = vec![];
+    export::to_flat(&db, &mut flat).unwrap();
+    assert_eq!(
+        flat,
+        b"user\\tb\tline\\none\\\\two\\r\\0\nuser.a\tplain\n".to_vec()
+    );
+
+    let mut copy = twoskip::create(dir.path().join("b.db")).unwrap();
+    assert_eq!(export::from_flat(&mut copy, &flat[..]).unwrap(), 2);
+    assert_eq!(
+        copy.get_value(b"user\tb").unwrap(),
+        Some(&b"line\none\\two\r\0"[..])
+    );
+
+    // nothing from a file with a bad line goes in
+    match export::from_flat(&mut copy, &b"c\td\n\nno tab\n"[..]) {
+        Err(twoskip::Error::InvalidLine { line: 3, .. }) => {}
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+    assert_eq!(copy.get(b"c").unwrap().map(|r| r.value().to_vec()), None);
+}
+
+#[test]
+fn skiplist() {
+    use byteorder::{BigEndian, WriteBytesExt};
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("old.db");
+
+    // by hand, pointers and all zero since they're never followed
+    let mut data = b"\xa1\x02\x8b\x0dskiplist file\0\0\0".to_vec();
+    for field in [1, 2, 20, 1, 2, 0, 0] {
+        data.write_u32::<BigEndian>(field).unwrap();
+    }
+    let record = |data: &mut Vec<u8>, typ: u32, key: &[u8], value: &[u8], level: usize| {
+        let offset = data.len() as u32;
+        data.write_u32::<BigEndian>(typ).unwrap();
+        for s in [key, value] {
+            data.write_u32::<BigEndian>(s.len() as u32).unwrap();
+            data.extend_from_slice(s);
+            data.resize((data.len() + 3) & !3, 0);
+        }
+        data.resize(data.len() + 4 * level, 0);
+        data.write_u32::<BigEndian>(0xffff_ffff).unwrap();
+        offset
+    };
+    record(&mut data, 257, b"", b"", 20);
+    let a = record(&mut data, 1, b"a", b"one", 1);
+    record(&mut data, 1, b"b", b"two", 2);
+    let log_start = data.len() as u32;
+    (&mut data[40..44])
+        .write_u32::<BigEndian>(log_start)
+        .unwrap();
+    record(&mut data, 2, b"c", b"three", 1);
+    data.write_u32::<BigEndian>(4).unwrap();
+    data.write_u32::<BigEndian>(a).unwrap();
+    data.write_u32::<BigEndian>(255).unwrap();
+    // never committed, and cut short
+    record(&mut data, 2, b"d", b"four", 1);
+    data.truncate(data.len() - 2);
+    std::fs::write(&path, &data).unwrap();
+
+    let db = skiplist::open(&path).unwrap();
+    assert_eq!(db.num_records(), 2);
+    assert_eq!(db.header().num_records(), 2);
+    assert_eq!(db.get_value(b"a").unwrap(), None);
+    let keys: Vec<Vec<u8>> = db.iter_live().map(|r| r.unwrap().key().to_vec()).collect();
+    assert_eq!(keys, vec![b"b".to_vec(), b"c".to_vec()]);
+
+    let new = dir.path().join("new.db");
+    db.to_twoskip(&new).unwrap();
+    let converted = twoskip::open(&new).unwrap();
+    assert_eq!(converted.get_value(b"c").unwrap(), Some(&b"three"[..]));
+    assert_eq!(converted.header().num_records(), 2);
+    assert!(db.to_twoskip(&new).is_err());
+
+    let mut db = cyrusdb::open("skiplist", &path, false).unwrap();
+    assert_eq!(db.fetchnext(b"a").unwrap(), Some((&b"b"[..], &b"two"[..])));
+    assert!(matches!(db.store(b"e", b""), Err(twoskip::Error::ReadOnly)));
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let sql = dir.path().join("records.sqlite");
+
+    let mut db = twoskip::create(dir.path().join("a.db")).unwrap();
+    db.put(b"user.a", b"").unwrap();
+    db.put(b"user.b", b"\0\xff binary").unwrap();
+    export::to_sqlite(&db, &sql).unwrap();
+    // the table is there now, and won't be filled twice
+    assert!(export::to_sqlite(&db, &sql).is_err());
+
+    let mut copy = twoskip::create(dir.path().join("b.db")).unwrap();
+    assert_eq!(export::from_sqlite(&mut copy, &sql).unwrap(), 2);
+    assert_eq!(copy.get_value(b"user.a").unwrap(), Some(&b""[..]));
+    assert_eq!(
+        copy.get_value(b"user.b").unwrap(),
+        Some(&b"\0\xff binary"[..])
+    );
+}
+
+#[cfg(feature = "parquet")]
+#[test]
+fn parquet() {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::{Field, RowAccessor};
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let out = dir.path().join("records.parquet");
+
+    let mut db = twoskip::create(dir.path().join("a.db")).unwrap();
+    // enough for more than one row group
+    let mut txn = db.begin().unwrap();
+    for i in 0..70000 {
+        txn.put(format!("user.{}", i).as_bytes(), b"x").unwrap();
+    }
+    txn.commit().unwrap();
+    db.put(b"user.0", b"\0\xff binary").unwrap();
+    db.delete(b"user.1").unwrap();
+    export::to_parquet(&db, &out).unwrap();
+    // it won't write over what's there
+    assert!(export::to_parquet(&db, &out).is_err());
+
+    let reader = SerializedFileReader::new(std::fs::File::open(&out).unwrap()).unwrap();
+    assert_eq!(reader.metadata().num_row_groups(), 2);
+    let rows: Vec<_> = reader
+        .get_row_iter(None)
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+    // DUMMY, the records and COMMIT, then a RECORD and COMMIT, then DELETE
+    // and COMMIT
+    assert_eq!(rows.len(), 70006);
+    assert_eq!(rows[0].get_string(2).unwrap(), "DUMMY");
+    assert_eq!(rows[0].get_long(3).unwrap(), 64);
+
+    let last = rows.len() - 1;
+    let kinds: Vec<_> = rows[last - 4..]
+        .iter()
+        .map(|r| r.get_string(2).unwrap().clone())
+        .collect();
+    assert_eq!(kinds, ["COMMIT", "RECORD", "COMMIT", "DELETE", "COMMIT"]);
+    let record = &rows[last - 3];
+    assert_eq!(record.get_bytes(0).unwrap().data(), b"user.0");
+    assert_eq!(record.get_bytes(1).unwrap().data(), b"\0\xff binary");
+    let delete = &rows[last - 1];
+    assert!(matches!(
+        delete.get_column_iter().next(),
+        Some((_, Field::Null))
+    ));
+    assert!(delete.get_long(3).unwrap() > record.get_long(3).unwrap());
+}
+
+#[cfg(feature = "lmdb")]
+#[test]
+fn lmdb() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let env = dir.path().join("env");
+
+    let mut db = twoskip::create(dir.path().join("a.db")).unwrap();
+    let keys: Vec<Vec<u8>> = (0..300)
+        .map(|i| format!("user.{}", i).into_bytes())
+        .collect();
+    for key in &keys {
+        db.put(key, b"value").unwrap();
+    }
+    db.put(b"user.empty", b"").unwrap();
+    export::to_lmdb(&db, &env).unwrap();
+    // it's not empty any more
+    assert!(export::to_lmdb(&db, &env).is_err());
+
+    let mut copy = twoskip::create(dir.path().join("b.db")).unwrap();
+    assert_eq!(export::from_lmdb(&mut copy, &env).unwrap(), 301);
+    assert_eq!(copy.get_value(b"user.empty").unwrap(), Some(&b""[..]));
+    let a: Vec<Vec<u8>> = db.keys().map(|k| k.unwrap().to_vec()).collect();
+    let b: Vec<Vec<u8>> = copy.keys().map(|k| k.unwrap().to_vec()).collect();
+    assert_eq!(a, b);
+}
+
+#[test]
+fn builder() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let records: Vec<(String, String)> = (0..1000)
+        .map(|i| (format!("key{:04}", i), format!("value{}", i)))
+        .collect();
+    let mut builder = twoskip::Builder::create(&path).unwrap();
+    builder.extend(records.iter().map(|(k, v)| (k, v))).unwrap();
+    let mut db = builder.finish().unwrap();
+
+    assert_eq!(db.header().num_records(), 1000);
+    assert_eq!(db.header().repack_size(), db.header().current_size());
+    assert_eq!(db.get_value(b"key0500").unwrap(), Some(&b"value500"[..]));
+    let stats = db.stats().unwrap();
+    assert_eq!(stats.levels[1], 500);
+    assert_eq!(stats.levels[2], 250);
+    db.put(b"key1000", b"more").unwrap();
+    assert_eq!(db.keys().count(), 1001);
+
+    // a key out of order, or dropping it unfinished, and the file's gone
+    let other = dir.path().join("other.db");
+    let mut builder = twoskip::Builder::create(&other).unwrap();
+    builder.add(b"b", b"").unwrap();
+    assert!(matches!(
+        builder.add(b"a", b""),
+        Err(twoskip::Error::OutOfOrder)
+    ));
+    drop(builder);
+    assert!(!other.exists());
+    assert!(twoskip::Builder::create(&path).is_err());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serialize() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let mut db = twoskip::create(dir.path().join("test.db")).unwrap();
+    db.put(b"user.\xff", b"value").unwrap();
+
+    let r = db.get(b"user.\xff").unwrap().unwrap();
+    assert_eq!(
+        serde_json::to_string(&r).unwrap(),
+        format!(
+            "{{\"offset\":{},\"key\":\"dXNlci7/\",\"value\":\"dmFsdWU=\"}}",
+            r.offset()
+        )
+    );
+
+    let info = serde_json::to_value(db.header_info()).unwrap();
+    assert_eq!(info["num_records"], 1);
+    assert_eq!(info["flags"], "");
+    let stats = serde_json::to_value(db.stats().unwrap()).unwrap();
+    assert_eq!(stats["live_records"], 1);
+    let report = serde_json::to_value(db.verify().unwrap()).unwrap();
+    assert_eq!(report["problems"], serde_json::json!([]));
+}
+
+#[test]
+fn cdb() {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let mut db = twoskip::create(dir.path().join("test.db")).unwrap();
+    for i in 0..200 {
+        db.put(
+            format!("user.{}", i).as_bytes(),
+            format!("{}", i * i).as_bytes(),
+        )
+        .unwrap();
+    }
+    db.delete(b"user.7").unwrap();
+
+    let mut out = std::io::Cursor::new(vec![]);
+    export::to_cdb(&db, &mut out).unwrap();
+    let data = out.into_inner();
+
+    // a lookup the way cdb readers do it
+    let word = |at: usize| LittleEndian::read_u32(&data[at..at + 4]) as usize;
+    let get = |key: &[u8]| -> Option<Vec<u8>> {
+        let hash = key
+            .iter()
+            .fold(5381u32, |h, &c| (h << 5).wrapping_add(h) ^ c as u32);
+        let table = (hash & 0xff) as usize * 8;
+        let (start, len) = (word(table), word(table + 4));
+        for n in 0..len {
+            let slot = start + ((hash as usize >> 8) + n) % len * 8;
+            let at = word(slot + 4);
+            if at == 0 {
+                return None;
+            }
+            let (klen, vlen) = (word(at), word(at + 4));
+            if word(slot) == hash as usize && &data[at + 8..at + 8 + klen] == key {
+                return Some(data[at + 8 + klen..at + 8 + klen + vlen].to_vec());
+            }
+        }
+        None
+    };
+    assert_eq!(get(b"user.12"), Some(b"144".to_vec()));
+    assert_eq!(get(b"user.199"), Some(b"39601".to_vec()));
+    assert_eq!(get(b"user.7"), None);
+    assert_eq!(get(b"nobody"), None);
+}
+
+#[test]
+fn db_dump() {
+    let dir = TempDir::new("twoskip").unwrap();
+
+    // as db_dump -p prints it, in hash order
+    let dump = b"VERSION=3\nformat=print\ntype=hash\nh_nelem=3\nHEADER=END\n \
+        user.fred\n 0 1 \\00\\\\\n user.bob\n 0 2\n  space\n \\ff\nDATA=END\n";
+    let path = dir.path().join("print.db");
+    assert_eq!(export::from_db_dump(&dump[..], &path).unwrap(), 3);
+    let db = twoskip::open(&path).unwrap();
+    let records: Vec<_> = db.iter_live().map(|r| r.unwrap().key().to_vec()).collect();
+    assert_eq!(
+        records,
+        vec![
+            b" space".to_vec(),
+            b"user.bob".to_vec(),
+            b"user.fred".to_vec()
+        ]
+    );
+    assert_eq!(
+        db.get_value_owned(b"user.fred").unwrap().unwrap(),
+        b"0 1 \0\\"
+    );
+    assert_eq!(db.get_value_owned(b" space").unwrap().unwrap(), b"\xff");
+
+    let dump =
+        b"VERSION=3\nformat=bytevalue\ntype=btree\nHEADER=END\n 6b6579\n 76616c7565\nDATA=END\n";
+    let path = dir.path().join("bytevalue.db");
+    assert_eq!(export::from_db_dump(&dump[..], &path).unwrap(), 1);
+    let db = twoskip::open(&path).unwrap();
+    assert_eq!(db.get_value_owned(b"key").unwrap().unwrap(), b"value");
+
+    let dump = b"VERSION=3\nformat=print\ntype=recno\nHEADER=END\n 1\nDATA=END\n";
+    let path = dir.path().join("recno.db");
+    match export::from_db_dump(&dump[..], &path) {
+        Err(twoskip::Error::InvalidLine { line: 3, .. }) => {}
+        r => panic!("{:?}", r.map(|_| ())),
+    }
+    let dump = b"VERSION=3\nformat=print\nHEADER=END\n key\nDATA=END\n";
+    match export::from_db_dump(&dump[..], &path) {
+        Err(twoskip::Error::InvalidLine { line: 5, .. }) => {}
+        r => panic!("{:?}", r.map(|_| ())),
+    }
+    assert!(!path.exists());
+}
+
+#[test]
+fn merge() {
+    let dir = TempDir::new("twoskip").unwrap();
+
+    let mut a = twoskip::create(dir.path().join("a.db")).unwrap();
+    a.put(b"same", b"1").unwrap();
+    a.put(b"only.a", b"a").unwrap();
+    a.put(b"differ", b"short").unwrap();
+    let mut b = twoskip::create(dir.path().join("b.db")).unwrap();
+    b.put(b"same", b"1").unwrap();
+    b.put(b"differ", b"much longer").unwrap();
+    b.put(b"drop", b"b").unwrap();
+    let mut c = twoskip::create(dir.path().join("c.db")).unwrap();
+    c.put(b"differ", b"newest").unwrap();
+    c.put(b"drop", b"c").unwrap();
+    c.repack().unwrap();
+    let inputs = [&a, &b, &c];
+
+    // newest generation
+    let mut calls = 0;
+    let merged = twoskip::merge(&inputs, dir.path().join("newest.db"), |key, values| {
+        calls += 1;
+        match key {
+            b"drop" => Ok(None),
+            _ => {
+                let newest = values
+                    .iter()
+                    .max_by_key(|(i, _)| inputs[*i].header().generation());
+                Ok(newest.map(|(_, v)| v.to_vec()))
+            }
+        }
+    })
+    .unwrap();
+    assert_eq!(calls, 2);
+    let records: Vec<_> = merged
+        .iter_live()
+        .map(|r| r.unwrap().to_owned().into_parts())
+        .collect();
+    let expected: Vec<(&[u8], &[u8])> =
+        vec![(b"differ", b"newest"), (b"only.a", b"a"), (b"same", b"1")];
+    let expected: Vec<_> = expected
+        .into_iter()
+        .map(|(k, v)| (k.to_vec(), v.to_vec()))
+        .collect();
+    assert_eq!(records, expected);
+
+    // longest value
+    let merged = twoskip::merge(&inputs, dir.path().join("longest.db"), |_, values| {
+        Ok(values
+            .iter()
+            .map(|(_, v)| v.to_vec())
+            .max_by_key(|v| v.len()))
+    })
+    .unwrap();
+    assert_eq!(
+        merged.get_value(b"differ").unwrap(),
+        Some(&b"much longer"[..])
+    );
+
+    let failed = dir.path().join("failed.db");
+    let r = twoskip::merge(&inputs, &failed, |_, _| Err(twoskip::Error::ValueMismatch));
+    assert!(matches!(r, Err(twoskip::Error::ValueMismatch)));
+    assert!(!failed.exists());
+
+    // nor from inputs that aren't in byte order
+    #[derive(Debug)]
+    struct CaseInsensitive;
+
+    impl twoskip::Comparator for CaseInsensitive {
+        fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+            a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase())
+        }
+    }
+
+    let folded = twoskip::OpenOptions::new()
+        .comparator(CaseInsensitive)
+        .open(dir.path().join("a.db"))
+        .unwrap();
+    let r = twoskip::merge(&[&folded, &b], &failed, |_, _| Ok(None));
+    assert!(matches!(r, Err(twoskip::Error::OutOfOrder)));
+    assert!(!failed.exists());
+}
+
+#[test]
+fn split() {
+    let dir = TempDir::new("twoskip").unwrap();
+
+    let mut db = twoskip::create(dir.path().join("mailboxes.db")).unwrap();
+    let mut txn = db.begin().unwrap();
+    for name in ["alice", "bob", "carol", "mallory", "zed"] {
+        txn.put(format!("user.{}", name).as_bytes(), b"%()")
+            .unwrap();
+        txn.put(format!("user.{}.Sent", name).as_bytes(), b"%()")
+            .unwrap();
+    }
+    txn.put(b"shared", b"%()").unwrap();
+    txn.commit().unwrap();
+
+    // by the first letter of the user's name, a-m and n-z
+    let partition = |key: &[u8]| match key.strip_prefix(b"user.")?.first()? {
+        b'a'..=b'm' => Some(0),
+        _ => Some(1),
+    };
+    let outputs = [dir.path().join("a-m.db"), dir.path().join("n-z.db")];
+    let parts = twoskip::split(&db, partition, &outputs).unwrap();
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[0].header().num_records(), 8);
+    assert_eq!(parts[1].header().num_records(), 2);
+    assert!(parts[1].get_value(b"user.zed.Sent").unwrap().is_some());
+    assert!(parts[0].get_value(b"shared").unwrap().is_none());
+
+    // there's no third output, so neither of the others is kept
+    let outputs = [dir.path().join("x.db"), dir.path().join("y.db")];
+    assert!(twoskip::split(&db, |_| Some(2), &outputs).is_err());
+    assert!(!outputs[0].exists() && !outputs[1].exists());
+}
+
+#[test]
+fn export_prefix() {
+    let dir = TempDir::new("twoskip").unwrap();
+
+    let mut db = twoskip::create(dir.path().join("mailboxes.db")).unwrap();
+    for key in [
+        "user.bob",
+        "user.fred",
+        "user.fred.Sent",
+        "user.fred.Trash",
+        "user.fredrick",
+    ] {
+        db.put(key.as_bytes(), b"%()").unwrap();
+    }
+    db.delete(b"user.fred.Trash").unwrap();
+
+    let path = dir.path().join("fred.db");
+    db.export_prefix(b"user.fred.", &path).unwrap();
+    let fred = twoskip::open(&path).unwrap();
+    let keys: Vec<_> = fred.keys().map(|k| k.unwrap()).collect();
+    assert_eq!(keys, vec![b"user.fred.Sent".to_vec()]);
+    assert!(db.export_prefix(b"user.fred.", &path).is_err());
+
+    let path = dir.path().join("none.db");
+    db.export_prefix(b"user.nobody.", &path).unwrap();
+    assert_eq!(twoskip::open(&path).unwrap().header().num_records(), 0);
+}
+
+#[test]
+fn csv() {
+    use export::{Base64, CsvOptions, Quoting};
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let mut db = twoskip::create(dir.path().join("test.db")).unwrap();
+    db.put(b"plain", b"1 2 3").unwrap();
+    db.put(b"quoted", b"a,\"b\"\nc").unwrap();
+    db.put(b"user.fred\0INBOX", b"\xff\x00").unwrap();
+    db.put(b"tricky", b"base64:AA==").unwrap();
+
+    let csv = |options: &CsvOptions| {
+        let mut out = vec![];
+        export::to_csv(&db, &mut out, options).unwrap();
+        String::from_utf8_lossy(&out).into_owned()
+    };
+
+    let mut options = CsvOptions::new();
+    options.base64(Base64::Binary);
+    assert_eq!(
+        csv(&options),
+        "key,value\n\
+         plain,1 2 3\n\
+         quoted,\"a,\"\"b\"\"\nc\"\n\
+         tricky,base64:YmFzZTY0OkFBPT0=\n\
+         base64:dXNlci5mcmVkAElOQk9Y,base64:/wA=\n"
+    );
+
+    let mut options = CsvOptions::new();
+    options
+        .delimiter(b'\t')
+        .quoting(Quoting::Always)
+        .base64(Base64::Always)
+        .header(false);
+    let out = csv(&options);
+    assert_eq!(out.lines().next(), Some("\"cGxhaW4=\"\t\"MSAyIDM=\""));
+    assert_eq!(out.lines().count(), 4);
+
+    let mut options = CsvOptions::new();
+    options.delimiter(b'"');
+    assert!(export::to_csv(&db, &mut vec![], &options).is_err());
+}
+
+#[test]
+fn stream() {
+    let dir = TempDir::new("twoskip").unwrap();
+
+    let mut db = twoskip::create(dir.path().join("a.db")).unwrap();
+    let mut txn = db.begin().unwrap();
+    for i in 0..100 {
+        txn.put(format!("user.{:03}", i).as_bytes(), &vec![b'x'; i])
+            .unwrap();
+    }
+    txn.commit().unwrap();
+
+    let mut sent = vec![];
+    export::to_stream(&db, &mut sent, None).unwrap();
+
+    // the link goes down part way through
+    let mut copy = twoskip::create(dir.path().join("b.db")).unwrap();
+    let report = export::from_stream(&mut copy, &sent[..sent.len() / 2]).unwrap();
+    assert!(!report.complete);
+    let position = report.position.unwrap();
+    assert_eq!(position.generation, db.header().generation());
+    let last = format!("user.{:03}", report.records - 1);
+    assert!(copy.get_value(last.as_bytes()).unwrap().is_some());
+
+    // and the record it got to is replaced before it comes back up, which
+    // doesn't stop it going on from there
+    db.put(last.as_bytes(), b"changed").unwrap();
+    let mut rest = vec![];
+    export::to_stream(&db, &mut rest, Some(position)).unwrap();
+    let report = export::from_stream(&mut copy, &rest[..]).unwrap();
+    assert!(report.complete);
+    assert_eq!(copy.header().num_records(), 100);
+    assert_eq!(
+        copy.get_value(b"user.099").unwrap(),
+        Some(&vec![b'x'; 99][..])
+    );
+
+    assert!(export::from_stream(&mut copy, &b"not a stream at all"[..]).is_err());
+
+    db.repack().unwrap();
+    assert!(matches!(
+        export::to_stream(&db, &mut vec![], Some(position)),
+        Err(twoskip::Error::GenerationMismatch)
+    ));
+}
+
+#[test]
+fn diff() {
+    use twoskip::DiffEntry;
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let mut a = twoskip::create(dir.path().join("a.db")).unwrap();
+    let mut b = twoskip::create(dir.path().join("b.db")).unwrap();
+    for (k, v) in [("a", "1"), ("b", "2"), ("c", "3"), ("e", "5")] {
+        a.put(k.as_bytes(), v.as_bytes()).unwrap();
+    }
+    for (k, v) in [("b", "2"), ("c", "three"), ("d", "4"), ("f", "6")] {
+        b.put(k.as_bytes(), v.as_bytes()).unwrap();
+    }
+
+    let entries: Vec<_> = twoskip::diff(&a, &b).map(|e| e.unwrap()).collect();
+    assert_eq!(
+        entries,
+        vec![
+            DiffEntry::Removed {
+                key: b"a".to_vec(),
+                value: b"1".to_vec()
+            },
+            DiffEntry::Changed {
+                key: b"c".to_vec(),
+                old: b"3".to_vec(),
+                new: b"three".to_vec()
+            },
+            DiffEntry::Added {
+                key: b"d".to_vec(),
+                value: b"4".to_vec()
+            },
+            DiffEntry::Removed {
+                key: b"e".to_vec(),
+                value: b"5".to_vec()
+            },
+            DiffEntry::Added {
+                key: b"f".to_vec(),
+                value: b"6".to_vec()
+            },
+        ]
+    );
+    assert_eq!(twoskip::diff(&a, &a).count(), 0);
+
+    // the same keys in another order still line up
+    #[derive(Debug)]
+    struct CaseInsensitive;
+
+    impl twoskip::Comparator for CaseInsensitive {
+        fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+            a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase())
+        }
+    }
+
+    let mut options = twoskip::OpenOptions::new();
+    options.comparator(CaseInsensitive);
+    twoskip::create(dir.path().join("c.db")).unwrap();
+    twoskip::create(dir.path().join("d.db")).unwrap();
+    let mut c = options.open(dir.path().join("c.db")).unwrap();
+    let mut d = options.open(dir.path().join("d.db")).unwrap();
+    for (k, v) in [("a", "1"), ("B", "2"), ("c", "3")] {
+        c.put(k.as_bytes(), v.as_bytes()).unwrap();
+        d.put(k.as_bytes(), v.as_bytes()).unwrap();
+    }
+    d.put(b"c", b"three").unwrap();
+    let entries: Vec<_> = twoskip::diff(&c, &d).map(|e| e.unwrap()).collect();
+    assert_eq!(
+        entries,
+        vec![DiffEntry::Changed {
+            key: b"c".to_vec(),
+            old: b"3".to_vec(),
+            new: b"three".to_vec()
+        }]
+    );
+}
+
+#[test]
+fn apply_patch() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let mut primary = twoskip::create(dir.path().join("primary.db")).unwrap();
+    let mut replica = twoskip::create(dir.path().join("replica.db")).unwrap();
+    for (k, v) in [("a", "1"), ("b", "2"), ("c", "3")] {
+        primary.put(k.as_bytes(), v.as_bytes()).unwrap();
+        replica.put(k.as_bytes(), v.as_bytes()).unwrap();
+    }
+    primary.put(b"b", b"two").unwrap();
+    primary.delete(b"c").unwrap();
+    primary.put(b"d", b"4").unwrap();
+
+    let patch: Vec<_> = twoskip::diff(&replica, &primary)
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(replica.apply_patch(patch.clone()).unwrap(), 3);
+    assert_eq!(twoskip::diff(&replica, &primary).count(), 0);
+
+    // a second time round, none of it is what it expects any more
+    for entry in patch {
+        assert!(matches!(
+            replica.apply_patch([entry]),
+            Err(twoskip::Error::ValueMismatch)
+        ));
+    }
+    assert_eq!(twoskip::diff(&replica, &primary).count(), 0);
+}
+
+#[test]
+fn quotalegacy() {
+    use std::fs;
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let quota = dir.path().join("quota");
+    let file = |path: &str, data: &str| {
+        let path = dir.path().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    };
+    file("quota/f/user.fred", "1234\n10240\n");
+    file("quota/b/user.bob", "0\n-1\n");
+    file("quota/b/user.bob.NEW", "half written");
+    file("domain/e/example.com/quota/root", "99\n100000\n");
+    file(
+        "domain/e/example.com/quota/j/user.jo",
+        "5 100 MESSAGE 2 50\n",
+    );
+
+    let path = dir.path().join("quotas.db");
+    assert_eq!(export::from_quotalegacy(&quota, &path).unwrap(), 4);
+    let db = twoskip::open(&path).unwrap();
+    let records: Vec<_> = db
+        .iter_live()
+        .map(|r| r.unwrap().to_owned().into_parts())
+        .collect();
+    let expected: Vec<(&[u8], &[u8])> = vec![
+        (b"example.com!", b"99 100000"),
+        (b"example.com!user.jo", b"5 100 MESSAGE 2 50"),
+        (b"user.bob", b"0 -1"),
+        (b"user.fred", b"1234 10240"),
+    ];
+    let expected: Vec<_> = expected
+        .into_iter()
+        .map(|(k, v)| (k.to_vec(), v.to_vec()))
+        .collect();
+    assert_eq!(records, expected);
+
+    file("quota/x/user.x", "nonsense\n");
+    assert!(export::from_quotalegacy(&quota, dir.path().join("bad.db")).is_err());
+    assert!(!dir.path().join("bad.db").exists());
+}
+
+#[test]
+fn cyrusdb() {
+    use cyrusdb::CyrusDb;
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    // written once, for any backend
+    fn fill(db: &mut dyn CyrusDb) {
+        db.store(b"user.a", b"1").unwrap();
+        let mut txn = db.begin().unwrap();
+        txn.store(b"user.b", b"2").unwrap();
+        txn.store(b"user.c", b"3").unwrap();
+        assert_eq!(txn.fetch(b"user.c").unwrap(), Some(&b"3"[..]));
+        assert!(txn.delete(b"user.a").unwrap());
+        txn.commit().unwrap();
+
+        let mut txn = db.begin().unwrap();
+        txn.store(b"user.d", b"4").unwrap();
+        txn.abort().unwrap();
+    }
+
+    assert!(cyrusdb::open("twoskip", &path, false).is_err());
+    let mut db = cyrusdb::open("twoskip", &path, true).unwrap();
+    fill(db.as_mut());
+
+    assert_eq!(db.fetch(b"user.a").unwrap(), None);
+    assert_eq!(db.fetch(b"user.d").unwrap(), None);
+    assert_eq!(
+        db.fetchnext(b"user.a").unwrap(),
+        Some((&b"user.b"[..], &b"2"[..]))
+    );
+    assert_eq!(db.fetchnext(b"user.c").unwrap(), None);
+
+    let mut seen = vec![];
+    let r = db
+        .foreach(b"user.", &mut |_, v| v != b"2", &mut |k, _| {
+            seen.push(k.to_vec());
+            7
+        })
+        .unwrap();
+    assert_eq!(r, 7);
+    assert_eq!(seen, vec![b"user.c".to_vec()]);
+
+    assert!(cyrusdb::open("berkeley", &path, false).is_err());
+}
+
+#[test]
+fn dlist() {
+    use dlist::Dlist;
+    use std::borrow::Cow;
+
+    let db = twoskip::open("mailboxes.db").unwrap();
+    let value = db
+        .get_value(
+            b"DELETED.user.pinguser254.#calendars.11883388-c851-4304-a31d-ed696d96815e.5671CEC6",
+        )
+        .unwrap()
+        .unwrap();
+    let d = dlist::parse(value).unwrap();
+    for r in db.iter_live() {
+        let r = r.unwrap();
+        if !r.key().starts_with(b"$RACL") {
+            dlist::parse(r.value()).unwrap();
+        }
+    }
+    assert_eq!(d.get(b"T").and_then(|t| t.as_str()), Some("c"));
+    assert_eq!(d.get(b"P").and_then(|t| t.as_str()), Some("default"));
+    let acl = d.get(b"A").and_then(|a| a.as_map()).unwrap();
+    assert_eq!(acl.len(), 3);
+    assert_eq!(&acl[1].0[..], b"admin");
+    assert!(matches!(d.get(b"I"), Some(Dlist::Atom(Cow::Borrowed(_)))));
+
+    let d = dlist::parse(b"(a \"b c\" \"d\\\"e\" NIL {3}\r\nf)g () %())").unwrap();
+    assert_eq!(
+        d,
+        Dlist::List(vec![
+            Dlist::Atom(Cow::Borrowed(b"a")),
+            Dlist::Atom(Cow::Borrowed(b"b c")),
+            Dlist::Atom(Cow::Owned(b"d\"e".to_vec())),
+            Dlist::Nil,
+            Dlist::Atom(Cow::Borrowed(b"f)g")),
+            Dlist::List(vec![]),
+            Dlist::Map(vec![]),
+        ])
+    );
+
+    for bad in [
+        &b"(a"[..],
+        b"%(a)",
+        b"(a))",
+        b"\"open",
+        b"{5}\r\nab",
+        b"%((a) b)",
+        b"",
+    ] {
+        assert!(
+            matches!(dlist::parse(bad), Err(twoskip::Error::InvalidDlist { .. })),
+            "{:?}",
+            String::from_utf8_lossy(bad)
+        );
+    }
+
+    // line breaks and tabs between things are only for parse_multiline
+    let spread = b"%(\n  A (a b)\n\tB \"c d\"\r\n)";
+    assert!(dlist::parse(spread).is_err());
+    assert_eq!(
+        dlist::parse_multiline(spread).unwrap().to_vec(),
+        b"%(A (a b) B \"c d\")"
+    );
+}
+
+#[test]
+fn dlist_to_vec() {
+    use dlist::Dlist;
+    use std::borrow::Cow;
+
+    // everything cyrus wrote comes back out exactly as it was
+    let db = twoskip::open("mailboxes.db").unwrap();
+    for r in db.iter_live() {
+        let r = r.unwrap();
+        if !r.key().starts_with(b"$RACL") {
+            assert_eq!(dlist::parse(r.value()).unwrap().to_vec(), r.value());
+        }
+    }
+
+    let atom = |s: &'static [u8]| Dlist::Atom(Cow::Borrowed(s));
+    let mut d = Dlist::Map(vec![
+        (Cow::Borrowed(b"A"), atom(b"plain")),
+        (Cow::Borrowed(b"B"), atom(b"two words")),
+        (Cow::Borrowed(b"C"), atom(b"")),
+        (Cow::Borrowed(b"D"), atom(b"NIL")),
+        (Cow::Borrowed(b"E"), Dlist::Nil),
+        (Cow::Borrowed(b"F"), atom(b"50%")),
+        (Cow::Borrowed(b"G"), atom("caf\u{e9}".as_bytes())),
+        (
+            Cow::Borrowed(b"H"),
+            Dlist::List(vec![atom(b"*"), atom(b"a\\b")]),
+        ),
+    ]);
+    assert_eq!(
+        d.to_vec(),
+        &b"%(A plain B \"two words\" C \"\" D \"NIL\" E NIL F {3}\r\n50% G {5}\r\ncaf\xc3\xa9 H (\"*\" {3}\r\na\\b))"[..]
+    );
+    assert_eq!(dlist::parse(&d.to_vec()).unwrap(), d);
+
+    *d.get_mut(b"A").unwrap() = Dlist::List(vec![]);
+    assert!(d.to_vec().starts_with(b"%(A () B"));
+    assert_eq!(atom(&[b'x'; 1024]).to_vec().len(), 1024 + 8);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn dlist_deserialize() {
+    use std::collections::BTreeMap;
+
+    #[derive(serde::Deserialize, Debug, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Kind {
+        C,
+        E,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Entry<'a> {
+        #[serde(rename = "A")]
+        acl: BTreeMap<&'a str, &'a str>,
+        #[serde(rename = "I")]
+        uniqueid: &'a str,
+        #[serde(rename = "P")]
+        partition: String,
+        #[serde(rename = "S")]
+        server: Option<String>,
+        #[serde(rename = "T")]
+        mbtype: Kind,
+        #[serde(rename = "V")]
+        uidvalidity: u32,
+        #[serde(rename = "F")]
+        foldermodseq: u64,
+    }
+
+    let db = twoskip::open("mailboxes.db").unwrap();
+    let value = db
+        .get_value(
+            b"DELETED.user.pinguser254.#calendars.11883388-c851-4304-a31d-ed696d96815e.5671CEC6",
+        )
+        .unwrap()
+        .unwrap();
+    let entry: Entry = dlist::from_bytes(value).unwrap();
+    assert_eq!(entry.acl.len(), 3);
+    assert_eq!(entry.acl["anyone"], "p");
+    assert_eq!(entry.uniqueid, "2eababff-a28e-40bc-b00c-00d6ff6ad10b");
+    assert_eq!(entry.partition, "default");
+    assert_eq!(entry.server, None);
+    assert_eq!(entry.mbtype, Kind::C);
+    assert_eq!(entry.uidvalidity, 1450299080);
+    assert_eq!(entry.foldermodseq, 17365878007025498411);
+
+    #[derive(serde::Deserialize, Debug, PartialEq)]
+    enum Item {
+        Uid(u32),
+        Flag(bool),
+        Range(u32, u32),
+    }
+    let items: Vec<(String, Option<&[u8]>, Item)> = dlist::from_bytes(
+        b"((\"a b\" {3}\r\nx\0y %(Uid 4)) (c NIL %(Flag 1)) (d \"\" %(Range (1 5))))",
+    )
+    .unwrap();
+    assert_eq!(
+        items,
+        vec![
+            ("a b".to_string(), Some(&b"x\0y"[..]), Item::Uid(4)),
+            ("c".to_string(), None, Item::Flag(true)),
+            ("d".to_string(), Some(&b""[..]), Item::Range(1, 5)),
+        ]
+    );
+    assert_eq!(
+        dlist::from_bytes::<Vec<u32>>(b"()").unwrap(),
+        Vec::<u32>::new()
+    );
+    assert_eq!(
+        dlist::from_bytes::<Vec<u32>>(b"NIL").unwrap(),
+        Vec::<u32>::new()
+    );
+
+    assert!(dlist::from_bytes::<u32>(b"x").is_err());
+    assert!(dlist::from_bytes::<Entry>(b"(a b)").is_err());
+    assert!(dlist::from_bytes::<bool>(b"2").is_err());
+}
+
+#[test]
+fn mbentry() {
+    use mbentry::Mbentry;
+
+    let db = twoskip::open("mailboxes.db").unwrap();
+    for r in db.iter_live() {
+        let r = r.unwrap();
+        if !r.key().starts_with(b"$RACL") {
+            assert_eq!(Mbentry::parse(r.value()).unwrap().to_value(), r.value());
+        }
+    }
+
+    let value = db
+        .get_value(
+            b"DELETED.user.pinguser254.#calendars.11883388-c851-4304-a31d-ed696d96815e.5671CEC6",
+        )
+        .unwrap()
+        .unwrap();
+    let mut entry = Mbentry::parse(value).unwrap();
+    assert_eq!(entry.acl.len(), 3);
+    assert_eq!(entry.acl[2], ("anyone".to_string(), "p".to_string()));
+    assert_eq!(
+        entry.uniqueid.as_deref(),
+        Some("2eababff-a28e-40bc-b00c-00d6ff6ad10b")
+    );
+    assert_eq!(entry.partition.as_deref(), Some("default"));
+    assert_eq!(entry.server, None);
+    assert_eq!(entry.mbtype, "c");
+    assert_eq!(entry.uidvalidity, 1450299080);
+    assert_eq!(entry.foldermodseq, 17365878007025498411);
+    assert_eq!(entry.createdmodseq, 0);
+    assert_eq!(entry.mtime, 1450299078);
+    assert!(entry.other.is_empty());
+
+    entry.acl.retain(|(id, _)| id != "anyone");
+    entry.createdmodseq = 5;
+    entry.partition = Some("spare disk".to_string());
+    assert_eq!(
+        entry.to_value(),
+        &b"%(A %(pinguser254 lrswipkxtecdn admin lrswipkxtecdan) I 2eababff-a28e-40bc-b00c-00d6ff6ad10b P \"spare disk\" T c V 1450299080 C 5 F 17365878007025498411 M 1450299078)"[..]
+    );
+
+    let entry = Mbentry::parse(b"%(A \"fred\tlrs\tanyone\tp\t\" P default X (1 2))").unwrap();
+    assert_eq!(entry.acl[0], ("fred".to_string(), "lrs".to_string()));
+    assert_eq!(entry.acl[1], ("anyone".to_string(), "p".to_string()));
+    assert_eq!(entry.other.len(), 1);
+    assert_eq!(
+        entry.to_value(),
+        &b"%(A %(fred lrs anyone p) P default X (1 2))"[..]
+    );
+
+    assert!(matches!(
+        Mbentry::parse(b"%(V soon)"),
+        Err(twoskip::Error::InvalidField { field: "V" })
+    ));
+    assert!(Mbentry::parse(b"(a b)").is_err());
+}
+
+#[test]
+fn acl() {
+    use acl::{Acl, Rights};
+
+    let all = Rights::from_letters("lrswipkxtecdan");
+    assert_eq!(all.to_string(), "lrswipkxtecdan");
+    assert!(all.contains(Rights::ADMIN | Rights::CREATE | Rights::EXPUNGE));
+    assert!(!all.contains(Rights::USER0));
+    assert_eq!(Rights::from_letters("lrs").to_string(), "lrs");
+    // c and d on their own, in the old rfc 2086 way
+    assert_eq!(
+        Rights::from_letters("lrcd"),
+        Rights::LOOKUP | Rights::READ | Rights::CREATE | Rights::DELETE_MESSAGE | Rights::EXPUNGE
+    );
+    assert_eq!(Rights::from_letters("x?9").to_string(), "x9");
+
+    let db = twoskip::open("mailboxes.db").unwrap();
+    let value = db
+        .get_value(
+            b"DELETED.user.pinguser254.#calendars.11883388-c851-4304-a31d-ed696d96815e.5671CEC6",
+        )
+        .unwrap()
+        .unwrap();
+    let mut acl = Acl::parse(value).unwrap();
+    assert_eq!(acl.get("admin"), all);
+    assert!(!acl.get("pinguser254").contains(Rights::ADMIN));
+    assert_eq!(acl.get("anyone"), Rights::POST);
+    assert_eq!(acl.get("nobody"), Rights::empty());
+    assert_eq!(
+        acl.effective("nobody"),
+        Rights::POST,
+        "everyone gets what anyone has"
+    );
+
+    acl.grant("fred", Rights::LOOKUP | Rights::READ);
+    acl.grant("-fred", Rights::READ);
+    assert_eq!(acl.effective("fred"), Rights::LOOKUP | Rights::POST);
+    acl.revoke("anyone", Rights::POST);
+    acl.revoke("pinguser254", Rights::from_letters("xte"));
+    acl.set("-fred", Rights::empty());
+    assert_eq!(
+        acl.entries(),
+        vec![
+            ("pinguser254".to_string(), "lrswipkcn".to_string()),
+            ("admin".to_string(), "lrswipkxtecdan".to_string()),
+            ("fred".to_string(), "lr".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn mailboxes() {
+    use mailboxes::Mailboxes;
+
+    let mailboxes = Mailboxes::open("mailboxes.db").unwrap();
+    let entry = mailboxes.lookup("user.pinguser254.Sent").unwrap().unwrap();
+    assert_eq!(
+        entry.uniqueid.as_deref(),
+        Some("1a2b3c4d-5e6f-4a7b-8c9d-0e1f2a3b4c5d")
+    );
+    assert!(mailboxes.lookup("user.nobody").unwrap().is_none());
+
+    let names = |list: Vec<(String, mbentry::Mbentry)>| -> Vec<String> {
+        list.into_iter().map(|(name, _)| name).collect()
+    };
+    assert_eq!(
+        names(mailboxes.list_user("pinguser254").unwrap()),
+        [
+            "user.pinguser254",
+            "user.pinguser254.#calendars",
+            "user.pinguser254.Drafts",
+            "user.pinguser254.Sent"
+        ]
+    );
+    assert!(mailboxes.list_user("pinguser25").unwrap().is_empty());
+    assert_eq!(
+        names(mailboxes.children("user").unwrap()),
+        ["user.pinguser254"]
+    );
+    let deleted = mailboxes.deleted_entries().unwrap();
+    assert_eq!(deleted.len(), 1);
+    assert_eq!(deleted[0].1.mbtype, "c");
+
+    assert_eq!(
+        mailboxes::user_inbox("fred.bloggs@example.com"),
+        "example.com!user.fred^bloggs"
+    );
+    assert!(mailboxes::is_deleted(
+        "example.com!DELETED.user.fred.5671CEC6"
+    ));
+    assert!(!mailboxes::is_deleted("user.fred.DELETED"));
+    assert!(!mailboxes::is_mailbox("$RACL"));
+}
+
+#[test]
+fn tree() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("mailboxes.db");
+    let mut db = twoskip::create(&path).unwrap();
+    for name in [
+        "$RACL",
+        "user.fred",
+        "user.fred-x",
+        "user.fred.Sent",
+        "user.fred.Archive.2015",
+        "DELETED.user.fred.Sent.5671CEC6",
+        "DELETED.user.fred.Old.5671CEC7",
+        "DELETED.user.fred-x.5671CEC8",
+        "example.com!user.anne",
+    ] {
+        db.put(name.as_bytes(), b"%()").unwrap();
+    }
+
+    let lines = |prefix| -> Vec<String> {
+        db.tree(prefix)
+            .unwrap()
+            .into_iter()
+            .map(|e| {
+                let mark = match (e.exists, e.deleted) {
+                    (false, _) => "-".to_string(),
+                    (true, Some(when)) => format!(" deleted {:x}", when),
+                    (true, None) => String::new(),
+                };
+                format!("{}{}{}", "  ".repeat(e.depth), e.name, mark)
+            })
+            .collect()
+    };
+    assert_eq!(
+        lines("user.fred"),
+        [
+            "user.fred",
+            "  user.fred.Archive-",
+            "    user.fred.Archive.2015",
+            "  DELETED.user.fred.Old.5671CEC7 deleted 5671cec7",
+            "  user.fred.Sent",
+            "  DELETED.user.fred.Sent.5671CEC6 deleted 5671cec6",
+        ]
+    );
+    assert_eq!(
+        lines(""),
+        [
+            "example.com!-",
+            "  example.com!user-",
+            "    example.com!user.anne",
+            "user-",
+            "  user.fred",
+            "    user.fred.Archive-",
+            "      user.fred.Archive.2015",
+            "    DELETED.user.fred.Old.5671CEC7 deleted 5671cec7",
+            "    user.fred.Sent",
+            "    DELETED.user.fred.Sent.5671CEC6 deleted 5671cec6",
+            "  user.fred-x",
+            "  DELETED.user.fred-x.5671CEC8 deleted 5671cec8",
+        ]
+    );
+    assert_eq!(lines("example.com!").len(), 3);
+    assert_eq!(
+        lines("example.com!user"),
+        ["example.com!user-", "  example.com!user.anne"]
+    );
+    assert!(db.tree("user.nobody").unwrap().is_empty());
+}
+
+#[test]
+fn seen() {
+    use seen::{Seen, UidSet};
+
+    let seen = Seen::parse(b"1 1450299080 12 1450299090 1:5,7,9:12").unwrap();
+    assert_eq!(seen.lastread, 1450299080);
+    assert_eq!(seen.lastuid, 12);
+    assert_eq!(seen.lastchange, 1450299090);
+    assert_eq!(seen.uids.ranges(), [(1, 5), (7, 7), (9, 12)]);
+    assert_eq!(seen.uids.len(), 10);
+    assert!(seen.uids.contains(7) && !seen.uids.contains(8) && !seen.uids.contains(13));
+    assert_eq!(seen.to_value(), b"1 1450299080 12 1450299090 1:5,7,9:12");
+
+    let empty = Seen::parse(b"1 0 0 0 ").unwrap();
+    assert!(empty.uids.is_empty());
+    assert_eq!(empty.to_value(), b"1 0 0 0 ");
+    assert!(matches!(
+        Seen::parse(b"2 0 0 0 1"),
+        Err(twoskip::Error::VersionMismatch)
+    ));
+    assert!(matches!(
+        Seen::parse(b"1 0 x 0 1"),
+        Err(twoskip::Error::InvalidField { field: "lastuid" })
+    ));
+    assert!(Seen::parse(b"1 0 0 0 1:x").is_err());
+
+    let mut uids = UidSet::parse("9:7,1,3:4,2").unwrap();
+    assert_eq!(uids.to_string(), "1:4,7:9");
+    uids.insert(6);
+    uids.insert(5);
+    assert_eq!(uids.to_string(), "1:9");
+    uids.remove(3);
+    uids.remove_range(8, 20);
+    assert_eq!(uids.to_string(), "1:2,4:7");
+    assert_eq!(uids.iter().collect::<Vec<_>>(), [1, 2, 4, 5, 6, 7]);
+
+    let other = UidSet::parse("2:5,100:*").unwrap();
+    assert_eq!(uids.union(&other).to_string(), "1:7,100:4294967295");
+    assert_eq!(uids.difference(&other).to_string(), "1,6:7");
+    assert_eq!(uids.intersection(&other).to_string(), "2,4:5");
+}
+
+#[test]
+fn annotations() {
+    use annotations::{Annotation, AnnotationKey};
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("annotations.db");
+    let mut db = twoskip::create(&path).unwrap();
+
+    // as cyrus 3 on x86_64 writes them
+    let comment = b"\0\0\0\x05\0\0\0\0hello\0text/plain\0\0\0\0\0\0\0\0\x07\0";
+    db.put(b"user.fred\0/comment\0\0", comment).unwrap();
+    let key = AnnotationKey::parse(b"user.fred\0/comment\0\0").unwrap();
+    assert_eq!(key.mboxname, "user.fred");
+    assert_eq!(key.entry, "/comment");
+    assert!(key.is_shared());
+    assert_eq!(key.to_key(), b"user.fred\0/comment\0\0");
+    let annotation = Annotation::parse(comment).unwrap();
+    assert_eq!(annotation.value, b"hello");
+    assert_eq!(annotation.content_type, "text/plain");
+    assert_eq!(annotation.modseq, 7);
+    assert!(!annotation.deleted);
+    assert_eq!(annotation.to_value(), comment);
+
+    // an old one, without modseq or flags
+    let old = Annotation::parse(b"\0\0\0\x02\0\0\0\0hi\0text/plain\0").unwrap();
+    assert_eq!((old.value.as_slice(), old.modseq), (&b"hi"[..], 0));
+
+    let mut put = |mboxname: &str, entry: &str, userid: &str, value: &[u8], deleted| {
+        let key = AnnotationKey {
+            mboxname: mboxname.to_string(),
+            entry: entry.to_string(),
+            userid: userid.to_string(),
+        };
+        let annotation = Annotation {
+            value: value.to_vec(),
+            content_type: "text/plain".to_string(),
+            modseq: 1,
+            deleted,
+        };
+        db.put(&key.to_key(), &annotation.to_value()).unwrap();
+    };
+    put("", "/motd", "", b"welcome", false);
+    put(
+        "user.fred",
+        "/vendor/cmu/cyrus-imapd/color",
+        "fred",
+        b"red",
+        false,
+    );
+    put("user.fred.Sent", "/specialuse", "fred", b"\\Sent", false);
+    put("user.fred-x", "/comment", "", b"gone", true);
+
+    let groups: Vec<_> = annotations::by_mailbox(&db)
+        .map(|g| {
+            let (name, annotations) = g.unwrap();
+            (name, annotations.len())
+        })
+        .collect();
+    assert_eq!(
+        groups,
+        [
+            ("".to_string(), 1),
+            ("user.fred".to_string(), 2),
+            ("user.fred-x".to_string(), 1),
+            ("user.fred.Sent".to_string(), 1),
+        ]
+    );
+    let (_, gone) = annotations::by_mailbox(&db).nth(2).unwrap().unwrap();
+    assert!(gone[0].1.deleted);
+
+    assert!(AnnotationKey::parse(b"user.fred\0/comment\0").is_err());
+    assert!(Annotation::parse(b"\0\0\0\x09\0\0\0\0short\0").is_err());
+    db.put(b"junk", b"").unwrap();
+    let mut groups = annotations::by_mailbox(&db);
+    assert!(groups.by_ref().any(|g| g.is_err()));
+    assert!(groups.next().is_some_and(|g| g.is_ok()));
+}
+
+#[test]
+fn deliver() {
+    use deliver::{DeliverKey, Delivery};
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("deliver.db");
+    let mut db = twoskip::create(&path).unwrap();
+
+    let key = b"<1234@example.com>\0user.fred\0Mon, 14 Dec 2015 21:04:40 +0000";
+    let value = b"\x40\x2f\x6f\x56\0\0\0\0\x2a\0\0\0\0\0\0\0";
+    let k = DeliverKey::parse(key).unwrap();
+    assert_eq!(k.id, b"<1234@example.com>");
+    assert_eq!(k.to, b"user.fred");
+    assert_eq!(k.date, b"Mon, 14 Dec 2015 21:04:40 +0000");
+    assert_eq!(k.to_key(), key);
+    let d = Delivery::parse(value).unwrap();
+    assert_eq!(
+        d,
+        Delivery {
+            mark: 1450127168,
+            uid: 42
+        }
+    );
+    assert_eq!(d.to_value(), value);
+    // from before cyrus kept the uid
+    assert_eq!(Delivery::parse(&value[..8]).unwrap().uid, 0);
+    assert!(Delivery::parse(b"\0\0").is_err());
+    assert!(DeliverKey::parse(b"just an id").is_err());
+
+    for (id, mark) in [("a", 100), ("b", 200), ("c", 300)] {
+        let key = DeliverKey {
+            id: id.as_bytes().to_vec(),
+            to: b".vacation.".to_vec(),
+            date: b"".to_vec(),
+        };
+        db.put(&key.to_key(), &Delivery { mark, uid: 0 }.to_value())
+            .unwrap();
+    }
+    let ids: Vec<Vec<u8>> = deliver::expired_before(&db, 250)
+        .map(|e| e.unwrap().0.id)
+        .collect();
+    assert_eq!(ids, [b"a", b"b"]);
+    assert_eq!(deliver::expire(&mut db, 250).unwrap(), 2);
+    assert_eq!(db.iter_live().count(), 1);
+    assert_eq!(deliver::expire(&mut db, 250).unwrap(), 0);
+}
+
+#[test]
+fn delete_where() {
+    use deliver::Delivery;
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("deliver.db");
+    let mut db = twoskip::create(&path).unwrap();
+
+    let delivery = |mark| Delivery { mark, uid: 0 }.to_value();
+    db.put(b"a", &delivery(100)).unwrap();
+    db.put(b"b", &delivery(300)).unwrap();
+    db.put(b"c", b"\0\0").unwrap();
+    db.put(b"d", &delivery(200)).unwrap();
+    let size = db.header().current_size();
+
+    // c won't decode, and that stops the lot, a included
+    assert!(matches!(
+        deliver::expire(&mut db, 250),
+        Err(twoskip::Error::InvalidField { field: "mark" })
+    ));
+    assert_eq!(db.iter_live().count(), 4);
+    assert!(db.get(b"a").unwrap().is_some());
+    assert_eq!(db.header().current_size(), size);
+    let db2 = twoskip::open(&path).unwrap();
+    assert_eq!(db2.iter_live().count(), 4);
+    drop(db2);
+
+    db.delete(b"c").unwrap();
+    assert_eq!(deliver::expire(&mut db, 250).unwrap(), 2);
+    let keys: Vec<Vec<u8>> = db.keys().map(|k| k.unwrap().to_vec()).collect();
+    assert_eq!(keys, [b"b"]);
+
+    // the empty key comes first, and is looked at like any other
+    db.put(b"", &delivery(100)).unwrap();
+    assert_eq!(deliver::expire(&mut db, 250).unwrap(), 1);
+    assert!(db.get(b"").unwrap().is_none());
+    assert_eq!(db.iter_live().count(), 1);
+}
+
+#[test]
+fn statuscache() {
+    use statuscache::{Status, StatusEntry, StatusItems, StatusKey};
+
+    let key = StatusKey::parse(b"user.fred.Sent\0fred").unwrap();
+    assert_eq!(key.mboxname, b"user.fred.Sent");
+    assert_eq!(key.userid, b"fred");
+    assert_eq!(key.to_key(), b"user.fred.Sent\0fred");
+    assert!(StatusKey::parse(b"user.fred").is_err());
+
+    let value = b"8 29 12 0 13 1450299004 3 27 1450299010 9";
+    let entry = StatusEntry::parse(value).unwrap();
+    assert_eq!(entry.version, 8);
+    assert_eq!(
+        entry.items,
+        StatusItems::MESSAGES
+            | StatusItems::UIDNEXT
+            | StatusItems::UIDVALIDITY
+            | StatusItems::UNSEEN
+    );
+    let actual = Status {
+        messages: 12,
+        recent: 0,
+        uidnext: 13,
+        uidvalidity: 1450299004,
+        unseen: 3,
+        highestmodseq: 27,
+    };
+    assert_eq!(entry.status, actual);
+    assert_eq!(entry.extra, [1450299010, 9]);
+    assert_eq!(entry.to_value(), value);
+
+    assert!(entry.stale_items(&actual).is_empty());
+    // highestmodseq wasn't asked for, so it doesn't matter that it's moved
+    let moved = Status {
+        unseen: 2,
+        highestmodseq: 30,
+        ..actual
+    };
+    assert_eq!(entry.stale_items(&moved), StatusItems::UNSEEN);
+
+    assert!(matches!(
+        StatusEntry::parse(b"8 29 12 0 13"),
+        Err(twoskip::Error::InvalidField {
+            field: "uidvalidity"
+        })
+    ));
+    assert!(StatusEntry::parse(b"8 29 12 0 13 1 3 27 x").is_err());
+}
+
+#[test]
+fn tls_sessions() {
+    use tls_sessions::TlsSession;
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("tls_sessions.db");
+    let mut db = twoskip::create(&path).unwrap();
+
+    let mut value = 1450299080i64.to_le_bytes().to_vec();
+    value.extend_from_slice(b"\x30\x82\x01\x02");
+    let session = TlsSession::parse(&value).unwrap();
+    assert_eq!(session.expire, 1450299080);
+    assert_eq!(session.session, b"\x30\x82\x01\x02");
+    assert!(TlsSession::parse(b"\x01\x02").is_err());
+
+    for (id, expire) in [(&b"\x01\x02"[..], 100i64), (b"\x03", 200), (b"\x04", 300)] {
+        db.put(id, &expire.to_le_bytes()).unwrap();
+    }
+    let ids: Vec<Vec<u8>> = tls_sessions::expired_before(&db, 200)
+        .map(|e| e.unwrap().0)
+        .collect();
+    assert_eq!(ids, [b"\x01\x02"]);
+    assert_eq!(tls_sessions::expire(&mut db, 250).unwrap(), 2);
+    assert_eq!(db.iter_live().count(), 1);
+}
+
+#[test]
+fn ptscache() {
+    use ptscache::{PtsEntry, KEY_SIZE};
+
+    let ident = |name: &str| {
+        let mut ident = vec![0xaa; 4];
+        ident.extend_from_slice(name.as_bytes());
+        ident.resize(4 + KEY_SIZE, 0);
+        ident
+    };
+    let entry = |userid: &str, mark: i64, groups: &[&str]| {
+        let mut value = ident(userid);
+        value.resize((value.len() + 7) & !7, 0);
+        value.extend_from_slice(&mark.to_le_bytes());
+        value.extend_from_slice(&(groups.len() as i32).to_le_bytes());
+        for group in groups {
+            value.extend_from_slice(&ident(group));
+        }
+        value
+    };
+
+    let value = entry("fred", 1450299080, &["group:staff", "group:admins"]);
+    let parsed = PtsEntry::parse(&value).unwrap();
+    assert_eq!(parsed.userid, "fred");
+    assert_eq!(parsed.mark, 1450299080);
+    assert_eq!(parsed.groups, ["group:staff", "group:admins"]);
+    assert!(PtsEntry::parse(&value[..value.len() - 1]).is_err());
+    assert!(PtsEntry::parse(b"fred").is_err());
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("ptscache.db");
+    let mut db = twoskip::create(&path).unwrap();
+    db.put(b"fred", &entry("fred", 100, &[])).unwrap();
+    db.put(b"Anne", &entry("anne", 300, &["group:staff"]))
+        .unwrap();
+    let expired: Vec<String> = ptscache::expired_before(&db, 200)
+        .map(|e| e.unwrap().1.userid)
+        .collect();
+    assert_eq!(expired, ["fred"]);
+    assert_eq!(ptscache::expire(&mut db, 200).unwrap(), 1);
+    assert!(db.get(b"fred").unwrap().is_none());
+}
+
+#[test]
+fn conversations() {
+    use conversations::{ConvKey, Conversation, FolderStatus, GuidLocation};
+
+    let guid = "3c6f7a1b2d4e5f60718293a4b5c6d7e8f9012345";
+    assert_eq!(ConvKey::parse(b"$COUNTED_FLAGS"), ConvKey::CountedFlags);
+    assert_eq!(ConvKey::parse(b"$FOLDER_NAMES"), ConvKey::FolderNames);
+    assert_eq!(
+        ConvKey::parse(b"B08f2b3c4d5e6f701"),
+        ConvKey::Conversation(0x08f2b3c4d5e6f701)
+    );
+    assert_eq!(
+        ConvKey::parse(format!("G{}", guid).as_bytes()),
+        ConvKey::Guid(guid)
+    );
+    assert_eq!(
+        ConvKey::parse(b"Fuser.fred.Sent"),
+        ConvKey::FolderStatus(b"user.fred.Sent")
+    );
+    assert_eq!(
+        ConvKey::parse(b"<1234@example.com>"),
+        ConvKey::MessageId(b"<1234@example.com>")
+    );
+    assert_eq!(ConvKey::parse(b"Bnothex"), ConvKey::Other(b"Bnothex"));
+
+    assert_eq!(
+        conversations::counted_flags(b"\\Seen \\Flagged").unwrap(),
+        ["\\Seen", "\\Flagged"]
+    );
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("fred.conversations");
+    let mut db = twoskip::create(&path).unwrap();
+    db.put(b"$FOLDER_NAMES", b"(user.fred user.fred.Sent)")
+        .unwrap();
+    assert_eq!(
+        conversations::folders(&db).unwrap(),
+        [&b"user.fred"[..], b"user.fred.Sent"]
+    );
+
+    let value = b"1 (27 3 2 1 (1 0) ((0 25 2 2 1) (1 27 1 0 0)) ((NIL NIL fred example.com 1450299080 2) (\"Anne Other\" NIL anne example.org 1450299070 1)) \"lunch?\" 4096 (x) 20)";
+    let conv = Conversation::parse(value).unwrap();
+    assert_eq!(conv.version, 1);
+    assert_eq!(
+        (conv.modseq, conv.num_records, conv.exists, conv.unseen),
+        (27, 3, 2, 1)
+    );
+    assert_eq!(conv.counts, [1, 0]);
+    assert_eq!(conv.folders.len(), 2);
+    assert_eq!(conv.folders[1].number, 1);
+    assert_eq!(conv.folders[1].modseq, 27);
+    assert_eq!(conv.senders[0].name, b"");
+    assert_eq!(conv.senders[1].name, b"Anne Other");
+    assert_eq!(conv.senders[1].lastseen, 1450299070);
+    assert_eq!(conv.subject, b"lunch?");
+    assert_eq!(conv.size, 4096);
+    assert_eq!(conv.rest.len(), 2);
+    assert!(Conversation::parse(b"1 (27 3 x)").is_err());
+
+    let status = FolderStatus::parse(b"1 (27 2 1)").unwrap();
+    assert_eq!(
+        (status.version, status.modseq, status.exists, status.unseen),
+        (1, 27, 2, 1)
+    );
+    assert_eq!(FolderStatus::parse(b"(5 0 0)").unwrap().version, 0);
+
+    let locations = GuidLocation::parse_all(b"(0:12 1:4:1.2)").unwrap();
+    assert_eq!(
+        locations,
+        [
+            GuidLocation {
+                folder: 0,
+                uid: 12,
+                part: None
+            },
+            GuidLocation {
+                folder: 1,
+                uid: 4,
+                part: Some("1.2".to_string())
+            },
+        ]
+    );
+    assert!(GuidLocation::parse_all(b"(0)").is_err());
+}
+
+#[test]
+fn mboxname() {
+    use mboxname::{decode_utf7, encode_utf7, to_external, to_internal};
+
+    for (utf8, utf7) in [
+        ("Entwürfe", "Entw&APw-rfe"),
+        ("Tom & Jerry", "Tom &- Jerry"),
+        ("日本語", "&ZeVnLIqe-"),
+        ("~peter/mail/台北/日本語", "~peter/mail/&U,BTFw-/&ZeVnLIqe-"),
+        ("🎉", "&2DzfiQ-"),
+        ("plain", "plain"),
+    ] {
+        assert_eq!(encode_utf7(utf8), utf7);
+        assert_eq!(decode_utf7(utf7).unwrap(), utf8);
+    }
+    for bad in ["&ZeVnLIqe", "&Z-", "&AGE", "tab\there", "&2Dw-"] {
+        assert!(decode_utf7(bad).is_err(), "{}", bad);
+    }
+
+    assert_eq!(
+        to_internal("Archive/2015.old/Entwürfe", '/'),
+        "Archive.2015^old.Entw&APw-rfe"
+    );
+    assert_eq!(
+        to_external("Archive.2015^old.Entw&APw-rfe", '/').unwrap(),
+        "Archive/2015.old/Entwürfe"
+    );
+    assert_eq!(to_internal("Archive.2015", '.'), "Archive.2015");
+    assert_eq!(to_external("Archive.2015", '.').unwrap(), "Archive.2015");
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("mailboxes.db");
+    let mut db = twoskip::create(&path).unwrap();
+    db.put(b"user.fred.Entw&APw-rfe", b"%(P default)").unwrap();
+    let r = db.get_mailbox("user.fred.Entwürfe").unwrap().unwrap();
+    assert_eq!(r.key(), b"user.fred.Entw&APw-rfe");
+    drop(db);
+    let mailboxes = mailboxes::Mailboxes::open(&path).unwrap();
+    let entry = mailboxes
+        .lookup_utf8("user.fred.Entwürfe")
+        .unwrap()
+        .unwrap();
+    assert_eq!(entry.partition.as_deref(), Some("default"));
+}
+
+#[test]
+fn typed() {
+    use mbentry::Mbentry;
+    use typed::{Raw, Str, TypedDb, U64Be};
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let mut db: TypedDb<U64Be, Str> = TypedDb::create(dir.path().join("test.db")).unwrap();
+    for n in [300u64, 2, 70000] {
+        db.put(&n, &format!("n{}", n)).unwrap();
+    }
+    assert_eq!(db.get(&2).unwrap().as_deref(), Some("n2"));
+    assert_eq!(db.get(&3).unwrap(), None);
+    assert!(db.contains_key(&70000).unwrap());
+    let all: Vec<(u64, String)> = db.iter().collect::<Result<_, _>>().unwrap();
+    assert_eq!(
+        all,
+        vec![
+            (2, "n2".to_string()),
+            (300, "n300".to_string()),
+            (70000, "n70000".to_string())
+        ]
+    );
+    assert!(db.delete(&300).unwrap());
+    db.db_mut().put(b"short", b"\xff").unwrap();
+    assert_eq!(db.iter().filter(|r| r.is_err()).count(), 1);
+    let raw: TypedDb<Raw, Raw> = TypedDb::from_db(db.into_db());
+    assert_eq!(raw.iter().count(), 3);
+
+    let mailboxes: TypedDb<Str, Mbentry> = TypedDb::open("mailboxes.db").unwrap();
+    let sent = mailboxes
+        .get(&"user.pinguser254.Sent".to_string())
+        .unwrap()
+        .unwrap();
+    assert_eq!(sent.partition.as_deref(), Some("default"));
+    assert!(mailboxes
+        .iter_prefix(b"user.pinguser254.")
+        .all(|r| r.is_ok()));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn typed_serde() {
+    use typed::{Serde, Str, TypedDb};
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+    enum State {
+        Active,
+        Moved { to: String },
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+    struct Quota {
+        used: u64,
+        limits: Vec<(String, i64)>,
+        note: Option<String>,
+        state: State,
+        over: bool,
+    }
+
+    let dir = TempDir::new("twoskip").unwrap();
+    let mut db: TypedDb<Str, Serde<Quota>> = TypedDb::create(dir.path().join("test.db")).unwrap();
+    let quota = Quota {
+        used: 1024,
+        limits: vec![("STORAGE".to_string(), 4096), ("MESSAGE".to_string(), -1)],
+        note: None,
+        state: State::Moved {
+            to: "two words".to_string(),
+        },
+        over: false,
+    };
+    db.put(&"user.fred".to_string(), &quota).unwrap();
+    assert_eq!(
+        db.db().get_value(b"user.fred").unwrap().unwrap(),
+        &b"%(used 1024 limits ((STORAGE 4096) (MESSAGE -1)) note NIL state %(Moved %(to \"two words\")) over 0)"[..]
+    );
+    assert_eq!(db.get(&"user.fred".to_string()).unwrap().unwrap(), quota);
+
+    assert!(dlist::to_bytes(&1.5f64).is_err());
+    assert!(dlist::to_bytes(&std::collections::BTreeMap::from([((1, 2), 3)])).is_err());
+}
+
+#[test]
+fn bloom() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..1000 {
+        db.put(format!("user.{}", i).as_bytes(), b"value").unwrap();
+    }
+    assert!(!db.has_bloom());
+    db.build_bloom().unwrap();
+    assert!(db.has_bloom());
+    assert!(dir.path().join("test.db.bloom").exists());
+    for i in 0..2000 {
+        let key = format!("user.{}", i);
+        assert_eq!(
+            db.contains_key(key.as_bytes()).unwrap(),
+            i < 1000,
+            "{}",
+            key
+        );
+    }
+
+    // written after it was built
+    db.put(b"user.late", b"value").unwrap();
+    db.delete(b"user.7").unwrap();
+    assert!(db.has_bloom());
+    assert_eq!(db.get_value(b"user.late").unwrap(), Some(&b"value"[..]));
+    assert!(db.get(b"user.7").unwrap().is_none());
+
+    drop(db);
+    let mut db = twoskip::open(&path).unwrap();
+    assert!(db.has_bloom());
+    assert!(db.contains_key(b"user.late").unwrap());
+    assert!(!db.contains_key(b"user.1000").unwrap());
+
+    db.repack().unwrap();
+    assert!(!db.has_bloom());
+    assert!(db.contains_key(b"user.late").unwrap());
+    db.build_bloom().unwrap();
+    assert!(db.has_bloom());
+
+    let mut big = vec![0u8; 70 * 1024];
+    big[0] = 1;
+    db.put(b"user.big", &big).unwrap();
+    assert!(!db.has_bloom());
+    assert!(db.contains_key(b"user.big").unwrap());
+    drop(db);
+
+    std::fs::write(dir.path().join("test.db.bloom"), b"TWOBLOOM junk").unwrap();
+    let mut db = twoskip::open(&path).unwrap();
+    assert!(!db.has_bloom());
+    assert!(db.contains_key(b"user.999").unwrap());
+
+    // keys that compare equal without being the same bytes would get past
+    // the filter, so it's left alone for comparators like that
+    #[derive(Debug)]
+    struct CaseInsensitive;
+
+    impl twoskip::Comparator for CaseInsensitive {
+        fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+            a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase())
+        }
+    }
+
+    db.build_bloom().unwrap();
+    drop(db);
+    let db = twoskip::OpenOptions::new()
+        .comparator(CaseInsensitive)
+        .open(&path)
+        .unwrap();
+    assert!(!db.has_bloom());
+    assert!(db.contains_key(b"USER.999").unwrap());
+    drop(db);
+
+    // nor is one that claims more hashes than anything would use
+    let bloom_path = dir.path().join("test.db.bloom");
+    let mut sidecar = std::fs::read(&bloom_path).unwrap();
+    assert!(twoskip::open(&path).unwrap().has_bloom());
+    sidecar[24..28].copy_from_slice(&1000u32.to_be_bytes());
+    std::fs::write(&bloom_path, &sidecar).unwrap();
+    assert!(!twoskip::open(&path).unwrap().has_bloom());
+}
+
+#[test]
+fn preload_index() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+    let mut db = twoskip::create(&path).unwrap();
+    for i in 0..500 {
+        db.put(format!("user.{}", i).as_bytes(), b"value").unwrap();
+    }
+    db.delete(b"user.7").unwrap();
+    drop(db);
+
+    let mut db = twoskip::OpenOptions::new()
+        .preload_index(true)
+        .open(&path)
+        .unwrap();
+    for i in 0..1000 {
+        let key = format!("user.{}", i);
+        assert_eq!(
+            db.contains_key(key.as_bytes()).unwrap(),
+            i < 500 && i != 7,
+            "{}",
+            key
+        );
+    }
+    let r = db.get(b"user.42").unwrap().unwrap();
+    assert_eq!(r.key(), b"user.42");
+    assert_eq!(r.value(), b"value");
+
+    db.put(b"user.42", b"changed").unwrap();
+    db.put(b"user.new", b"value").unwrap();
+    assert_eq!(db.get_value(b"user.42").unwrap(), Some(&b"changed"[..]));
+    assert!(db.contains_key(b"user.new").unwrap());
+    db.delete(b"user.new").unwrap();
+    assert!(!db.contains_key(b"user.new").unwrap());
+
+    {
+        let mut txn = db.begin().unwrap();
+        txn.put(b"user.txn", b"value").unwrap();
+        assert!(txn.get(b"user.txn").unwrap().is_some());
+        txn.abort().unwrap();
+    }
+    assert!(!db.contains_key(b"user.txn").unwrap());
+
+    let mut other = twoskip::open(&path).unwrap();
+    other.put(b"user.other", b"value").unwrap();
+    db.reopen().unwrap();
+    assert!(db.contains_key(b"user.other").unwrap());
+    drop(other);
+
+    db.repack().unwrap();
+    assert!(db.contains_key(b"user.other").unwrap());
+    assert!(!db.contains_key(b"user.7").unwrap());
+    assert_eq!(db.get_value(b"user.42").unwrap(), Some(&b"changed"[..]));
+    drop(db);
+
+    // a key that only matches through the comparator isn't in the index
+    #[derive(Debug)]
+    struct CaseInsensitive;
+
+    impl twoskip::Comparator for CaseInsensitive {
+        fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+            a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase())
+        }
+    }
+
+    let db = twoskip::OpenOptions::new()
+        .comparator(CaseInsensitive)
+        .preload_index(true)
+        .open(&path)
+        .unwrap();
+    assert_eq!(db.get_value(b"USER.42").unwrap(), Some(&b"changed"[..]));
+}
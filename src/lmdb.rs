@@ -0,0 +1,194 @@
+// just enough of the lmdb C library to copy records in and out of an
+// environment's main database, linked directly against the system's liblmdb
+
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+use crate::twoskip::Error;
+
+#[allow(non_camel_case_types)]
+enum MDB_env {}
+#[allow(non_camel_case_types)]
+enum MDB_txn {}
+#[allow(non_camel_case_types)]
+enum MDB_cursor {}
+#[allow(non_camel_case_types)]
+type MDB_dbi = c_uint;
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct MDB_val {
+    mv_size: usize,
+    mv_data: *mut c_void,
+}
+
+const MDB_RDONLY: c_uint = 0x20000;
+const MDB_APPEND: c_uint = 0x20000;
+const MDB_NOTFOUND: c_int = -30798;
+
+const MDB_FIRST: c_int = 0;
+const MDB_NEXT: c_int = 8;
+
+#[link(name = "lmdb")]
+extern "C" {
+    fn mdb_env_create(env: *mut *mut MDB_env) -> c_int;
+    fn mdb_env_set_mapsize(env: *mut MDB_env, size: usize) -> c_int;
+    fn mdb_env_open(env: *mut MDB_env, path: *const c_char, flags: c_uint, mode: u32) -> c_int;
+    fn mdb_env_close(env: *mut MDB_env);
+    fn mdb_txn_begin(
+        env: *mut MDB_env,
+        parent: *mut MDB_txn,
+        flags: c_uint,
+        txn: *mut *mut MDB_txn,
+    ) -> c_int;
+    fn mdb_txn_commit(txn: *mut MDB_txn) -> c_int;
+    fn mdb_txn_abort(txn: *mut MDB_txn);
+    fn mdb_dbi_open(
+        txn: *mut MDB_txn,
+        name: *const c_char,
+        flags: c_uint,
+        dbi: *mut MDB_dbi,
+    ) -> c_int;
+    fn mdb_put(
+        txn: *mut MDB_txn,
+        dbi: MDB_dbi,
+        key: *mut MDB_val,
+        data: *mut MDB_val,
+        flags: c_uint,
+    ) -> c_int;
+    fn mdb_cursor_open(txn: *mut MDB_txn, dbi: MDB_dbi, cursor: *mut *mut MDB_cursor) -> c_int;
+    fn mdb_cursor_get(
+        cursor: *mut MDB_cursor,
+        key: *mut MDB_val,
+        data: *mut MDB_val,
+        op: c_int,
+    ) -> c_int;
+    fn mdb_cursor_close(cursor: *mut MDB_cursor);
+    fn mdb_strerror(err: c_int) -> *const c_char;
+}
+
+pub(crate) struct Env {
+    env: *mut MDB_env,
+}
+
+// always on the main, unnamed database
+pub(crate) struct Txn<'e> {
+    _env: &'e Env,
+    txn: *mut MDB_txn,
+    dbi: MDB_dbi,
+}
+
+impl Env {
+    // path is the environment's directory. map_size is as big as the data
+    // can ever get, which only costs address space
+    pub(crate) fn open(path: &Path, read_only: bool, map_size: usize) -> Result<Env, Error> {
+        let name = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| Error::InternalError(Box::new(e)))?;
+        let mut env = ptr::null_mut();
+        check(unsafe { mdb_env_create(&mut env) })?;
+        let env = Env { env };
+        if !read_only {
+            check(unsafe { mdb_env_set_mapsize(env.env, map_size) })?;
+        }
+        let flags = if read_only { MDB_RDONLY } else { 0 };
+        check(unsafe { mdb_env_open(env.env, name.as_ptr(), flags, 0o644) })?;
+        Ok(env)
+    }
+
+    pub(crate) fn begin(&self, read_only: bool) -> Result<Txn<'_>, Error> {
+        let flags = if read_only { MDB_RDONLY } else { 0 };
+        let mut txn = ptr::null_mut();
+        check(unsafe { mdb_txn_begin(self.env, ptr::null_mut(), flags, &mut txn) })?;
+        let mut txn = Txn {
+            _env: self,
+            txn,
+            dbi: 0,
+        };
+        check(unsafe { mdb_dbi_open(txn.txn, ptr::null(), 0, &mut txn.dbi) })?;
+        Ok(txn)
+    }
+}
+
+impl Drop for Env {
+    fn drop(&mut self) {
+        unsafe { mdb_env_close(self.env) };
+    }
+}
+
+impl<'e> Txn<'e> {
+    // keys have to come in order, after everything already there
+    pub(crate) fn append(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let mut key = val(key);
+        let mut value = val(value);
+        check(unsafe { mdb_put(self.txn, self.dbi, &mut key, &mut value, MDB_APPEND) })
+    }
+
+    // every record in key order, until f says stop by failing
+    pub(crate) fn each<F>(&self, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<(), Error>,
+    {
+        let mut cursor = ptr::null_mut();
+        check(unsafe { mdb_cursor_open(self.txn, self.dbi, &mut cursor) })?;
+        let mut key = val(&[]);
+        let mut value = val(&[]);
+        let mut op = MDB_FIRST;
+        let r = loop {
+            match unsafe { mdb_cursor_get(cursor, &mut key, &mut value, op) } {
+                MDB_NOTFOUND => break Ok(()),
+                0 => {}
+                rc => break check(rc),
+            }
+            if let Err(e) = f(bytes(&key), bytes(&value)) {
+                break Err(e);
+            }
+            op = MDB_NEXT;
+        };
+        unsafe { mdb_cursor_close(cursor) };
+        r
+    }
+
+    pub(crate) fn commit(mut self) -> Result<(), Error> {
+        let txn = mem::replace(&mut self.txn, ptr::null_mut());
+        check(unsafe { mdb_txn_commit(txn) })
+    }
+}
+
+impl<'e> Drop for Txn<'e> {
+    fn drop(&mut self) {
+        if !self.txn.is_null() {
+            unsafe { mdb_txn_abort(self.txn) };
+        }
+    }
+}
+
+// lmdb only reads through what it's given to put
+fn val(data: &[u8]) -> MDB_val {
+    MDB_val {
+        mv_size: data.len(),
+        mv_data: data.as_ptr() as *mut c_void,
+    }
+}
+
+// good for as long as the transaction is
+fn bytes(v: &MDB_val) -> &[u8] {
+    match v.mv_size {
+        0 => &[],
+        n => unsafe { slice::from_raw_parts(v.mv_data as *const u8, n) },
+    }
+}
+
+fn check(rc: c_int) -> Result<(), Error> {
+    match rc {
+        0 => Ok(()),
+        _ => {
+            let msg = unsafe { CStr::from_ptr(mdb_strerror(rc)) }.to_string_lossy();
+            Err(Error::InternalError(format!("lmdb: {}", msg).into()))
+        }
+    }
+}
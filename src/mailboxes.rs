@@ -0,0 +1,270 @@
+// mailboxes.db by mailbox rather than by record. names are cyrus' internal
+// ones, with . between levels, a ^ where the user typed a . and, on a
+// server with virtual domains, the domain and a ! in front:
+//
+//   user.fred.Sent
+//   example.com!user.fred^bloggs.Drafts
+//   DELETED.user.fred.Trash.5671CEC6
+//
+// the last is what's left of a mailbox after delayed delete, until
+// cyr_expire cleans it up. keys starting with $, like $RACL, aren't
+// mailboxes and get skipped
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::mbentry::Mbentry;
+use crate::mboxname;
+use crate::twoskip::{self, Db, Error};
+
+pub struct Mailboxes {
+    db: Db,
+}
+
+impl Mailboxes {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Mailboxes, Error> {
+        Ok(Mailboxes::from_db(twoskip::open(path)?))
+    }
+
+    pub fn from_db(db: Db) -> Mailboxes {
+        Mailboxes { db }
+    }
+
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    pub fn into_db(self) -> Db {
+        self.db
+    }
+
+    pub fn lookup(&self, mboxname: &str) -> Result<Option<Mbentry>, Error> {
+        match self.db.get_value(mboxname.as_bytes())? {
+            Some(value) => Ok(Some(Mbentry::parse(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    // the same, with the name in utf-8, user.fred.Entwürfe say
+    pub fn lookup_utf8(&self, mboxname: &str) -> Result<Option<Mbentry>, Error> {
+        self.lookup(&mboxname::encode_utf7(mboxname))
+    }
+
+    // a user's inbox and everything under it. userid is as they log in,
+    // fred.bloggs@example.com say
+    pub fn list_user(&self, userid: &str) -> Result<Vec<(String, Mbentry)>, Error> {
+        let inbox = user_inbox(userid);
+        let mut mailboxes = vec![];
+        if let Some(entry) = self.lookup(&inbox)? {
+            mailboxes.push((inbox.clone(), entry));
+        }
+        mailboxes.extend(self.under(&format!("{}.", inbox), false)?);
+        Ok(mailboxes)
+    }
+
+    // just the next level down, not their children too
+    pub fn children(&self, mboxname: &str) -> Result<Vec<(String, Mbentry)>, Error> {
+        self.under(&format!("{}.", mboxname), true)
+    }
+
+    // every DELETED mailbox, in every domain
+    pub fn deleted_entries(&self) -> Result<Vec<(String, Mbentry)>, Error> {
+        let mut deleted = vec![];
+        for r in self.db.iter_live() {
+            let r = r?;
+            let name = String::from_utf8_lossy(r.key());
+            if is_mailbox(&name) && is_deleted(&name) {
+                deleted.push((name.into_owned(), Mbentry::parse(r.value())?));
+            }
+        }
+        Ok(deleted)
+    }
+
+    pub fn tree(&self, prefix: &str) -> Result<Vec<TreeEntry>, Error> {
+        self.db.tree(prefix)
+    }
+
+    fn under(&self, prefix: &str, direct: bool) -> Result<Vec<(String, Mbentry)>, Error> {
+        let mut mailboxes = vec![];
+        for r in self.db.iter_prefix(prefix.as_bytes()) {
+            let r = r?;
+            let name = String::from_utf8_lossy(r.key());
+            if direct && name[prefix.len()..].contains('.') {
+                continue;
+            }
+            mailboxes.push((name.into_owned(), Mbentry::parse(r.value())?));
+        }
+        Ok(mailboxes)
+    }
+}
+
+// fred.bloggs@example.com is example.com!user.fred^bloggs
+pub fn user_inbox(userid: &str) -> String {
+    let (user, domain) = match userid.rsplit_once('@') {
+        Some((user, domain)) => (user, Some(domain)),
+        None => (userid, None),
+    };
+    let user = user.replace('.', "^");
+    match domain {
+        Some(domain) => format!("{}!user.{}", domain, user),
+        None => format!("user.{}", user),
+    }
+}
+
+pub fn is_mailbox(mboxname: &str) -> bool {
+    !mboxname.is_empty() && !mboxname.starts_with('$')
+}
+
+pub fn is_deleted(mboxname: &str) -> bool {
+    split_domain(mboxname).1.starts_with("DELETED.")
+}
+
+// one line of a folder tree, from Db::tree
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TreeEntry {
+    // the record's key, or for a level with nothing there, just its name
+    pub name: String,
+    // 0 for the prefix itself, or the top level under no prefix. domains
+    // are a level, example.com! with example.com!user under it
+    pub depth: usize,
+    // false for a level that's only in the tree for what's under it
+    pub exists: bool,
+    // for a DELETED mailbox, when it was deleted. it goes in the tree where
+    // it was before, after the live mailbox of that name if there is one
+    pub deleted: Option<u32>,
+}
+
+#[derive(Default)]
+struct Node {
+    records: Vec<(String, Option<u32>)>,
+    children: BTreeMap<String, Node>,
+}
+
+// depth first, each level's children in name order. the whole tree is
+// made before any of it comes out, since the order it's in the file isn't
+// that: user.fred-x sorts between user.fred and user.fred.Sent
+pub(crate) fn tree(db: &Db, prefix: &str) -> Result<Vec<TreeEntry>, Error> {
+    let mut root = Node::default();
+    let mut add = |key: &[u8]| {
+        let key = String::from_utf8_lossy(key);
+        if !is_mailbox(&key) {
+            return;
+        }
+        let (name, deleted) = undelete(&key);
+        let under = match prefix {
+            "" => true,
+            _ if prefix.ends_with('!') => name.starts_with(prefix),
+            _ => name == prefix || name.starts_with(&format!("{}.", prefix)),
+        };
+        if !under {
+            return;
+        }
+        let mut node = &mut root;
+        for part in levels(&name) {
+            node = node.children.entry(part.to_string()).or_default();
+        }
+        node.records.push((key.into_owned(), deleted));
+    };
+    match prefix {
+        "" => {
+            for r in db.iter_live() {
+                add(r?.key());
+            }
+        }
+        _ => {
+            for r in db.iter_prefix(prefix.as_bytes()) {
+                add(r?.key());
+            }
+            for r in db.iter_prefix(deleted_name(prefix).as_bytes()) {
+                add(r?.key());
+            }
+        }
+    }
+
+    let mut entries = vec![];
+    match prefix {
+        "" => {
+            for (part, child) in &root.children {
+                walk(part.clone(), child, 0, &mut entries);
+            }
+        }
+        _ => {
+            let mut node = Some(&root);
+            for part in levels(prefix) {
+                node = node.and_then(|n| n.children.get(part));
+            }
+            if let Some(node) = node {
+                walk(prefix.to_string(), node, 0, &mut entries);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn walk(name: String, node: &Node, depth: usize, entries: &mut Vec<TreeEntry>) {
+    let mut records = node.records.clone();
+    records.sort_by_key(|&(_, deleted)| deleted);
+    if records.is_empty() {
+        entries.push(TreeEntry {
+            name: name.clone(),
+            depth,
+            exists: false,
+            deleted: None,
+        });
+    }
+    for (key, deleted) in records {
+        entries.push(TreeEntry {
+            name: key,
+            depth,
+            exists: true,
+            deleted,
+        });
+    }
+    for (part, child) in &node.children {
+        let name = match name.ends_with('!') {
+            true => format!("{}{}", name, part),
+            false => format!("{}.{}", name, part),
+        };
+        walk(name, child, depth + 1, entries);
+    }
+}
+
+// a domain is a level of its own, above its user and shared mailboxes
+fn levels(mboxname: &str) -> impl Iterator<Item = &str> {
+    let (domain, local) = split_domain(mboxname);
+    (!domain.is_empty()).then_some(domain).into_iter().chain(
+        (!local.is_empty())
+            .then(|| local.split('.'))
+            .into_iter()
+            .flatten(),
+    )
+}
+
+// DELETED.user.fred.Trash.5671CEC6 was user.fred.Trash until 0x5671CEC6
+fn undelete(mboxname: &str) -> (String, Option<u32>) {
+    let (domain, local) = split_domain(mboxname);
+    let stamped = local
+        .strip_prefix("DELETED.")
+        .and_then(|rest| rest.rsplit_once('.'))
+        .filter(|(_, stamp)| stamp.len() == 8)
+        .and_then(|(name, stamp)| Some((name, u32::from_str_radix(stamp, 16).ok()?)));
+    match stamped {
+        Some((name, when)) => (domain.to_string() + name, Some(when)),
+        None => (mboxname.to_string(), None),
+    }
+}
+
+// where mailboxes deleted from under mboxname go
+fn deleted_name(mboxname: &str) -> String {
+    let (domain, local) = split_domain(mboxname);
+    format!("{}DELETED.{}", domain, local)
+}
+
+// the domain with its !, if there is one, and the rest
+fn split_domain(mboxname: &str) -> (&str, &str) {
+    match mboxname.find('!') {
+        Some(at) => mboxname.split_at(at + 1),
+        None => ("", mboxname),
+    }
+}
@@ -0,0 +1,148 @@
+// what mailboxes.db says about a mailbox, which cyrus calls an mbentry. the
+// key is the mailbox's name and the value a dlist map of one letter fields:
+//
+//   A  the acl, identifier to rights    I  uniqueid
+//   P  partition                         S  server, in a murder
+//   T  type letters, none for mail       V  uidvalidity
+//   C  createdmodseq                     F  foldermodseq
+//   M  when it last changed
+//
+// cyrus leaves out whatever's empty or 0, and so does to_value, writing the
+// fields in the same order cyrus does. anything else in there is kept in
+// other and goes back on the end, so a value cyrus wrote comes out of
+// parse and to_value as it went in
+
+use std::borrow::Cow;
+use std::str::FromStr;
+
+use crate::dlist::{self, Dlist, Pair};
+use crate::twoskip::Error;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Mbentry {
+    // identifier and rights letters, in the order they were written
+    pub acl: Vec<(String, String)>,
+    pub uniqueid: Option<String>,
+    pub partition: Option<String>,
+    pub server: Option<String>,
+    // c for calendars, a for addressbooks, d for deleted and so on
+    pub mbtype: String,
+    pub uidvalidity: u32,
+    pub createdmodseq: u64,
+    pub foldermodseq: u64,
+    pub mtime: i64,
+    pub other: Vec<Pair<'static>>,
+}
+
+impl Mbentry {
+    pub fn parse(value: &[u8]) -> Result<Mbentry, Error> {
+        let pairs = match dlist::parse(value)? {
+            Dlist::Map(pairs) => pairs,
+            _ => return Err(Error::InvalidDlist { offset: 0 }),
+        };
+        let mut entry = Mbentry::default();
+        for (key, value) in pairs {
+            match &key[..] {
+                b"A" => entry.acl = acl(&value)?,
+                b"I" => entry.uniqueid = Some(text(&value, "I")?),
+                b"P" => entry.partition = Some(text(&value, "P")?),
+                b"S" => entry.server = Some(text(&value, "S")?),
+                b"T" => entry.mbtype = text(&value, "T")?,
+                b"V" => entry.uidvalidity = number(&value, "V")?,
+                b"C" => entry.createdmodseq = number(&value, "C")?,
+                b"F" => entry.foldermodseq = number(&value, "F")?,
+                b"M" => entry.mtime = number(&value, "M")?,
+                _ => entry
+                    .other
+                    .push((Cow::Owned(key.into_owned()), value.into_owned())),
+            }
+        }
+        Ok(entry)
+    }
+
+    pub fn to_value(&self) -> Vec<u8> {
+        let atom = |s: &str| Dlist::Atom(Cow::Owned(s.as_bytes().to_vec()));
+        let mut pairs: Vec<Pair> = vec![];
+        let mut add = |key: &'static [u8], value| pairs.push((Cow::Borrowed(key), value));
+        if !self.acl.is_empty() {
+            let acl = self
+                .acl
+                .iter()
+                .map(|(id, rights)| (Cow::Borrowed(id.as_bytes()), atom(rights)))
+                .collect();
+            add(b"A", Dlist::Map(acl));
+        }
+        for (key, value) in [
+            (b"I", &self.uniqueid),
+            (b"P", &self.partition),
+            (b"S", &self.server),
+        ] {
+            if let Some(value) = value {
+                add(key, atom(value));
+            }
+        }
+        if !self.mbtype.is_empty() {
+            add(b"T", atom(&self.mbtype));
+        }
+        for (key, value) in [
+            (b"V", self.uidvalidity as u64),
+            (b"C", self.createdmodseq),
+            (b"F", self.foldermodseq),
+        ] {
+            if value != 0 {
+                add(key, atom(&value.to_string()));
+            }
+        }
+        if self.mtime != 0 {
+            add(b"M", atom(&self.mtime.to_string()));
+        }
+        pairs.extend(self.other.iter().cloned());
+        Dlist::Map(pairs).to_vec()
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.mbtype.contains('d')
+    }
+}
+
+// a map these days, but older cyrus wrote one string of tab separated
+// identifiers and rights
+fn acl(value: &Dlist) -> Result<Vec<(String, String)>, Error> {
+    let pairs: Vec<(&[u8], &Dlist)> = match value {
+        Dlist::Map(pairs) => pairs.iter().map(|(k, v)| (&k[..], v)).collect(),
+        Dlist::Atom(_) => return legacy_acl(&text(value, "A")?),
+        _ => return Err(Error::InvalidField { field: "A" }),
+    };
+    pairs
+        .into_iter()
+        .map(|(id, rights)| match std::str::from_utf8(id) {
+            Ok(id) => Ok((id.to_string(), text(rights, "A")?)),
+            Err(_) => Err(Error::InvalidField { field: "A" }),
+        })
+        .collect()
+}
+
+fn legacy_acl(acl: &str) -> Result<Vec<(String, String)>, Error> {
+    let fields: Vec<&str> = acl.split('\t').filter(|f| !f.is_empty()).collect();
+    if !fields.len().is_multiple_of(2) {
+        return Err(Error::InvalidField { field: "A" });
+    }
+    Ok(fields
+        .chunks(2)
+        .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+        .collect())
+}
+
+fn text(value: &Dlist, field: &'static str) -> Result<String, Error> {
+    value
+        .as_str()
+        .map(str::to_string)
+        .ok_or(Error::InvalidField { field })
+}
+
+fn number<T: FromStr>(value: &Dlist, field: &'static str) -> Result<T, Error> {
+    value
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .ok_or(Error::InvalidField { field })
+}
@@ -0,0 +1,132 @@
+// mailbox names as cyrus keeps them, which isn't as anyone types them.
+// anything that isn't printable ascii is imap's modified utf-7 (rfc 3501
+// 5.1.3): utf-16 in base64 with , for /, between & and -, and & itself as
+// &-. so Entwürfe is Entw&APw-rfe
+//
+// the levels are split with . whatever the client sees, and with
+// unixhierarchysep on, where clients see /, a . in a name is kept as ^.
+// to_internal and to_external go between the two for one level and below,
+// leaving the user. or shared prefix to whoever knows the namespace
+
+use crate::export::encode_base64;
+use crate::twoskip::Error;
+
+pub fn encode_utf7(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut run: Vec<u16> = vec![];
+    for c in name.chars() {
+        if (' '..='~').contains(&c) {
+            flush(&mut run, &mut out);
+            match c {
+                '&' => out.push_str("&-"),
+                _ => out.push(c),
+            }
+        } else {
+            let mut units = [0; 2];
+            run.extend_from_slice(c.encode_utf16(&mut units));
+        }
+    }
+    flush(&mut run, &mut out);
+    out
+}
+
+// a run of anything else, as &base64-
+fn flush(run: &mut Vec<u16>, out: &mut String) {
+    if run.is_empty() {
+        return;
+    }
+    let bytes: Vec<u8> = run.iter().flat_map(|u| u.to_be_bytes()).collect();
+    out.push('&');
+    out.extend(
+        encode_base64(&bytes)
+            .chars()
+            .filter(|&c| c != '=')
+            .map(|c| if c == '/' { ',' } else { c }),
+    );
+    out.push('-');
+    run.clear();
+}
+
+// anything that isn't modified utf-7 is an InvalidField
+pub fn decode_utf7(name: &str) -> Result<String, Error> {
+    let invalid = || Error::InvalidField { field: "mboxname" };
+    let mut out = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(at) = rest.find(|c: char| c == '&' || !(' '..='~').contains(&c)) {
+        if !rest[at..].starts_with('&') {
+            return Err(invalid());
+        }
+        out.push_str(&rest[..at]);
+        let end = rest[at..].find('-').ok_or_else(invalid)? + at;
+        match &rest[at + 1..end] {
+            "" => out.push('&'),
+            encoded => {
+                let bytes = decode_base64(encoded).ok_or_else(invalid)?;
+                if !bytes.len().is_multiple_of(2) {
+                    return Err(invalid());
+                }
+                let units: Vec<u16> = bytes
+                    .chunks(2)
+                    .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                    .collect();
+                out.push_str(&String::from_utf16(&units).map_err(|_| invalid())?);
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// with , for /, and no padding. bits left over at the end have to be 0
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut bits, mut n) = (0u32, 0);
+    for c in s.bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b',' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | v as u32;
+        n += 6;
+        if n >= 8 {
+            n -= 8;
+            out.push((bits >> n) as u8);
+            bits &= (1 << n) - 1;
+        }
+    }
+    match bits {
+        0 => Some(out),
+        _ => None,
+    }
+}
+
+// Archive/2015.old with / as the separator is Archive.2015^old
+pub fn to_internal(name: &str, sep: char) -> String {
+    let levels: Vec<String> = name
+        .split(sep)
+        .map(|level| match sep {
+            '.' => encode_utf7(level),
+            _ => encode_utf7(&level.replace('.', "^")),
+        })
+        .collect();
+    levels.join(".")
+}
+
+pub fn to_external(name: &str, sep: char) -> Result<String, Error> {
+    let levels = name
+        .split('.')
+        .map(|level| {
+            let level = decode_utf7(level)?;
+            Ok(match sep {
+                '.' => level,
+                _ => level.replace('^', "."),
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    Ok(levels.join(&sep.to_string()))
+}
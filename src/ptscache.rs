@@ -0,0 +1,91 @@
+// ptscache.db, where ptloader keeps what it's found out about users from
+// ldap or afs, so authorisation doesn't have to ask every time. a key is
+// the identifier as it was asked about, and a value cyrus' struct
+// auth_state as it is in memory:
+//
+//   userid, mark, ngroups, groups
+//
+// userid and each of the groups being an auth_ident, a 4 byte hash and
+// then the name in a buffer KEY_SIZE long, nul terminated. mark is when it
+// was loaded, a time_t, and ngroups an int. that's as laid out on x86_64,
+// with 4 bytes of padding after userid to line mark up on 8 and everything
+// little endian. ptexpire takes out entries older than its -E, and expire
+// here does the same given the time that works out to
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::twoskip::{Db, Error};
+
+// PTS_DB_KEYSIZE. a cyrus built with some other size needs parse_with
+pub const KEY_SIZE: usize = 512;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PtsEntry {
+    // the canonical one, which can differ from the key
+    pub userid: String,
+    // unix time
+    pub mark: i64,
+    pub groups: Vec<String>,
+}
+
+impl PtsEntry {
+    pub fn parse(value: &[u8]) -> Result<PtsEntry, Error> {
+        PtsEntry::parse_with(value, KEY_SIZE)
+    }
+
+    pub fn parse_with(value: &[u8], key_size: usize) -> Result<PtsEntry, Error> {
+        let ident = 4 + key_size;
+        let mark_at = (ident + 7) & !7;
+        let groups_at = mark_at + 12;
+        if value.len() < groups_at {
+            return Err(Error::InvalidField { field: "ptscache" });
+        }
+        let ngroups = LittleEndian::read_i32(&value[mark_at + 8..]);
+        let ngroups =
+            usize::try_from(ngroups).map_err(|_| Error::InvalidField { field: "ngroups" })?;
+        let groups = (0..ngroups)
+            .map(|n| {
+                let at = groups_at + n * ident;
+                match value.get(at..at + ident) {
+                    Some(group) => name(group, "groups"),
+                    None => Err(Error::InvalidField { field: "groups" }),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(PtsEntry {
+            userid: name(&value[..ident], "userid")?,
+            mark: LittleEndian::read_i64(&value[mark_at..]),
+            groups,
+        })
+    }
+}
+
+// an auth_ident's name, skipping its hash
+fn name(ident: &[u8], field: &'static str) -> Result<String, Error> {
+    let id = &ident[4..];
+    let end = id
+        .iter()
+        .position(|&c| c == 0)
+        .ok_or(Error::InvalidField { field })?;
+    String::from_utf8(id[..end].to_vec()).map_err(|_| Error::InvalidField { field })
+}
+
+// the entries loaded before time, a unix time, and their keys
+pub fn expired_before(
+    db: &Db,
+    time: i64,
+) -> impl Iterator<Item = Result<(Vec<u8>, PtsEntry), Error>> + '_ {
+    db.iter_live().filter_map(move |r| {
+        let parsed = r.and_then(|r| Ok((r.key().to_vec(), PtsEntry::parse(r.value())?)));
+        match parsed {
+            Ok((_, ref entry)) if entry.mark >= time => None,
+            parsed => Some(parsed),
+        }
+    })
+}
+
+// delete every entry loaded before time, in one transaction, and say how
+// many went
+pub fn expire(db: &mut Db, time: i64) -> Result<usize, Error> {
+    db.delete_where(|_, value| Ok(PtsEntry::parse(value)?.mark < time))
+}
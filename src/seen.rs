@@ -0,0 +1,190 @@
+// a user's seen state, user/f/fred.seen, for the mailboxes where cyrus
+// doesn't keep it in the index itself (shared mailboxes, and other people's
+// mailboxes that user can see). the key is the mailbox's uniqueid and the
+// value one line of
+//
+//   version lastread lastuid lastchange uids
+//   1 1450299080 12 1450299080 1:5,7,9:12
+//
+// where the times are unix times, lastuid the highest uid when the user
+// last looked, and uids the ones they've seen, as an imap sequence set
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::twoskip::Error;
+
+const VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Seen {
+    pub lastread: i64,
+    pub lastuid: u32,
+    pub lastchange: i64,
+    pub uids: UidSet,
+}
+
+// in order, with none touching or overlapping
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UidSet {
+    ranges: Vec<(u32, u32)>,
+}
+
+impl Seen {
+    pub fn parse(value: &[u8]) -> Result<Seen, Error> {
+        let value =
+            std::str::from_utf8(value).map_err(|_| Error::InvalidField { field: "seen" })?;
+        let mut fields = value.splitn(5, ' ');
+        let mut next = |field| fields.next().ok_or(Error::InvalidField { field });
+        if next("version")?.parse::<u32>().ok() != Some(VERSION) {
+            return Err(Error::VersionMismatch);
+        }
+        Ok(Seen {
+            lastread: number(next("lastread")?, "lastread")?,
+            lastuid: number(next("lastuid")?, "lastuid")?,
+            lastchange: number(next("lastchange")?, "lastchange")?,
+            uids: UidSet::parse(next("uids").unwrap_or("").trim())?,
+        })
+    }
+
+    pub fn to_value(&self) -> Vec<u8> {
+        format!(
+            "{} {} {} {} {}",
+            VERSION, self.lastread, self.lastuid, self.lastchange, self.uids
+        )
+        .into_bytes()
+    }
+}
+
+fn number<T: FromStr>(s: &str, field: &'static str) -> Result<T, Error> {
+    s.parse().map_err(|_| Error::InvalidField { field })
+}
+
+impl UidSet {
+    pub fn new() -> UidSet {
+        UidSet::default()
+    }
+
+    // 1:5,7,9:12. * is as high as a uid goes, and ranges can be either way
+    // round or out of order, as imap allows
+    pub fn parse(s: &str) -> Result<UidSet, Error> {
+        let mut set = UidSet::new();
+        if s.is_empty() {
+            return Ok(set);
+        }
+        let uid = |s: &str| match s {
+            "*" => Ok(u32::MAX),
+            _ => number(s, "uids"),
+        };
+        for range in s.split(',') {
+            let (first, last) = match range.split_once(':') {
+                Some((first, last)) => (uid(first)?, uid(last)?),
+                None => (uid(range)?, uid(range)?),
+            };
+            set.insert_range(first.min(last), first.max(last));
+        }
+        Ok(set)
+    }
+
+    pub fn ranges(&self) -> &[(u32, u32)] {
+        &self.ranges
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    // how many uids, not ranges
+    pub fn len(&self) -> u64 {
+        self.ranges
+            .iter()
+            .map(|&(first, last)| (last - first) as u64 + 1)
+            .sum()
+    }
+
+    pub fn contains(&self, uid: u32) -> bool {
+        let at = self.ranges.partition_point(|&(_, last)| last < uid);
+        self.ranges.get(at).is_some_and(|&(first, _)| first <= uid)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.ranges.iter().flat_map(|&(first, last)| first..=last)
+    }
+
+    pub fn insert(&mut self, uid: u32) {
+        self.insert_range(uid, uid);
+    }
+
+    pub fn insert_range(&mut self, first: u32, last: u32) {
+        // everything this overlaps or touches gets folded into it
+        let start = self
+            .ranges
+            .partition_point(|&(_, l)| l.saturating_add(1) < first);
+        let end = self
+            .ranges
+            .partition_point(|&(f, _)| f <= last.saturating_add(1));
+        let (mut first, mut last) = (first, last);
+        if start < end {
+            first = first.min(self.ranges[start].0);
+            last = last.max(self.ranges[end - 1].1);
+        }
+        self.ranges.splice(start..end, [(first, last)]);
+    }
+
+    pub fn remove(&mut self, uid: u32) {
+        self.remove_range(uid, uid);
+    }
+
+    pub fn remove_range(&mut self, first: u32, last: u32) {
+        let mut ranges = Vec::with_capacity(self.ranges.len() + 1);
+        for &(f, l) in &self.ranges {
+            if l < first || f > last {
+                ranges.push((f, l));
+                continue;
+            }
+            if f < first {
+                ranges.push((f, first - 1));
+            }
+            if l > last {
+                ranges.push((last + 1, l));
+            }
+        }
+        self.ranges = ranges;
+    }
+
+    pub fn union(&self, other: &UidSet) -> UidSet {
+        let mut set = self.clone();
+        for &(first, last) in &other.ranges {
+            set.insert_range(first, last);
+        }
+        set
+    }
+
+    pub fn difference(&self, other: &UidSet) -> UidSet {
+        let mut set = self.clone();
+        for &(first, last) in &other.ranges {
+            set.remove_range(first, last);
+        }
+        set
+    }
+
+    pub fn intersection(&self, other: &UidSet) -> UidSet {
+        self.difference(&self.difference(other))
+    }
+}
+
+// the way cyrus writes them, with a lone uid on its own
+impl fmt::Display for UidSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, &(first, last)) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            match first == last {
+                true => write!(f, "{}", first)?,
+                false => write!(f, "{}:{}", first, last)?,
+            }
+        }
+        Ok(())
+    }
+}
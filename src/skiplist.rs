@@ -0,0 +1,319 @@
+// cyrus' older skiplist backend, read only, for getting the records out of
+// files nobody got round to converting.
+//
+// after a 48 byte header comes a DUMMY record and then the records in key
+// order as the last checkpoint wrote them (INORDER). from the header's
+// logstart on there's a log of ADD and DELETE records, each transaction
+// ending in a COMMIT. everything is 32 bit big endian, and keys and values
+// are padded out to 4 bytes:
+//
+//   INORDER/ADD/DUMMY: type, keylen, key, datalen, data, pointers, -1
+//   DELETE:            type, offset of the record it deletes
+//   COMMIT:            type
+//
+// cyrus changes pointers in place before a transaction commits, so rather
+// than trust them this goes through the file the way cyrus' recovery does
+// and keeps an index of what's committed. keys come out in byte order,
+// which is what cyrus uses unless it was given some other comparison
+
+use std::collections::{btree_map, BTreeMap, HashMap};
+use std::fs::File;
+use std::ops::Bound;
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::twoskip::{lock_file, map_file, Builder, Error, Map};
+
+const MAGIC: &[u8] = b"\xa1\x02\x8b\x0dskiplist file\0\0\0";
+
+const OFFSET_VERSION: usize = 20;
+const OFFSET_VERSION_MINOR: usize = 24;
+const OFFSET_MAXLEVEL: usize = 28;
+const OFFSET_CURLEVEL: usize = 32;
+const OFFSET_LISTSIZE: usize = 36;
+const OFFSET_LOGSTART: usize = 40;
+const OFFSET_LASTRECOVERY: usize = 44;
+const HEADER_SIZE: usize = 48;
+
+const VERSION: u32 = 1;
+
+const INORDER: u32 = 1;
+const ADD: u32 = 2;
+const DELETE: u32 = 4;
+const COMMIT: u32 = 255;
+const DUMMY: u32 = 257;
+
+// ends the list of pointers
+const PADDING: u32 = 0xffff_ffff;
+
+pub struct Header {
+    version: u32,
+    version_minor: u32,
+    max_level: u32,
+    cur_level: u32,
+    num_records: u32,
+    log_start: usize,
+    last_recovery: u32,
+}
+
+pub struct Db {
+    map: Map,
+    header: Header,
+    // every committed key, and where its record is
+    index: BTreeMap<Vec<u8>, usize>,
+}
+
+pub struct Record<'a> {
+    offset: usize,
+    key: &'a [u8],
+    value: &'a [u8],
+}
+
+pub struct DbIter<'a> {
+    db: &'a Db,
+    prefix: Vec<u8>,
+    range: btree_map::Range<'a, Vec<u8>, usize>,
+}
+
+enum Entry<'a> {
+    Record(u32, Record<'a>),
+    Delete(usize),
+    Commit,
+}
+
+pub fn open<P: AsRef<Path>>(path: P) -> Result<Db, Error> {
+    let file = File::open(path)?;
+    if file.metadata()?.len() < HEADER_SIZE as u64 {
+        return Err(Error::InvalidFileSize);
+    }
+    let map = map_file(&file)?;
+    let header = read_header(map.as_slice())?;
+
+    // cyrus takes the same kind of lock, so this keeps out a writer that's
+    // halfway through a checkpoint
+    lock_file(&file, libc::F_RDLCK)?;
+    let index = scan(map.as_slice(), header.log_start);
+    lock_file(&file, libc::F_UNLCK)?;
+
+    Ok(Db {
+        map,
+        header,
+        index: index?,
+    })
+}
+
+fn read_header(data: &[u8]) -> Result<Header, Error> {
+    if &data[..MAGIC.len()] != MAGIC {
+        return Err(Error::InvalidHeaderMagic);
+    }
+    let field = |at: usize| BigEndian::read_u32(&data[at..at + 4]);
+    if field(OFFSET_VERSION) != VERSION {
+        return Err(Error::VersionMismatch);
+    }
+    Ok(Header {
+        version: field(OFFSET_VERSION),
+        version_minor: field(OFFSET_VERSION_MINOR),
+        max_level: field(OFFSET_MAXLEVEL),
+        cur_level: field(OFFSET_CURLEVEL),
+        num_records: field(OFFSET_LISTSIZE),
+        log_start: field(OFFSET_LOGSTART) as usize,
+        last_recovery: field(OFFSET_LASTRECOVERY),
+    })
+}
+
+// INORDER records count as soon as they're seen, the log only once its
+// COMMIT is. a crash can leave the last record cut short, and then that
+// transaction never happened
+fn scan(data: &[u8], log_start: usize) -> Result<BTreeMap<Vec<u8>, usize>, Error> {
+    let mut index = BTreeMap::new();
+    let mut keys: HashMap<usize, &[u8]> = HashMap::new();
+    let mut pending: Vec<Entry> = vec![];
+
+    let mut offset = HEADER_SIZE;
+    while offset < data.len() {
+        let (entry, len) = match parse(data, offset) {
+            Ok(parsed) => parsed,
+            Err(Error::InvalidFileSize) if offset >= log_start => break,
+            Err(e) => return Err(e),
+        };
+        match entry {
+            Entry::Record(DUMMY, _) if offset == HEADER_SIZE => {}
+            Entry::Record(INORDER, r) if offset < log_start => {
+                keys.insert(offset, r.key);
+                index.insert(r.key.to_vec(), offset);
+            }
+            Entry::Record(ADD, r) => {
+                keys.insert(offset, r.key);
+                pending.push(Entry::Record(ADD, r));
+            }
+            Entry::Delete(target) => pending.push(Entry::Delete(target)),
+            Entry::Commit => {
+                for entry in pending.drain(..) {
+                    match entry {
+                        Entry::Record(_, r) => {
+                            index.insert(r.key.to_vec(), r.offset);
+                        }
+                        Entry::Delete(target) => match keys.get(&target) {
+                            Some(key) => {
+                                index.remove(*key);
+                            }
+                            None => return Err(Error::InvalidOffset),
+                        },
+                        Entry::Commit => {}
+                    }
+                }
+            }
+            Entry::Record(..) => return Err(Error::InvalidRecordType),
+        }
+        offset += len;
+    }
+
+    Ok(index)
+}
+
+// the entry at offset and how long it is. InvalidFileSize if it doesn't fit
+fn parse(data: &[u8], offset: usize) -> Result<(Entry<'_>, usize), Error> {
+    let bytes = |at: usize, len: usize| data.get(at..at + len).ok_or(Error::InvalidFileSize);
+    let word = |at: usize| bytes(at, 4).map(BigEndian::read_u32);
+
+    let typ = word(offset)?;
+    match typ {
+        COMMIT => return Ok((Entry::Commit, 4)),
+        DELETE => return Ok((Entry::Delete(word(offset + 4)? as usize), 8)),
+        INORDER | ADD | DUMMY => {}
+        _ => return Err(Error::InvalidRecordType),
+    }
+
+    let key_len = word(offset + 4)? as usize;
+    let key = bytes(offset + 8, key_len)?;
+    let at = offset + 8 + roundup(key_len);
+    let value_len = word(at)? as usize;
+    let value = bytes(at + 4, value_len)?;
+
+    let mut at = at + 4 + roundup(value_len);
+    while word(at)? != PADDING {
+        at += 4;
+    }
+    let r = Record { offset, key, value };
+    Ok((Entry::Record(typ, r), at + 4 - offset))
+}
+
+fn roundup(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+impl Header {
+    pub fn version(&self) -> (u32, u32) {
+        (self.version, self.version_minor)
+    }
+
+    pub fn max_level(&self) -> u32 {
+        self.max_level
+    }
+
+    pub fn cur_level(&self) -> u32 {
+        self.cur_level
+    }
+
+    // what the header says, which a crash can leave out of date
+    pub fn num_records(&self) -> u32 {
+        self.num_records
+    }
+
+    pub fn log_start(&self) -> usize {
+        self.log_start
+    }
+
+    pub fn last_recovery(&self) -> u32 {
+        self.last_recovery
+    }
+}
+
+impl Db {
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    // how many records there are, counted rather than taken from the header
+    pub fn num_records(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Record<'_>>, Error> {
+        match self.index.get(key) {
+            Some(&offset) => Ok(Some(self.record_at(offset)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_value(&self, key: &[u8]) -> Result<Option<&[u8]>, Error> {
+        Ok(self.get(key)?.map(|r| r.value))
+    }
+
+    // the record with the smallest key strictly greater than this one
+    pub fn fetchnext(&self, key: &[u8]) -> Result<Option<Record<'_>>, Error> {
+        let after = (Bound::Excluded(key), Bound::Unbounded);
+        match self.index.range::<[u8], _>(after).next() {
+            Some((_, &offset)) => Ok(Some(self.record_at(offset)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn iter_live(&self) -> DbIter<'_> {
+        self.iter_prefix(b"")
+    }
+
+    pub fn iter_prefix(&self, prefix: &[u8]) -> DbIter<'_> {
+        DbIter {
+            db: self,
+            prefix: prefix.to_vec(),
+            range: self.index.range(prefix.to_vec()..),
+        }
+    }
+
+    // a new twoskip file at path with the same records, packed as a repack
+    // would. it's an error if path already exists
+    pub fn to_twoskip<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut builder = Builder::create(path)?;
+        for r in self.iter_live() {
+            let r = r?;
+            builder.add(r.key, r.value)?;
+        }
+        builder.finish()?;
+        Ok(())
+    }
+
+    fn record_at(&self, offset: usize) -> Result<Record<'_>, Error> {
+        match parse(self.map.as_slice(), offset)? {
+            (Entry::Record(_, r), _) => Ok(r),
+            _ => Err(Error::InvalidRecordType),
+        }
+    }
+}
+
+impl<'a> Record<'a> {
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn key(&self) -> &'a [u8] {
+        self.key
+    }
+
+    pub fn value(&self) -> &'a [u8] {
+        self.value
+    }
+}
+
+impl<'a> Iterator for DbIter<'a> {
+    type Item = Result<Record<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, &offset) = self.range.next()?;
+        if !key.starts_with(&self.prefix) {
+            return None;
+        }
+        Some(self.db.record_at(offset))
+    }
+}
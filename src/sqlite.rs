@@ -0,0 +1,191 @@
+// just enough of the sqlite3 C library to copy records in and out of a
+// table, linked directly against the system's libsqlite3
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+use crate::twoskip::Error;
+
+#[allow(non_camel_case_types)]
+enum sqlite3 {}
+#[allow(non_camel_case_types)]
+enum sqlite3_stmt {}
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_ROW: c_int = 100;
+const SQLITE_DONE: c_int = 101;
+
+const SQLITE_OPEN_READONLY: c_int = 0x1;
+const SQLITE_OPEN_READWRITE: c_int = 0x2;
+const SQLITE_OPEN_CREATE: c_int = 0x4;
+
+// tells sqlite to take its own copy of what's bound
+const SQLITE_TRANSIENT: isize = -1;
+
+#[link(name = "sqlite3")]
+extern "C" {
+    fn sqlite3_open_v2(
+        filename: *const c_char,
+        db: *mut *mut sqlite3,
+        flags: c_int,
+        vfs: *const c_char,
+    ) -> c_int;
+    fn sqlite3_close(db: *mut sqlite3) -> c_int;
+    fn sqlite3_errmsg(db: *mut sqlite3) -> *const c_char;
+    fn sqlite3_exec(
+        db: *mut sqlite3,
+        sql: *const c_char,
+        callback: *const c_void,
+        arg: *mut c_void,
+        errmsg: *mut *mut c_char,
+    ) -> c_int;
+    fn sqlite3_prepare_v2(
+        db: *mut sqlite3,
+        sql: *const c_char,
+        len: c_int,
+        stmt: *mut *mut sqlite3_stmt,
+        tail: *mut *const c_char,
+    ) -> c_int;
+    fn sqlite3_bind_blob(
+        stmt: *mut sqlite3_stmt,
+        index: c_int,
+        data: *const c_void,
+        len: c_int,
+        destructor: isize,
+    ) -> c_int;
+    fn sqlite3_step(stmt: *mut sqlite3_stmt) -> c_int;
+    fn sqlite3_reset(stmt: *mut sqlite3_stmt) -> c_int;
+    fn sqlite3_column_blob(stmt: *mut sqlite3_stmt, col: c_int) -> *const c_void;
+    fn sqlite3_column_bytes(stmt: *mut sqlite3_stmt, col: c_int) -> c_int;
+    fn sqlite3_finalize(stmt: *mut sqlite3_stmt) -> c_int;
+}
+
+pub(crate) struct Connection {
+    db: *mut sqlite3,
+}
+
+pub(crate) struct Statement<'c> {
+    conn: &'c Connection,
+    stmt: *mut sqlite3_stmt,
+}
+
+impl Connection {
+    pub(crate) fn open(path: &Path, create: bool) -> Result<Connection, Error> {
+        let name = cstring(path.as_os_str().as_bytes())?;
+        let flags = match create {
+            true => SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE,
+            false => SQLITE_OPEN_READONLY,
+        };
+        let mut db = ptr::null_mut();
+        let rc = unsafe { sqlite3_open_v2(name.as_ptr(), &mut db, flags, ptr::null()) };
+        // even a failed open hands back a handle, for the message and to close
+        let conn = Connection { db };
+        match rc {
+            SQLITE_OK => Ok(conn),
+            _ => Err(conn.error()),
+        }
+    }
+
+    pub(crate) fn exec(&self, sql: &str) -> Result<(), Error> {
+        let sql = cstring(sql.as_bytes())?;
+        let rc = unsafe {
+            sqlite3_exec(
+                self.db,
+                sql.as_ptr(),
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        self.check(rc)
+    }
+
+    pub(crate) fn prepare(&self, sql: &str) -> Result<Statement<'_>, Error> {
+        let sql = cstring(sql.as_bytes())?;
+        let mut stmt = ptr::null_mut();
+        let rc =
+            unsafe { sqlite3_prepare_v2(self.db, sql.as_ptr(), -1, &mut stmt, ptr::null_mut()) };
+        self.check(rc)?;
+        Ok(Statement { conn: self, stmt })
+    }
+
+    fn check(&self, rc: c_int) -> Result<(), Error> {
+        match rc {
+            SQLITE_OK => Ok(()),
+            _ => Err(self.error()),
+        }
+    }
+
+    fn error(&self) -> Error {
+        let msg = match self.db.is_null() {
+            true => "out of memory".to_string(),
+            false => unsafe { CStr::from_ptr(sqlite3_errmsg(self.db)) }
+                .to_string_lossy()
+                .into_owned(),
+        };
+        Error::InternalError(format!("sqlite: {}", msg).into())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe { sqlite3_close(self.db) };
+    }
+}
+
+impl<'c> Statement<'c> {
+    pub(crate) fn bind_blob(&mut self, index: c_int, data: &[u8]) -> Result<(), Error> {
+        let len = c_int::try_from(data.len())
+            .map_err(|_| Error::InternalError("sqlite: blob too big".into()))?;
+        let rc = unsafe {
+            sqlite3_bind_blob(
+                self.stmt,
+                index,
+                data.as_ptr() as *const c_void,
+                len,
+                SQLITE_TRANSIENT,
+            )
+        };
+        self.conn.check(rc)
+    }
+
+    // true while there are rows to look at
+    pub(crate) fn step(&mut self) -> Result<bool, Error> {
+        match unsafe { sqlite3_step(self.stmt) } {
+            SQLITE_ROW => Ok(true),
+            SQLITE_DONE => Ok(false),
+            _ => Err(self.conn.error()),
+        }
+    }
+
+    pub(crate) fn reset(&mut self) -> Result<(), Error> {
+        let rc = unsafe { sqlite3_reset(self.stmt) };
+        self.conn.check(rc)
+    }
+
+    // good until the next step or reset
+    pub(crate) fn column_blob(&self, col: c_int) -> &[u8] {
+        unsafe {
+            let data = sqlite3_column_blob(self.stmt, col);
+            let len = sqlite3_column_bytes(self.stmt, col);
+            match data.is_null() {
+                true => &[],
+                false => slice::from_raw_parts(data as *const u8, len as usize),
+            }
+        }
+    }
+}
+
+impl<'c> Drop for Statement<'c> {
+    fn drop(&mut self) {
+        unsafe { sqlite3_finalize(self.stmt) };
+    }
+}
+
+fn cstring(s: &[u8]) -> Result<CString, Error> {
+    CString::new(s).map_err(|e| Error::InternalError(Box::new(e)))
+}
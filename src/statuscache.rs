@@ -0,0 +1,151 @@
+// statuscache.db, where imapd keeps the answers to STATUS so it doesn't
+// have to open the mailbox every time a client polls. a key is
+//
+//   mboxname \0 userid
+//
+// since unseen depends on who's asking, and a value one line of numbers,
+//
+//   version statusitems messages recent uidnext uidvalidity unseen highestmodseq ...
+//
+// statusitems saying which of them were asked for and so are worth
+// anything. newer versions carry more after those, which are kept in
+// extra as they were
+
+use std::str::FromStr;
+
+use bitflags::bitflags;
+
+use crate::twoskip::Error;
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
+    pub struct StatusItems: u32 {
+        const MESSAGES = 1 << 0;
+        const RECENT = 1 << 1;
+        const UIDNEXT = 1 << 2;
+        const UIDVALIDITY = 1 << 3;
+        const UNSEEN = 1 << 4;
+        const HIGHESTMODSEQ = 1 << 5;
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StatusKey {
+    pub mboxname: Vec<u8>,
+    pub userid: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Status {
+    pub messages: u32,
+    pub recent: u32,
+    pub uidnext: u32,
+    pub uidvalidity: u32,
+    pub unseen: u32,
+    pub highestmodseq: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StatusEntry {
+    pub version: u32,
+    pub items: StatusItems,
+    pub status: Status,
+    pub extra: Vec<u64>,
+}
+
+impl StatusKey {
+    pub fn parse(key: &[u8]) -> Result<StatusKey, Error> {
+        match key.iter().position(|&c| c == 0) {
+            Some(at) => Ok(StatusKey {
+                mboxname: key[..at].to_vec(),
+                userid: key[at + 1..].to_vec(),
+            }),
+            None => Err(Error::InvalidField { field: "key" }),
+        }
+    }
+
+    pub fn to_key(&self) -> Vec<u8> {
+        [&self.mboxname[..], b"\0", &self.userid[..]].concat()
+    }
+}
+
+impl StatusEntry {
+    pub fn parse(value: &[u8]) -> Result<StatusEntry, Error> {
+        let value =
+            std::str::from_utf8(value).map_err(|_| Error::InvalidField { field: "value" })?;
+        let mut fields = value.split_ascii_whitespace();
+        let mut next = |field| number(fields.next(), field);
+        let version = next("version")?;
+        let items = StatusItems::from_bits_retain(next("statusitems")?);
+        let status = Status {
+            messages: next("messages")?,
+            recent: next("recent")?,
+            uidnext: next("uidnext")?,
+            uidvalidity: next("uidvalidity")?,
+            unseen: next("unseen")?,
+            highestmodseq: number(fields.next(), "highestmodseq")?,
+        };
+        let extra = fields
+            .map(|f| number(Some(f), "extra"))
+            .collect::<Result<_, _>>()?;
+        Ok(StatusEntry {
+            version,
+            items,
+            status,
+            extra,
+        })
+    }
+
+    pub fn to_value(&self) -> Vec<u8> {
+        let s = &self.status;
+        let mut value = format!(
+            "{} {} {} {} {} {} {} {}",
+            self.version,
+            self.items.bits(),
+            s.messages,
+            s.recent,
+            s.uidnext,
+            s.uidvalidity,
+            s.unseen,
+            s.highestmodseq
+        );
+        for n in &self.extra {
+            value.push_str(&format!(" {}", n));
+        }
+        value.into_bytes()
+    }
+
+    // of the items this has, the ones that don't match what the mailbox
+    // actually has. anything not asked for doesn't count
+    pub fn stale_items(&self, actual: &Status) -> StatusItems {
+        let s = &self.status;
+        let mut stale = StatusItems::empty();
+        for (item, same) in [
+            (StatusItems::MESSAGES, s.messages == actual.messages),
+            (StatusItems::RECENT, s.recent == actual.recent),
+            (StatusItems::UIDNEXT, s.uidnext == actual.uidnext),
+            (
+                StatusItems::UIDVALIDITY,
+                s.uidvalidity == actual.uidvalidity,
+            ),
+            (StatusItems::UNSEEN, s.unseen == actual.unseen),
+            (
+                StatusItems::HIGHESTMODSEQ,
+                s.highestmodseq == actual.highestmodseq,
+            ),
+        ] {
+            if self.items.contains(item) && !same {
+                stale |= item;
+            }
+        }
+        stale
+    }
+}
+
+fn number<T: FromStr>(field: Option<&str>, name: &'static str) -> Result<T, Error> {
+    field
+        .and_then(|f| f.parse().ok())
+        .ok_or(Error::InvalidField { field: name })
+}
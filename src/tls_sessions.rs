@@ -0,0 +1,53 @@
+// tls_sessions.db, where cyrus keeps tls sessions so clients can resume
+// them without a full handshake. a key is the session id, and a value
+//
+//   expire, session
+//
+// expire being when it stops being any use, a time_t as the machine has it
+// (8 bytes of little endian these days), and session the rest, as openssl's
+// i2d_SSL_SESSION wrote it. tls_prune, which ctl_cyrusdb -c runs, takes out
+// the ones that have expired, and expire here does the same
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::twoskip::{Db, Error};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsSession {
+    // unix time
+    pub expire: i64,
+    // DER, for openssl or anything else that reads asn.1
+    pub session: Vec<u8>,
+}
+
+impl TlsSession {
+    pub fn parse(value: &[u8]) -> Result<TlsSession, Error> {
+        match value.split_at_checked(8) {
+            Some((expire, session)) => Ok(TlsSession {
+                expire: LittleEndian::read_i64(expire),
+                session: session.to_vec(),
+            }),
+            None => Err(Error::InvalidField { field: "expire" }),
+        }
+    }
+}
+
+// the sessions that expire before time, a unix time, and their ids
+pub fn expired_before(
+    db: &Db,
+    time: i64,
+) -> impl Iterator<Item = Result<(Vec<u8>, TlsSession), Error>> + '_ {
+    db.iter_live().filter_map(move |r| {
+        let parsed = r.and_then(|r| Ok((r.key().to_vec(), TlsSession::parse(r.value())?)));
+        match parsed {
+            Ok((_, ref session)) if session.expire >= time => None,
+            parsed => Some(parsed),
+        }
+    })
+}
+
+// delete every session that expires before time, in one transaction, and
+// say how many went
+pub fn expire(db: &mut Db, time: i64) -> Result<usize, Error> {
+    db.delete_where(|_, value| Ok(TlsSession::parse(value)?.expire < time))
+}
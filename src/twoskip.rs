@@ -1,38 +1,88 @@
+use bitflags::bitflags;
 use byteorder::{BigEndian, ByteOrder};
 use crc::Crc;
 use mmap;
 use mmap::{MapOption, MemoryMap};
 use num::Zero;
+use std::cell::{Cell, RefCell};
 use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error as StdError;
+use std::ffi::OsString;
 use std::fmt;
+use std::fs;
 use std::fs::File;
 use std::io;
+use std::io::Write;
+use std::marker::PhantomData;
 use std::mem;
-use std::ops::{Add, Rem, Sub};
-use std::os::unix::io::IntoRawFd;
-use std::path::Path;
+use std::ops::{Add, Deref, Rem, Sub};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::slice;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::bloom::Bloom;
+use crate::mailboxes::{self, TreeEntry};
+use crate::mboxname;
 
 const CRC32: Crc<u32> = Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
 
-const MAX_LEVEL: u8 = 31;
+pub const MAX_LEVEL: u8 = 31;
+
+// chance of a record reaching each next level, as in cyrus
+const PROB: f64 = 0.5;
+
+// don't bother repacking files smaller than this, as in cyrus
+const MIN_REWRITE: usize = 16834;
+
+// the smallest record there is: type, level, lengths, one pointer and crcs
+const MIN_RECORD: usize = 24;
 
+// the least address space a mapping takes, however small the file
+const MIN_MAP: usize = 1 << 20;
+
+// how much can be written after a bloom filter was built before it's not
+// worth looking through it all for a key the filter doesn't know about
+const BLOOM_MAX_TAIL: usize = 64 * 1024;
+
+#[derive(Clone, Copy, PartialEq)]
 enum RecordType {
     Dummy,
     Record,
     Delete,
     Commit,
+    Blank,
+}
+
+impl From<RecordType> for u8 {
+    fn from(t: RecordType) -> u8 {
+        match t {
+            RecordType::Dummy => b'=',
+            RecordType::Record => b'+',
+            RecordType::Delete => b'-',
+            RecordType::Commit => b'$',
+            RecordType::Blank => b'_',
+        }
+    }
 }
 
-impl From<u8> for RecordType {
-    fn from(c: u8) -> RecordType {
+// a bad type byte is corruption, and has to come back as an error rather
+// than take the process down
+impl TryFrom<u8> for RecordType {
+    type Error = Error;
+
+    fn try_from(c: u8) -> Result<RecordType, Error> {
         match c {
-            b'=' => RecordType::Dummy,
-            b'+' => RecordType::Record,
-            b'-' => RecordType::Delete,
-            b'$' => RecordType::Commit,
-            _ => panic!("no conversion for RecordType from 0x{:0x}", c),
+            b'=' => Ok(RecordType::Dummy),
+            b'+' => Ok(RecordType::Record),
+            b'-' => Ok(RecordType::Delete),
+            b'$' => Ok(RecordType::Commit),
+            b'_' => Ok(RecordType::Blank),
+            _ => Err(Error::InvalidRecordType),
         }
     }
 }
@@ -45,26 +95,39 @@ pub struct Record<'a> {
     level: u8,
     key_len: usize,
     val_len: usize,
-    next_loc: Vec<usize>,
+    loc_offset: usize,
     crc32_head: u32,
     crc32_tail: u32,
     key_offset: usize,
     val_offset: usize,
 }
 
-/*
+// one page of a listing. next is the token to pass back as `after` for the
+// following page, or None if this was the last
+#[derive(Clone, Debug, Default)]
+pub struct Page {
+    pub records: Vec<OwnedRecord>,
+    pub next: Option<Vec<u8>>,
+}
+
+// a copy of a record's key and value that doesn't borrow the Db, so it can
+// outlive it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct OwnedRecord {
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+// where a key is, or would be, in the skip structure. back_loc[l] is the last
+// record before the key on level l, forward_loc[l] is whatever follows it (or,
+// for an exact match, whatever follows the matching record)
 struct Location {
-  key:            String,
-  is_exact_match: bool,
-  record:         Record,
-  back_loc:       Vec<usize>,
-  forward_loc:    Vec<usize>,
-  generation:     u64,
-  end:            usize,
+    record: Option<usize>,
+    back_loc: [usize; MAX_LEVEL as usize],
+    forward_loc: [usize; MAX_LEVEL as usize],
 }
-*/
 
-const HEADER_MAGIC: &'static [u8; 20] = b"\xa1\x02\x8b\x0dtwoskip file\x00\x00\x00\x00";
+const HEADER_MAGIC: &[u8; 20] = b"\xa1\x02\x8b\x0dtwoskip file\x00\x00\x00\x00";
 const HEADER_SIZE: usize = 64;
 
 const HEADER_VERSION: u32 = 1;
@@ -80,27 +143,331 @@ const OFFSET_CRC32: usize = 60;
 
 const START_OFFSET: usize = HEADER_SIZE;
 
-struct Header {
+bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
+    pub struct HeaderFlags: u32 {
+        // a transaction is in progress. if there's no writer holding the
+        // lock, it crashed, and the file needs recovery
+        const DIRTY = 1 << 0;
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
     version: u32,
-    flags: u32, // XXX bitflags
+    flags: HeaderFlags,
     generation: u64,
     num_records: u64,
     repack_size: usize,
     current_size: usize,
 }
 
-type Txn = usize;
+// a plain copy of the header, for monitoring tools to keep or report
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct HeaderInfo {
+    pub version: u32,
+    pub generation: u64,
+    pub num_records: u64,
+    pub current_size: usize,
+    pub repack_size: usize,
+    pub flags: HeaderFlags,
+}
+
+// what's in the file, for capacity planning. dead records are old versions
+// and deleted records that a repack would drop
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Stats {
+    pub live_records: u64,
+    pub dead_records: u64,
+    pub delete_records: u64,
+    pub commits: u64,
+    pub blank_bytes: u64,
+    pub key_bytes: u64,
+    pub value_bytes: u64,
+    pub key_sizes: SizeStats,
+    pub value_sizes: SizeStats,
+    // levels[n] is how many live records are on exactly n levels
+    pub levels: Vec<u64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SizeStats {
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+    pub p50: usize,
+    pub p90: usize,
+    pub p99: usize,
+}
+
+// everything Db::verify found wrong, in file order
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct VerifyReport {
+    pub records: u64,
+    pub problems: Vec<Problem>,
+}
+
+// something a transaction did, as read back from the file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    // None when it's too late to tell which key it was
+    Delete { key: Option<Vec<u8>> },
+    Commit,
+}
+
+// how two databases differ at one key, as diff() finds them going from the
+// first to the second
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiffEntry {
+    // only in the second
+    Added {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    // only in the first
+    Removed {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    // in both, with different values
+    Changed {
+        key: Vec<u8>,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+}
+
+// what salvage() managed to get back
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SalvageReport {
+    // live records written to the new file
+    pub recovered: u64,
+    // how many short of the old header's count that is, if it could be read
+    pub lost: Option<u64>,
+    // stretches of the file that didn't hold a good record
+    pub damaged: u64,
+    // DELETEs whose record couldn't be worked out, so it may have come back
+    pub unmatched_deletes: u64,
+}
+
+// offset 0 means the problem is with the file as a whole
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Problem {
+    pub offset: usize,
+    pub kind: ProblemKind,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ProblemKind {
+    // couldn't make sense of the record at all, so nothing after it was
+    // checked either
+    Unreadable(String),
+    HeadChecksum,
+    TailChecksum,
+    // pointer number `slot` doesn't lead to the start of a record
+    BadPointer { slot: usize, target: usize },
+    // the last record runs past the end of the committed data
+    Overrun,
+    NumRecords { header: u64, counted: u64 },
+}
+
+pub struct Txn<'a> {
+    db: &'a mut Db,
+    start: usize,
+    done: bool,
+}
+
+pub struct Snapshot<'a> {
+    db: Db,
+    _parent: PhantomData<&'a Db>,
+}
+
+// live records in key order, starting from the first one matching the
+// prefix and stopping at the first one that doesn't
+pub struct DbIter<'a> {
+    db: &'a Db,
+    prefix: Vec<u8>,
+    offset: Option<usize>,
+    done: bool,
+}
+
+// a position in the database that can be moved around. it sits on a record,
+// before the first one (the DUMMY's offset) or past the last one (0)
+pub struct Cursor<'a> {
+    db: &'a Db,
+    offset: usize,
+}
+
+// live records whose keys match an IMAP LIST style pattern
+pub struct MatchIter<'a> {
+    iter: DbIter<'a>,
+    pattern: Vec<u8>,
+}
+
+// the differences between two databases, from diff()
+pub struct Diff<'a> {
+    a: DbIter<'a>,
+    b: DbIter<'a>,
+    a_next: Option<Record<'a>>,
+    b_next: Option<Record<'a>>,
+    started: bool,
+    done: bool,
+}
+
+// just the keys of the live records, in order
+pub struct Keys<'a> {
+    iter: DbIter<'a>,
+}
+
+// live records in descending key order
+pub struct RevIter<'a> {
+    cursor: Cursor<'a>,
+    done: bool,
+}
+
+// puts and deletes to be applied together in a single transaction
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Clone, Debug)]
+enum BatchOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
 
 pub struct Db {
-    map: MemoryMap,
-    header: Header,
-    /*
-      loc:          Location,
-      is_open:      bool,
-      end:          usize,
-      next_txn_num: usize,
-      current_txn:  Txn,
-    */
+    path: PathBuf,
+    options: OpenOptions,
+    file: File,
+    writable: bool,
+    // these three move on under a read lock when someone else has
+    // committed, so they're cells
+    map: Mapping,
+    header: Cell<Header>,
+    end: Cell<usize>,
+    last_sync: Instant,
+    lock: Cell<LockState>,
+    undo: Vec<(usize, Vec<usize>)>,
+    txn_num: u64,
+    problems: RefCell<Vec<Problem>>,
+    bloom: Option<Bloom>,
+    index: RefCell<Option<Index>>,
+}
+
+// OpenOptions::preload_index's map of every live key to its record, as of
+// the header's generation and current_size. the keys are copies rather than
+// slices of the map, which moves whenever the file grows
+struct Index {
+    generation: u64,
+    end: usize,
+    offsets: BTreeMap<Vec<u8>, usize>,
+}
+
+// a point inside a transaction that it can be rolled back to
+#[derive(Clone, Debug)]
+pub struct Savepoint {
+    txn_num: u64,
+    end: usize,
+    undo: usize,
+    num_records: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LockState {
+    Unlocked,
+    Shared,
+    Exclusive,
+}
+
+// how hard commits try to get to stable storage
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    // fsync on every commit. this is what cyrus does
+    EveryCommit,
+    // fsync on commit only when it's been at least this long since the last
+    Periodic(Duration),
+    // leave it to the OS. a crash can lose or tear recent commits
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockMode {
+    // fcntl locks, compatible with cyrus
+    Fcntl,
+    // no locking at all. only safe if nothing else can touch the file
+    Disabled,
+}
+
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    read_only: bool,
+    create: bool,
+    verify_checksums: bool,
+    verify_tails: bool,
+    permissive: bool,
+    recover: bool,
+    lock: LockMode,
+    sync: SyncPolicy,
+    checkpoint_threshold: Option<f64>,
+    repack: Option<RepackPolicy>,
+    comparator: Arc<dyn Comparator>,
+    preload_index: bool,
+}
+
+// the order keys are kept in. cyrus always uses memcmp order, and anything
+// else only works on files that were written in that same order
+pub trait Comparator: fmt::Debug + Send + Sync {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+
+    // whether keys only compare equal when they're the same bytes. the
+    // .bloom sidecar knows keys by their bytes, so it's only used if so
+    fn is_bytewise(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Memcmp;
+
+impl Comparator for Memcmp {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn is_bytewise(&self) -> bool {
+        true
+    }
+}
+
+// when to repack after a commit: once the file is at least min_size and
+// more than ratio times its size after the last repack. the callback, if
+// set, sees the header first and can say no, to log it or put it off
+#[derive(Clone)]
+pub struct RepackPolicy {
+    pub ratio: f64,
+    pub min_size: usize,
+    pub callback: Option<RepackCallback>,
+}
+
+pub type RepackCallback = Arc<dyn Fn(&Header) -> bool + Send + Sync>;
+
+// the parts of the file that have their own crc
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Structure {
+    Header,
+    RecordHead,
+    RecordTail,
 }
 
 #[derive(Debug)]
@@ -108,8 +475,44 @@ pub enum Error {
     InvalidFileSize,
     InvalidHeaderMagic,
     VersionMismatch,
-    ChecksumMismatch,
+    // the key is whatever the record's lengths say it is, so it's only as
+    // trustworthy as they are, and there's none for the header
+    ChecksumMismatch {
+        structure: Structure,
+        offset: usize,
+        key: Option<Vec<u8>>,
+        expected: u32,
+        actual: u32,
+    },
     InvalidLevel,
+    InvalidRecordType,
+    InvalidOffset,
+    PointerCycle {
+        offset: usize,
+    },
+    ReadOnly,
+    InvalidSavepoint,
+    ValueMismatch,
+    Dirty,
+    // keys given to a Builder have to go up, and byte order is the only
+    // order a merge can build its output in
+    OutOfOrder,
+    // a position in a file from before it was last repacked
+    GenerationMismatch,
+    // a value that won't parse as a dlist, and how far in it went wrong
+    InvalidDlist {
+        offset: usize,
+    },
+    // a value that's the right shape, but with a field in it that isn't
+    // what it should be
+    InvalidField {
+        field: &'static str,
+    },
+    // a line of an import that can't be made into a record, counting from 1
+    InvalidLine {
+        line: usize,
+        reason: &'static str,
+    },
     InternalError(Box<dyn StdError>),
 }
 
@@ -119,24 +522,67 @@ impl StdError for Error {
             Error::InvalidFileSize => "invalid file size",
             Error::InvalidHeaderMagic => "invalid header magic",
             Error::VersionMismatch => "version mismatch",
-            Error::ChecksumMismatch => "checksum mismatch",
+            Error::ChecksumMismatch { .. } => "checksum mismatch",
             Error::InvalidLevel => "invalid level",
+            Error::InvalidRecordType => "invalid record type",
+            Error::InvalidOffset => "invalid record offset",
+            Error::PointerCycle { .. } => "pointers go round in a cycle",
+            Error::ReadOnly => "database is read-only",
+            Error::InvalidSavepoint => "savepoint no longer valid",
+            Error::ValueMismatch => "current value is not the expected value",
+            Error::Dirty => "database needs recovery",
+            Error::OutOfOrder => "keys out of order",
+            Error::GenerationMismatch => "file has been repacked since",
+            Error::InvalidDlist { .. } => "invalid dlist",
+            Error::InvalidField { .. } => "invalid field",
+            Error::InvalidLine { .. } => "invalid line",
             Error::InternalError(_) => "internal error",
         }
     }
 }
 
 impl fmt::Display for Error {
+    #[allow(deprecated)]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match *self {
-                Error::InternalError(ref err) =>
-                    format!("{} ({})", self.to_string(), err.to_string()),
-                ref e => e.to_string(),
+        match *self {
+            Error::InternalError(ref err) => write!(f, "{} ({})", self.description(), err),
+            Error::ChecksumMismatch {
+                structure,
+                offset,
+                ref key,
+                expected,
+                actual,
+            } => {
+                write!(
+                    f,
+                    "{} in {:?} at {:08x}",
+                    self.description(),
+                    structure,
+                    offset
+                )?;
+                if let Some(key) = key {
+                    write!(f, " (key {:?})", String::from_utf8_lossy(key))?;
+                }
+                write!(f, ": expected {:08x}, got {:08x}", expected, actual)
             }
-        )
+            Error::InvalidLine { line, reason } => {
+                write!(f, "{} {}: {}", self.description(), line, reason)
+            }
+            Error::InvalidDlist { offset } => {
+                write!(f, "{} at byte {}", self.description(), offset)
+            }
+            Error::InvalidField { field } => write!(f, "{} {}", self.description(), field),
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl Error {
+    pub(crate) fn io_kind(&self) -> Option<io::ErrorKind> {
+        match *self {
+            Error::InternalError(ref err) => err.downcast_ref::<io::Error>().map(|e| e.kind()),
+            _ => None,
+        }
     }
 }
 
@@ -152,303 +598,3442 @@ impl From<mmap::MapError> for Error {
     }
 }
 
-fn read_header(map: &MemoryMap) -> Result<Header, Error> {
-    if map.len() < HEADER_SIZE {
-        return Err(Error::InvalidFileSize);
+#[cfg(feature = "parquet")]
+impl From<parquet::errors::ParquetError> for Error {
+    fn from(err: parquet::errors::ParquetError) -> Error {
+        Error::InternalError(Box::new(err))
     }
+}
 
-    let base = map.data();
+fn read_header(data: &[u8], verify: bool) -> Result<Header, Error> {
+    if data.len() < HEADER_SIZE {
+        return Err(Error::InvalidFileSize);
+    }
 
-    let magic =
-        unsafe { slice::from_raw_parts(base.offset(OFFSET_HEADER as isize), HEADER_MAGIC.len()) };
-    if magic != HEADER_MAGIC {
+    if &data[OFFSET_HEADER..OFFSET_HEADER + HEADER_MAGIC.len()] != HEADER_MAGIC {
         return Err(Error::InvalidHeaderMagic);
     }
 
-    let version = BigEndian::read_u32(unsafe {
-        slice::from_raw_parts(base.offset(OFFSET_VERSION as isize), mem::size_of::<u32>())
-    });
+    let version = BigEndian::read_u32(&data[OFFSET_VERSION..]);
     if version != HEADER_VERSION {
         return Err(Error::VersionMismatch);
     }
 
-    let generation = BigEndian::read_u64(unsafe {
-        slice::from_raw_parts(
-            base.offset(OFFSET_GENERATION as isize),
-            mem::size_of::<u64>(),
-        )
-    });
-    let num_records = BigEndian::read_u64(unsafe {
-        slice::from_raw_parts(
-            base.offset(OFFSET_NUM_RECORDS as isize),
-            mem::size_of::<u64>(),
-        )
-    });
-    let repack_size = BigEndian::read_u64(unsafe {
-        slice::from_raw_parts(
-            base.offset(OFFSET_REPACK_SIZE as isize),
-            mem::size_of::<u64>(),
-        )
-    }) as usize;
-    let current_size = BigEndian::read_u64(unsafe {
-        slice::from_raw_parts(
-            base.offset(OFFSET_CURRENT_SIZE as isize),
-            mem::size_of::<u64>(),
-        )
-    }) as usize;
-
-    // XXX flags
+    let generation = BigEndian::read_u64(&data[OFFSET_GENERATION..]);
+    let num_records = BigEndian::read_u64(&data[OFFSET_NUM_RECORDS..]);
+    let repack_size = BigEndian::read_u64(&data[OFFSET_REPACK_SIZE..]) as usize;
+    let current_size = BigEndian::read_u64(&data[OFFSET_CURRENT_SIZE..]) as usize;
+    let flags = HeaderFlags::from_bits_retain(BigEndian::read_u32(&data[OFFSET_FLAGS..]));
 
-    let crc = BigEndian::read_u32(unsafe {
-        slice::from_raw_parts(base.offset(OFFSET_CRC32 as isize), mem::size_of::<u32>())
-    });
-    if crc != CRC32.checksum(unsafe { slice::from_raw_parts(base, OFFSET_CRC32 as usize) }) {
-        return Err(Error::ChecksumMismatch);
+    let crc = BigEndian::read_u32(&data[OFFSET_CRC32..]);
+    let actual = match verify {
+        true => CRC32.checksum(&data[..OFFSET_CRC32]),
+        false => crc,
+    };
+    if crc != actual {
+        return Err(Error::ChecksumMismatch {
+            structure: Structure::Header,
+            offset: OFFSET_HEADER,
+            key: None,
+            expected: crc,
+            actual,
+        });
     }
 
     let header = Header {
-        version: version,
-        flags: 0,
-        generation: generation,
-        num_records: num_records,
-        repack_size: repack_size,
-        current_size: current_size,
+        version,
+        flags,
+        generation,
+        num_records,
+        repack_size,
+        current_size,
     };
 
     Ok(header)
 }
 
-pub fn open<P: AsRef<Path>>(path: P) -> Result<Db, Error> {
-    let f = File::open(path)?;
+// cyrus locks the whole file with fcntl, shared for reading and exclusive for
+// writing. on linux we use open file description locks, which conflict with
+// cyrus' locks in the same way, but also with other handles in this process,
+// and aren't dropped when some unrelated fd on the same file is closed
+#[cfg(target_os = "linux")]
+const LOCK_CMD: libc::c_int = libc::F_OFD_SETLKW;
+#[cfg(not(target_os = "linux"))]
+const LOCK_CMD: libc::c_int = libc::F_SETLKW;
+
+pub(crate) fn lock_file(file: &File, typ: libc::c_int) -> io::Result<()> {
+    let mut fl: libc::flock = unsafe { mem::zeroed() };
+    fl.l_type = typ as libc::c_short;
+    fl.l_whence = libc::SEEK_SET as libc::c_short;
+
+    loop {
+        match unsafe { libc::fcntl(file.as_raw_fd(), LOCK_CMD, &fl) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            _ => return Ok(()),
+        }
+    }
+}
 
-    let md = f.metadata()?;
-    let len = md.len() as usize;
-    let fd = f.into_raw_fd();
+fn write_header(file: &File, header: &Header) -> Result<(), Error> {
+    let mut buf = [0u8; HEADER_SIZE];
 
-    let map = MemoryMap::new(len, &[MapOption::MapReadable, MapOption::MapFd(fd)])?;
+    buf[OFFSET_HEADER..OFFSET_HEADER + HEADER_MAGIC.len()].copy_from_slice(HEADER_MAGIC);
+    BigEndian::write_u32(&mut buf[OFFSET_VERSION..], header.version);
+    BigEndian::write_u64(&mut buf[OFFSET_GENERATION..], header.generation);
+    BigEndian::write_u64(&mut buf[OFFSET_NUM_RECORDS..], header.num_records);
+    BigEndian::write_u64(&mut buf[OFFSET_REPACK_SIZE..], header.repack_size as u64);
+    BigEndian::write_u64(&mut buf[OFFSET_CURRENT_SIZE..], header.current_size as u64);
+    BigEndian::write_u32(&mut buf[OFFSET_FLAGS..], header.flags.bits());
 
-    let header = read_header(&map)?;
+    let crc = CRC32.checksum(&buf[..OFFSET_CRC32]);
+    BigEndian::write_u32(&mut buf[OFFSET_CRC32..], crc);
 
-    let db = Db {
-        map: map,
-        header: header,
-    };
+    file.write_all_at(&buf, 0)?;
 
-    Ok(db)
+    Ok(())
 }
 
-fn round_up<T>(n: T, to: T) -> T
-where
-    T: Add<Output = T> + Sub<Output = T> + Rem<Output = T> + Zero + PartialEq + Copy,
-{
-    let r = n % to;
-    match r == T::zero() {
-        true => n,
-        false => n + to - r,
-    }
+// the file mapped in, or for a snapshot, a copy of it that nobody else can
+// write to. a mapping reaches past the end of the file, so that when the
+// file grows it can usually just be read further, and the real file length
+// is kept alongside it
+pub(crate) struct Map {
+    map: Option<MemoryMap>,
+    copy: Vec<u8>,
+    len: Cell<usize>,
+    capacity: usize,
 }
 
-impl Db {
-    pub fn get(&self, key: &[u8]) -> Result<Option<Record>, Error> {
-        let mut r = self.record_at(START_OFFSET)?;
-        let mut level = r.level;
+impl Map {
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        match self.map {
+            Some(ref map) => unsafe { slice::from_raw_parts(map.data(), self.len.get()) },
+            None => &self.copy,
+        }
+    }
 
-        loop {
-            println!("loop iter level {}", level);
+    pub(crate) fn len(&self) -> usize {
+        self.len.get()
+    }
+}
 
-            let mut offset = 0;
-            while offset == 0 && level > 0 {
-                offset = r.next_loc[level as usize];
-                if offset == 0 {
-                    level = level - 1
-                };
-            }
-            if level == 0 || offset == 0 {
-                return Ok(None);
-            }
+pub(crate) fn map_file(file: &File) -> Result<Map, Error> {
+    let len = file.metadata()?.len() as usize;
+    // room to double, so a reader keeping up with a writer only has to map
+    // the file again every so often
+    let capacity = len.saturating_mul(2).max(MIN_MAP);
+    let map = MemoryMap::new(
+        capacity,
+        &[MapOption::MapReadable, MapOption::MapFd(file.as_raw_fd())],
+    )?;
+    Ok(Map {
+        map: Some(map),
+        copy: vec![],
+        len: Cell::new(len),
+        capacity,
+    })
+}
 
-            let next = self.record_at(offset)?;
+fn copy_map(data: &[u8]) -> Map {
+    Map {
+        map: None,
+        copy: data.to_vec(),
+        len: Cell::new(data.len()),
+        capacity: data.len(),
+    }
+}
 
-            println!("next key {:?}", next.key());
+// a Db's mapping, and every one before it that a &Db might still be
+// holding slices of. catching up under a read lock can outgrow the current
+// one and need another, but the old ones can't go while what they handed
+// out is still borrowed, so they're only let go by set(), which needs &mut
+pub(crate) struct Mapping {
+    maps: RefCell<Vec<Map>>,
+}
 
-            match key.cmp(next.key()) {
-                Ordering::Equal => return Ok(Some(next)),
-                Ordering::Less => {
-                    level = level - 1;
-                    if level == 0 {
-                        return Ok(None);
-                    }
-                }
-                Ordering::Greater => {
-                    r = next;
-                    level = r.level;
-                }
-            };
+impl Mapping {
+    fn new(map: Map) -> Mapping {
+        Mapping {
+            maps: RefCell::new(vec![map]),
         }
     }
 
-    pub fn dump(&self) -> Result<(), Error> {
-        println!("HEADER: v={version} fl={flags:x} num={num_records} sz={current_size:08x}/{repack_size:08x}",
-      version      = self.header.version,
-      flags        = self.header.flags,
-      num_records  = self.header.num_records,
-      current_size = self.header.current_size,
-      repack_size  = self.header.repack_size,
-    );
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        let maps = self.maps.borrow();
+        let data = maps[maps.len() - 1].as_slice();
+        // the bytes are in the mapping or the copy's own buffer, not the
+        // Vec, so they stay where they are until set() drops them
+        unsafe { slice::from_raw_parts(data.as_ptr(), data.len()) }
+    }
 
-        let mut offset = START_OFFSET;
-        while offset < self.header.current_size {
-            let r = self.record_at(offset)?;
-            println!("{:08x} {}", offset, r.dump());
-            offset += r.len;
-        }
+    pub(crate) fn len(&self) -> usize {
+        let maps = self.maps.borrow();
+        maps[maps.len() - 1].len()
+    }
+
+    fn set(&mut self, map: Map) {
+        *self.maps.get_mut() = vec![map];
+    }
 
+    // see as much of the file as there is now. a copy never changes
+    fn follow(&self, file: &File) -> Result<(), Error> {
+        let len = file.metadata()?.len() as usize;
+        let mut maps = self.maps.borrow_mut();
+        let current = maps.len() - 1;
+        match maps[current].map {
+            None => {}
+            Some(_) if len <= maps[current].capacity => maps[current].len.set(len),
+            Some(_) => maps.push(map_file(file)?),
+        }
         Ok(())
     }
+}
+
+impl OpenOptions {
+    pub fn new() -> OpenOptions {
+        OpenOptions {
+            read_only: false,
+            create: false,
+            verify_checksums: true,
+            verify_tails: false,
+            permissive: false,
+            recover: true,
+            lock: LockMode::Fcntl,
+            sync: SyncPolicy::EveryCommit,
+            checkpoint_threshold: None,
+            repack: None,
+            comparator: Arc::new(Memcmp),
+            preload_index: false,
+        }
+    }
 
-    fn record_at(&self, offset: usize) -> Result<Record, Error> {
-        let base: *mut u8 = self.map.data();
+    pub fn read_only(&mut self, read_only: bool) -> &mut OpenOptions {
+        self.read_only = read_only;
+        self
+    }
 
-        let mut next = offset;
+    pub fn create(&mut self, create: bool) -> &mut OpenOptions {
+        self.create = create;
+        self
+    }
 
-        // XXX consts or sizeofs or whatever through here
+    pub fn verify_checksums(&mut self, verify: bool) -> &mut OpenOptions {
+        self.verify_checksums = verify;
+        self
+    }
 
-        let raw_type = unsafe { *(base.offset(next as isize)) };
-        next += 1;
-        let level = unsafe { *(base.offset(next as isize)) };
-        next += 1;
-        if level > MAX_LEVEL {
-            return Err(Error::InvalidLevel);
-        }
+    // also check the key and value of every record we read against its tail
+    // crc. off by default, because it means reading every value in full
+    pub fn verify_tails(&mut self, verify: bool) -> &mut OpenOptions {
+        self.verify_tails = verify;
+        self
+    }
 
-        let mut key_len = BigEndian::read_u16(unsafe {
-            slice::from_raw_parts(base.offset(next as isize), mem::size_of::<u16>())
-        }) as usize;
-        next += mem::size_of::<u16>();
-        let mut val_len = BigEndian::read_u32(unsafe {
-            slice::from_raw_parts(base.offset(next as isize), mem::size_of::<u32>())
-        }) as usize;
-        next += mem::size_of::<u32>();
+    // don't give up on records whose crcs are wrong: iteration and dump step
+    // over them and note them in problems() instead. their pointers still
+    // get followed, so this finds what it can rather than everything. the
+    // file is only ever opened read only, so nothing gets built on top of
+    // the damage, and one that needs recovery is read as of its last commit
+    // rather than refused
+    pub fn permissive(&mut self, permissive: bool) -> &mut OpenOptions {
+        self.permissive = permissive;
+        self
+    }
 
-        if key_len == u16::max_value() as usize {
-            key_len = BigEndian::read_u64(unsafe {
-                slice::from_raw_parts(base.offset(next as isize), mem::size_of::<u64>())
-            }) as usize;
-            next += mem::size_of::<u64>();
-        }
+    pub fn recover(&mut self, recover: bool) -> &mut OpenOptions {
+        self.recover = recover;
+        self
+    }
+
+    pub fn lock(&mut self, mode: LockMode) -> &mut OpenOptions {
+        self.lock = mode;
+        self
+    }
+
+    pub fn sync(&mut self, policy: SyncPolicy) -> &mut OpenOptions {
+        self.sync = policy;
+        self
+    }
 
-        if val_len == u32::max_value() as usize {
-            val_len = BigEndian::read_u64(unsafe {
-                slice::from_raw_parts(base.offset(next as isize), mem::size_of::<u64>())
-            }) as usize;
-            next += mem::size_of::<u64>();
+    // repack on open if the file has grown to more than this many times its
+    // size after the last repack
+    pub fn checkpoint_threshold(&mut self, ratio: f64) -> &mut OpenOptions {
+        self.checkpoint_threshold = Some(ratio);
+        self
+    }
+
+    // check after every commit whether it's time to repack
+    pub fn repack_policy(&mut self, policy: RepackPolicy) -> &mut OpenOptions {
+        self.repack = Some(policy);
+        self
+    }
+
+    pub fn comparator<C: Comparator + 'static>(&mut self, comparator: C) -> &mut OpenOptions {
+        self.comparator = Arc::new(comparator);
+        self
+    }
+
+    // read every live key into memory at open, so a get is one map lookup
+    // rather than a walk down the levels. worth it for small files that get
+    // read a lot. it keeps up with later commits by reading what they added,
+    // or all over again after a delete or a repack. keys are matched byte
+    // for byte, so like the bloom filter it's only used with a comparator
+    // that does the same
+    pub fn preload_index(&mut self, preload: bool) -> &mut OpenOptions {
+        self.preload_index = preload;
+        self
+    }
+
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<Db, Error> {
+        if self.create {
+            match create_file(&path) {
+                // lost a race with another creator; theirs is as good as ours
+                Err(ref err) if err.io_kind() == Some(io::ErrorKind::AlreadyExists) => {}
+                r => r?,
+            }
         }
 
-        let len = (next - offset) +               // header including lengths
-      8 * (level+1) as usize +        // ptrs
-      8 +                             // crc32s
-      round_up(key_len + val_len, 8); // key/val
+        // like cyrus, we want to be able to write, but we'll settle for reading
+        let (f, writable) = match self.read_only || self.permissive {
+            true => (File::open(&path)?, false),
+            false => match fs::OpenOptions::new().read(true).write(true).open(&path) {
+                Ok(f) => (f, true),
+                Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                    (File::open(&path)?, false)
+                }
+                Err(err) => return Err(err.into()),
+            },
+        };
+
+        let map = map_file(&f)?;
 
-        if offset + len > self.map.len() {
+        let header = read_header(map.as_slice(), self.verify_checksums)?;
+        if header.current_size > map.len() {
             return Err(Error::InvalidFileSize);
         }
+        let end = header.current_size;
+
+        let mut db = Db {
+            path: path.as_ref().to_path_buf(),
+            options: self.clone(),
+            file: f,
+            writable,
+            map: Mapping::new(map),
+            header: Cell::new(header),
+            end: Cell::new(end),
+            last_sync: Instant::now(),
+            lock: Cell::new(LockState::Unlocked),
+            undo: vec![],
+            txn_num: 0,
+            problems: RefCell::new(vec![]),
+            bloom: Bloom::load(path.as_ref()),
+            index: RefCell::new(None),
+        };
 
-        let mut next_loc: Vec<usize> = vec![];
-        for _ in 0..level + 1 {
-            next_loc.push(BigEndian::read_u64(unsafe {
-                slice::from_raw_parts(base.offset(next as isize), mem::size_of::<u64>())
-            }) as usize);
-            next += mem::size_of::<u64>();
+        // anything past current_size, or a dirty header, is a transaction
+        // that hasn't committed. once we have the lock, it's one that never
+        // will, probably because the writer crashed. roll it back so it can't
+        // confuse us later. if we can't write, we can't fix it, so refuse.
+        // a permissive handle is for getting what it can out of a file, so
+        // it leaves the file as it is and reads what was last committed
+        if self.recover && !self.permissive && db.needs_recovery() {
+            match db.writable {
+                true => db.lock_exclusive()?,
+                false => {
+                    db.lock_current(LockState::Shared)?;
+                    db.refresh()?;
+                    if db.header.get().flags.contains(HeaderFlags::DIRTY) {
+                        db.unlock();
+                        return Err(Error::Dirty);
+                    }
+                }
+            }
+            db.unlock();
         }
 
-        let crc32_head = BigEndian::read_u32(unsafe {
-            slice::from_raw_parts(base.offset(next as isize), mem::size_of::<u32>())
-        });
-        if crc32_head
-            != CRC32.checksum(unsafe {
-                slice::from_raw_parts(base.offset(offset as isize), next - offset)
-            })
-        {
-            return Err(Error::ChecksumMismatch);
+        if let Some(ratio) = self.checkpoint_threshold {
+            let current_size = db.header.get().current_size;
+            if db.writable
+                && current_size > MIN_REWRITE
+                && current_size as f64 > ratio * db.header.get().repack_size as f64
+            {
+                db.repack()?;
+            }
         }
-        next += mem::size_of::<u32>();
-
-        let crc32_tail = BigEndian::read_u32(unsafe {
-            slice::from_raw_parts(base.offset(next as isize), mem::size_of::<u32>())
-        });
-        next += mem::size_of::<u32>();
 
-        let key_offset = next;
-        let val_offset = next + key_len;
+        if self.preload_index && self.comparator.is_bytewise() {
+            let index = db.with_read_lock(|| db.build_index())?;
+            db.index = RefCell::new(Some(index));
+        }
 
-        let r = Record {
-            db: self,
-            offset: offset,
-            len: len,
-            typ: RecordType::from(raw_type),
-            level: level,
-            key_len: key_len,
-            val_len: val_len,
-            next_loc: next_loc,
-            crc32_head: crc32_head,
-            crc32_tail: crc32_tail,
-            key_offset: key_offset,
-            val_offset: val_offset,
-        };
+        Ok(db)
+    }
+}
 
-        Ok(r)
+impl Default for OpenOptions {
+    fn default() -> OpenOptions {
+        OpenOptions::new()
     }
 }
 
-impl<'a> Record<'a> {
-    pub fn key(&self) -> &[u8] {
-        let base: *mut u8 = self.db.map.data();
-        unsafe { slice::from_raw_parts(base.offset(self.key_offset as isize), self.key_len) }
+impl RepackPolicy {
+    pub fn new() -> RepackPolicy {
+        RepackPolicy {
+            ratio: 2.0,
+            min_size: MIN_REWRITE,
+            callback: None,
+        }
     }
 
-    pub fn value(&self) -> &[u8] {
-        let base: *mut u8 = self.db.map.data();
-        unsafe { slice::from_raw_parts(base.offset(self.val_offset as isize), self.val_len) }
+    pub fn callback<F>(mut self, f: F) -> RepackPolicy
+    where
+        F: Fn(&Header) -> bool + Send + Sync + 'static,
+    {
+        self.callback = Some(Arc::new(f));
+        self
     }
 
-    fn format_data_record(&self, name: &str) -> String {
-        format!(
-            "{name} kl={key_len:08x} dl={val_len:08x} lvl={level} ({key})\n    {next_loc}",
-            name = name,
-            key_len = self.key_len,
-            val_len = self.val_len,
-            level = self.level,
-            key = match std::str::from_utf8(self.key()) {
-                Ok(s) => s,
-                Err(_) => "[Utf8Error]",
-            },
-            next_loc = self.format_next_loc(),
-        )
+    fn due(&self, header: &Header) -> bool {
+        let current_size = header.current_size;
+        if current_size < self.min_size
+            || current_size as f64 <= self.ratio * header.repack_size as f64
+        {
+            return false;
+        }
+        match self.callback {
+            Some(ref f) => f(header),
+            None => true,
+        }
     }
+}
 
-    fn format_next_loc(&self) -> String {
-        (0..self.level + 1)
-            .map(|l| self.next_loc[l as usize])
-            .map(|loc| format!("{:08x}", loc))
-            .collect::<Vec<String>>()
-            .chunks(8)
-            .map(|v| v.join(" "))
-            .collect::<Vec<String>>()
-            .join("\n    ")
+impl Default for RepackPolicy {
+    fn default() -> RepackPolicy {
+        RepackPolicy::new()
     }
+}
 
-    pub fn dump(&self) -> String {
-        match self.typ {
-            RecordType::Dummy => self.format_data_record("DUMMY"),
-            RecordType::Record => self.format_data_record("RECORD"),
+impl fmt::Debug for RepackPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RepackPolicy")
+            .field("ratio", &self.ratio)
+            .field("min_size", &self.min_size)
+            .field("callback", &self.callback.is_some())
+            .finish()
+    }
+}
 
-            RecordType::Delete => {
-                format!("DELETE ptr={next_loc:08x}", next_loc = self.next_loc[0],)
-            }
+pub fn open<P: AsRef<Path>>(path: P) -> Result<Db, Error> {
+    OpenOptions::new().open(path)
+}
 
-            RecordType::Commit => {
-                format!("COMMIT start={next_loc:08x}", next_loc = self.next_loc[0],)
-            }
-        }
+fn create_file<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+    let f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+
+    Packer::new(f)?.finish(1)?;
+
+    Ok(())
+}
+
+pub fn create<P: AsRef<Path>>(path: P) -> Result<Db, Error> {
+    create_file(&path)?;
+    open(path)
+}
+
+pub fn open_or_create<P: AsRef<Path>>(path: P) -> Result<Db, Error> {
+    OpenOptions::new().create(true).open(path)
+}
+
+// get back what can be got from a damaged file, by reading it from one end
+// to the other rather than following pointers that can't be trusted. where
+// there isn't a record with good crcs, step along a word at a time until
+// there is one again. transactions are replayed in the order they were
+// written, and whatever's left standing goes into dst, which mustn't exist
+pub fn salvage<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> Result<SalvageReport, Error> {
+    let file = File::open(&src)?;
+    // what's broken may be the header, which with_read_lock would want to
+    // read, so the lock is taken here and held for the whole scan
+    lock_file(&file, libc::F_RDLCK)?;
+    let map = map_file(&file)?;
+    let header = read_header(map.as_slice(), true).ok();
+    let db = Db {
+        path: src.as_ref().to_path_buf(),
+        options: OpenOptions::new(),
+        file,
+        writable: false,
+        header: Cell::new(header.unwrap_or(Header {
+            version: HEADER_VERSION,
+            flags: HeaderFlags::empty(),
+            generation: 0,
+            num_records: 0,
+            repack_size: 0,
+            current_size: map.len(),
+        })),
+        end: Cell::new(map.len()),
+        map: Mapping::new(map),
+        last_sync: Instant::now(),
+        lock: Cell::new(LockState::Shared),
+        undo: vec![],
+        txn_num: 0,
+        problems: RefCell::new(vec![]),
+        bloom: None,
+        index: RefCell::new(None),
+    };
+
+    let mut report = SalvageReport::default();
+    let live = db.salvage_scan(&mut report);
+    db.unlock();
+    let live = live?;
+
+    let mut builder = Builder::create(&dst)?;
+    builder.extend(&live)?;
+    builder.finish()?;
+
+    report.recovered = live.len() as u64;
+    report.lost = header.map(|h| h.num_records.saturating_sub(report.recovered));
+    Ok(report)
+}
+
+// a new file at output with the live records of every input. they're all
+// walked in key order together, so it's one pass over each, and the output
+// is written as it goes. where inputs disagree about a key, resolve is given
+// the key and, in the order of inputs, the index and value of each that has
+// it, and says what value to keep, or None to leave the key out. an error
+// from it stops the merge, and leaves nothing at output. the output is
+// built in byte order, so every input has to be kept in it too
+pub fn merge<P, F>(inputs: &[&Db], output: P, mut resolve: F) -> Result<Db, Error>
+where
+    P: AsRef<Path>,
+    F: FnMut(&[u8], &[(usize, &[u8])]) -> Result<Option<Vec<u8>>, Error>,
+{
+    if inputs.iter().any(|db| !db.options.comparator.is_bytewise()) {
+        return Err(Error::OutOfOrder);
+    }
+    let mut builder = Builder::create(output)?;
+    let mut iters: Vec<_> = inputs.iter().map(|db| db.iter_live()).collect();
+    let mut heads = iters
+        .iter_mut()
+        .map(|i| i.next().transpose())
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    // the least key by the inputs' comparator goes next, and it's the
+    // comparator that says which inputs have it
+    while let Some(first) = heads
+        .iter()
+        .flatten()
+        .min_by(|a, b| a.db.compare(a.key(), b.key()))
+    {
+        let (db, key) = (first.db, first.key());
+        let values: Vec<(usize, &[u8])> = heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| match head {
+                Some(r) if db.compare(r.key(), key) == Ordering::Equal => Some((i, r.value())),
+                _ => None,
+            })
+            .collect();
+
+        match values.iter().all(|(_, v)| *v == values[0].1) {
+            true => builder.add(key, values[0].1)?,
+            false => {
+                if let Some(value) = resolve(key, &values)? {
+                    builder.add(key, &value)?;
+                }
+            }
+        }
+
+        for (i, _) in values {
+            heads[i] = iters[i].next().transpose()?;
+        }
+    }
+
+    builder.finish()
+}
+
+// what it takes to get from a to b, a key at a time in key order. both are
+// walked together, once each, and keys are matched up by a's comparator, so
+// b should have been opened with the same one
+pub fn diff<'a>(a: &'a Db, b: &'a Db) -> Diff<'a> {
+    Diff {
+        a: a.iter_live(),
+        b: b.iter_live(),
+        a_next: None,
+        b_next: None,
+        started: false,
+        done: false,
+    }
+}
+
+// the live records of db shared out between new files, one for each of
+// outputs. partition says which output a key goes in by its index, or None
+// to leave it out altogether; an index past the end is an error. if anything
+// goes wrong, none of the outputs are left behind
+pub fn split<P, F>(db: &Db, mut partition: F, outputs: &[P]) -> Result<Vec<Db>, Error>
+where
+    P: AsRef<Path>,
+    F: FnMut(&[u8]) -> Option<usize>,
+{
+    let mut builders = outputs
+        .iter()
+        .map(Builder::create)
+        .collect::<Result<Vec<_>, Error>>()?;
+    for r in db.iter_live() {
+        let r = r?;
+        let builder = match partition(r.key()) {
+            Some(i) => builders
+                .get_mut(i)
+                .ok_or_else(|| Error::InternalError(format!("split: no output {}", i).into()))?,
+            None => continue,
+        };
+        builder.add(r.key(), r.value())?;
+    }
+
+    // the builders not got to yet clean up after themselves when they're
+    // dropped, but the ones already finished need taking away by hand
+    let mut finished = vec![];
+    for builder in builders {
+        match builder.finish() {
+            Ok(db) => finished.push(db),
+            Err(e) => {
+                for path in &outputs[..finished.len()] {
+                    fs::remove_file(path).ok();
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(finished)
+}
+
+thread_local! {
+    static RAND_STATE: Cell<u64> = Cell::new(rand_seed());
+}
+
+fn rand_seed() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    (now ^ ((process::id() as u64) << 32)) | 1
+}
+
+// xorshift64*, scaled to [0,1) like rand()/RAND_MAX
+fn rand_f64() -> f64 {
+    RAND_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        (x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+// randlvl() from cyrusdb_twoskip.c: start at 1 and keep going up with
+// probability PROB, stopping at MAX_LEVEL
+pub fn level_from<F: FnMut() -> f64>(mut rand: F) -> u8 {
+    let mut level = 1;
+    while rand() < PROB {
+        level += 1;
+        if level == MAX_LEVEL {
+            break;
+        }
+    }
+    level
+}
+
+pub fn random_level() -> u8 {
+    level_from(rand_f64)
+}
+
+// the record head: type, level, lengths and pointers, followed by its crc
+fn format_head(
+    typ: RecordType,
+    level: u8,
+    key_len: usize,
+    val_len: usize,
+    next_loc: &[usize],
+) -> Vec<u8> {
+    let mut buf: Vec<u8> = vec![typ.into(), level];
+
+    let mut lens = [0u8; 6];
+    BigEndian::write_u16(&mut lens[0..], key_len.min(u16::MAX as usize) as u16);
+    BigEndian::write_u32(&mut lens[2..], val_len.min(u32::MAX as usize) as u32);
+    buf.extend_from_slice(&lens);
+
+    let mut word = [0u8; 8];
+    if key_len >= u16::MAX as usize {
+        BigEndian::write_u64(&mut word, key_len as u64);
+        buf.extend_from_slice(&word);
+    }
+    if val_len >= u32::MAX as usize {
+        BigEndian::write_u64(&mut word, val_len as u64);
+        buf.extend_from_slice(&word);
+    }
+
+    for loc in next_loc {
+        BigEndian::write_u64(&mut word, *loc as u64);
+        buf.extend_from_slice(&word);
+    }
+
+    let mut crc = [0u8; 4];
+    BigEndian::write_u32(&mut crc, CRC32.checksum(&buf));
+    buf.extend_from_slice(&crc);
+
+    buf
+}
+
+fn format_record(
+    typ: RecordType,
+    level: u8,
+    key: &[u8],
+    value: &[u8],
+    next_loc: &[usize],
+) -> Vec<u8> {
+    let mut buf = format_head(typ, level, key.len(), value.len(), next_loc);
+
+    let pad = round_up(key.len() + value.len(), 8) - (key.len() + value.len());
+    let mut data: Vec<u8> = Vec::with_capacity(key.len() + value.len() + pad);
+    data.extend_from_slice(key);
+    data.extend_from_slice(value);
+    data.resize(data.len() + pad, 0);
+
+    let mut crc = [0u8; 4];
+    BigEndian::write_u32(&mut crc, CRC32.checksum(&data));
+    buf.extend_from_slice(&crc);
+    buf.extend_from_slice(&data);
+
+    buf
+}
+
+// sixteen bytes to a line, with the label on the first
+fn hex_lines<W: Write>(
+    out: &mut W,
+    data: &[u8],
+    start: usize,
+    end: usize,
+    label: &str,
+) -> io::Result<()> {
+    for (i, at) in (start..end).step_by(16).enumerate() {
+        let chunk = &data[at..end.min(at + 16)];
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        let text: String = chunk
+            .iter()
+            .map(|&b| match b.is_ascii_graphic() || b == b' ' {
+                true => b as char,
+                false => '.',
+            })
+            .collect();
+        let label = if i == 0 { label } else { "" };
+        let line = format!(
+            "    {:08x}  {:<47}  {:<16}  {}",
+            at,
+            hex.join(" "),
+            text,
+            label
+        );
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+fn round_up<T>(n: T, to: T) -> T
+where
+    T: Add<Output = T> + Sub<Output = T> + Rem<Output = T> + Zero + PartialEq + Copy,
+{
+    let r = n % to;
+    match r == T::zero() {
+        true => n,
+        false => n + to - r,
+    }
+}
+
+impl Header {
+    pub fn info(&self) -> HeaderInfo {
+        HeaderInfo {
+            version: self.version,
+            generation: self.generation,
+            num_records: self.num_records,
+            current_size: self.current_size,
+            repack_size: self.repack_size,
+            flags: self.flags,
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn flags(&self) -> HeaderFlags {
+        self.flags
+    }
+
+    // bumped each time the file is repacked
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn num_records(&self) -> u64 {
+        self.num_records
+    }
+
+    // size of the file after the last repack
+    pub fn repack_size(&self) -> usize {
+        self.repack_size
+    }
+
+    // end of the last committed transaction
+    pub fn current_size(&self) -> usize {
+        self.current_size
+    }
+}
+
+impl Db {
+    pub fn header(&self) -> Header {
+        self.header.get()
+    }
+
+    pub fn header_info(&self) -> HeaderInfo {
+        self.header.get().info()
+    }
+
+    // the records a permissive handle has stepped over so far
+    pub fn problems(&self) -> Vec<Problem> {
+        self.problems.borrow().clone()
+    }
+
+    // check every record up to the end of the last commit: both crcs, that
+    // every pointer leads to the start of a record, and that the header's
+    // record count is right. problems are collected rather than returned,
+    // so one bad record doesn't hide the rest
+    pub fn verify(&self) -> Result<VerifyReport, Error> {
+        self.with_read_lock(|| {
+            let mut report = VerifyReport::default();
+            let mut problem = |offset, kind| report.problems.push(Problem { offset, kind });
+
+            let mut starts = HashSet::new();
+            let mut pointers = vec![];
+            let mut offset = START_OFFSET;
+            while offset < self.header.get().current_size {
+                let r = match self.parse_record(offset, false) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        problem(offset, ProblemKind::Unreadable(e.to_string()));
+                        break;
+                    }
+                };
+                starts.insert(offset);
+                if !r.head_ok() {
+                    problem(offset, ProblemKind::HeadChecksum);
+                }
+                if r.verify().is_err() {
+                    problem(offset, ProblemKind::TailChecksum);
+                }
+                for (slot, target) in r.locs().enumerate() {
+                    if target != 0 {
+                        pointers.push((offset, slot, target));
+                    }
+                }
+                offset += r.len;
+            }
+            if offset > self.header.get().current_size {
+                problem(offset, ProblemKind::Overrun);
+            }
+
+            // an unfinished transaction leaves pointers to what it wrote
+            // after the last commit, and recovery puts those back
+            let unfinished = self.needs_recovery();
+            for (offset, slot, target) in pointers {
+                if unfinished && target >= self.header.get().current_size {
+                    continue;
+                }
+                if !starts.contains(&target) {
+                    problem(offset, ProblemKind::BadPointer { slot, target });
+                }
+            }
+
+            let mut counted = 0;
+            for r in self.iter_live() {
+                match r {
+                    Ok(_) => counted += 1,
+                    Err(e) => {
+                        problem(0, ProblemKind::Unreadable(e.to_string()));
+                        break;
+                    }
+                }
+            }
+            if counted != self.header.get().num_records {
+                problem(
+                    0,
+                    ProblemKind::NumRecords {
+                        header: self.header.get().num_records,
+                        counted,
+                    },
+                );
+            }
+
+            report.records = starts.len() as u64;
+            Ok(report)
+        })
+    }
+
+    // whether someone else has committed or repacked since we last looked.
+    // a commit is picked up by the next read, but a repack puts a new file
+    // in place and reads keep going to the old one until reopen()
+    pub fn is_stale(&self) -> Result<bool, Error> {
+        if self.replaced()? {
+            return Ok(true);
+        }
+        let header = read_header(
+            map_file(&self.file)?.as_slice(),
+            self.options.verify_checksums,
+        )?;
+        Ok(header.generation != self.header.get().generation
+            || header.current_size != self.header.get().current_size)
+    }
+
+    // catch up with the file on disk, following it if it's been replaced
+    pub fn reopen(&mut self) -> Result<(), Error> {
+        if self.replaced()? {
+            self.reopen_file()?;
+        }
+        self.lock_current(LockState::Shared)?;
+        let r = self.refresh();
+        self.unlock();
+        r
+    }
+
+    // everything committed after offset, which should be the end of some
+    // earlier commit (eg a current_size from before), with where each change
+    // is in the file
+    pub fn changes_since(&self, offset: usize) -> Result<Vec<(usize, Change)>, Error> {
+        self.with_read_lock(|| {
+            let mut changes = vec![];
+            let mut offset = offset.max(START_OFFSET);
+            while offset < self.header.get().current_size {
+                let r = self.record_at(offset)?;
+                let change = match r.typ {
+                    RecordType::Record => Change::Put {
+                        key: r.key().to_vec(),
+                        value: r.value().to_vec(),
+                    },
+                    RecordType::Delete => Change::Delete {
+                        key: self.deleted_key(&r)?,
+                    },
+                    RecordType::Commit => Change::Commit,
+                    RecordType::Dummy | RecordType::Blank => {
+                        offset += r.len;
+                        continue;
+                    }
+                };
+                changes.push((offset, change));
+                offset += r.len;
+            }
+            Ok(changes)
+        })
+    }
+
+    // one walk down the live chain for the sizes and levels, and one over
+    // the whole file for everything else
+    pub fn stats(&self) -> Result<Stats, Error> {
+        self.with_read_lock(|| {
+            let mut stats = Stats {
+                levels: vec![0; MAX_LEVEL as usize + 1],
+                ..Stats::default()
+            };
+
+            let mut key_sizes = vec![];
+            let mut value_sizes = vec![];
+            for r in self.iter_live() {
+                let r = r?;
+                stats.live_records += 1;
+                stats.levels[r.level as usize] += 1;
+                key_sizes.push(r.key_len);
+                value_sizes.push(r.val_len);
+            }
+            stats.key_bytes = key_sizes.iter().sum::<usize>() as u64;
+            stats.value_bytes = value_sizes.iter().sum::<usize>() as u64;
+            stats.key_sizes = SizeStats::from_sizes(&mut key_sizes);
+            stats.value_sizes = SizeStats::from_sizes(&mut value_sizes);
+
+            let mut records = 0;
+            let mut offset = START_OFFSET;
+            while offset < self.header.get().current_size {
+                let r = self.record_at(offset)?;
+                match r.typ {
+                    RecordType::Record => records += 1,
+                    RecordType::Delete => stats.delete_records += 1,
+                    RecordType::Commit => stats.commits += 1,
+                    RecordType::Blank => stats.blank_bytes += r.len as u64,
+                    RecordType::Dummy => {}
+                }
+                offset += r.len;
+            }
+            // past the last commit is either blanked out by an abort or
+            // left over from a crash, and the next writer reuses it either
+            // way, so it's all blank space rather than records to parse
+            stats.blank_bytes += self.map.len().saturating_sub(offset) as u64;
+            stats.dead_records = records - stats.live_records;
+
+            Ok(stats)
+        })
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Record<'_>>, Error> {
+        self.with_read_lock(|| self.lookup(key))
+    }
+
+    // just the value, when that's all that's wanted
+    pub fn get_value(&self, key: &[u8]) -> Result<Option<&[u8]>, Error> {
+        Ok(self.get(key)?.map(|r| r.value()))
+    }
+
+    pub fn get_value_owned(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.get_value(key)?.map(|v| v.to_vec()))
+    }
+
+    // get, for a mailbox name written in utf-8 rather than the modified
+    // utf-7 cyrus keeps it in. see mboxname.rs
+    pub fn get_mailbox(&self, name: &str) -> Result<Option<Record<'_>>, Error> {
+        self.get(mboxname::encode_utf7(name).as_bytes())
+    }
+
+    // whether the key exists. the search stops as soon as it meets the key on
+    // any level, and never looks at the value
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool, Error> {
+        self.with_read_lock(|| Ok(self.lookup(key)?.is_some()))
+    }
+
+    // whether there's a .bloom sidecar for this file that get and
+    // contains_key are using to turn away keys that aren't there. see bloom.rs
+    pub fn has_bloom(&self) -> bool {
+        self.usable_bloom().is_some()
+    }
+
+    fn usable_bloom(&self) -> Option<&Bloom> {
+        self.bloom.as_ref().filter(|b| {
+            self.options.comparator.is_bytewise()
+                && b.generation == self.header.get().generation
+                && b.end <= self.end.get()
+                && self.end.get() - b.end <= BLOOM_MAX_TAIL
+        })
+    }
+
+    // whether the key certainly isn't there: the filter's never heard of it,
+    // and nothing written since it was built is a record for it
+    fn bloom_excludes(&self, key: &[u8]) -> Result<bool, Error> {
+        let bloom = match self.usable_bloom() {
+            Some(bloom) if !bloom.may_contain(key) => bloom,
+            _ => return Ok(false),
+        };
+        let mut offset = bloom.end;
+        while offset < self.end.get().min(self.map.len()) {
+            let r = self.record_at(offset)?;
+            if r.typ == RecordType::Record && self.compare(key, r.key()) == Ordering::Equal {
+                return Ok(false);
+            }
+            offset += r.len;
+        }
+        Ok(true)
+    }
+
+    fn build_index(&self) -> Result<Index, Error> {
+        let mut offsets = BTreeMap::new();
+        for r in self.iter_live() {
+            let r = r?;
+            offsets.insert(r.key().to_vec(), r.offset);
+        }
+        Ok(Index {
+            generation: self.header.get().generation,
+            end: self.header.get().current_size,
+            offsets,
+        })
+    }
+
+    // bring the index up to what's committed, if there is one. new records
+    // can just be added, but a delete would mean working out which key it
+    // took out, so that's a rebuild. inside a transaction that's written
+    // something, the index doesn't know about it and can't be used
+    fn index_is_current(&self) -> Result<bool, Error> {
+        let mut index = self.index.borrow_mut();
+        let stale = match *index {
+            None => return Ok(false),
+            Some(_) if self.end.get() != self.header.get().current_size => return Ok(false),
+            Some(ref i) => i.generation != self.header.get().generation || i.end > self.end.get(),
+        };
+        if stale {
+            *index = Some(self.build_index()?);
+            return Ok(true);
+        }
+        if let Some(ref mut i) = *index {
+            let mut offset = i.end;
+            while offset < self.end.get() {
+                let r = self.record_at(offset)?;
+                match r.typ {
+                    RecordType::Record => {
+                        i.offsets.insert(r.key().to_vec(), offset);
+                    }
+                    RecordType::Delete => {
+                        *index = Some(self.build_index()?);
+                        return Ok(true);
+                    }
+                    _ => {}
+                }
+                offset += r.len;
+            }
+            i.end = self.end.get();
+        }
+        Ok(true)
+    }
+
+    // the usual skip list descent: go right on each level while the next key
+    // is smaller, drop a level when it isn't. every record a pointer leads to
+    // is live, so the first time we meet the key on any level, that's it
+    fn lookup(&self, key: &[u8]) -> Result<Option<Record<'_>>, Error> {
+        if self.bloom_excludes(key)? {
+            return Ok(None);
+        }
+        if self.index_is_current()? {
+            let index = self.index.borrow();
+            let offset = index.as_ref().and_then(|i| i.offsets.get(key).copied());
+            return offset.map(|offset| self.record_at(offset)).transpose();
+        }
+
+        let mut r = self.record_at(START_OFFSET)?;
+        let mut level = r.level;
+
+        while level > 0 {
+            if let Some(next) = self.record_after(&r, level - 1)? {
+                match self.compare(key, next.key()) {
+                    Ordering::Equal => return Ok(Some(next)),
+                    Ordering::Greater => {
+                        r = next;
+                        continue;
+                    }
+                    Ordering::Less => {}
+                }
+            }
+            level -= 1;
+        }
+
+        Ok(None)
+    }
+
+    // the same descent as lookup, written out a step at a time: every
+    // pointer followed, the key it led to and which way that sent us. says
+    // whether the key was found. a broken chain shows up as the last step
+    // written before the error
+    pub fn explain_to<W: Write>(&self, out: &mut W, key: &[u8]) -> Result<bool, Error> {
+        self.with_read_lock(|| {
+            let mut r = self.record_at(START_OFFSET)?;
+            let mut level = r.level;
+            let mut compares = 0;
+            writeln!(
+                out,
+                "looking for {:?} from the DUMMY at {:08x}, {} levels",
+                String::from_utf8_lossy(key),
+                r.offset,
+                level
+            )?;
+
+            // the top levels of the DUMMY are usually empty, and one line
+            // covers a run of those
+            let mut empty = None;
+            while level > 0 {
+                let l = level - 1;
+                let ptr = r.next_at(l);
+                let next = match self.record_after(&r, l) {
+                    Ok(Some(next)) => Ok(next),
+                    Ok(None) => {
+                        empty.get_or_insert(l);
+                        level -= 1;
+                        continue;
+                    }
+                    Err(e) => Err(e),
+                };
+                if let Some(top) = empty.take() {
+                    writeln!(
+                        out,
+                        "level {:2}-{}: {:08x} -> end, down",
+                        top,
+                        l + 1,
+                        r.offset
+                    )?;
+                }
+                write!(out, "level {:2}: {:08x} -> ", l, r.offset)?;
+                let next = match next {
+                    Ok(next) => next,
+                    Err(e) => {
+                        writeln!(out, "{:08x} broken", ptr)?;
+                        return Err(e);
+                    }
+                };
+                if next.offset != ptr {
+                    write!(out, "(DELETE at {:08x}) ", ptr)?;
+                }
+                compares += 1;
+                let ord = self.compare(key, next.key());
+                writeln!(
+                    out,
+                    "{:08x} {:?}: {}",
+                    next.offset,
+                    String::from_utf8_lossy(next.key()),
+                    match ord {
+                        Ordering::Equal => "found",
+                        Ordering::Greater => "greater, right",
+                        Ordering::Less => "less, down",
+                    }
+                )?;
+                match ord {
+                    Ordering::Equal => {
+                        writeln!(
+                            out,
+                            "found at {:08x} on level {} after {} comparisons",
+                            next.offset, l, compares
+                        )?;
+                        return Ok(true);
+                    }
+                    Ordering::Greater => r = next,
+                    Ordering::Less => level -= 1,
+                }
+            }
+
+            if let Some(top) = empty {
+                writeln!(out, "level {:2}-0: {:08x} -> end, down", top, r.offset)?;
+            }
+            writeln!(out, "not found after {} comparisons", compares)?;
+            Ok(false)
+        })
+    }
+
+    pub fn dump(&self) -> Result<(), Error> {
+        self.dump_to(&mut io::stdout())
+    }
+
+    pub fn dump_to<W: Write>(&self, out: &mut W) -> Result<(), Error> {
+        writeln!(out, "HEADER: v={version} fl={flags:x} num={num_records} sz={current_size:08x}/{repack_size:08x}",
+      version      = self.header.get().version,
+      flags        = self.header.get().flags.bits(),
+      num_records  = self.header.get().num_records,
+      current_size = self.header.get().current_size,
+      repack_size  = self.header.get().repack_size,
+    )?;
+
+        // past current_size there's only blank space, unless a writer is
+        // busy or died, and it's worth seeing either way
+        let mut offset = START_OFFSET;
+        while offset < self.map.len() {
+            let r = self.record_at(offset)?;
+            if self.keep(&r) {
+                writeln!(out, "{:08x} {}", offset, r.dump())?;
+            }
+            offset += r.len;
+        }
+
+        Ok(())
+    }
+
+    // the raw bytes of every record from the one holding `from` on, split up
+    // into their parts and labelled, for when dump() won't say what's wrong.
+    // the header comes first if `from` is inside it
+    pub fn hexdump_to<W: Write>(&self, out: &mut W, from: usize) -> Result<(), Error> {
+        let data = self.map.as_slice();
+        if from < START_OFFSET {
+            writeln!(
+                out,
+                "{:08x} HEADER v={} gen={} num={} sz={:08x}/{:08x}",
+                0,
+                self.header.get().version,
+                self.header.get().generation,
+                self.header.get().num_records,
+                self.header.get().current_size,
+                self.header.get().repack_size
+            )?;
+            hex_lines(out, data, OFFSET_HEADER, OFFSET_VERSION, "magic")?;
+            hex_lines(out, data, OFFSET_VERSION, OFFSET_CRC32, "fields")?;
+            hex_lines(out, data, OFFSET_CRC32, HEADER_SIZE, "crc")?;
+        }
+
+        let mut offset = START_OFFSET;
+        while offset < self.map.len() {
+            let r = match self.parse_record(offset, false) {
+                Ok(r) => r,
+                Err(e) => {
+                    writeln!(out, "{:08x} unreadable: {}", offset, e)?;
+                    hex_lines(out, data, offset, (offset + 64).min(data.len()), "")?;
+                    break;
+                }
+            };
+            if offset + r.len > from {
+                let ok = |good| if good { "ok" } else { "BAD" };
+                let crcs = format!(
+                    "crc head {:08x} {}, tail {:08x} {}",
+                    r.crc32_head,
+                    ok(r.head_ok()),
+                    r.crc32_tail,
+                    ok(r.verify().is_ok())
+                );
+                let ptrs = r.loc_offset;
+                writeln!(
+                    out,
+                    "{:08x} {}",
+                    offset,
+                    r.dump().lines().next().unwrap_or("")
+                )?;
+                hex_lines(out, data, offset, ptrs, "head")?;
+                hex_lines(out, data, ptrs, r.key_offset - 8, "next")?;
+                hex_lines(out, data, r.key_offset - 8, r.key_offset, &crcs)?;
+                hex_lines(out, data, r.key_offset, offset + r.len, "key, value")?;
+            }
+            offset += r.len;
+        }
+
+        Ok(())
+    }
+
+    // look up a batch of keys, results in the same order. the keys are looked
+    // up in sorted order, and each search starts from where the last one got
+    // to on every level rather than from the DUMMY
+    pub fn multi_get<'k, I>(&self, keys: I) -> Result<Vec<Option<Record<'_>>>, Error>
+    where
+        I: IntoIterator<Item = &'k [u8]>,
+    {
+        let keys: Vec<&[u8]> = keys.into_iter().collect();
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|&a, &b| self.compare(keys[a], keys[b]));
+
+        self.with_read_lock(|| {
+            let mut results: Vec<Option<Record>> = keys.iter().map(|_| None).collect();
+            let mut back = [START_OFFSET; MAX_LEVEL as usize];
+
+            for &i in &order {
+                let key = keys[i];
+                let mut r = self.record_at(START_OFFSET)?;
+                let mut level = r.level;
+
+                while level > 0 {
+                    let l = level - 1;
+
+                    // the last key's place on this level can't be past ours
+                    if back[l as usize] != START_OFFSET && back[l as usize] != r.offset {
+                        let b = self.record_at(back[l as usize])?;
+                        if r.offset == START_OFFSET
+                            || self.compare(b.key(), r.key()) == Ordering::Greater
+                        {
+                            r = b;
+                        }
+                    }
+
+                    while let Some(next) = self.record_after(&r, l)? {
+                        match self.compare(key, next.key()) {
+                            Ordering::Greater => r = next,
+                            Ordering::Equal if l == 0 => {
+                                results[i] = Some(next);
+                                break;
+                            }
+                            _ => break,
+                        }
+                    }
+
+                    back[l as usize] = r.offset;
+                    level -= 1;
+                }
+            }
+
+            Ok(results)
+        })
+    }
+
+    // the record with the smallest key strictly greater than this one, found
+    // the same way as a lookup rather than by walking the file
+    pub fn fetchnext(&self, key: &[u8]) -> Result<Option<Record<'_>>, Error> {
+        self.with_read_lock(|| match self.find_loc(key)?.forward_loc[0] {
+            0 => Ok(None),
+            offset => Ok(Some(self.record_at(offset)?)),
+        })
+    }
+
+    // every live record in key order, straight down the DUMMY's level 0 chain.
+    // old versions, deletes and commits aren't on it
+    pub fn iter_live(&self) -> DbIter<'_> {
+        DbIter {
+            db: self,
+            prefix: vec![],
+            offset: Some(START_OFFSET),
+            done: false,
+        }
+    }
+
+    // how many live keys start with the prefix. the header already knows how
+    // many there are altogether
+    pub fn count_prefix(&self, prefix: &[u8]) -> Result<usize, Error> {
+        if prefix.is_empty() {
+            return Ok(self.header.get().num_records as usize);
+        }
+        let mut count = 0;
+        for r in self.iter_prefix(prefix) {
+            r?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // up to limit records matching the prefix, starting after the one the
+    // token came from. the token is just a position in key order, so it still
+    // works after commits, repacks and reopens
+    pub fn list(&self, prefix: &[u8], after: Option<&[u8]>, limit: usize) -> Result<Page, Error> {
+        let mut cursor = self.cursor();
+        let mut r = match after {
+            Some(after) if self.compare(after, prefix) == Ordering::Greater => match cursor
+                .seek(after)?
+            {
+                Some(ref r) if self.compare(r.key(), after) == Ordering::Equal => cursor.next()?,
+                r => r,
+            },
+            _ => cursor.seek(prefix)?,
+        };
+
+        let mut page = Page::default();
+        while let Some(rec) = r {
+            if !rec.key().starts_with(prefix) {
+                break;
+            }
+            if page.records.len() == limit {
+                // with a limit of 0 there's no last record, so the token is
+                // wherever this page started. the prefix itself does for
+                // that, as it's never after anything that matches it
+                page.next = Some(match page.records.last() {
+                    Some(last) => last.key.clone(),
+                    None => after.unwrap_or(prefix).to_vec(),
+                });
+                break;
+            }
+            page.records.push(rec.to_owned());
+            r = cursor.next()?;
+        }
+
+        Ok(page)
+    }
+
+    // records matching a pattern where % is anything within one level of the
+    // '.' hierarchy and * is anything at all, as in IMAP LIST. everything up
+    // to the first wildcard is a plain prefix, so we seek straight to it
+    pub fn iter_match(&self, pattern: &[u8]) -> MatchIter<'_> {
+        let head = pattern
+            .iter()
+            .position(|&c| c == b'%' || c == b'*')
+            .unwrap_or(pattern.len());
+        MatchIter {
+            iter: self.iter_prefix(&pattern[..head]),
+            pattern: pattern.to_vec(),
+        }
+    }
+
+    // only the head and key of each record is read, so pages holding nothing
+    // but values are never touched
+    pub fn keys(&self) -> Keys<'_> {
+        Keys {
+            iter: self.iter_live(),
+        }
+    }
+
+    // a cursor positioned before the first record
+    pub fn cursor(&self) -> Cursor<'_> {
+        Cursor {
+            db: self,
+            offset: START_OFFSET,
+        }
+    }
+
+    // live records from the last key back to the first. each step is a fresh
+    // search, so this is O(n log n) rather than O(n)
+    pub fn iter_rev(&self) -> RevIter<'_> {
+        RevIter {
+            cursor: Cursor {
+                db: self,
+                offset: 0,
+            },
+            done: false,
+        }
+    }
+
+    pub fn iter_prefix(&self, prefix: &[u8]) -> DbIter<'_> {
+        DbIter {
+            db: self,
+            prefix: prefix.to_vec(),
+            offset: None,
+            done: false,
+        }
+    }
+
+    // cyrusdb_foreach: for every record matching the prefix that goodp likes,
+    // call cb. a nonzero return from cb stops the walk and is passed back,
+    // otherwise the result is 0
+    pub fn foreach<G, C>(&self, prefix: &[u8], mut goodp: G, mut cb: C) -> Result<i32, Error>
+    where
+        G: FnMut(&[u8], &[u8]) -> bool,
+        C: FnMut(&[u8], &[u8]) -> i32,
+    {
+        for r in self.iter_prefix(prefix) {
+            let r = r?;
+            if !goodp(r.key(), r.value()) {
+                continue;
+            }
+            match cb(r.key(), r.value()) {
+                0 => {}
+                n => return Ok(n),
+            }
+        }
+        Ok(0)
+    }
+
+    // a read-only view of the database as it was committed right now, which
+    // later commits don't change. writers rewrite pointers in records that
+    // are already there, so the view is a copy of the file up to
+    // current_size, taken under a shared lock. after that it needs no locks
+    // and holds nobody up, but it costs as much memory as the file
+    pub fn snapshot(&self) -> Result<Snapshot<'_>, Error> {
+        let mut options = self.options.clone();
+        options
+            .read_only(true)
+            .create(false)
+            .recover(false)
+            .preload_index(false);
+
+        let mut db = options.open(&self.path)?;
+        db.lock_current(LockState::Shared)?;
+        let copied = db.refresh().map(|_| {
+            let end = db.header.get().current_size.min(db.map.len());
+            copy_map(&db.map.as_slice()[..end])
+        });
+        db.unlock();
+        db.map.set(copied?);
+        db.options.lock = LockMode::Disabled;
+
+        Ok(Snapshot {
+            db,
+            _parent: PhantomData,
+        })
+    }
+
+    pub fn begin(&mut self) -> Result<Txn<'_>, Error> {
+        self.check_writable()?;
+        self.lock_exclusive()?;
+        self.undo.clear();
+        self.txn_num += 1;
+        let start = self.header.get().current_size;
+        Ok(Txn {
+            db: self,
+            start,
+            done: false,
+        })
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let mut txn = self.begin()?;
+        txn.put(key, value)?;
+        txn.commit()
+    }
+
+    // store the new value only if the current one is what the caller expects,
+    // where None means the key must not exist yet
+    pub fn store_if(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        value: &[u8],
+    ) -> Result<(), Error> {
+        let mut txn = self.begin()?;
+        txn.store_if(key, expected, value)?;
+        txn.commit()
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool, Error> {
+        let mut txn = self.begin()?;
+        let found = txn.delete(key)?;
+        txn.commit()?;
+        Ok(found)
+    }
+
+    // what diff() found, done here in one transaction: adds, changes and
+    // removes. each one has to find the value it expects, nothing for an add
+    // or the old value otherwise, and if any doesn't, it's ValueMismatch and
+    // none of them happen. says how many there were
+    pub fn apply_patch<I>(&mut self, patch: I) -> Result<usize, Error>
+    where
+        I: IntoIterator<Item = DiffEntry>,
+    {
+        let mut txn = self.begin()?;
+        let mut count = 0;
+        for entry in patch {
+            match entry {
+                DiffEntry::Added { key, value } => txn.store_if(&key, None, &value)?,
+                DiffEntry::Changed { key, old, new } => txn.store_if(&key, Some(&old), &new)?,
+                DiffEntry::Removed { key, value } => {
+                    if txn.get(&key)?.as_ref().map(|r| r.value()) != Some(&value[..]) {
+                        return Err(Error::ValueMismatch);
+                    }
+                    txn.delete(&key)?;
+                }
+            }
+            count += 1;
+        }
+        txn.commit()?;
+        Ok(count)
+    }
+
+    // delete every record f says to, in one transaction, and say how many
+    // went. f sees each record under the write lock, so nothing can change
+    // between it deciding and the delete
+    pub fn delete_where<F>(&mut self, mut f: F) -> Result<usize, Error>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<bool, Error>,
+    {
+        let mut txn = self.begin()?;
+        let mut count = 0;
+        // fetchnext only finds what's after the key it's given, so starting
+        // it at the empty key would pass over a record that has one
+        let mut next = match txn.get(b"")? {
+            Some(r) => Some(r),
+            None => txn.fetchnext(b"")?,
+        };
+        while let Some(r) = next {
+            let key = r.key().to_vec();
+            if f(r.key(), r.value())? {
+                txn.delete(&key)?;
+                count += 1;
+            }
+            next = txn.fetchnext(&key)?;
+        }
+        txn.commit()?;
+        Ok(count)
+    }
+
+    pub fn write_batch(&mut self, batch: &WriteBatch) -> Result<(), Error> {
+        let mut txn = self.begin()?;
+        for op in &batch.ops {
+            match *op {
+                BatchOp::Put(ref key, ref value) => txn.put(key, value)?,
+                BatchOp::Delete(ref key) => {
+                    txn.delete(key)?;
+                }
+            }
+        }
+        txn.commit()
+    }
+
+    pub fn repack(&mut self) -> Result<(), Error> {
+        self.check_writable()?;
+        self.lock_exclusive()?;
+        let r = self.repack_locked();
+        self.unlock();
+        r
+    }
+
+    // throw away every record, leaving just the header and DUMMY. it's done
+    // by writing a fresh file and renaming it over this one, so readers see
+    // either everything or nothing
+    pub fn clear(&mut self) -> Result<(), Error> {
+        self.check_writable()?;
+        self.lock_exclusive()?;
+        let r = self.clear_locked();
+        self.unlock();
+        r
+    }
+
+    fn clear_locked(&mut self) -> Result<(), Error> {
+        self.replace_locked(|_, _| Ok(()))
+    }
+
+    // write a bloom filter of every live key out to the .bloom sidecar, and
+    // start using it. it goes stale with the next repack and has to be built
+    // again, and gets less useful the more is written after it. it's only
+    // used with a comparator that's bytewise
+    pub fn build_bloom(&mut self) -> Result<(), Error> {
+        let bloom = self.with_read_lock(|| {
+            let mut bloom = Bloom::new(
+                self.header.get().num_records as usize,
+                self.header.get().generation,
+                self.header.get().current_size,
+            );
+            for r in self.iter_live() {
+                bloom.insert(r?.key());
+            }
+            Ok(bloom)
+        })?;
+        bloom.save(&self.path)?;
+        self.bloom = Some(bloom);
+        Ok(())
+    }
+
+    // write a freshly packed copy to a new file, leaving this one as it is.
+    // that only needs a shared lock, so writers just wait for it
+    pub fn repack_to<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let f = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        let built = self.with_read_lock(|| {
+            let mut packer = Packer::new(f)?;
+            self.pack_into(&mut packer)?;
+            packer.finish(self.header.get().generation + 1)
+        });
+        if let Err(e) = built {
+            fs::remove_file(&path).ok();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // the same, but with only the records whose keys start with prefix, say
+    // one user's for a backup or a move to another server. they're read
+    // under one shared lock, so it's a consistent copy
+    pub fn export_prefix<P: AsRef<Path>>(&self, prefix: &[u8], path: P) -> Result<(), Error> {
+        let mut builder = Builder::create(path)?;
+        self.with_read_lock(|| {
+            for r in self.iter_prefix(prefix) {
+                let r = r?;
+                builder.add(r.key(), r.value())?;
+            }
+            Ok(())
+        })?;
+        builder.finish()?;
+        Ok(())
+    }
+
+    // the keys as cyrus mailbox names, laid out as the folder tree they make,
+    // everything from prefix down or with "" all of it. see mailboxes.rs
+    pub fn tree(&self, prefix: &str) -> Result<Vec<TreeEntry>, Error> {
+        self.with_read_lock(|| mailboxes::tree(self, prefix))
+    }
+
+    fn repack_locked(&mut self) -> Result<(), Error> {
+        self.replace_locked(|db, packer| db.pack_into(packer))
+    }
+
+    fn pack_into(&self, packer: &mut Packer) -> Result<(), Error> {
+        let mut offset = self.next_offset(START_OFFSET)?;
+        while offset != 0 {
+            let r = self.record_at(offset)?;
+            packer.add(r.key(), r.value(), r.level)?;
+            offset = self.next_offset(offset)?;
+        }
+        Ok(())
+    }
+
+    // every whole-file rewrite goes through here, as in cyrus: build the new
+    // file at path.NEW, sync it, and rename it over the original while we
+    // still hold the exclusive lock. anyone waiting on the old file notices
+    // it's been replaced when they get the lock, so nobody ever sees a
+    // half-written file
+    fn replace_locked<F>(&mut self, fill: F) -> Result<(), Error>
+    where
+        F: FnOnce(&Db, &mut Packer) -> Result<(), Error>,
+    {
+        let mut new_path = OsString::from(&self.path);
+        new_path.push(".NEW");
+
+        let f = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&new_path)?;
+
+        let built = Packer::new(f).and_then(|mut packer| {
+            fill(self, &mut packer)?;
+            packer.finish(self.header.get().generation + 1)
+        });
+        if let Err(e) = built {
+            fs::remove_file(&new_path).ok();
+            return Err(e);
+        }
+
+        fs::rename(&new_path, &self.path)?;
+
+        // and make sure the rename itself survives a crash
+        if let Some(dir) = self.path.parent() {
+            let dir = match dir.as_os_str().is_empty() {
+                true => Path::new("."),
+                false => dir,
+            };
+            File::open(dir)?.sync_all()?;
+        }
+
+        // the fresh file doesn't need checking again
+        let options = self.options.clone();
+        let mut reopen = options.clone();
+        reopen.checkpoint_threshold = None;
+        *self = reopen.open(&self.path)?;
+        self.options = options;
+
+        Ok(())
+    }
+
+    // before the first change in a transaction, flag the header so anyone
+    // finding it later knows we didn't finish
+    fn mark_dirty(&mut self) -> Result<(), Error> {
+        if !self.header.get().flags.contains(HeaderFlags::DIRTY) {
+            self.header.get_mut().flags.insert(HeaderFlags::DIRTY);
+            write_header(&self.file, &self.header.get())?;
+            if self.options.sync != SyncPolicy::Never {
+                self.file.sync_data()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn store(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.mark_dirty()?;
+        let loc = self.find_loc(key)?;
+
+        let level = random_level();
+
+        // replacing a record means linking around all of its levels, even
+        // the ones the new record doesn't have
+        let mut max_level = level;
+        if let Some(offset) = loc.record {
+            max_level = max_level.max(self.record_at(offset)?.level);
+        } else {
+            self.header.get_mut().num_records += 1;
+        }
+
+        let mut next_loc = vec![0; level as usize + 1];
+        next_loc[1..].copy_from_slice(&loc.forward_loc[..level as usize]);
+
+        let offset = self.append_record(RecordType::Record, level, key, value, &next_loc)?;
+
+        let mut new_loc = loc.forward_loc;
+        for l in new_loc.iter_mut().take(level as usize) {
+            *l = offset;
+        }
+        self.stitch(&loc, max_level, &new_loc)?;
+
+        self.map.set(map_file(&self.file)?);
+
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<bool, Error> {
+        let loc = self.find_loc(key)?;
+        let offset = match loc.record {
+            Some(offset) => offset,
+            None => return Ok(false),
+        };
+        self.mark_dirty()?;
+
+        let level = self.record_at(offset)?.level;
+        self.header.get_mut().num_records -= 1;
+
+        // the DELETE record stands in for the old one on level 0, so that
+        // recovery can tell the pointer to it was changed in this transaction
+        let next = loc.forward_loc[0];
+        let offset = self.append_record(RecordType::Delete, 0, &[], &[], &[next])?;
+
+        let mut new_loc = loc.forward_loc;
+        new_loc[0] = offset;
+        self.stitch(&loc, level, &new_loc)?;
+
+        self.map.set(map_file(&self.file)?);
+
+        Ok(true)
+    }
+
+    // take a shared lock for the duration of a read, unless we already hold
+    // one (or the write lock)
+    fn with_read_lock<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        if self.options.lock == LockMode::Disabled || self.lock.get() != LockState::Unlocked {
+            return f();
+        }
+
+        lock_file(&self.file, libc::F_RDLCK)?;
+        self.lock.set(LockState::Shared);
+        let r = self.catch_up().and_then(|_| f());
+        self.unlock();
+        r
+    }
+
+    // what refresh does, for a read: see everything committed since we last
+    // looked, as cyrus' read_lock does. if the file was replaced by a repack
+    // meanwhile, nobody writes to the one we have open any more, so it still
+    // reads as it did when it was replaced, until reopen()
+    fn catch_up(&self) -> Result<(), Error> {
+        self.map.follow(&self.file)?;
+        let header = read_header(self.map.as_slice(), self.options.verify_checksums)?;
+        if header.current_size > self.map.len() {
+            return Err(Error::InvalidFileSize);
+        }
+        self.header.set(header);
+        self.end.set(header.current_size);
+        Ok(())
+    }
+
+    fn lock_exclusive(&mut self) -> Result<(), Error> {
+        self.lock_current(LockState::Exclusive)?;
+
+        // pick up anything other writers committed since we last looked,
+        // and clean up after any that didn't make it
+        self.refresh()?;
+        if self.needs_recovery() {
+            self.recovery()?;
+        }
+
+        Ok(())
+    }
+
+    // lock the file that's at our path right now. if someone replaced it (eg
+    // repack) while we were waiting, we're holding a lock on a file nobody
+    // else is looking at, so open the new one and try again
+    fn lock_current(&mut self, state: LockState) -> Result<(), Error> {
+        let typ = match state {
+            LockState::Shared => libc::F_RDLCK,
+            _ => libc::F_WRLCK,
+        };
+
+        while self.options.lock == LockMode::Fcntl {
+            lock_file(&self.file, typ)?;
+            self.lock.set(state);
+
+            if !self.replaced()? {
+                break;
+            }
+
+            self.unlock();
+            self.reopen_file()?;
+        }
+
+        Ok(())
+    }
+
+    // whether the file at our path isn't the one we have open any more
+    fn replaced(&self) -> Result<bool, Error> {
+        let ours = self.file.metadata()?;
+        let theirs = fs::metadata(&self.path)?;
+        Ok(ours.dev() != theirs.dev() || ours.ino() != theirs.ino())
+    }
+
+    fn reopen_file(&mut self) -> Result<(), Error> {
+        self.file = fs::OpenOptions::new()
+            .read(true)
+            .write(self.writable)
+            .open(&self.path)?;
+        Ok(())
+    }
+
+    // whether a transaction was left unfinished, so that the next writer to
+    // lock the file will roll it back
+    pub fn needs_recovery(&self) -> bool {
+        self.header.get().flags.contains(HeaderFlags::DIRTY) || !self.tail_is_blank()
+    }
+
+    // everything after the last commit should be blank space left over from
+    // transactions we threw away. anything else is from one that didn't finish
+    fn tail_is_blank(&self) -> bool {
+        let mut offset = self.header.get().current_size;
+        while offset < self.map.len() {
+            match self.record_at(offset) {
+                Ok(ref r) if r.typ == RecordType::Blank => offset += r.len,
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    fn refresh(&mut self) -> Result<(), Error> {
+        self.map.set(map_file(&self.file)?);
+        self.header.set(read_header(
+            self.map.as_slice(),
+            self.options.verify_checksums,
+        )?);
+        self.end.set(self.header.get().current_size);
+        // whoever repacked it may have built a new one
+        if matches!(self.bloom, Some(ref b) if b.generation != self.header.get().generation) {
+            let generation = self.header.get().generation;
+            self.bloom = Bloom::load(&self.path).filter(|b| b.generation == generation);
+        }
+        Ok(())
+    }
+
+    fn unlock(&self) {
+        if self.lock.get() != LockState::Unlocked {
+            lock_file(&self.file, libc::F_UNLCK).ok();
+            self.lock.set(LockState::Unlocked);
+        }
+    }
+
+    fn check_writable(&self) -> Result<(), Error> {
+        match self.writable {
+            true => Ok(()),
+            false => Err(Error::ReadOnly),
+        }
+    }
+
+    fn savepoint(&self) -> Savepoint {
+        Savepoint {
+            txn_num: self.txn_num,
+            end: self.end.get(),
+            undo: self.undo.len(),
+            num_records: self.header.get().num_records,
+        }
+    }
+
+    // put back every pointer changed since the savepoint, newest first, then
+    // drop the records written after it
+    fn rollback_to(&mut self, sp: &Savepoint) -> Result<(), Error> {
+        if sp.txn_num != self.txn_num || sp.end > self.end.get() || sp.undo > self.undo.len() {
+            return Err(Error::InvalidSavepoint);
+        }
+
+        while self.undo.len() > sp.undo {
+            if let Some((offset, next_loc)) = self.undo.pop() {
+                let r = self.record_at(offset)?;
+                self.rewrite_record(&r, &next_loc)?;
+            }
+        }
+
+        self.end.set(sp.end);
+        self.header.get_mut().num_records = sp.num_records;
+        self.blank_tail()?;
+        self.map.set(map_file(&self.file)?);
+
+        Ok(())
+    }
+
+    fn commit(&mut self, start: usize) -> Result<(), Error> {
+        // nothing written, nothing to do
+        if self.end.get() == self.header.get().current_size {
+            return self.clear_dirty();
+        }
+
+        self.append_record(RecordType::Commit, 0, &[], &[], &[start])?;
+
+        // if we wrote over blank space from an earlier abort, whatever's
+        // left of it needs to be blank again
+        self.blank_tail()?;
+
+        let sync = match self.options.sync {
+            SyncPolicy::EveryCommit => true,
+            SyncPolicy::Periodic(interval) => self.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        };
+
+        // all records must be on disk before the header points past them
+        if sync {
+            self.file.sync_data()?;
+        }
+
+        let header = Header {
+            current_size: self.end.get(),
+            flags: self.header.get().flags - HeaderFlags::DIRTY,
+            ..self.header.get()
+        };
+        write_header(&self.file, &header)?;
+
+        if sync {
+            self.file.sync_data()?;
+            self.last_sync = Instant::now();
+        }
+
+        self.header.set(header);
+        self.map.set(map_file(&self.file)?);
+
+        // we still hold the lock, so if it's time, repack now
+        let due = match self.options.repack {
+            Some(ref policy) => policy.due(&self.header.get()),
+            None => false,
+        };
+        if due {
+            self.repack_locked()?;
+        }
+
+        Ok(())
+    }
+
+    fn abort(&mut self) -> Result<(), Error> {
+        // nothing written, nothing to undo
+        if self.end.get() == self.header.get().current_size {
+            return self.clear_dirty();
+        }
+        self.recovery()
+    }
+
+    // roll back to the last commit: throw away everything past current_size,
+    // and repair any pointers the unfinished transaction changed
+    fn recovery(&mut self) -> Result<(), Error> {
+        self.map.set(map_file(&self.file)?);
+        self.header.set(read_header(
+            self.map.as_slice(),
+            self.options.verify_checksums,
+        )?);
+        self.end.set(self.header.get().current_size);
+
+        // level 0 always has a committed pointer, so follow it to find every
+        // live record in order
+        let mut chain = vec![];
+        let mut offset = START_OFFSET;
+        while offset != 0 {
+            chain.push(offset);
+            offset = self.next_offset(offset)?;
+        }
+
+        // and rebuild the upper levels from that, back to front
+        let mut next = [0; MAX_LEVEL as usize];
+        for &offset in chain.iter().rev() {
+            let r = self.record_at(offset)?;
+            // every record has at least level 1, and this is a file that's
+            // already gone wrong once, so don't take that on trust
+            if r.level == 0 {
+                return Err(Error::InvalidLevel);
+            }
+
+            let mut next_loc = r.next_locs();
+            for loc in next_loc.iter_mut().take(2) {
+                if *loc >= self.end.get() {
+                    *loc = 0;
+                }
+            }
+            let level = r.level as usize;
+            next_loc[2..level + 1].copy_from_slice(&next[1..level]);
+            for l in next.iter_mut().take(r.level as usize) {
+                *l = offset;
+            }
+
+            if next_loc != r.next_locs() {
+                self.rewrite_record(&r, &next_loc)?;
+            }
+        }
+
+        self.blank_tail()?;
+        self.file.sync_data()?;
+
+        self.clear_dirty()?;
+        self.map.set(map_file(&self.file)?);
+
+        Ok(())
+    }
+
+    // the transaction was rolled all the way back, so the file is already
+    // what it was at the last commit, apart from the flag
+    fn clear_dirty(&mut self) -> Result<(), Error> {
+        if self.header.get().flags.contains(HeaderFlags::DIRTY) {
+            self.header.get_mut().flags.remove(HeaderFlags::DIRTY);
+            write_header(&self.file, &self.header.get())?;
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn find_loc(&self, key: &[u8]) -> Result<Location, Error> {
+        let mut loc = Location {
+            record: None,
+            back_loc: [0; MAX_LEVEL as usize],
+            forward_loc: [0; MAX_LEVEL as usize],
+        };
+
+        let mut r = self.record_at(START_OFFSET)?;
+        let mut level = r.level;
+
+        while level > 0 {
+            let l = level - 1;
+            loc.back_loc[l as usize] = r.offset;
+            loc.forward_loc[l as usize] = 0;
+
+            if let Some(next) = self.record_after(&r, l)? {
+                loc.forward_loc[l as usize] = next.offset;
+                match self.compare(key, next.key()) {
+                    Ordering::Greater => {
+                        r = next;
+                        continue;
+                    }
+                    Ordering::Equal if l == 0 => loc.record = Some(next.offset),
+                    _ => {}
+                }
+            }
+
+            level -= 1;
+        }
+
+        if let Some(offset) = loc.record {
+            let r = self.record_at(offset)?;
+            for l in 0..r.level {
+                loc.forward_loc[l as usize] = self.skip_delete(r.next_at(l))?;
+            }
+        }
+
+        Ok(loc)
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.options.comparator.compare(a, b)
+    }
+
+    // the offset of the last live record, or the DUMMY if there are none.
+    // like find_loc, but always going right when we can
+    fn find_last(&self) -> Result<usize, Error> {
+        let mut r = self.record_at(START_OFFSET)?;
+        let mut level = r.level;
+
+        while level > 0 {
+            match self.record_after(&r, level - 1)? {
+                Some(next) => r = next,
+                None => level -= 1,
+            }
+        }
+
+        Ok(r.offset)
+    }
+
+    // which key a DELETE took out. it only says what came after, so find the
+    // record before that: when its pointer was changed to the DELETE, the
+    // old one, to the deleted record, was kept in its other level 0 slot. it
+    // stays there until that slot is needed again, and then there's no
+    // telling any more
+    fn deleted_key(&self, delete: &Record) -> Result<Option<Vec<u8>>, Error> {
+        let next = match delete.loc(0) {
+            0 => None,
+            offset => Some(self.record_at(offset)?),
+        };
+        let prev = match next {
+            None => self.find_last()?,
+            Some(ref next) => self.find_loc(next.key())?.back_loc[0],
+        };
+        let prev = self.record_at(prev)?;
+
+        for slot in prev.locs().take(2) {
+            if slot >= delete.offset || slot < START_OFFSET {
+                continue;
+            }
+            let r = match self.skip_delete(slot).and_then(|at| self.record_at(at)) {
+                Ok(r) => r,
+                Err(_) => continue,
+            };
+            let after_prev = prev.typ == RecordType::Dummy
+                || self.compare(r.key(), prev.key()) == Ordering::Greater;
+            let before_next = match next {
+                Some(ref next) => self.compare(r.key(), next.key()) == Ordering::Less,
+                None => true,
+            };
+            if r.typ == RecordType::Record && r.offset < delete.offset && after_prev && before_next
+            {
+                return Ok(Some(r.key().to_vec()));
+            }
+        }
+        Ok(None)
+    }
+
+    // DELETE records are invisible when following level 0 pointers
+    fn skip_delete(&self, mut offset: usize) -> Result<usize, Error> {
+        let mut seen = vec![];
+        while offset != 0 {
+            let r = self.record_at(offset)?;
+            if r.typ != RecordType::Delete {
+                break;
+            }
+            if seen.contains(&offset) {
+                return Err(Error::PointerCycle { offset });
+            }
+            seen.push(offset);
+            offset = r.loc(0);
+        }
+        Ok(offset)
+    }
+
+    // the next live record after r on a level. keys only ever go up along a
+    // level, so if they don't, the pointers are corrupt and following them
+    // could go round forever
+    fn record_after(&self, r: &Record, level: u8) -> Result<Option<Record<'_>>, Error> {
+        let next = match self.record_skip_delete(r.next_at(level))? {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+        if r.typ != RecordType::Dummy && self.compare(next.key(), r.key()) != Ordering::Greater {
+            return Err(Error::PointerCycle {
+                offset: next.offset,
+            });
+        }
+        Ok(Some(next))
+    }
+
+    // where the live record after this one on level 0 is, or 0
+    fn next_offset(&self, offset: usize) -> Result<usize, Error> {
+        let r = self.record_at(offset)?;
+        Ok(self.record_after(&r, 0)?.map_or(0, |next| next.offset))
+    }
+
+    fn record_skip_delete(&self, offset: usize) -> Result<Option<Record<'_>>, Error> {
+        match self.skip_delete(offset)? {
+            0 => Ok(None),
+            offset => Ok(Some(self.record_at(offset)?)),
+        }
+    }
+
+    // point every record before the location at the new records, for each
+    // level up to (but not including) max_level
+    fn stitch(&mut self, loc: &Location, max_level: u8, new_loc: &[usize]) -> Result<(), Error> {
+        let mut level = 0;
+        while level < max_level {
+            let r = self.record_at(loc.back_loc[level as usize])?;
+            // remember what it was, for rolling back to a savepoint. it has
+            // to be now, since r reads its pointers from the file
+            let was = r.next_locs();
+            let mut next_loc = was.clone();
+
+            let top = r.level.min(max_level);
+            for l in level..top {
+                r.set_next(&mut next_loc, l, new_loc[l as usize]);
+            }
+
+            self.rewrite_record(&r, &next_loc)?;
+            self.undo.push((r.offset, was));
+
+            level = top;
+        }
+
+        Ok(())
+    }
+
+    fn append_record(
+        &mut self,
+        typ: RecordType,
+        level: u8,
+        key: &[u8],
+        value: &[u8],
+        next_loc: &[usize],
+    ) -> Result<usize, Error> {
+        let buf = format_record(typ, level, key, value, next_loc);
+
+        let offset = self.end.get();
+        self.file.write_all_at(&buf, offset as u64)?;
+        *self.end.get_mut() += buf.len();
+
+        Ok(offset)
+    }
+
+    // turn everything from the end of our records to the end of the file into
+    // blank space, so the next writer can reuse it rather than growing the
+    // file again
+    fn blank_tail(&mut self) -> Result<(), Error> {
+        let size = self.file.metadata()?.len() as usize;
+        match size - self.end.get() {
+            0 => Ok(()),
+            len if len < MIN_RECORD => Ok(self.file.set_len(self.end.get() as u64)?),
+            len => self.blank_region(self.end.get(), len),
+        }
+    }
+
+    // cover a region nothing points into with a single BLANK record. only the
+    // head is written; the value is whatever was there before
+    fn blank_region(&mut self, offset: usize, len: usize) -> Result<(), Error> {
+        // right on the edge of needing a long length, neither form fits, so
+        // peel a minimal blank off the front
+        if len == MIN_RECORD + u32::MAX as usize + 1 {
+            self.blank_region(offset, MIN_RECORD)?;
+            return self.blank_region(offset + MIN_RECORD, len - MIN_RECORD);
+        }
+
+        let val_len = match len - MIN_RECORD {
+            n if n >= u32::MAX as usize => n - 8,
+            n => n,
+        };
+        let mut buf = format_head(RecordType::Blank, 0, 0, val_len, &[0]);
+        buf.extend_from_slice(&[0u8; 4]); // tail crc, which nobody checks
+        self.file.write_all_at(&buf, offset as u64)?;
+
+        Ok(())
+    }
+
+    // replace the pointers in an existing record, and fix up its head crc
+    fn rewrite_record(&self, r: &Record, next_loc: &[usize]) -> Result<(), Error> {
+        let ptr_offset = r.key_offset - 8 - 8 * next_loc.len();
+
+        let mut buf: Vec<u8> = self.slice(r.offset, ptr_offset - r.offset).to_vec();
+        let mut word = [0u8; 8];
+        for loc in next_loc {
+            BigEndian::write_u64(&mut word, *loc as u64);
+            buf.extend_from_slice(&word);
+        }
+        let mut crc = [0u8; 4];
+        BigEndian::write_u32(&mut crc, CRC32.checksum(&buf));
+        buf.extend_from_slice(&crc);
+
+        self.file.write_all_at(&buf, r.offset as u64)?;
+
+        Ok(())
+    }
+
+    fn slice(&self, offset: usize, len: usize) -> &[u8] {
+        &self.map.as_slice()[offset..offset + len]
+    }
+
+    fn record_at(&self, offset: usize) -> Result<Record<'_>, Error> {
+        // permissive reads take records as they find them, and it's up to
+        // whatever hands them out to keep() the good ones
+        if self.options.permissive {
+            return self.parse_record(offset, false);
+        }
+        let r = self.parse_record(offset, self.options.verify_checksums)?;
+        if self.options.verify_tails {
+            r.verify()?;
+        }
+        Ok(r)
+    }
+
+    // the key of the RECORD at offset, live or not. until the next repack the
+    // file only grows, so one seen once stays where it was
+    pub(crate) fn key_at(&self, offset: usize) -> Result<&[u8], Error> {
+        self.with_read_lock(|| {
+            let r = self.record_at(offset)?;
+            match r.typ {
+                RecordType::Record => Ok(r.key()),
+                _ => Err(Error::InvalidOffset),
+            }
+        })
+    }
+
+    // every record in the file in the order they were written, dead ones and
+    // all, for exports that want to show how it's laid out
+    #[cfg(feature = "parquet")]
+    pub(crate) fn each_record<F>(&self, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&Record) -> Result<(), Error>,
+    {
+        self.with_read_lock(|| {
+            let mut offset = START_OFFSET;
+            while offset < self.map.len() {
+                let r = self.record_at(offset)?;
+                if self.keep(&r) {
+                    f(&r)?;
+                }
+                offset += r.len;
+            }
+            Ok(())
+        })
+    }
+
+    // replay every good record from the start of the file. a DELETE only
+    // says what the next record was, so it takes out the key just before
+    // that one, but only if that key's record pointed there too, possibly
+    // through other DELETEs. a transaction whose COMMIT was lost just gets
+    // folded into the next one that has one
+    fn salvage_scan(
+        &self,
+        report: &mut SalvageReport,
+    ) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, Error> {
+        enum Op {
+            Put(usize, Vec<u8>, Vec<u8>),
+            Delete(usize),
+        }
+
+        // where a pointer really leads once any DELETEs are stepped over
+        fn follow(deletes: &HashMap<usize, usize>, mut offset: usize) -> usize {
+            for _ in 0..=deletes.len() {
+                match deletes.get(&offset) {
+                    Some(&next) => offset = next,
+                    None => break,
+                }
+            }
+            offset
+        }
+
+        let mut live: BTreeMap<Vec<u8>, (usize, Vec<u8>)> = BTreeMap::new();
+        let mut keys: HashMap<usize, Vec<u8>> = HashMap::new();
+        let mut slots: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut deletes: HashMap<usize, usize> = HashMap::new();
+        let mut pending = vec![];
+
+        let mut offset = START_OFFSET;
+        let mut damaged = false;
+        while offset < self.map.len() {
+            let r = match self.parse_record(offset, true) {
+                Ok(r) if r.verify().is_ok() => r,
+                _ => {
+                    report.damaged += !damaged as u64;
+                    damaged = true;
+                    offset += 8;
+                    continue;
+                }
+            };
+            damaged = false;
+
+            match r.typ {
+                RecordType::Record => {
+                    keys.insert(offset, r.key().to_vec());
+                    slots.insert(offset, r.locs().take(2).collect());
+                    pending.push(Op::Put(offset, r.key().to_vec(), r.value().to_vec()));
+                }
+                RecordType::Delete => {
+                    deletes.insert(offset, r.loc(0));
+                    pending.push(Op::Delete(r.loc(0)));
+                }
+                RecordType::Commit => {
+                    for op in pending.drain(..) {
+                        match op {
+                            Op::Put(at, key, value) => {
+                                live.insert(key, (at, value));
+                            }
+                            Op::Delete(next) => {
+                                let before = match next {
+                                    0 => live.iter().next_back(),
+                                    next => keys
+                                        .get(&next)
+                                        .and_then(|k| live.range(..k.clone()).next_back()),
+                                };
+                                let key = before
+                                    .filter(|(_, (at, _))| {
+                                        slots[at].iter().any(|&slot| follow(&deletes, slot) == next)
+                                    })
+                                    .map(|(key, _)| key.clone());
+                                match key {
+                                    Some(key) => {
+                                        live.remove(&key);
+                                    }
+                                    None => report.unmatched_deletes += 1,
+                                }
+                            }
+                        }
+                    }
+                }
+                RecordType::Dummy | RecordType::Blank => {}
+            }
+            offset += r.len;
+        }
+
+        Ok(live
+            .into_iter()
+            .map(|(key, (_, value))| (key, value))
+            .collect())
+    }
+
+    // whether a record is fit to hand out. only permissive handles ever see
+    // one that isn't, and they note it down and move on
+    fn keep(&self, r: &Record) -> bool {
+        if !self.options.permissive {
+            return true;
+        }
+        let kind = if !r.head_ok() {
+            ProblemKind::HeadChecksum
+        } else if r.verify().is_err() {
+            ProblemKind::TailChecksum
+        } else {
+            return true;
+        };
+        let problem = Problem {
+            offset: r.offset,
+            kind,
+        };
+        let mut problems = self.problems.borrow_mut();
+        if !problems.contains(&problem) {
+            problems.push(problem);
+        }
+        false
+    }
+
+    // every length and pointer here comes from the file, so nothing is read
+    // until it's known to be inside the map
+    fn parse_record(&self, offset: usize, verify_head: bool) -> Result<Record<'_>, Error> {
+        if offset < START_OFFSET || !offset.is_multiple_of(8) {
+            return Err(Error::InvalidOffset);
+        }
+
+        let data = self.map.as_slice();
+        let bytes = |at: usize, n: usize| -> Result<&[u8], Error> {
+            at.checked_add(n)
+                .and_then(|end| data.get(at..end))
+                .ok_or(Error::InvalidFileSize)
+        };
+
+        let head = bytes(offset, 8)?;
+        let typ = RecordType::try_from(head[0])?;
+        let level = head[1];
+        if level > MAX_LEVEL {
+            return Err(Error::InvalidLevel);
+        }
+        let mut key_len = BigEndian::read_u16(&head[2..4]) as usize;
+        let mut val_len = BigEndian::read_u32(&head[4..8]) as usize;
+        let mut next = offset + 8;
+
+        if key_len == u16::MAX as usize {
+            key_len = BigEndian::read_u64(bytes(next, 8)?) as usize;
+            next += 8;
+        }
+        if val_len == u32::MAX as usize {
+            val_len = BigEndian::read_u64(bytes(next, 8)?) as usize;
+            next += 8;
+        }
+
+        // header including lengths, ptrs, crc32s, then key/val
+        let len = key_len
+            .checked_add(val_len)
+            .and_then(|n| n.checked_add(7))
+            .map(|n| n & !7)
+            .and_then(|n| n.checked_add((next - offset) + 8 * (level as usize + 1) + 8))
+            .ok_or(Error::InvalidFileSize)?;
+        bytes(offset, len)?;
+
+        // from here on everything is inside the record. the pointers are
+        // left where they are until something asks for one
+        let loc_offset = next;
+        next += 8 * (level as usize + 1);
+
+        let crc32_head = BigEndian::read_u32(&data[next..next + 4]);
+        let actual = match verify_head {
+            true => CRC32.checksum(&data[offset..next]),
+            false => crc32_head,
+        };
+        if crc32_head != actual {
+            return Err(Error::ChecksumMismatch {
+                structure: Structure::RecordHead,
+                offset,
+                key: Some(data[next + 8..next + 8 + key_len].to_vec()),
+                expected: crc32_head,
+                actual,
+            });
+        }
+        next += 4;
+
+        let crc32_tail = BigEndian::read_u32(&data[next..next + 4]);
+        next += 4;
+
+        let key_offset = next;
+        let val_offset = next + key_len;
+
+        let r = Record {
+            db: self,
+            offset,
+            len,
+            typ,
+            level,
+            key_len,
+            val_len,
+            loc_offset,
+            crc32_head,
+            crc32_tail,
+            key_offset,
+            val_offset,
+        };
+
+        Ok(r)
+    }
+}
+
+impl<'a> Record<'a> {
+    // pointer i, read straight out of the map. there's one for each level
+    // and an extra for level 0, which has two
+    fn loc(&self, i: usize) -> usize {
+        BigEndian::read_u64(&self.db.map.as_slice()[self.loc_offset + 8 * i..]) as usize
+    }
+
+    fn num_locs(&self) -> usize {
+        self.level as usize + 1
+    }
+
+    fn locs(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.num_locs()).map(|i| self.loc(i))
+    }
+
+    fn next_locs(&self) -> Vec<usize> {
+        self.locs().collect()
+    }
+
+    // the next record on the given level. level 0 has two pointers, and the
+    // live one is the newest that isn't past the end of the committed data
+    fn next_at(&self, level: u8) -> usize {
+        // an upper level pointer past the end belongs to a transaction we
+        // can't see. dropping to the level below gets to the same place
+        if level > 0 {
+            return match self.loc(level as usize + 1) {
+                loc if loc >= self.db.end.get() => 0,
+                loc => loc,
+            };
+        }
+        if self.num_locs() < 2 {
+            return self.loc(0);
+        }
+
+        let end = self.db.end.get();
+        match (self.loc(0), self.loc(1)) {
+            (a, b) if a >= end && b >= end => 0,
+            (a, b) if a >= end => b,
+            (a, b) if b >= end => a,
+            (a, b) => a.max(b),
+        }
+    }
+
+    // update the pointer for a level. on level 0 we keep the committed
+    // pointer intact so an interrupted transaction can be rolled back
+    fn set_next(&self, next_loc: &mut [usize], level: u8, offset: usize) {
+        if level > 0 {
+            next_loc[level as usize + 1] = offset;
+            return;
+        }
+
+        let current_size = self.db.header.get().current_size;
+        if next_loc[0] >= current_size {
+            next_loc[0] = offset;
+        } else if next_loc[1] >= current_size || next_loc[1] <= next_loc[0] {
+            next_loc[1] = offset;
+        } else {
+            next_loc[0] = offset;
+        }
+    }
+
+    // check the key and value against the tail crc. blank space has no real
+    // value, so there's nothing to check
+    fn head_ok(&self) -> bool {
+        let len = self.key_offset - 8 - self.offset;
+        CRC32.checksum(self.db.slice(self.offset, len)) == self.crc32_head
+    }
+
+    pub fn verify(&self) -> Result<(), Error> {
+        if self.typ == RecordType::Blank {
+            return Ok(());
+        }
+        let data = self
+            .db
+            .slice(self.key_offset, round_up(self.key_len + self.val_len, 8));
+        let actual = CRC32.checksum(data);
+        match actual == self.crc32_tail {
+            true => Ok(()),
+            false => Err(Error::ChecksumMismatch {
+                structure: Structure::RecordTail,
+                offset: self.offset,
+                key: Some(self.key().to_vec()),
+                expected: self.crc32_tail,
+                actual,
+            }),
+        }
+    }
+
+    // how many levels of the skip structure the record is on
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    // the next live record on one of this record's levels, 0 being the
+    // bottom one that every record is on
+    pub fn next_at_level(&self, level: u8) -> Result<Option<Record<'a>>, Error> {
+        if level >= self.level {
+            return Err(Error::InvalidLevel);
+        }
+        let db = self.db;
+        db.with_read_lock(|| db.record_after(self, level))
+    }
+
+    pub fn to_owned(&self) -> OwnedRecord {
+        OwnedRecord {
+            key: self.key().to_vec(),
+            value: self.value().to_vec(),
+        }
+    }
+
+    // where the record starts in the file
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    // good for as long as the database is, not just the record
+    pub fn key(&self) -> &'a [u8] {
+        self.db.slice(self.key_offset, self.key_len)
+    }
+
+    pub fn value(&self) -> &'a [u8] {
+        self.db.slice(self.val_offset, self.val_len)
+    }
+
+    // what dump() calls it
+    pub(crate) fn kind(&self) -> &'static str {
+        match self.typ {
+            RecordType::Dummy => "DUMMY",
+            RecordType::Record => "RECORD",
+            RecordType::Delete => "DELETE",
+            RecordType::Commit => "COMMIT",
+            RecordType::Blank => "BLANK",
+        }
+    }
+
+    // only DUMMY and RECORD have a key and value, even an empty one
+    #[cfg(feature = "parquet")]
+    pub(crate) fn has_data(&self) -> bool {
+        matches!(self.typ, RecordType::Dummy | RecordType::Record)
+    }
+
+    // read the value straight out of the map, a page at a time as it's needed
+    pub fn value_reader(&self) -> io::Cursor<&[u8]> {
+        io::Cursor::new(self.value())
+    }
+
+    fn format_data_record(&self, name: &str) -> String {
+        format!(
+            "{name} kl={key_len:08x} dl={val_len:08x} lvl={level} ({key})\n    {next_loc}",
+            name = name,
+            key_len = self.key_len,
+            val_len = self.val_len,
+            level = self.level,
+            key = std::str::from_utf8(self.key()).unwrap_or("[Utf8Error]"),
+            next_loc = self.format_next_loc(),
+        )
+    }
+
+    fn format_next_loc(&self) -> String {
+        (0..self.level + 1)
+            .map(|l| self.loc(l as usize))
+            .map(|loc| format!("{:08x}", loc))
+            .collect::<Vec<String>>()
+            .chunks(8)
+            .map(|v| v.join(" "))
+            .collect::<Vec<String>>()
+            .join("\n    ")
+    }
+
+    pub fn dump(&self) -> String {
+        match self.typ {
+            RecordType::Dummy | RecordType::Record => self.format_data_record(self.kind()),
+
+            RecordType::Delete => {
+                format!("DELETE ptr={next_loc:08x}", next_loc = self.loc(0),)
+            }
+
+            RecordType::Commit => {
+                format!("COMMIT start={next_loc:08x}", next_loc = self.loc(0),)
+            }
+
+            RecordType::Blank => format!("BLANK len={len:08x}", len = self.len),
+        }
+    }
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl SizeStats {
+    fn from_sizes(sizes: &mut [usize]) -> SizeStats {
+        if sizes.is_empty() {
+            return SizeStats::default();
+        }
+        sizes.sort_unstable();
+        let percentile = |p: usize| sizes[(sizes.len() - 1) * p / 100];
+        SizeStats {
+            min: sizes[0],
+            max: sizes[sizes.len() - 1],
+            mean: sizes.iter().sum::<usize>() as f64 / sizes.len() as f64,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+        }
+    }
+}
+
+// key and value are base64 for formats meant for people, like json, and
+// plain bytes for the rest
+#[cfg(feature = "serde")]
+fn serialize_record<S: serde::Serializer>(
+    serializer: S,
+    offset: Option<usize>,
+    key: &[u8],
+    value: &[u8],
+) -> Result<S::Ok, S::Error> {
+    use serde::ser::SerializeStruct;
+
+    struct Bytes<'a>(&'a [u8]);
+    impl<'a> serde::Serialize for Bytes<'a> {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match serializer.is_human_readable() {
+                true => serializer.serialize_str(&crate::export::encode_base64(self.0)),
+                false => serializer.serialize_bytes(self.0),
+            }
+        }
+    }
+
+    let mut s = serializer.serialize_struct("Record", 2 + offset.is_some() as usize)?;
+    if let Some(offset) = offset {
+        s.serialize_field("offset", &offset)?;
+    }
+    s.serialize_field("key", &Bytes(key))?;
+    s.serialize_field("value", &Bytes(value))?;
+    s.end()
+}
+
+#[cfg(feature = "serde")]
+impl<'a> serde::Serialize for Record<'a> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_record(serializer, Some(self.offset), self.key(), self.value())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for OwnedRecord {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_record(serializer, None, &self.key, &self.value)
+    }
+}
+
+impl OwnedRecord {
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    pub fn into_parts(self) -> (Vec<u8>, Vec<u8>) {
+        (self.key, self.value)
+    }
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch { ops: vec![] }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Put(key.to_vec(), value.to_vec()));
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Delete(key.to_vec()));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+}
+
+impl<'a> Deref for Snapshot<'a> {
+    type Target = Db;
+
+    fn deref(&self) -> &Db {
+        &self.db
+    }
+}
+
+impl<'a> Cursor<'a> {
+    // move to the first record with a key at or after this one
+    pub fn seek(&mut self, key: &[u8]) -> Result<Option<Record<'a>>, Error> {
+        let db = self.db;
+        let loc = db.with_read_lock(|| db.find_loc(key))?;
+        self.offset = loc.record.unwrap_or(loc.forward_loc[0]);
+        self.current()
+    }
+
+    // the record we're on, if any
+    pub fn current(&self) -> Result<Option<Record<'a>>, Error> {
+        let db = self.db;
+        match self.offset {
+            0 => Ok(None),
+            START_OFFSET => Ok(None),
+            offset => db.with_read_lock(|| db.record_at(offset)).map(Some),
+        }
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<Record<'a>>, Error> {
+        if self.offset == 0 {
+            return Ok(None);
+        }
+        let db = self.db;
+        let offset = self.offset;
+        self.offset = db.with_read_lock(|| db.next_offset(offset))?;
+        self.current()
+    }
+
+    // there are no back pointers, so finding the record before this one means
+    // searching for it again. moving back past the first record leaves us
+    // before it
+    pub fn prev(&mut self) -> Result<Option<Record<'a>>, Error> {
+        let db = self.db;
+        self.offset = match self.offset {
+            START_OFFSET => return Ok(None),
+            0 => db.with_read_lock(|| db.find_last())?,
+            offset => db.with_read_lock(|| {
+                let r = db.record_at(offset)?;
+                Ok(db.find_loc(r.key())?.back_loc[0])
+            })?,
+        };
+        self.current()
+    }
+}
+
+impl<'a> Iterator for MatchIter<'a> {
+    type Item = Result<Record<'a>, Error>;
+
+    fn next(&mut self) -> Option<Result<Record<'a>, Error>> {
+        for r in self.iter.by_ref() {
+            match r {
+                Ok(r) if !list_match(&self.pattern, r.key()) => continue,
+                r => return Some(r),
+            }
+        }
+        None
+    }
+}
+
+fn list_match(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => (0..=key.len()).any(|i| list_match(rest, &key[i..])),
+        Some((b'%', rest)) => (0..=key.len())
+            .take_while(|&i| i == 0 || key[i - 1] != b'.')
+            .any(|i| list_match(rest, &key[i..])),
+        Some((&c, rest)) => key.first() == Some(&c) && list_match(rest, &key[1..]),
+    }
+}
+
+impl<'a> Iterator for Keys<'a> {
+    type Item = Result<&'a [u8], Error>;
+
+    fn next(&mut self) -> Option<Result<&'a [u8], Error>> {
+        self.iter
+            .next()
+            .map(|r| r.map(|r| r.db.slice(r.key_offset, r.key_len)))
+    }
+}
+
+impl<'a> Diff<'a> {
+    fn step(&mut self) -> Result<Option<DiffEntry>, Error> {
+        if !self.started {
+            self.started = true;
+            self.a_next = self.a.next().transpose()?;
+            self.b_next = self.b.next().transpose()?;
+        }
+        loop {
+            let order = match (&self.a_next, &self.b_next) {
+                (None, None) => return Ok(None),
+                (Some(ra), Some(rb)) => self.a.db.compare(ra.key(), rb.key()),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+            };
+            let ra = match order {
+                Ordering::Greater => None,
+                _ => mem::replace(&mut self.a_next, self.a.next().transpose()?),
+            };
+            let rb = match order {
+                Ordering::Less => None,
+                _ => mem::replace(&mut self.b_next, self.b.next().transpose()?),
+            };
+            match (ra, rb) {
+                (Some(ra), Some(rb)) if ra.value() == rb.value() => continue,
+                (Some(ra), Some(rb)) => {
+                    return Ok(Some(DiffEntry::Changed {
+                        key: ra.key().to_vec(),
+                        old: ra.value().to_vec(),
+                        new: rb.value().to_vec(),
+                    }))
+                }
+                (Some(ra), None) => {
+                    return Ok(Some(DiffEntry::Removed {
+                        key: ra.key().to_vec(),
+                        value: ra.value().to_vec(),
+                    }))
+                }
+                (None, Some(rb)) => {
+                    return Ok(Some(DiffEntry::Added {
+                        key: rb.key().to_vec(),
+                        value: rb.value().to_vec(),
+                    }))
+                }
+                (None, None) => return Ok(None),
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Diff<'a> {
+    type Item = Result<DiffEntry, Error>;
+
+    fn next(&mut self) -> Option<Result<DiffEntry, Error>> {
+        if self.done {
+            return None;
+        }
+        let r = self.step();
+        if !matches!(r, Ok(Some(_))) {
+            self.done = true;
+        }
+        r.transpose()
+    }
+}
+
+impl DiffEntry {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            DiffEntry::Added { key, .. } => key,
+            DiffEntry::Removed { key, .. } => key,
+            DiffEntry::Changed { key, .. } => key,
+        }
+    }
+}
+
+impl<'a> Iterator for RevIter<'a> {
+    type Item = Result<Record<'a>, Error>;
+
+    fn next(&mut self) -> Option<Result<Record<'a>, Error>> {
+        if self.done {
+            return None;
+        }
+        match self.cursor.prev() {
+            Ok(Some(r)) => Some(Ok(r)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for DbIter<'a> {
+    type Item = Result<Record<'a>, Error>;
+
+    fn next(&mut self) -> Option<Result<Record<'a>, Error>> {
+        if self.done {
+            return None;
+        }
+
+        // the first time through, use the skip pointers to get to the start
+        // of the prefix. after that it's just the next record on level 0
+        let db = self.db;
+        let r = db.with_read_lock(|| {
+            let mut offset = match self.offset {
+                None => {
+                    let loc = db.find_loc(&self.prefix)?;
+                    loc.record.unwrap_or(loc.forward_loc[0])
+                }
+                Some(offset) => db.next_offset(offset)?,
+            };
+            while offset != 0 {
+                let r = db.record_at(offset)?;
+                if db.keep(&r) {
+                    return Ok(Some(r));
+                }
+                offset = db.next_offset(offset)?;
+            }
+            Ok(None)
+        });
+
+        match r {
+            Ok(Some(r)) if r.key().starts_with(&self.prefix) => {
+                self.offset = Some(r.offset);
+                Some(Ok(r))
+            }
+            Ok(_) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<'a> Txn<'a> {
+    pub fn get(&self, key: &[u8]) -> Result<Option<Record<'_>>, Error> {
+        self.db.get(key)
+    }
+
+    pub fn fetchnext(&self, key: &[u8]) -> Result<Option<Record<'_>>, Error> {
+        self.db.fetchnext(key)
+    }
+
+    pub fn foreach<G, C>(&self, prefix: &[u8], goodp: G, cb: C) -> Result<i32, Error>
+    where
+        G: FnMut(&[u8], &[u8]) -> bool,
+        C: FnMut(&[u8], &[u8]) -> i32,
+    {
+        self.db.foreach(prefix, goodp, cb)
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.db.store(key, value)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool, Error> {
+        self.db.remove(key)
+    }
+
+    pub fn store_if(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        value: &[u8],
+    ) -> Result<(), Error> {
+        if self.get(key)?.as_ref().map(|r| r.value()) != expected {
+            return Err(Error::ValueMismatch);
+        }
+        self.put(key, value)
+    }
+
+    pub fn savepoint(&self) -> Savepoint {
+        self.db.savepoint()
+    }
+
+    // undo everything done since the savepoint was taken. the savepoint stays
+    // valid, so it can be rolled back to again
+    pub fn rollback_to(&mut self, sp: &Savepoint) -> Result<(), Error> {
+        self.db.rollback_to(sp)
+    }
+
+    pub fn commit(mut self) -> Result<(), Error> {
+        self.done = true;
+        let r = self.db.commit(self.start);
+        self.db.unlock();
+        r
+    }
+
+    pub fn abort(mut self) -> Result<(), Error> {
+        self.done = true;
+        let r = self.db.abort();
+        self.db.unlock();
+        r
+    }
+}
+
+impl<'a> Drop for Txn<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.db.abort().ok();
+            self.db.unlock();
+        }
+    }
+}
+
+// makes a new file straight from records that are already in key order,
+// which is much quicker than putting them one at a time: it's all one pass
+// and one transaction. levels go up in the ideal pattern, where every second
+// record is on level 2, every fourth on level 3 and so on, rather than by
+// chance, and the file comes out as small as a repack would make it.
+//
+// it's an error if the file's already there. if the Builder is dropped
+// without finishing, or anything goes wrong, the file is removed again
+pub struct Builder {
+    path: PathBuf,
+    packer: Option<Packer>,
+    last: Option<Vec<u8>>,
+}
+
+impl Builder {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Builder, Error> {
+        let f = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // made first so that dropping it cleans up if the packer fails
+        let mut builder = Builder {
+            path: path.as_ref().to_path_buf(),
+            packer: None,
+            last: None,
+        };
+        builder.packer = Some(Packer::new(f)?);
+        Ok(builder)
+    }
+
+    // keys have to go up, in byte order
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        if let Some(ref last) = self.last {
+            if key <= last.as_slice() {
+                return Err(Error::OutOfOrder);
+            }
+        }
+        let packer = self.packer()?;
+        let n = packer.num_records + 1;
+        let level = (n.trailing_zeros() as u8 + 1).min(MAX_LEVEL);
+        packer.add(key, value, level)?;
+        self.last = Some(key.to_vec());
+        Ok(())
+    }
+
+    pub fn extend<I, K, V>(&mut self, records: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        for (key, value) in records {
+            self.add(key.as_ref(), value.as_ref())?;
+        }
+        Ok(())
+    }
+
+    // writes the header and commits, and opens the result
+    pub fn finish(mut self) -> Result<Db, Error> {
+        self.packer()?;
+        if let Err(e) = self
+            .packer
+            .take()
+            .map(|packer| packer.finish(1))
+            .transpose()
+        {
+            fs::remove_file(&self.path).ok();
+            return Err(e);
+        }
+        open(&self.path)
+    }
+
+    // there's only no packer once it's finished, and finish takes the
+    // Builder with it
+    fn packer(&mut self) -> Result<&mut Packer, Error> {
+        match self.packer {
+            Some(ref mut packer) => Ok(packer),
+            None => Err(Error::InternalError("builder already finished".into())),
+        }
+    }
+}
+
+impl Drop for Builder {
+    fn drop(&mut self) {
+        if self.packer.is_some() {
+            fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+// writes records in key order into a fresh file. a record's pointers can't be
+// known until the next record on each of its levels arrives, so its head is
+// written with empty pointers and filled in once they're all known
+struct Packer {
+    file: File,
+    end: usize,
+    num_records: u64,
+    pending: Vec<Pending>,
+}
+
+// pending records are stacked lowest level on top; each one is waiting for
+// the levels between the one above it and its own
+struct Pending {
+    offset: usize,
+    typ: RecordType,
+    level: u8,
+    key_len: usize,
+    val_len: usize,
+    next_loc: Vec<usize>,
+}
+
+fn ptr_index(level: u8) -> usize {
+    match level {
+        0 => 0,
+        l => l as usize + 1,
+    }
+}
+
+impl Packer {
+    fn new(file: File) -> Result<Packer, Error> {
+        let mut packer = Packer {
+            file,
+            end: START_OFFSET,
+            num_records: 0,
+            pending: vec![],
+        };
+        packer.push(RecordType::Dummy, MAX_LEVEL, &[], &[])?;
+        Ok(packer)
+    }
+
+    fn add(&mut self, key: &[u8], value: &[u8], level: u8) -> Result<(), Error> {
+        let offset = self.end;
+
+        let mut low = 0;
+        while let Some(p) = self.pending.last_mut() {
+            for l in low..p.level.min(level) {
+                p.next_loc[ptr_index(l)] = offset;
+            }
+            if p.level > level {
+                break;
+            }
+            low = p.level;
+            self.flush()?;
+        }
+
+        self.push(RecordType::Record, level, key, value)?;
+        self.num_records += 1;
+
+        Ok(())
+    }
+
+    fn finish(mut self, generation: u64) -> Result<File, Error> {
+        while !self.pending.is_empty() {
+            self.flush()?;
+        }
+
+        if self.num_records > 0 {
+            let start = START_OFFSET + self.dummy_len();
+            let buf = format_record(RecordType::Commit, 0, &[], &[], &[start]);
+            self.file.write_all_at(&buf, self.end as u64)?;
+            self.end += buf.len();
+        }
+
+        let header = Header {
+            version: HEADER_VERSION,
+            flags: HeaderFlags::empty(),
+            generation,
+            num_records: self.num_records,
+            repack_size: self.end,
+            current_size: self.end,
+        };
+        write_header(&self.file, &header)?;
+        self.file.set_len(self.end as u64)?;
+        self.file.sync_all()?;
+
+        Ok(self.file)
+    }
+
+    fn dummy_len(&self) -> usize {
+        8 + 8 * (MAX_LEVEL as usize + 1) + 8
+    }
+
+    fn push(&mut self, typ: RecordType, level: u8, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let next_loc = vec![0; level as usize + 1];
+        let buf = format_record(typ, level, key, value, &next_loc);
+        self.file.write_all_at(&buf, self.end as u64)?;
+
+        self.pending.push(Pending {
+            offset: self.end,
+            typ,
+            level,
+            key_len: key.len(),
+            val_len: value.len(),
+            next_loc,
+        });
+        self.end += buf.len();
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if let Some(p) = self.pending.pop() {
+            let buf = format_head(p.typ, p.level, p.key_len, p.val_len, &p.next_loc);
+            self.file.write_all_at(&buf, p.offset as u64)?;
+        }
+        Ok(())
     }
 }
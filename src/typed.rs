@@ -0,0 +1,247 @@
+// a Db whose keys and values are something other than bytes, turned into
+// bytes on the way in and back on the way out by a codec for each. the
+// codecs here are Raw (bytes as they are), Str (utf-8 text), U64Be (8 bytes
+// big endian, which sorts the way the numbers do), dlists and mbentries as
+// cyrus writes them, and with serde on, Serde<T> for anything that's
+// Serialize and Deserialize, kept as a dlist
+//
+//   let mut db: TypedDb<Str, Mbentry> = TypedDb::open("mailboxes.db")?;
+//   if let Some(entry) = db.get(&"user.fred".to_string())? { ... }
+//
+// a key or value that doesn't decode is an error where it turns up, and
+// iteration carries on past it
+
+use std::marker::PhantomData;
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::dlist::{self, Dlist};
+use crate::mbentry::Mbentry;
+use crate::twoskip::{self, Db, DbIter, Error};
+
+pub trait KeyCodec {
+    type Key;
+
+    fn encode_key(key: &Self::Key) -> Result<Vec<u8>, Error>;
+    fn decode_key(bytes: &[u8]) -> Result<Self::Key, Error>;
+}
+
+pub trait ValueCodec {
+    type Value;
+
+    fn encode_value(value: &Self::Value) -> Result<Vec<u8>, Error>;
+    fn decode_value(bytes: &[u8]) -> Result<Self::Value, Error>;
+}
+
+pub struct Raw;
+
+pub struct Str;
+
+pub struct U64Be;
+
+#[cfg(feature = "serde")]
+pub struct Serde<T>(PhantomData<T>);
+
+pub struct TypedDb<K, V> {
+    db: Db,
+    codecs: PhantomData<(K, V)>,
+}
+
+pub struct Iter<'a, K, V> {
+    records: DbIter<'a>,
+    codecs: PhantomData<(K, V)>,
+}
+
+impl KeyCodec for Raw {
+    type Key = Vec<u8>;
+
+    fn encode_key(key: &Vec<u8>) -> Result<Vec<u8>, Error> {
+        Ok(key.clone())
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl ValueCodec for Raw {
+    type Value = Vec<u8>;
+
+    fn encode_value(value: &Vec<u8>) -> Result<Vec<u8>, Error> {
+        Ok(value.clone())
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(bytes.to_vec())
+    }
+}
+
+fn utf8(bytes: &[u8]) -> Result<String, Error> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| Error::InvalidField { field: "str" })
+}
+
+impl KeyCodec for Str {
+    type Key = String;
+
+    fn encode_key(key: &String) -> Result<Vec<u8>, Error> {
+        Ok(key.as_bytes().to_vec())
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<String, Error> {
+        utf8(bytes)
+    }
+}
+
+impl ValueCodec for Str {
+    type Value = String;
+
+    fn encode_value(value: &String) -> Result<Vec<u8>, Error> {
+        Ok(value.as_bytes().to_vec())
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<String, Error> {
+        utf8(bytes)
+    }
+}
+
+fn u64_be(bytes: &[u8]) -> Result<u64, Error> {
+    match bytes.len() {
+        8 => Ok(BigEndian::read_u64(bytes)),
+        _ => Err(Error::InvalidField { field: "u64" }),
+    }
+}
+
+impl KeyCodec for U64Be {
+    type Key = u64;
+
+    fn encode_key(key: &u64) -> Result<Vec<u8>, Error> {
+        Ok(key.to_be_bytes().to_vec())
+    }
+
+    fn decode_key(bytes: &[u8]) -> Result<u64, Error> {
+        u64_be(bytes)
+    }
+}
+
+impl ValueCodec for U64Be {
+    type Value = u64;
+
+    fn encode_value(value: &u64) -> Result<Vec<u8>, Error> {
+        Ok(value.to_be_bytes().to_vec())
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<u64, Error> {
+        u64_be(bytes)
+    }
+}
+
+impl ValueCodec for Dlist<'static> {
+    type Value = Dlist<'static>;
+
+    fn encode_value(value: &Dlist<'static>) -> Result<Vec<u8>, Error> {
+        Ok(value.to_vec())
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<Dlist<'static>, Error> {
+        Ok(dlist::parse(bytes)?.into_owned())
+    }
+}
+
+impl ValueCodec for Mbentry {
+    type Value = Mbentry;
+
+    fn encode_value(value: &Mbentry) -> Result<Vec<u8>, Error> {
+        Ok(value.to_value())
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<Mbentry, Error> {
+        Mbentry::parse(bytes)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> ValueCodec for Serde<T> {
+    type Value = T;
+
+    fn encode_value(value: &T) -> Result<Vec<u8>, Error> {
+        dlist::to_bytes(value)
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<T, Error> {
+        dlist::from_bytes(bytes)
+    }
+}
+
+impl<K: KeyCodec, V: ValueCodec> TypedDb<K, V> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<TypedDb<K, V>, Error> {
+        Ok(TypedDb::from_db(twoskip::open(path)?))
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<TypedDb<K, V>, Error> {
+        Ok(TypedDb::from_db(twoskip::create(path)?))
+    }
+
+    pub fn from_db(db: Db) -> TypedDb<K, V> {
+        TypedDb {
+            db,
+            codecs: PhantomData,
+        }
+    }
+
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    pub fn db_mut(&mut self) -> &mut Db {
+        &mut self.db
+    }
+
+    pub fn into_db(self) -> Db {
+        self.db
+    }
+
+    pub fn get(&self, key: &K::Key) -> Result<Option<V::Value>, Error> {
+        match self.db.get_value(&K::encode_key(key)?)? {
+            Some(value) => Ok(Some(V::decode_value(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains_key(&self, key: &K::Key) -> Result<bool, Error> {
+        self.db.contains_key(&K::encode_key(key)?)
+    }
+
+    pub fn put(&mut self, key: &K::Key, value: &V::Value) -> Result<(), Error> {
+        self.db.put(&K::encode_key(key)?, &V::encode_value(value)?)
+    }
+
+    pub fn delete(&mut self, key: &K::Key) -> Result<bool, Error> {
+        self.db.delete(&K::encode_key(key)?)
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            records: self.db.iter_live(),
+            codecs: PhantomData,
+        }
+    }
+
+    // the prefix is of the encoded keys, so for Str it's the start of the
+    // text and for U64Be the high bytes
+    pub fn iter_prefix(&self, prefix: &[u8]) -> Iter<'_, K, V> {
+        Iter {
+            records: self.db.iter_prefix(prefix),
+            codecs: PhantomData,
+        }
+    }
+}
+
+impl<'a, K: KeyCodec, V: ValueCodec> Iterator for Iter<'a, K, V> {
+    type Item = Result<(K::Key, V::Value), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let r = self.records.next()?;
+        Some(r.and_then(|r| Ok((K::decode_key(r.key())?, V::decode_value(r.value())?))))
+    }
+}
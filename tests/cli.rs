@@ -0,0 +1,74 @@
+use std::path::Path;
+use std::process::Command;
+
+use tempdir::TempDir;
+
+fn twoskip<P: AsRef<Path>>(args: &[&str], path: P) -> i32 {
+    let status = Command::new(env!("CARGO_BIN_EXE_twoskip"))
+        .arg(args[0])
+        .arg(path.as_ref())
+        .args(&args[1..])
+        .output()
+        .unwrap()
+        .status;
+    status.code().unwrap()
+}
+
+#[test]
+fn verify_exit_codes() {
+    let dir = TempDir::new("twoskip").unwrap();
+
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("mailboxes.db");
+    assert_eq!(twoskip(&["verify"], &fixture), 0);
+
+    // a writer that died mid-transaction only needs recovery
+    let path = dir.path().join("dirty.db");
+    let mut db = twoskip::twoskip::create(&path).unwrap();
+    db.put(b"key", b"committed").unwrap();
+    let mut txn = db.begin().unwrap();
+    txn.put(b"key", b"uncommitted").unwrap();
+    std::mem::forget(txn);
+    drop(db);
+    assert_eq!(twoskip(&["verify"], &path), 1);
+
+    // and once that's done it's clean again
+    assert_eq!(twoskip(&["recover"], &path), 1);
+    assert_eq!(twoskip(&["verify"], &path), 0);
+
+    // a file that isn't there can't be checked at all
+    assert_eq!(twoskip(&["verify"], dir.path().join("missing.db")), 8);
+    assert_eq!(twoskip(&["get", "key"], dir.path().join("missing.db")), 2);
+}
+
+#[test]
+fn edit_dlist() {
+    let dir = TempDir::new("twoskip").unwrap();
+    let path = dir.path().join("test.db");
+
+    let value = b"%(A %(fred lrswipkxtecdan) P default V 1450299080 Q (a (b c)))";
+    let mut db = twoskip::twoskip::create(&path).unwrap();
+    db.put(b"user.fred", value).unwrap();
+    drop(db);
+
+    // spread over lines, a pair to each, and joined up again after
+    let edit = |editor: &str| {
+        Command::new(env!("CARGO_BIN_EXE_twoskip"))
+            .args(["edit", "--dlist"])
+            .arg(&path)
+            .arg("user.fred")
+            .env_remove("VISUAL")
+            .env("EDITOR", editor)
+            .output()
+            .unwrap()
+    };
+    let out = edit("cat");
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("\n  P default\n"));
+
+    assert!(edit("sed -i s/default/other/").status.success());
+    let db = twoskip::twoskip::open(&path).unwrap();
+    assert_eq!(
+        db.get_value(b"user.fred").unwrap().unwrap(),
+        b"%(A %(fred lrswipkxtecdan) P other V 1450299080 Q (a (b c)))"
+    );
+}